@@ -0,0 +1,176 @@
+// A simple memory model
+//
+// Each address has a base, which is the stack variable or the global
+// variable it is derived from by adding offsets, or unknown if it is a
+// pointer from elsewhere. Accesses with different known bases never
+// overlap. A stack variable whose address is used other than to load or
+// store, e.g. passed to a function or stored to memory, escapes, and may
+// then be accessed through any unknown pointer or by any function call.
+
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Clone, PartialEq)]
+pub enum Base {
+    Stack(usize),
+    Global(String),
+    Unknown,
+}
+
+pub struct MemInfo {
+    pub bases: HashMap<i32, Base>,
+    pub escaped: HashSet<usize>,
+}
+
+pub fn var_key(var: &Option<Rc<RefCell<Var>>>) -> usize {
+    match var {
+        Some(var) => Rc::as_ptr(var) as usize,
+        None => 0,
+    }
+}
+
+// Returns the number of definitions of each register. A register
+// defined more than once is not in SSA form.
+pub fn count_defs(bbs: &Vec<Rc<RefCell<BB>>>) -> HashMap<i32, usize> {
+    let mut ndefs = HashMap::new();
+    for bb in bbs.iter() {
+        for r in bb.borrow().params.iter() {
+            *ndefs.entry(r.borrow().vn).or_insert(0) += 1;
+        }
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            for r in ir.r0.iter().chain(ir.outs.iter()) {
+                *ndefs.entry(r.borrow().vn).or_insert(0) += 1;
+            }
+        }
+    }
+    return ndefs;
+}
+
+// Returns true if the instruction may write memory that is not
+// identified by its operands.
+pub fn clobbers_memory(op: &IRType) -> bool {
+    match op {
+        IRType::CALL
+        | IRType::TAIL_CALL
+        | IRType::ASM
+        | IRType::ATOMIC_STORE
+        | IRType::ATOMIC_FETCH_ADD
+        | IRType::ATOMIC_CAS
+        | IRType::FENCE => true,
+        _ => false,
+    }
+}
+
+impl MemInfo {
+    pub fn base(&self, r: &Option<Rc<RefCell<Reg>>>) -> Base {
+        match r {
+            Some(r) => self
+                .bases
+                .get(&r.borrow().vn)
+                .cloned()
+                .unwrap_or(Base::Unknown),
+            None => Base::Unknown,
+        }
+    }
+
+    pub fn may_alias(&self, a: &Base, b: &Base) -> bool {
+        match (a, b) {
+            (Base::Stack(x), Base::Stack(y)) => x == y,
+            (Base::Global(x), Base::Global(y)) => x == y,
+            (Base::Stack(_), Base::Global(_)) | (Base::Global(_), Base::Stack(_)) => false,
+            (Base::Stack(v), Base::Unknown) | (Base::Unknown, Base::Stack(v)) => {
+                self.escaped.contains(v)
+            }
+            _ => true,
+        }
+    }
+
+    // Returns true if a memory access with the base survives a CALL.
+    pub fn is_local(&self, base: &Base) -> bool {
+        match base {
+            Base::Stack(v) => !self.escaped.contains(v),
+            _ => false,
+        }
+    }
+
+    // Returns the base of memory written by a STORE or STORE_ARG.
+    pub fn store_base(&self, ir: &IR) -> Option<Base> {
+        match ir.op {
+            IRType::STORE => Some(self.base(&ir.r1)),
+            IRType::STORE_ARG => Some(Base::Stack(var_key(&ir.var))),
+            _ => None,
+        }
+    }
+}
+
+pub fn mem_info(bbs: &Vec<Rc<RefCell<BB>>>, ndefs: &HashMap<i32, usize>) -> MemInfo {
+    let mut m = MemInfo {
+        bases: HashMap::new(),
+        escaped: HashSet::new(),
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for bb in bbs.iter() {
+            for ir in bb.borrow().ir.iter() {
+                let ir = ir.borrow();
+                let r0 = match ir.r0.as_ref() {
+                    Some(r0) => r0.borrow().vn,
+                    None => continue,
+                };
+                if ndefs.get(&r0) != Some(&1) || m.bases.contains_key(&r0) {
+                    continue;
+                }
+
+                let base = match ir.op {
+                    IRType::BPREL => Base::Stack(var_key(&ir.var)),
+                    IRType::LABEL_ADDR | IRType::TLS_ADDR => Base::Global(ir.name.clone()),
+                    IRType::ADD | IRType::SUB => match (m.base(&ir.r1), m.base(&ir.r2)) {
+                        (Base::Unknown, Base::Unknown) => continue,
+                        (base, Base::Unknown) => base,
+                        (Base::Unknown, base) if ir.op == IRType::ADD => base,
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                m.bases.insert(r0, base);
+                changed = true;
+            }
+        }
+    }
+
+    for bb in bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            let mut addr = match ir.op {
+                IRType::LOAD => ir.r2.as_ref().map(|r| r.borrow().vn),
+                IRType::STORE => ir.r1.as_ref().map(|r| r.borrow().vn),
+                _ => None,
+            };
+            let derived = match ir.r0.as_ref() {
+                Some(r0) => m.bases.contains_key(&r0.borrow().vn),
+                None => false,
+            };
+
+            for r in uses(&ir) {
+                let vn = r.borrow().vn;
+                if Some(vn) == addr {
+                    addr = None;
+                    continue;
+                }
+                if derived {
+                    continue;
+                }
+                if let Some(Base::Stack(v)) = m.bases.get(&vn) {
+                    m.escaped.insert(*v);
+                }
+            }
+        }
+    }
+    return m;
+}
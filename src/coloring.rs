@@ -0,0 +1,549 @@
+// Graph coloring register allocator
+//
+// This is an alternative to the linear scan in regalloc.rs, enabled by
+// -fregalloc=graph. It is the iterated register coalescing algorithm of
+// George and Appel.
+//
+// Two registers interfere if one of them is defined where the other is
+// live, which is computed from the live-out sets of liveness.rs by
+// walking each BB backwards. Registers are the nodes of an interference
+// graph, and we color the graph with the physical registers so that no
+// two adjacent nodes have the same color.
+//
+// A node with fewer neighbors than the number of colors can always be
+// colored, so it is removed from the graph and pushed to a stack
+// (simplify). Removing it may make its neighbors colorable as well. The
+// nodes are colored in the reverse order when the graph becomes empty
+// (select).
+//
+// The source and the destination of a MOV which don't interfere are
+// merged into one node, so that the MOV can be removed (coalesce). This
+// eliminates most of the MOVs inserted to convert IRs to two-address
+// form. Merging is done only if it doesn't make the graph uncolorable
+// (Briggs' test). A MOV which can't be coalesced is given up (freeze)
+// so that its operands can be simplified.
+//
+// If no node can be simplified, one of them is removed optimistically
+// hoping that it can be colored anyway. We choose a node with a low
+// spill cost relative to its degree. The cost is the number of uses and
+// definitions of a register, each of which counts ten times as much per
+// loop level. If a node can't be colored after all, it is spilled to the
+// stack in the same way as the linear scan does, and we start over.
+//
+// Registers clobbered by inline assembly or a function call are
+// excluded from the colors of the registers live across it, as are
+// argument registers from the colors of the registers live before the
+// parameters are read.
+
+use crate::gen_ir::*;
+use crate::gen_x86::*;
+use crate::loops::*;
+use crate::parse::*;
+use crate::regalloc::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Node {
+    Initial,
+    Simplify,
+    Freeze,
+    Spill,
+    Select,
+    Coalesced,
+    Colored,
+    Spilled,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Move {
+    Worklist,
+    Active,
+    Coalesced,
+    Constrained,
+    Frozen,
+}
+
+struct Graph {
+    regs: Vec<Rc<RefCell<Reg>>>,
+    index: HashMap<i32, usize>,
+    k: usize,
+
+    adj_set: HashSet<(usize, usize)>,
+    adj_list: Vec<Vec<usize>>,
+    degree: Vec<usize>,
+    mask: Vec<u32>,
+    cost: Vec<f64>,
+    spillable: Vec<bool>,
+
+    state: Vec<Node>,
+    alias: Vec<usize>,
+    color: Vec<usize>,
+    move_list: Vec<Vec<usize>>,
+
+    // (dst, src) of each MOV
+    moves: Vec<(usize, usize)>,
+    move_state: Vec<Move>,
+
+    // Worklists may contain stale entries whose state has changed.
+    simplify_wl: Vec<usize>,
+    freeze_wl: Vec<usize>,
+    move_wl: Vec<usize>,
+    stack: Vec<usize>,
+}
+
+fn is_move(ir: &IR) -> bool {
+    return ir.op == IRType::MOV && ir.r2.is_some();
+}
+
+fn defs(ir: &IR) -> Vec<Rc<RefCell<Reg>>> {
+    return ir.r0.iter().chain(ir.outs.iter()).cloned().collect();
+}
+
+impl Graph {
+    fn node(&mut self, r: &Rc<RefCell<Reg>>) -> usize {
+        let vn = r.borrow().vn;
+        if let Some(&n) = self.index.get(&vn) {
+            return n;
+        }
+
+        let n = self.regs.len();
+        self.regs.push(r.clone());
+        self.index.insert(vn, n);
+        self.adj_list.push(Vec::new());
+        self.degree.push(0);
+        self.mask.push(0);
+        self.cost.push(0.0);
+        // Temporaries for spill code live very briefly, so spilling them
+        // again would not help.
+        self.spillable.push(r.borrow().var.is_none());
+        self.state.push(Node::Initial);
+        self.alias.push(n);
+        self.color.push(0);
+        self.move_list.push(Vec::new());
+        return n;
+    }
+
+    fn add_edge(&mut self, u: usize, v: usize) {
+        if u == v || self.adj_set.contains(&(u, v)) {
+            return;
+        }
+        self.adj_set.insert((u, v));
+        self.adj_set.insert((v, u));
+        self.adj_list[u].push(v);
+        self.adj_list[v].push(u);
+        self.degree[u] += 1;
+        self.degree[v] += 1;
+    }
+
+    fn build(&mut self, fun: &Rc<RefCell<Function>>) {
+        let bbs = fun.borrow().bbs.clone();
+        let info = find_loops(fun);
+        let params = param_regs(fun);
+
+        for (b, bb) in bbs.iter().enumerate() {
+            let weight = 10f64.powi(info.depth[b].min(8) as i32);
+            let mut live = HashSet::new();
+            for r in bb.borrow().out_regs.borrow().iter() {
+                live.insert(self.node(r));
+            }
+
+            for (i, ir) in bb.borrow().ir.iter().enumerate().rev() {
+                let ir = ir.borrow();
+                let defs: Vec<usize> = defs(&ir).iter().map(|r| self.node(r)).collect();
+                let uses: Vec<usize> = uses(&ir).iter().map(|r| self.node(r)).collect();
+                for &n in defs.iter().chain(uses.iter()) {
+                    self.cost[n] += weight;
+                }
+
+                if is_move(&ir) {
+                    let m = self.moves.len();
+                    self.moves.push((defs[0], uses[0]));
+                    self.move_state.push(Move::Worklist);
+                    self.move_wl.push(m);
+                    self.move_list[defs[0]].push(m);
+                    self.move_list[uses[0]].push(m);
+                    live.remove(&uses[0]);
+                }
+
+                if ir.op == IRType::CALL {
+                    for &n in live.iter() {
+                        if !defs.contains(&n) {
+                            self.mask[n] |= caller_saved_mask();
+                        }
+                    }
+                }
+
+                for &d in defs.iter() {
+                    live.insert(d);
+                }
+                for &d in defs.iter() {
+                    for &l in live.iter() {
+                        self.add_edge(d, l);
+                    }
+                }
+
+                if ir.op == IRType::ASM {
+                    // An early-clobber output is written before inputs
+                    // are consumed.
+                    if ir.asm_args.iter().any(|a| a.is_earlyclobber) {
+                        for &d in defs.iter() {
+                            for &u in uses.iter() {
+                                self.add_edge(d, u);
+                            }
+                        }
+                    }
+                    let mask = clobber_mask(&ir);
+                    for &n in live.iter().chain(uses.iter()) {
+                        self.mask[n] |= mask;
+                    }
+                }
+
+                if let Some((last, mask)) = params {
+                    if b == 0 && i <= last {
+                        for &n in live.iter().chain(uses.iter()) {
+                            self.mask[n] |= mask;
+                        }
+                    }
+                }
+
+                for d in defs.iter() {
+                    live.remove(d);
+                }
+                for &u in uses.iter() {
+                    live.insert(u);
+                }
+            }
+        }
+    }
+
+    fn adjacent(&self, n: usize) -> Vec<usize> {
+        return self.adj_list[n]
+            .iter()
+            .cloned()
+            .filter(|&m| self.state[m] != Node::Select && self.state[m] != Node::Coalesced)
+            .collect();
+    }
+
+    fn node_moves(&self, n: usize) -> Vec<usize> {
+        return self.move_list[n]
+            .iter()
+            .cloned()
+            .filter(|&m| self.move_state[m] == Move::Active || self.move_state[m] == Move::Worklist)
+            .collect();
+    }
+
+    fn move_related(&self, n: usize) -> bool {
+        return !self.node_moves(n).is_empty();
+    }
+
+    fn push_simplify(&mut self, n: usize) {
+        self.state[n] = Node::Simplify;
+        self.simplify_wl.push(n);
+    }
+
+    fn push_freeze(&mut self, n: usize) {
+        self.state[n] = Node::Freeze;
+        self.freeze_wl.push(n);
+    }
+
+    fn make_worklist(&mut self) {
+        for n in 0..self.regs.len() {
+            if self.degree[n] >= self.k {
+                self.state[n] = Node::Spill;
+            } else if self.move_related(n) {
+                self.push_freeze(n);
+            } else {
+                self.push_simplify(n);
+            }
+        }
+    }
+
+    fn enable_moves(&mut self, n: usize) {
+        for m in self.node_moves(n) {
+            if self.move_state[m] == Move::Active {
+                self.move_state[m] = Move::Worklist;
+                self.move_wl.push(m);
+            }
+        }
+    }
+
+    fn decrement_degree(&mut self, m: usize) {
+        let d = self.degree[m];
+        self.degree[m] -= 1;
+        if d != self.k || self.state[m] != Node::Spill {
+            return;
+        }
+
+        self.enable_moves(m);
+        for n in self.adjacent(m) {
+            self.enable_moves(n);
+        }
+        if self.move_related(m) {
+            self.push_freeze(m);
+        } else {
+            self.push_simplify(m);
+        }
+    }
+
+    fn simplify(&mut self, n: usize) {
+        self.state[n] = Node::Select;
+        self.stack.push(n);
+        for m in self.adjacent(n) {
+            self.decrement_degree(m);
+        }
+    }
+
+    fn get_alias(&self, n: usize) -> usize {
+        let mut n = n;
+        while self.state[n] == Node::Coalesced {
+            n = self.alias[n];
+        }
+        return n;
+    }
+
+    fn add_worklist(&mut self, u: usize) {
+        if self.state[u] == Node::Freeze && !self.move_related(u) && self.degree[u] < self.k {
+            self.push_simplify(u);
+        }
+    }
+
+    // Briggs' test: the merged node can be simplified if it has fewer
+    // than K neighbors of significant degree.
+    fn conservative(&self, u: usize, v: usize) -> bool {
+        if (self.mask[u] | self.mask[v]).count_ones() as usize >= self.k {
+            return false;
+        }
+        let mut nodes = self.adjacent(u);
+        for n in self.adjacent(v) {
+            if !nodes.contains(&n) {
+                nodes.push(n);
+            }
+        }
+        return nodes.iter().filter(|&&n| self.degree[n] >= self.k).count() < self.k;
+    }
+
+    fn combine(&mut self, u: usize, v: usize) {
+        self.state[v] = Node::Coalesced;
+        self.alias[v] = u;
+        let moves = self.move_list[v].clone();
+        self.move_list[u].extend(moves);
+        self.enable_moves(v);
+
+        self.mask[u] |= self.mask[v];
+        self.cost[u] += self.cost[v];
+        self.spillable[u] = self.spillable[u] || self.spillable[v];
+
+        for t in self.adjacent(v) {
+            self.add_edge(t, u);
+            self.decrement_degree(t);
+        }
+        if self.degree[u] >= self.k && self.state[u] == Node::Freeze {
+            self.state[u] = Node::Spill;
+        }
+    }
+
+    fn coalesce(&mut self, m: usize) {
+        let (x, y) = self.moves[m];
+        let u = self.get_alias(x);
+        let v = self.get_alias(y);
+
+        if u == v {
+            self.move_state[m] = Move::Coalesced;
+            self.add_worklist(u);
+        } else if self.adj_set.contains(&(u, v)) {
+            self.move_state[m] = Move::Constrained;
+            self.add_worklist(u);
+            self.add_worklist(v);
+        } else if self.conservative(u, v) {
+            self.move_state[m] = Move::Coalesced;
+            self.combine(u, v);
+            self.add_worklist(u);
+        } else {
+            self.move_state[m] = Move::Active;
+        }
+    }
+
+    fn freeze_moves(&mut self, u: usize) {
+        for m in self.node_moves(u) {
+            let (x, y) = self.moves[m];
+            let v = if self.get_alias(y) == self.get_alias(u) {
+                self.get_alias(x)
+            } else {
+                self.get_alias(y)
+            };
+            self.move_state[m] = Move::Frozen;
+            if self.state[v] == Node::Freeze && !self.move_related(v) && self.degree[v] < self.k {
+                self.push_simplify(v);
+            }
+        }
+    }
+
+    fn freeze(&mut self, u: usize) {
+        self.push_simplify(u);
+        self.freeze_moves(u);
+    }
+
+    fn priority(&self, n: usize) -> f64 {
+        return self.cost[n] / self.degree[n] as f64;
+    }
+
+    // Chooses a node to remove from the graph optimistically.
+    fn select_spill(&mut self) -> bool {
+        let mut best: Option<usize> = None;
+        for n in 0..self.regs.len() {
+            if self.state[n] != Node::Spill {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some(b) => {
+                    if self.spillable[b] != self.spillable[n] {
+                        self.spillable[n]
+                    } else {
+                        self.priority(n) < self.priority(b)
+                    }
+                }
+            };
+            if better {
+                best = Some(n);
+            }
+        }
+
+        match best {
+            Some(n) => {
+                self.push_simplify(n);
+                self.freeze_moves(n);
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    fn pop(wl: &mut Vec<usize>, state: &Vec<Node>, s: Node) -> Option<usize> {
+        while let Some(n) = wl.pop() {
+            if state[n] == s {
+                return Some(n);
+            }
+        }
+        return None;
+    }
+
+    fn pop_move(&mut self) -> Option<usize> {
+        while let Some(m) = self.move_wl.pop() {
+            if self.move_state[m] == Move::Worklist {
+                return Some(m);
+            }
+        }
+        return None;
+    }
+
+    fn reduce(&mut self) {
+        loop {
+            if let Some(n) = Graph::pop(&mut self.simplify_wl, &self.state, Node::Simplify) {
+                self.simplify(n);
+            } else if let Some(m) = self.pop_move() {
+                self.coalesce(m);
+            } else if let Some(n) = Graph::pop(&mut self.freeze_wl, &self.state, Node::Freeze) {
+                self.freeze(n);
+            } else if !self.select_spill() {
+                return;
+            }
+        }
+    }
+
+    // Returns false if some nodes are spilled.
+    fn assign_colors(&mut self) -> bool {
+        let mut ok = true;
+        while let Some(n) = self.stack.pop() {
+            let mut used = self.mask[n];
+            for &w in self.adj_list[n].iter() {
+                let a = self.get_alias(w);
+                if self.state[a] == Node::Colored {
+                    used |= 1 << self.color[a];
+                }
+            }
+
+            match (0..self.k).find(|&c| used & (1 << c) == 0) {
+                Some(c) => {
+                    self.state[n] = Node::Colored;
+                    self.color[n] = c;
+                }
+                None => {
+                    if !self.spillable[n] {
+                        panic!("too many registers are in use");
+                    }
+                    self.state[n] = Node::Spilled;
+                    ok = false;
+                }
+            }
+        }
+
+        for n in 0..self.regs.len() {
+            let a = self.get_alias(n);
+            if self.state[a] == Node::Spilled {
+                self.regs[n].borrow_mut().spill = true;
+            } else {
+                self.regs[n].borrow_mut().rn = self.color[a] as i32;
+            }
+        }
+        return ok;
+    }
+}
+
+fn new_graph() -> Graph {
+    Graph {
+        regs: Vec::new(),
+        index: HashMap::new(),
+        k: num_regs(),
+
+        adj_set: HashSet::new(),
+        adj_list: Vec::new(),
+        degree: Vec::new(),
+        mask: Vec::new(),
+        cost: Vec::new(),
+        spillable: Vec::new(),
+
+        state: Vec::new(),
+        alias: Vec::new(),
+        color: Vec::new(),
+        move_list: Vec::new(),
+
+        moves: Vec::new(),
+        move_state: Vec::new(),
+
+        simplify_wl: Vec::new(),
+        freeze_wl: Vec::new(),
+        move_wl: Vec::new(),
+        stack: Vec::new(),
+    }
+}
+
+// Removes MOVs whose source and destination got the same register.
+fn remove_moves(fun: &Rc<RefCell<Function>>) {
+    for bb in fun.borrow().bbs.iter() {
+        bb.borrow_mut().ir.retain(|ir| {
+            let ir = ir.borrow();
+            if !is_move(&ir) {
+                return true;
+            }
+            return ir.r0.as_ref().unwrap().borrow().rn != ir.r2.as_ref().unwrap().borrow().rn;
+        });
+    }
+}
+
+pub fn color_regs(fun: &Rc<RefCell<Function>>) {
+    loop {
+        reset_regs(fun);
+        let mut g = new_graph();
+        g.build(fun);
+        g.make_worklist();
+        g.reduce();
+        if g.assign_colors() {
+            break;
+        }
+        spill_regs(fun, &g.regs);
+    }
+    remove_moves(fun);
+}
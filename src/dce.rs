@@ -0,0 +1,327 @@
+// Dead code elimination and CFG cleanup
+//
+// `dce` removes instructions whose results are never used, if they have
+// no side effects. It starts from instructions that must be kept, such as
+// stores, calls and jumps, and marks the definitions of registers they
+// read, and so on. Everything not marked is dead. A block parameter is
+// an instruction as well; if it is dead, it is removed along with the
+// values passed to it.
+//
+// `simplify_cfg` removes unreachable BBs, threads jumps to BBs that do
+// nothing but jump, and merges a BB into its predecessor if it is the
+// only one.
+
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// A volatile load is kept even if its value is unused.
+fn has_side_effects(ir: &IR) -> bool {
+    if ir.is_volatile {
+        return true;
+    }
+    match ir.op {
+        IRType::IMM
+        | IRType::BPREL
+        | IRType::MOV
+        | IRType::ARG
+        | IRType::LABEL_ADDR
+        | IRType::TLS_ADDR
+        | IRType::STACK_SAVE
+        | IRType::CLZ
+        | IRType::CTZ
+        | IRType::POPCOUNT
+        | IRType::EQ
+        | IRType::NE
+        | IRType::LE
+        | IRType::LT
+        | IRType::ULE
+        | IRType::ULT
+        | IRType::AND
+        | IRType::OR
+        | IRType::XOR
+        | IRType::SHL
+        | IRType::SHR
+        | IRType::SAR
+        | IRType::MOD
+        | IRType::UMOD
+        | IRType::LOAD
+        | IRType::ADD
+        | IRType::SUB
+        | IRType::MUL
+        | IRType::MULH
+        | IRType::DIV
+        | IRType::UDIV
+        | IRType::NOP => false,
+        _ => true,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Def {
+    IR(usize, usize),
+    Param(usize, usize),
+}
+
+pub fn dce(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+    let bbs = fun.borrow().bbs.clone();
+
+    let mut defs: HashMap<i32, Vec<Def>> = HashMap::new();
+    let mut live = HashSet::new();
+    let mut live_params = HashSet::new();
+    let mut work = Vec::new();
+
+    for (b, bb) in bbs.iter().enumerate() {
+        for (k, r) in bb.borrow().params.iter().enumerate() {
+            defs.entry(r.borrow().vn)
+                .or_insert(Vec::new())
+                .push(Def::Param(b, k));
+        }
+        for (i, ir) in bb.borrow().ir.iter().enumerate() {
+            let ir = ir.borrow();
+            for r in ir.r0.iter().chain(ir.outs.iter()) {
+                defs.entry(r.borrow().vn)
+                    .or_insert(Vec::new())
+                    .push(Def::IR(b, i));
+            }
+            if has_side_effects(&ir) {
+                live.insert((b, i));
+                work.push((b, i));
+            }
+        }
+    }
+
+    // Registers needed by live instructions
+    let mut needed: Vec<i32> = Vec::new();
+    loop {
+        while let Some((b, i)) = work.pop() {
+            let ir = bbs[b].borrow().ir[i].clone();
+            let ir = ir.borrow();
+            for r in ir.r1.iter().chain(ir.r2.iter()).chain(ir.args.iter()) {
+                needed.push(r.borrow().vn);
+            }
+        }
+
+        let vn = match needed.pop() {
+            Some(vn) => vn,
+            None => break,
+        };
+        for def in defs.get(&vn).cloned().unwrap_or(Vec::new()) {
+            match def {
+                Def::IR(b, i) => {
+                    if live.insert((b, i)) {
+                        work.push((b, i));
+                    }
+                }
+                Def::Param(b, k) => {
+                    if !live_params.insert((b, k)) {
+                        continue;
+                    }
+                    for pred in bbs[b].borrow().pred.iter() {
+                        let jmp = pred.borrow().ir.last().unwrap().clone();
+                        needed.push(jmp.borrow().bbargs[k].borrow().vn);
+                    }
+                }
+            }
+        }
+    }
+
+    for (b, bb) in bbs.iter().enumerate() {
+        let irs = bb.borrow().ir.clone();
+        let mut v = Vec::new();
+        for (i, ir) in irs.into_iter().enumerate() {
+            if live.contains(&(b, i)) {
+                v.push(ir);
+            }
+        }
+        bb.borrow_mut().ir = v;
+    }
+
+    for (b, bb) in bbs.iter().enumerate() {
+        let params = bb.borrow().params.clone();
+        let keep: Vec<bool> = (0..params.len())
+            .map(|k| live_params.contains(&(b, k)))
+            .collect();
+        if keep.iter().all(|k| *k) {
+            continue;
+        }
+
+        bb.borrow_mut().params = retain(params, &keep);
+        for pred in bb.borrow().pred.iter() {
+            let jmp = pred.borrow().ir.last().unwrap().clone();
+            let args = jmp.borrow().bbargs.clone();
+            jmp.borrow_mut().bbargs = retain(args, &keep);
+        }
+    }
+}
+
+fn retain(v: Vec<Rc<RefCell<Reg>>>, keep: &Vec<bool>) -> Vec<Rc<RefCell<Reg>>> {
+    return v
+        .into_iter()
+        .zip(keep.iter())
+        .filter(|(_, k)| **k)
+        .map(|(r, _)| r)
+        .collect();
+}
+
+// Returns the target of a BB that does nothing but jump.
+fn forward_target(bb: &Rc<RefCell<BB>>) -> Option<Rc<RefCell<BB>>> {
+    let b = bb.borrow();
+    if !b.params.is_empty() || b.ir.len() != 1 {
+        return None;
+    }
+    let ir = b.ir[0].borrow();
+    if ir.op != IRType::JMP {
+        return None;
+    }
+    let t = ir.bb1.clone().unwrap();
+    if Rc::ptr_eq(&t, bb) {
+        return None;
+    }
+    return Some(t);
+}
+
+// Follows jumps from `bb`. Returns the final destination and the values
+// passed to it.
+fn resolve_jump(bb: &Rc<RefCell<BB>>) -> (Rc<RefCell<BB>>, Vec<Rc<RefCell<Reg>>>) {
+    let mut cur = bb.clone();
+    let mut args = Vec::new();
+    let mut seen = HashSet::new();
+
+    while let Some(next) = forward_target(&cur) {
+        if !seen.insert(cur.borrow().label) {
+            break;
+        }
+        args = cur.borrow().ir[0].borrow().bbargs.clone();
+        cur = next;
+    }
+    return (cur, args);
+}
+
+fn thread_jumps(fun: &Rc<RefCell<Function>>) -> bool {
+    let mut changed = false;
+
+    for bb in fun.borrow().bbs.iter() {
+        let ir = match bb.borrow().ir.last() {
+            Some(ir) => ir.clone(),
+            None => continue,
+        };
+        let mut ir = ir.borrow_mut();
+
+        if ir.op == IRType::JMP {
+            let (t, args) = resolve_jump(ir.bb1.as_ref().unwrap());
+            if !Rc::ptr_eq(&t, ir.bb1.as_ref().unwrap()) {
+                ir.bb1 = Some(t);
+                ir.bbargs = args;
+                changed = true;
+            }
+            continue;
+        }
+
+        if ir.op != IRType::BR {
+            continue;
+        }
+
+        // A conditional branch cannot pass values.
+        for i in 0..2 {
+            let old = if i == 0 {
+                ir.bb1.clone()
+            } else {
+                ir.bb2.clone()
+            }
+            .unwrap();
+            let (t, _) = resolve_jump(&old);
+            if Rc::ptr_eq(&t, &old) || !t.borrow().params.is_empty() {
+                continue;
+            }
+            if i == 0 {
+                ir.bb1 = Some(t);
+            } else {
+                ir.bb2 = Some(t);
+            }
+            changed = true;
+        }
+
+        if Rc::ptr_eq(ir.bb1.as_ref().unwrap(), ir.bb2.as_ref().unwrap()) {
+            ir.op = IRType::JMP;
+            ir.r2 = None;
+            ir.bb2 = None;
+            changed = true;
+        }
+    }
+    return changed;
+}
+
+fn subst(map: &HashMap<i32, Rc<RefCell<Reg>>>, r: &Rc<RefCell<Reg>>) -> Rc<RefCell<Reg>> {
+    let mut r = r.clone();
+    loop {
+        let vn = r.borrow().vn;
+        match map.get(&vn) {
+            Some(next) => r = next.clone(),
+            None => return r,
+        }
+    }
+}
+
+// Merges a BB into its predecessor if it is the only one and the
+// predecessor unconditionally jumps to it. Block parameters are replaced
+// with the passed values.
+fn merge_blocks(fun: &Rc<RefCell<Function>>) -> bool {
+    let bbs = fun.borrow().bbs.clone();
+    let mut map = HashMap::new();
+    let mut changed = false;
+
+    for bb in bbs.iter() {
+        loop {
+            let ir = match bb.borrow().ir.last() {
+                Some(ir) => ir.clone(),
+                None => break,
+            };
+            if ir.borrow().op != IRType::JMP {
+                break;
+            }
+            let t = ir.borrow().bb1.clone().unwrap();
+            if Rc::ptr_eq(&t, bb) || t.borrow().pred.len() != 1 {
+                break;
+            }
+
+            let params = t.borrow().params.clone();
+            for (p, arg) in params.iter().zip(ir.borrow().bbargs.iter()) {
+                map.insert(p.borrow().vn, arg.clone());
+            }
+
+            let mut irs = t.borrow().ir.clone();
+            t.borrow_mut().ir = Vec::new();
+            t.borrow_mut().params = Vec::new();
+            bb.borrow_mut().ir.pop();
+            bb.borrow_mut().ir.append(&mut irs);
+            changed = true;
+        }
+    }
+
+    if !map.is_empty() {
+        for bb in bbs.iter() {
+            for ir in bb.borrow().ir.iter() {
+                map_uses(&mut ir.borrow_mut(), |r| subst(&map, r));
+            }
+        }
+    }
+    return changed;
+}
+
+pub fn simplify_cfg(fun: &Rc<RefCell<Function>>) {
+    loop {
+        build_cfg(fun);
+        let mut changed = thread_jumps(fun);
+        build_cfg(fun);
+        changed |= merge_blocks(fun);
+        if !changed {
+            break;
+        }
+    }
+    build_cfg(fun);
+}
@@ -0,0 +1,211 @@
+// Control flow graph and dominator tree
+//
+// A BB X dominates Y if every path from the entry BB to Y goes through X.
+// The immediate dominators are computed by the iterative algorithm of
+// Cooper, Harvey and Kennedy ("A Simple, Fast Dominance Algorithm"),
+// which works on BBs in reverse postorder. The dominance frontier of X is
+// the set of BBs where the dominance of X ends, which is where SSA
+// construction places block parameters.
+//
+// BBs are identified by their indices in `Function.bbs`.
+
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub struct DomTree {
+    pub idom: Vec<usize>,
+    pub children: Vec<Vec<usize>>,
+    pub frontier: Vec<Vec<usize>>,
+}
+
+fn add_edge(from: &Rc<RefCell<BB>>, to: &Rc<RefCell<BB>>) {
+    if from.borrow().succ.iter().any(|bb| Rc::ptr_eq(bb, to)) {
+        return;
+    }
+    from.borrow_mut().succ.push(to.clone());
+    to.borrow_mut().pred.push(from.clone());
+}
+
+// Returns the jump targets of a BB, which are in the last IR.
+pub fn targets(bb: &Rc<RefCell<BB>>) -> Vec<Rc<RefCell<BB>>> {
+    let mut v = Vec::new();
+    let bb = bb.borrow();
+    if let Some(ir) = bb.ir.last() {
+        let ir = ir.borrow();
+        for t in ir.bb1.iter().chain(ir.bb2.iter()) {
+            v.push(t.clone());
+        }
+    }
+    return v;
+}
+
+pub fn is_terminator(ir: &Rc<RefCell<IR>>) -> bool {
+    let op = ir.borrow().op.clone();
+    return op == IRType::JMP
+        || op == IRType::BR
+        || op == IRType::RETURN
+        || op == IRType::TAIL_CALL;
+}
+
+// Computes `succ` and `pred` of each BB and removes BBs that are not
+// reachable from the entry BB. Code after the first jump or return in
+// a BB, e.g. a jump following `break`, is removed as well.
+pub fn build_cfg(fun: &Rc<RefCell<Function>>) {
+    let bbs = fun.borrow().bbs.clone();
+    for bb in bbs.iter() {
+        let mut bb = bb.borrow_mut();
+        if let Some(i) = bb.ir.iter().position(is_terminator) {
+            bb.ir.truncate(i + 1);
+        }
+        bb.succ = Vec::new();
+        bb.pred = Vec::new();
+    }
+
+    let mut reachable = HashMap::new();
+    let mut stack = vec![bbs[0].clone()];
+    reachable.insert(bbs[0].borrow().label, ());
+    while let Some(bb) = stack.pop() {
+        for t in targets(&bb) {
+            add_edge(&bb, &t);
+            let label = t.borrow().label;
+            if reachable.insert(label, ()).is_none() {
+                stack.push(t);
+            }
+        }
+    }
+
+    let bbs = bbs
+        .into_iter()
+        .filter(|bb| reachable.contains_key(&bb.borrow().label))
+        .collect();
+    fun.borrow_mut().bbs = bbs;
+}
+
+// Returns a map from BB labels to indices in `bbs`.
+pub fn bb_indices(bbs: &Vec<Rc<RefCell<BB>>>) -> HashMap<usize, usize> {
+    let mut map = HashMap::new();
+    for (i, bb) in bbs.iter().enumerate() {
+        map.insert(bb.borrow().label, i);
+    }
+    return map;
+}
+
+fn postorder(i: usize, succ: &Vec<Vec<usize>>, visited: &mut Vec<bool>, order: &mut Vec<usize>) {
+    visited[i] = true;
+    for &s in succ[i].iter() {
+        if !visited[s] {
+            postorder(s, succ, visited, order);
+        }
+    }
+    order.push(i);
+}
+
+fn intersect(idom: &Vec<Option<usize>>, rpo_num: &Vec<usize>, mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while rpo_num[a] > rpo_num[b] {
+            a = idom[a].unwrap();
+        }
+        while rpo_num[b] > rpo_num[a] {
+            b = idom[b].unwrap();
+        }
+    }
+    return a;
+}
+
+// Builds the dominator tree. `build_cfg` must be called beforehand.
+pub fn dominators(fun: &Rc<RefCell<Function>>) -> DomTree {
+    let bbs = fun.borrow().bbs.clone();
+    let index = bb_indices(&bbs);
+    let n = bbs.len();
+
+    let mut succ = vec![Vec::new(); n];
+    let mut pred = vec![Vec::new(); n];
+    for (i, bb) in bbs.iter().enumerate() {
+        for s in bb.borrow().succ.iter() {
+            succ[i].push(index[&s.borrow().label]);
+        }
+        for p in bb.borrow().pred.iter() {
+            pred[i].push(index[&p.borrow().label]);
+        }
+    }
+
+    let mut rpo = Vec::new();
+    postorder(0, &succ, &mut vec![false; n], &mut rpo);
+    rpo.reverse();
+    let mut rpo_num = vec![0; n];
+    for (k, &i) in rpo.iter().enumerate() {
+        rpo_num[i] = k;
+    }
+
+    let mut idom: Vec<Option<usize>> = vec![None; n];
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in rpo.iter().skip(1) {
+            let mut new_idom = None;
+            for &p in pred[b].iter() {
+                if idom[p].is_none() {
+                    continue;
+                }
+                new_idom = match new_idom {
+                    None => Some(p),
+                    Some(d) => Some(intersect(&idom, &rpo_num, p, d)),
+                };
+            }
+            if new_idom.is_some() && idom[b] != new_idom {
+                idom[b] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    let idom: Vec<usize> = idom.into_iter().map(|d| d.unwrap()).collect();
+
+    let mut children = vec![Vec::new(); n];
+    for &b in rpo.iter().skip(1) {
+        children[idom[b]].push(b);
+    }
+
+    // A join point is in the frontier of each BB on the way from its
+    // predecessors up to its immediate dominator.
+    let mut frontier: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for b in 0..n {
+        if pred[b].len() < 2 {
+            continue;
+        }
+        for &p in pred[b].iter() {
+            let mut runner = p;
+            while runner != idom[b] {
+                if !frontier[runner].contains(&b) {
+                    frontier[runner].push(b);
+                }
+                if runner == 0 {
+                    break;
+                }
+                runner = idom[runner];
+            }
+        }
+    }
+
+    return DomTree {
+        idom: idom,
+        children: children,
+        frontier: frontier,
+    };
+}
+
+// Returns true if BB `a` dominates BB `b`.
+pub fn dominates(dom: &DomTree, a: usize, mut b: usize) -> bool {
+    loop {
+        if a == b {
+            return true;
+        }
+        if b == 0 {
+            return false;
+        }
+        b = dom.idom[b];
+    }
+}
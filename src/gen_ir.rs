@@ -31,6 +31,33 @@ thread_local! {
     static OUT: RefCell<Option<Rc<RefCell<BB>>>> = RefCell::new(None);
     static NREG: RefCell<i32> = RefCell::new(1);
     static BREAK_LABEL: RefCell<i32> = RefCell::new(0);
+
+    // Stack pointers saved at entry of blocks with variable-length arrays
+    static VLA_SP: RefCell<Vec<Rc<RefCell<Reg>>>> = RefCell::new(Vec::new());
+}
+
+fn vla_sp_push(r: Rc<RefCell<Reg>>) {
+    VLA_SP.with(|v| {
+        v.borrow_mut().push(r);
+    })
+}
+
+fn vla_sp_pop() {
+    VLA_SP.with(|v| {
+        v.borrow_mut().pop();
+    })
+}
+
+fn vla_sp_len() -> usize {
+    VLA_SP.with(|v| {
+        return v.borrow().len();
+    })
+}
+
+fn vla_sp_get(i: usize) -> Rc<RefCell<Reg>> {
+    VLA_SP.with(|v| {
+        return v.borrow()[i].clone();
+    })
 }
 
 fn set_fn(fun: Rc<RefCell<Function>>) {
@@ -50,6 +77,28 @@ fn fn_bbs_push(bb: Rc<RefCell<BB>>) {
     })
 }
 
+fn fn_bbs_len() -> usize {
+    FN.with(|f| match *f.borrow() {
+        Some(ref fun) => fun.borrow().bbs.len(),
+        None => panic!(),
+    })
+}
+
+// Moves `head` and the BBs created in [from, to) after all others,
+// so that a cold code path does not sit between hot ones.
+fn move_bbs_to_end(head: &Rc<RefCell<BB>>, from: usize, to: usize) {
+    FN.with(|f| match *f.borrow() {
+        Some(ref fun) => {
+            let mut fun = fun.borrow_mut();
+            let mut cold: Vec<Rc<RefCell<BB>>> = fun.bbs.drain(from..to).collect();
+            let i = fun.bbs.iter().position(|bb| Rc::ptr_eq(bb, head)).unwrap();
+            cold.insert(0, fun.bbs.remove(i));
+            fun.bbs.append(&mut cold);
+        }
+        None => panic!(),
+    })
+}
+
 fn set_out(bb: Rc<RefCell<BB>>) {
     OUT.with(|o| {
         *o.borrow_mut() = Some(bb);
@@ -70,7 +119,7 @@ fn out_ir_push(ir: Rc<RefCell<IR>>) {
 fn out_param_set(reg: Rc<RefCell<Reg>>) {
     OUT.with(|o| match *o.borrow() {
         Some(ref out) => {
-            out.borrow_mut().param = Some(reg);
+            out.borrow_mut().params = vec![reg];
         }
         None => {
             panic!();
@@ -81,7 +130,7 @@ fn out_param_set(reg: Rc<RefCell<Reg>>) {
 fn out_param_get() -> Rc<RefCell<Reg>> {
     OUT.with(|o| match *o.borrow() {
         Some(ref out) => {
-            return out.borrow_mut().param.clone().unwrap();
+            return out.borrow().params[0].clone();
         }
         None => {
             panic!();
@@ -102,19 +151,41 @@ pub enum IRType {
     IMM,
     BPREL,
     MOV,
+    ARG,
     RETURN,
     CALL,
+    TAIL_CALL,
+    ASM,
+    ALLOCA,
+    ATOMIC_LOAD,
+    ATOMIC_STORE,
+    ATOMIC_FETCH_ADD,
+    ATOMIC_CAS,
+    FENCE,
+    TRAP,
+    UNREACHABLE,
+    CLZ,
+    CTZ,
+    POPCOUNT,
+    STACK_SAVE,
+    STACK_RESTORE,
+    SCOPE_END,
     LABEL_ADDR,
+    TLS_ADDR,
     EQ,
     NE,
     LE,
     LT,
+    ULE,
+    ULT,
     AND,
     OR,
     XOR,
     SHL,
     SHR,
+    SAR,
     MOD,
+    UMOD,
     JMP,
     BR,
     LOAD,
@@ -125,7 +196,9 @@ pub enum IRType {
     ADD,
     SUB,
     MUL,
+    MULH,
     DIV,
+    UDIV,
     NOP,
 }
 
@@ -134,8 +207,8 @@ pub struct Reg {
     pub vn: i32, // virtual register number
     pub rn: i32, // real register number
 
-    // For optimizer
-    pub promoted: Option<Rc<RefCell<Reg>>>,
+    // For optimizer. The address of a promoted variable.
+    pub promoted: Option<Rc<RefCell<Var>>>,
 
     // For regalloc
     pub def: i32,
@@ -162,7 +235,10 @@ fn alloc_reg() -> Rc<RefCell<Reg>> {
 pub struct BB {
     pub label: usize,
     pub ir: Vec<Rc<RefCell<IR>>>,
-    pub param: Option<Rc<RefCell<Reg>>>,
+
+    // Block parameters. A jump to the BB passes values for them in
+    // `bbargs`, which works as phi functions of SSA form.
+    pub params: Vec<Rc<RefCell<Reg>>>,
 
     pub succ: Vec<Rc<RefCell<BB>>>,
     pub pred: Vec<Rc<RefCell<BB>>>,
@@ -175,7 +251,7 @@ pub fn alloc_bb() -> BB {
     BB {
         label: 0,
         ir: Vec::new(),
-        param: None,
+        params: Vec::new(),
 
         succ: Vec::new(),
         pred: Vec::new(),
@@ -193,15 +269,25 @@ pub struct IR {
     pub r1: Option<Rc<RefCell<Reg>>>,
     pub r2: Option<Rc<RefCell<Reg>>>,
 
-    pub imm: i32,
+    pub imm: i64,
     pub label: i32,
     pub var: Option<Rc<RefCell<Var>>>,
 
+    // For stack slot allocation. The local variable whose address is
+    // computed or used by the IR.
+    pub slot: Option<Rc<RefCell<Var>>>,
+
     pub bb1: Option<Rc<RefCell<BB>>>,
     pub bb2: Option<Rc<RefCell<BB>>>,
 
-    // Load/store size in bytes
+    // Load/store size in bytes. A narrower value loaded or returned by
+    // a call is sign-extended if `is_signed` and zero-extended if not.
     pub size: i32,
+    pub is_signed: bool,
+
+    // Volatile load or store. The optimizer neither removes, merges
+    // nor moves it.
+    pub is_volatile: bool,
 
     // Function call
     pub name: String,
@@ -217,7 +303,27 @@ pub struct IR {
     pub kill: Vec<Rc<RefCell<Reg>>>,
 
     // For SSA
-    pub bbarg: Option<Rc<RefCell<Reg>>>,
+    pub bbargs: Vec<Rc<RefCell<Reg>>>,
+
+    // Inline assembly. Registers read by the code are in `args`
+    // and registers written by it are in `outs`.
+    pub asm: Vec<AsmPiece>,
+    pub asm_args: Vec<AsmArg>,
+    pub clobbers: Vec<String>,
+    pub outs: Vec<Rc<RefCell<Reg>>>,
+}
+
+// Operand of an ASM instruction. For a memory operand, `reg` holds
+// its address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmArg {
+    pub kind: AsmKind,
+    pub reg: Option<Rc<RefCell<Reg>>>,
+    pub imm: i64,
+    pub size: i32,
+    pub is_input: bool,
+    pub is_output: bool,
+    pub is_earlyclobber: bool,
 }
 
 pub fn alloc_ir() -> IR {
@@ -231,11 +337,14 @@ pub fn alloc_ir() -> IR {
         imm: 0,
         label: 0,
         var: None,
+        slot: None,
 
         bb1: None,
         bb2: None,
 
         size: 0,
+        is_signed: false,
+        is_volatile: false,
 
         name: String::new(),
         nargs: 0,
@@ -247,7 +356,42 @@ pub fn alloc_ir() -> IR {
 
         kill: Vec::new(),
 
-        bbarg: None,
+        bbargs: Vec::new(),
+
+        asm: Vec::new(),
+        asm_args: Vec::new(),
+        clobbers: Vec::new(),
+        outs: Vec::new(),
+    }
+}
+
+// Returns the registers read by an IR.
+pub fn uses(ir: &IR) -> Vec<Rc<RefCell<Reg>>> {
+    let mut v = Vec::new();
+    for r in ir.r1.iter().chain(ir.r2.iter()) {
+        v.push(r.clone());
+    }
+    for r in ir.args.iter().chain(ir.bbargs.iter()) {
+        v.push(r.clone());
+    }
+    return v;
+}
+
+// Replaces each register read by an IR with `f(reg)`.
+pub fn map_uses<F>(ir: &mut IR, mut f: F)
+where
+    F: FnMut(&Rc<RefCell<Reg>>) -> Rc<RefCell<Reg>>,
+{
+    ir.r1 = ir.r1.as_ref().map(|r| f(r));
+    ir.r2 = ir.r2.as_ref().map(|r| f(r));
+    for r in ir.args.iter_mut().chain(ir.bbargs.iter_mut()) {
+        *r = f(r);
+    }
+    // The address of a memory operand is read even if it is an output.
+    for arg in ir.asm_args.iter_mut() {
+        if !arg.is_output || arg.kind == AsmKind::MEM {
+            arg.reg = arg.reg.as_ref().map(|r| f(r));
+        }
     }
 }
 
@@ -310,11 +454,11 @@ fn jmp(bb: Rc<RefCell<BB>>) -> Rc<RefCell<IR>> {
 fn jmp_arg(bb: Rc<RefCell<BB>>, r: Rc<RefCell<Reg>>) -> Rc<RefCell<IR>> {
     let ir = new_ir(IRType::JMP);
     ir.borrow_mut().bb1 = Some(bb);
-    ir.borrow_mut().bbarg = Some(r);
+    ir.borrow_mut().bbargs = vec![r];
     return ir;
 }
 
-fn imm(imm: i32) -> Rc<RefCell<Reg>> {
+fn imm(imm: i64) -> Rc<RefCell<Reg>> {
     let r = new_reg();
     let ir = new_ir(IRType::IMM);
     ir.borrow_mut().r0 = Some(r.clone());
@@ -322,10 +466,178 @@ fn imm(imm: i32) -> Rc<RefCell<Reg>> {
     return r;
 }
 
+// Appends `r0 = r1 op imm` and returns r0.
+fn emit_imm(op: IRType, r1: Rc<RefCell<Reg>>, imm: i64) -> Rc<RefCell<Reg>> {
+    let r0 = new_reg();
+    let ir = emit(op, Some(r0.clone()), Some(r1), None);
+    ir.borrow_mut().imm = imm;
+    return r0;
+}
+
+// Integers narrower than a register are kept extended to 64 bits as
+// their types say. Signed `short`, `int` and `long` are sign-extended,
+// and `char`, `_Bool` and unsigned types are zero-extended. Loads,
+// calls, casts and promoted variables all follow this rule.
+pub fn is_signed(ty: &Type) -> bool {
+    match ty.ty {
+        CType::SHORT | CType::INT | CType::LONG => !ty.is_unsigned,
+        _ => false,
+    }
+}
+
+// Returns the operations which extend the low bytes of a register to a
+// value of the type.
+pub fn extend_ops(ty: &Type) -> Vec<(IRType, i64)> {
+    if ty.size >= 8 {
+        return vec![];
+    }
+    let bits = 64 - ty.size as i64 * 8;
+    if is_signed(ty) {
+        return vec![(IRType::SHL, bits), (IRType::SAR, bits)];
+    }
+    if ty.size < 4 {
+        return vec![(IRType::AND, (1 << (ty.size * 8)) - 1)];
+    }
+    return vec![(IRType::SHL, bits), (IRType::SHR, bits)];
+}
+
+// Operations on types narrower than int are done in int, so only
+// unsigned int and unsigned long are compared and divided as unsigned.
+fn is_unsigned(ty: &Rc<RefCell<Type>>) -> bool {
+    let ty = ty.borrow();
+    return ty.is_unsigned && ty.size >= 4;
+}
+
 fn load(node: Rc<RefCell<Node>>, dst: Rc<RefCell<Reg>>, src: Rc<RefCell<Reg>>) {
-    let ir = emit(IRType::LOAD, Some(dst), None, Some(src));
     let ty = node.borrow().ty.clone();
+    let op = if ty.borrow().is_atomic {
+        IRType::ATOMIC_LOAD
+    } else {
+        IRType::LOAD
+    };
+    let ir = emit(op, Some(dst), None, Some(src));
     ir.borrow_mut().size = ty.borrow().size;
+    ir.borrow_mut().is_signed = is_signed(&ty.borrow());
+    ir.borrow_mut().is_volatile = ty.borrow().is_volatile;
+}
+
+// Size of the object pointed to by the first argument of an atomic
+// builtin.
+fn atomic_size(node: &Rc<RefCell<Node>>) -> i32 {
+    let arg = node.borrow().args[0].clone();
+    let ty = arg.borrow().ty.clone();
+    let size = ty.borrow().ptr_to.clone().unwrap().borrow().size;
+    return size;
+}
+
+fn atomic_is_signed(node: &Rc<RefCell<Node>>) -> bool {
+    let arg = node.borrow().args[0].clone();
+    let ty = arg.borrow().ty.clone();
+    let is_signed = is_signed(&ty.borrow().ptr_to.clone().unwrap().borrow());
+    return is_signed;
+}
+
+// Returns whether a condition is expected to be true.
+fn expected_value(cond: &Rc<RefCell<Node>>) -> Option<bool> {
+    let cond = cond.borrow();
+    if cond.op != NodeType::EXPECT {
+        return None;
+    }
+    let c = cond.args[1].borrow();
+    if c.op != NodeType::NUM {
+        return None;
+    }
+    return Some(c.val != 0);
+}
+
+fn gen_atomic(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
+    let mut args = Vec::new();
+    for a in node.borrow().args.iter() {
+        args.push(gen_expr(a.clone()));
+    }
+
+    let op = node.borrow().op.clone();
+    match op {
+        NodeType::ATOMIC_LOAD => {
+            let r = new_reg();
+            let ir = emit(
+                IRType::ATOMIC_LOAD,
+                Some(r.clone()),
+                None,
+                Some(args[0].clone()),
+            );
+            ir.borrow_mut().size = atomic_size(&node);
+            return r;
+        }
+        NodeType::ATOMIC_STORE => {
+            let ir = emit(
+                IRType::ATOMIC_STORE,
+                None,
+                Some(args[0].clone()),
+                Some(args[1].clone()),
+            );
+            ir.borrow_mut().size = atomic_size(&node);
+            return new_reg();
+        }
+        NodeType::ATOMIC_FETCH_ADD => {
+            let r = new_reg();
+            let ir = emit(
+                IRType::ATOMIC_FETCH_ADD,
+                Some(r.clone()),
+                Some(args[0].clone()),
+                Some(args[1].clone()),
+            );
+            ir.borrow_mut().size = atomic_size(&node);
+            ir.borrow_mut().is_signed = atomic_is_signed(&node);
+            return r;
+        }
+        NodeType::ATOMIC_CAS => {
+            // `r0 = CAS r1, r2` compares the object at args[0] with r1
+            // and replaces it with r2 if they are equal. r0 is the
+            // value the object had. The value is written back to
+            // `*expected`, which is unchanged on success.
+            let size = atomic_size(&node);
+            let is_signed = atomic_is_signed(&node);
+            let expected = new_reg();
+            let ir = emit(
+                IRType::LOAD,
+                Some(expected.clone()),
+                None,
+                Some(args[1].clone()),
+            );
+            ir.borrow_mut().size = size;
+            ir.borrow_mut().is_signed = is_signed;
+
+            let old = new_reg();
+            let ir = emit(
+                IRType::ATOMIC_CAS,
+                Some(old.clone()),
+                Some(expected.clone()),
+                Some(args[2].clone()),
+            );
+            ir.borrow_mut().size = size;
+            ir.borrow_mut().is_signed = is_signed;
+            ir.borrow_mut().nargs = 1;
+            ir.borrow_mut().args.push(args[0].clone());
+
+            let ir = emit(
+                IRType::STORE,
+                None,
+                Some(args[1].clone()),
+                Some(old.clone()),
+            );
+            ir.borrow_mut().size = size;
+
+            let r = new_reg();
+            emit(IRType::EQ, Some(r.clone()), Some(old), Some(expected));
+            return r;
+        }
+        NodeType::FENCE => {
+            emit(IRType::FENCE, None, None, None);
+            return new_reg();
+        }
+        _ => panic!("unknown atomic builtin {:?}", op),
+    }
 }
 
 // In C, all expressions that can be written on the left-hand side of
@@ -355,7 +667,7 @@ fn gen_lval(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
         let ty = node.borrow().ty.clone();
         let r1 = new_reg();
         let r2 = gen_lval(node.borrow().expr.clone().unwrap());
-        let r3 = imm(ty.borrow().offset);
+        let r3 = imm(ty.borrow().offset as i64);
         emit(
             IRType::ADD,
             Some(r1.clone()),
@@ -368,11 +680,26 @@ fn gen_lval(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
     assert!(node_op == NodeType::VARREF);
     let var = node.borrow().var.clone().unwrap();
 
+    // A variable-length array holds a pointer to its storage.
+    if var.borrow().ty.vla_len.is_some() {
+        let ir = new_ir(IRType::BPREL);
+        ir.borrow_mut().r0 = Some(new_reg());
+        ir.borrow_mut().var = Some(var);
+        let r = new_reg();
+        let load = emit(IRType::LOAD, Some(r.clone()), None, ir.borrow().r0.clone());
+        load.borrow_mut().size = 8;
+        return r;
+    }
+
     let ir: Rc<RefCell<IR>>;
     if var.borrow().is_local {
         ir = new_ir(IRType::BPREL);
         ir.borrow_mut().r0 = Some(new_reg());
         ir.borrow_mut().var = Some(var);
+    } else if var.borrow().is_thread_local {
+        ir = new_ir(IRType::TLS_ADDR);
+        ir.borrow_mut().r0 = Some(new_reg());
+        ir.borrow_mut().name = var.borrow().name.clone();
     } else {
         ir = new_ir(IRType::LABEL_ADDR);
         ir.borrow_mut().r0 = Some(new_reg());
@@ -478,6 +805,24 @@ fn gen_expr(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
             for i in 0..nargs {
                 ir.borrow_mut().args.push(args[i].clone());
             }
+
+            // Narrow integers returned are extended like loaded ones.
+            let ty = node.borrow().ty.clone();
+            match ty.borrow().ty {
+                CType::BOOL | CType::CHAR | CType::SHORT | CType::INT => {
+                    ir.borrow_mut().size = ty.borrow().size;
+                    ir.borrow_mut().is_signed = is_signed(&ty.borrow());
+                }
+                _ => (),
+            }
+
+            // A noreturn function never comes back, so whatever
+            // follows the call is unreachable.
+            if let Some(ref var) = node.borrow().var {
+                if var.borrow().ty.is_noreturn {
+                    set_out(new_bb());
+                }
+            }
             return ir.borrow().r0.clone().unwrap();
         }
 
@@ -485,6 +830,54 @@ fn gen_expr(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
             return gen_lval(node.borrow().expr.clone().unwrap());
         }
 
+        NodeType::ALLOCA => {
+            let r = new_reg();
+            let size = gen_expr(node.borrow().args[0].clone());
+            emit(IRType::ALLOCA, Some(r.clone()), None, Some(size));
+            return r;
+        }
+
+        // The expected value is only a hint for block layout.
+        NodeType::EXPECT => {
+            let r = gen_expr(node.borrow().args[0].clone());
+            gen_expr(node.borrow().args[1].clone());
+            return r;
+        }
+
+        // Whatever follows is unreachable.
+        NodeType::UNREACHABLE => {
+            emit(IRType::UNREACHABLE, None, None, None);
+            set_out(new_bb());
+            return new_reg();
+        }
+        NodeType::TRAP => {
+            emit(IRType::TRAP, None, None, None);
+            set_out(new_bb());
+            return new_reg();
+        }
+
+        NodeType::CLZ | NodeType::CTZ | NodeType::POPCOUNT => {
+            let arg = node.borrow().args[0].clone();
+            let r2 = gen_expr(arg.clone());
+            let r = new_reg();
+            let ir = match op {
+                NodeType::CLZ => IRType::CLZ,
+                NodeType::CTZ => IRType::CTZ,
+                _ => IRType::POPCOUNT,
+            };
+            let ir = emit(ir, Some(r.clone()), None, Some(r2));
+            ir.borrow_mut().size = arg.borrow().ty.borrow().size;
+            return r;
+        }
+
+        NodeType::ATOMIC_LOAD
+        | NodeType::ATOMIC_STORE
+        | NodeType::ATOMIC_FETCH_ADD
+        | NodeType::ATOMIC_CAS
+        | NodeType::FENCE => {
+            return gen_atomic(node);
+        }
+
         NodeType::DEREF => {
             let r = new_reg();
             load(
@@ -498,12 +891,20 @@ fn gen_expr(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
         NodeType::CAST => {
             let r1 = gen_expr(node.borrow().expr.clone().unwrap());
             let ty = node.borrow().ty.clone();
-            if ty.borrow().ty != CType::BOOL {
+            if ty.borrow().ty == CType::BOOL {
+                let r2 = new_reg();
+                emit(IRType::NE, Some(r2.clone()), Some(r1.clone()), Some(imm(0)));
+                return r2;
+            }
+
+            if ty.borrow().ty == CType::STRUCT {
                 return r1;
             }
-            let r2 = new_reg();
-            emit(IRType::NE, Some(r2.clone()), Some(r1.clone()), Some(imm(0)));
-            return r2;
+            let mut r = r1;
+            for (op, n) in extend_ops(&ty.borrow()) {
+                r = emit_imm(op, r, n);
+            }
+            return r;
         }
 
         NodeType::STMT_EXPR => {
@@ -517,9 +918,16 @@ fn gen_expr(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
             let r1 = gen_expr(node.borrow().rhs.clone().unwrap());
             let r2 = gen_lval(node.borrow().lhs.clone().unwrap());
 
-            let ir = emit(IRType::STORE, None, Some(r2.clone()), Some(r1.clone()));
             let ty = node.borrow().ty.clone();
+            let op = if ty.borrow().is_atomic {
+                IRType::ATOMIC_STORE
+            } else {
+                IRType::STORE
+            };
+            let ir = emit(op, None, Some(r2.clone()), Some(r1.clone()));
             ir.borrow_mut().size = ty.borrow().size;
+            let lhs = node.borrow().lhs.clone().unwrap();
+            ir.borrow_mut().is_volatile = lhs.borrow().ty.borrow().is_volatile;
             return r1;
         }
         NodeType::ADD => {
@@ -531,17 +939,27 @@ fn gen_expr(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
         NodeType::MUL => {
             return gen_binop(IRType::MUL, node);
         }
-        NodeType::DIV => {
-            return gen_binop(IRType::DIV, node);
+        NodeType::DIV | NodeType::MOD => {
+            let is_mod = node.borrow().op == NodeType::MOD;
+            let op = match (is_unsigned(&node.borrow().ty), is_mod) {
+                (false, false) => IRType::DIV,
+                (false, true) => IRType::MOD,
+                (true, false) => IRType::UDIV,
+                (true, true) => IRType::UMOD,
+            };
+            return gen_binop(op, node);
         }
-        NodeType::MOD => {
-            return gen_binop(IRType::MOD, node);
-        }
-        NodeType::LT => {
-            return gen_binop(IRType::LT, node);
-        }
-        NodeType::LE => {
-            return gen_binop(IRType::LE, node);
+        // The operands have been converted to the same type.
+        NodeType::LT | NodeType::LE => {
+            let lhs = node.borrow().lhs.clone().unwrap();
+            let is_le = node.borrow().op == NodeType::LE;
+            let op = match (is_unsigned(&lhs.borrow().ty), is_le) {
+                (false, false) => IRType::LT,
+                (false, true) => IRType::LE,
+                (true, false) => IRType::ULT,
+                (true, true) => IRType::ULE,
+            };
+            return gen_binop(op, node);
         }
         NodeType::AND => {
             return gen_binop(IRType::AND, node);
@@ -556,7 +974,10 @@ fn gen_expr(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
             return gen_binop(IRType::SHL, node);
         }
         NodeType::SHR => {
-            return gen_binop(IRType::SHR, node);
+            if is_unsigned(&node.borrow().ty) {
+                return gen_binop(IRType::SHR, node);
+            }
+            return gen_binop(IRType::SAR, node);
         }
         NodeType::NOT => {
             let r1 = new_reg();
@@ -606,6 +1027,78 @@ fn gen_expr(node: Rc<RefCell<Node>>) -> Rc<RefCell<Reg>> {
     }
 }
 
+// Releases variable-length arrays allocated in blocks nested deeper
+// than `depth`.
+fn restore_sp(depth: usize) {
+    if vla_sp_len() > depth {
+        emit(IRType::STACK_RESTORE, None, None, Some(vla_sp_get(depth)));
+    }
+}
+
+// Operands are evaluated before the asm and outputs are stored
+// back to their lvalues after it.
+fn gen_asm(node: Rc<RefCell<Node>>) {
+    let asm = node.borrow().asm.clone().unwrap();
+    let mut args = Vec::new();
+    let mut uses = Vec::new();
+    let mut outs = Vec::new();
+    let mut stores = Vec::new();
+
+    for op in asm.operands.iter() {
+        let ty = op.expr.borrow().ty.clone();
+        let mut arg = AsmArg {
+            kind: op.kind.clone(),
+            reg: None,
+            imm: op.val,
+            size: ty.borrow().size,
+            is_input: !op.is_output || op.is_inout,
+            is_output: op.is_output,
+            is_earlyclobber: op.is_earlyclobber,
+        };
+
+        match op.kind {
+            AsmKind::IMM => {}
+            AsmKind::MEM => {
+                let r = gen_lval(op.expr.clone());
+                uses.push(r.clone());
+                arg.reg = Some(r);
+            }
+            _ => {
+                if op.is_output {
+                    let addr = gen_lval(op.expr.clone());
+                    let r = new_reg();
+                    if op.is_inout {
+                        load(op.expr.clone(), r.clone(), addr.clone());
+                        uses.push(r.clone());
+                    }
+                    outs.push(r.clone());
+                    stores.push((op.expr.clone(), addr, r.clone()));
+                    arg.reg = Some(r);
+                } else {
+                    let r = gen_expr(op.expr.clone());
+                    uses.push(r.clone());
+                    arg.reg = Some(r);
+                }
+            }
+        }
+        args.push(arg);
+    }
+
+    let ir = new_ir(IRType::ASM);
+    ir.borrow_mut().asm = asm.template.clone();
+    ir.borrow_mut().asm_args = args;
+    ir.borrow_mut().clobbers = asm.clobbers.clone();
+    ir.borrow_mut().nargs = uses.len();
+    ir.borrow_mut().args = uses;
+    ir.borrow_mut().outs = outs;
+    ir.borrow_mut().label = bump_nlabel() as i32;
+
+    for (expr, addr, r) in stores {
+        let ir = emit(IRType::STORE, None, Some(addr), Some(r));
+        ir.borrow_mut().size = expr.borrow().ty.borrow().size;
+    }
+}
+
 fn gen_stmt(node: Rc<RefCell<Node>>) {
     if node.borrow().op == NodeType::NULL {
         return;
@@ -618,25 +1111,34 @@ fn gen_stmt(node: Rc<RefCell<Node>>) {
             let els = new_bb();
             let last = new_bb();
 
-            br(
-                gen_expr(node.borrow().cond.clone().unwrap()),
-                then.clone(),
-                els.clone(),
-            );
+            let cond = node.borrow().cond.clone().unwrap();
+            br(gen_expr(cond.clone()), then.clone(), els.clone());
 
-            set_out(then);
+            let n1 = fn_bbs_len();
+            set_out(then.clone());
             gen_stmt(node.borrow().then.clone().unwrap());
             jmp(last.clone());
 
-            set_out(els);
+            let n2 = fn_bbs_len();
+            set_out(els.clone());
             if node.borrow().els.is_some() {
                 gen_stmt(node.borrow().els.clone().unwrap());
             }
             jmp(last.clone());
+            let n3 = fn_bbs_len();
 
             set_out(last);
+
+            // Lay out the unlikely branch of `if (__builtin_expect(x, c))`
+            // at the end of the function.
+            match expected_value(&cond) {
+                Some(false) => move_bbs_to_end(&then, n1, n2),
+                Some(true) if node.borrow().els.is_some() => move_bbs_to_end(&els, n2, n3),
+                _ => {}
+            }
         }
         NodeType::FOR => {
+            node.borrow_mut().vla_depth = vla_sp_len();
             let cond = new_bb();
             node.borrow_mut().continue_ = new_bb();
             let body = new_bb();
@@ -668,6 +1170,7 @@ fn gen_stmt(node: Rc<RefCell<Node>>) {
             set_out(node.borrow().break_.clone());
         }
         NodeType::DO_WHILE => {
+            node.borrow_mut().vla_depth = vla_sp_len();
             node.borrow_mut().continue_ = new_bb();
             let body = new_bb();
             node.borrow_mut().break_ = new_bb();
@@ -685,6 +1188,7 @@ fn gen_stmt(node: Rc<RefCell<Node>>) {
             set_out(node.borrow().break_.clone());
         }
         NodeType::SWITCH => {
+            node.borrow_mut().vla_depth = vla_sp_len();
             node.borrow_mut().break_ = new_bb();
             node.borrow_mut().continue_ = new_bb();
 
@@ -717,11 +1221,13 @@ fn gen_stmt(node: Rc<RefCell<Node>>) {
         }
         NodeType::BREAK => {
             let target = node.borrow().target.clone().unwrap();
+            restore_sp(target.borrow().vla_depth);
             jmp(target.borrow().clone().break_);
             set_out(new_bb());
         }
         NodeType::CONTINUE => {
             let target = node.borrow().target.clone().unwrap();
+            restore_sp(target.borrow().vla_depth);
             jmp(target.borrow().clone().continue_);
             set_out(new_bb());
         }
@@ -734,10 +1240,43 @@ fn gen_stmt(node: Rc<RefCell<Node>>) {
         NodeType::EXPR_STMT => {
             gen_expr(node.borrow().expr.clone().unwrap());
         }
+        NodeType::ASM => {
+            gen_asm(node);
+        }
         NodeType::COMP_STMT => {
+            let has_vla = node.borrow().has_vla;
+            if has_vla {
+                let r = new_reg();
+                emit(IRType::STACK_SAVE, Some(r.clone()), None, None);
+                vla_sp_push(r);
+            }
+
             for n in node.borrow().stmts.iter() {
                 gen_stmt(n.clone());
             }
+
+            // Mark the end of the lifetimes of the variables.
+            for var in node.borrow().scope_vars.iter() {
+                let ir = new_ir(IRType::SCOPE_END);
+                ir.borrow_mut().var = Some(var.clone());
+            }
+
+            if has_vla {
+                restore_sp(vla_sp_len() - 1);
+                vla_sp_pop();
+            }
+        }
+        NodeType::VLA_ALLOC => {
+            let size = gen_expr(node.borrow().expr.clone().unwrap());
+            let r = new_reg();
+            emit(IRType::ALLOCA, Some(r.clone()), None, Some(size));
+
+            let ir = new_ir(IRType::BPREL);
+            ir.borrow_mut().r0 = Some(new_reg());
+            ir.borrow_mut().var = node.borrow().var.clone();
+            let addr = ir.borrow().r0.clone();
+            let ir = emit(IRType::STORE, None, addr, Some(r));
+            ir.borrow_mut().size = 8;
         }
         t => {
             panic!("unknown node: {:?}", t);
@@ -748,9 +1287,8 @@ fn gen_stmt(node: Rc<RefCell<Node>>) {
 fn gen_param(var: &Rc<RefCell<Var>>, i: usize) {
     let ir = new_ir(IRType::STORE_ARG);
     ir.borrow_mut().var = Some(var.clone());
-    ir.borrow_mut().imm = i as i32;
+    ir.borrow_mut().imm = i as i64;
     ir.borrow_mut().size = var.borrow().ty.size;
-    var.borrow_mut().address_taken = true;
 }
 
 pub fn gen_ir(prog: &mut Program) {
@@ -777,8 +1315,9 @@ pub fn gen_ir(prog: &mut Program) {
 
         // Make it always ends with a return to make later analysis easy.
 
+        let r = imm(0);
         let ret_ir = new_ir(IRType::RETURN);
-        ret_ir.borrow_mut().r2 = Some(imm(0));
+        ret_ir.borrow_mut().r2 = Some(r);
 
         // Later passes shouldn't need the AST, so make it explicit.
         func.borrow_mut().node = Rc::new(RefCell::new(alloc_node()));
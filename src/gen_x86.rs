@@ -1,7 +1,14 @@
 // This pass generates x86-64 assembly from IR.
+//
+// Instructions of a function are collected as Insns, which the peephole
+// optimizer rewrites before they are printed.
 
 #![allow(non_upper_case_globals)]
 
+use crate::insn::*;
+use crate::passes::*;
+use crate::peephole::*;
+use crate::slots::*;
 use crate::util::*;
 use crate::*;
 use std::cell::RefCell;
@@ -9,6 +16,7 @@ use std::collections::HashMap;
 
 thread_local! {
     static ASM: RefCell<String> = RefCell::new(String::new());
+    static CODE: RefCell<Vec<Insn>> = RefCell::new(Vec::new());
 }
 
 fn print_asm(a: &str) {
@@ -17,6 +25,24 @@ fn print_asm(a: &str) {
     })
 }
 
+fn push_insn(insn: Insn) {
+    CODE.with(|code| {
+        code.borrow_mut().push(insn);
+    })
+}
+
+// Prints the instructions collected so far.
+fn flush_code() {
+    let mut code = CODE.with(|code| code.replace(Vec::new()));
+    if is_enabled("peephole") {
+        peephole(&mut code);
+    }
+    for insn in code.iter() {
+        print_asm(&insn.to_string());
+        print_asm("\n");
+    }
+}
+
 fn get_asm() -> String {
     ASM.with(|asm| {
         return asm.borrow().clone();
@@ -34,31 +60,92 @@ macro_rules! p {
     };
 }
 
+// Appends an instruction with a mnemonic and operands.
 macro_rules! emit {
-    ($fmt:expr) => {
-        print_asm("\t");
-        print_asm($fmt);
-        print_asm("\n");
+    ($op:expr) => {
+        push_insn(Insn::Op($op.to_string(), vec![]));
     };
-    ($fmt:expr,$($x:tt)*) => {
-        print_asm("\t");
-        print_asm(format!($fmt, $( $x )*).as_str());
-        print_asm("\n");
+    ($op:expr, $($x:expr),+) => {
+        push_insn(Insn::Op($op.to_string(), vec![$( $x ),+]));
     };
 }
 
+fn reg_op(name: &str) -> Operand {
+    return Operand::Reg(name.to_string());
+}
+
+fn imm_op(n: i64) -> Operand {
+    return Operand::Imm(n);
+}
+
+// A memory operand at an address, e.g. "rbp-8"
+fn mem_op(addr: &str) -> Operand {
+    return Operand::Mem(addr.to_string());
+}
+
+fn sym_op(name: &str) -> Operand {
+    return Operand::Sym(name.to_string());
+}
+
 thread_local! {
     static ESCAPED: RefCell<HashMap<char,char>> = RefCell::new(HashMap::new());
 }
 
-const regs: [&'static str; 7] = ["r10", "r11", "rbx", "r12", "r13", "r14", "r15"];
-const regs8: [&'static str; 7] = ["r10b", "r11b", "bl", "r12b", "r13b", "r14b", "r15b"];
-const regs32: [&'static str; 7] = ["r10d", "r11d", "ebx", "r12d", "r13d", "r14d", "r15d"];
+// Allocatable registers. Caller-saved ones come first so that they
+// are preferred. rax, rcx and rdx are used as scratch registers.
+const regs: [&'static str; 11] = [
+    "r10", "r11", "rdi", "rsi", "r8", "r9", "rbx", "r12", "r13", "r14", "r15",
+];
+const regs8: [&'static str; 11] = [
+    "r10b", "r11b", "dil", "sil", "r8b", "r9b", "bl", "r12b", "r13b", "r14b", "r15b",
+];
+const regs16: [&'static str; 11] = [
+    "r10w", "r11w", "di", "si", "r8w", "r9w", "bx", "r12w", "r13w", "r14w", "r15w",
+];
+const regs32: [&'static str; 11] = [
+    "r10d", "r11d", "edi", "esi", "r8d", "r9d", "ebx", "r12d", "r13d", "r14d", "r15d",
+];
+
+// Registers preserved across function calls in the SysV ABI
+const callee_saved: [&'static str; 5] = ["rbx", "r12", "r13", "r14", "r15"];
 
 pub fn num_regs() -> usize {
     return regs.len();
 }
 
+// Returns the index of an allocatable register, which may be
+// referred to by any of its sizes (e.g. "rbx", "ebx" or "%bl").
+pub fn reg_index(name: &str) -> Option<usize> {
+    let name = name.trim_start_matches('%');
+    for i in 0..num_regs() {
+        if regs[i] == name || regs32[i] == name || regs16[i] == name || regs8[i] == name {
+            return Some(i);
+        }
+    }
+    return None;
+}
+
+// Returns the set of allocatable registers which a function call may
+// clobber.
+pub fn caller_saved_mask() -> u32 {
+    let mut mask = 0;
+    for i in 0..num_regs() {
+        if !callee_saved.contains(&regs[i]) {
+            mask |= 1 << i;
+        }
+    }
+    return mask;
+}
+
+// Returns the set containing the register of the i-th argument if it
+// is allocatable.
+pub fn argreg_mask(i: usize) -> u32 {
+    match reg_index(argregs[i]) {
+        Some(i) => 1 << i,
+        None => 0,
+    }
+}
+
 const argregs: [&'static str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
 const argregs8: [&'static str; 6] = ["dil", "sil", "dl", "cl", "r8b", "r9b"];
 const argregs32: [&'static str; 6] = ["edi", "esi", "edx", "ecx", "r8d", "r9d"];
@@ -92,28 +179,138 @@ fn escaped(c: char) -> Option<char> {
     })
 }
 
+// Returns the right operand of a binary operation, which is an
+// immediate if there is no r2.
+fn rhs(ir: &IR) -> Operand {
+    match ir.r2 {
+        Some(ref r) => reg_op(regs[r.borrow().rn as usize]),
+        None => imm_op(ir.imm),
+    }
+}
+
+fn emit_shift(insn: &str, ir: &IR) {
+    let r0 = ir.r0.as_ref().unwrap().borrow().rn as usize;
+    match ir.r2 {
+        Some(ref r2) => {
+            emit!("mov", reg_op("cl"), reg_op(regs8[r2.borrow().rn as usize]));
+            emit!(insn, reg_op(regs[r0]), reg_op("cl"));
+        }
+        None => {
+            emit!(insn, reg_op(regs[r0]), imm_op(ir.imm));
+        }
+    }
+}
+
 fn emit_cmp(insn: &str, ir: &IR) {
     let rr0 = ir.r0.clone().unwrap();
     let rr1 = ir.r1.clone().unwrap();
-    let rr2 = ir.r2.clone().unwrap();
     let r0 = rr0.borrow().rn as usize;
     let r1 = rr1.borrow().rn as usize;
-    let r2 = rr2.borrow().rn as usize;
 
-    emit!("cmp {}, {}", regs[r1], regs[r2]);
-    emit!("{} {}", insn, regs8[r0]);
-    emit!("movzb {}, {}", regs[r0], regs8[r0]);
+    emit!("cmp", reg_op(regs[r1]), rhs(ir));
+    emit!(insn, reg_op(regs8[r0]));
+    emit!("movzb", reg_op(regs[r0]), reg_op(regs8[r0]));
 }
 
 fn reg(r: usize, size: i32) -> &'static str {
     match size {
         1 => regs8[r],
+        2 => regs16[r],
         4 => regs32[r],
         8 => regs[r],
         _ => panic!(),
     }
 }
 
+fn rax(size: i32) -> &'static str {
+    match size {
+        1 => "al",
+        2 => "ax",
+        4 => "eax",
+        8 => "rax",
+        _ => panic!(),
+    }
+}
+
+// Extends the low `size` bytes of r0 to 64 bits as loads do.
+fn emit_extend(r0: usize, size: i32, is_signed: bool) {
+    match (size, is_signed) {
+        (1, true) => {
+            emit!("movsx", reg_op(regs[r0]), reg_op(regs8[r0]));
+        }
+        (1, false) => {
+            emit!("movzb", reg_op(regs[r0]), reg_op(regs8[r0]));
+        }
+        (2, true) => {
+            emit!("movsx", reg_op(regs[r0]), reg_op(regs16[r0]));
+        }
+        (2, false) => {
+            emit!("movzx", reg_op(regs[r0]), reg_op(regs16[r0]));
+        }
+        (4, true) => {
+            emit!("movsxd", reg_op(regs[r0]), reg_op(regs32[r0]));
+        }
+        // A write to a 32-bit register clears the upper half.
+        (4, false) => {
+            emit!("mov", reg_op(regs32[r0]), reg_op(regs32[r0]));
+        }
+        _ => (),
+    }
+}
+
+// Copies the result of an atomic operation left in RAX to r0.
+fn emit_rax_result(r0: usize, size: i32, is_signed: bool) {
+    emit!("mov", reg_op(regs[r0]), reg_op("rax"));
+    emit_extend(r0, size, is_signed);
+}
+
+// The results of the bit builtins are undefined for zero, so bsr and
+// bsf can be used where lzcnt and tzcnt are not available.
+fn emit_clz(r0: usize, r2: usize, size: i32) {
+    if options().lzcnt {
+        emit!("lzcnt", reg_op(reg(r0, size)), reg_op(reg(r2, size)));
+        return;
+    }
+    emit!("bsr", reg_op(reg(r0, size)), reg_op(reg(r2, size)));
+    emit!("xor", reg_op(reg(r0, size)), imm_op((size * 8 - 1) as i64));
+}
+
+fn emit_ctz(r0: usize, r2: usize, size: i32) {
+    let insn = if options().bmi { "tzcnt" } else { "bsf" };
+    emit!(insn, reg_op(reg(r0, size)), reg_op(reg(r2, size)));
+}
+
+// Without popcnt, bits are counted in parallel with the well-known
+// SWAR sequence using RAX and RDX as scratch registers. The operand
+// is zero-extended, so the 64-bit sequence works for both sizes.
+fn emit_popcount(r0: usize, r2: usize, size: i32) {
+    if options().popcnt {
+        emit!("popcnt", reg_op(reg(r0, size)), reg_op(reg(r2, size)));
+        return;
+    }
+    let x = regs[r0];
+    emit!("mov", reg_op(reg(r0, size)), reg_op(reg(r2, size)));
+    emit!("mov", reg_op("rax"), reg_op(x));
+    emit!("shr", reg_op("rax"), imm_op(1));
+    emit!("movabs", reg_op("rdx"), imm_op(0x5555555555555555));
+    emit!("and", reg_op("rax"), reg_op("rdx"));
+    emit!("sub", reg_op(x), reg_op("rax"));
+    emit!("movabs", reg_op("rdx"), imm_op(0x3333333333333333));
+    emit!("mov", reg_op("rax"), reg_op(x));
+    emit!("and", reg_op("rax"), reg_op("rdx"));
+    emit!("shr", reg_op(x), imm_op(2));
+    emit!("and", reg_op(x), reg_op("rdx"));
+    emit!("add", reg_op(x), reg_op("rax"));
+    emit!("mov", reg_op("rax"), reg_op(x));
+    emit!("shr", reg_op("rax"), imm_op(4));
+    emit!("add", reg_op(x), reg_op("rax"));
+    emit!("movabs", reg_op("rdx"), imm_op(0x0f0f0f0f0f0f0f0f));
+    emit!("and", reg_op(x), reg_op("rdx"));
+    emit!("movabs", reg_op("rdx"), imm_op(0x0101010101010101));
+    emit!("imul", reg_op(x), reg_op("rdx"));
+    emit!("shr", reg_op(x), imm_op(56));
+}
+
 fn argreg(r: usize, size: i32) -> &'static str {
     match size {
         1 => argregs8[r],
@@ -123,7 +320,181 @@ fn argreg(r: usize, size: i32) -> &'static str {
     }
 }
 
-fn emit_ir(ir: &IR, ret: &String) {
+// Registers for "a", "c", "d", "S" and "D" asm constraints
+pub fn fixed_reg(c: char, size: i32) -> &'static str {
+    let names = match c {
+        'a' => ["al", "ax", "eax", "rax"],
+        'c' => ["cl", "cx", "ecx", "rcx"],
+        'd' => ["dl", "dx", "edx", "rdx"],
+        'S' => ["sil", "si", "esi", "rsi"],
+        'D' => ["dil", "di", "edi", "rdi"],
+        _ => panic!(),
+    };
+    match size {
+        1 => names[0],
+        2 => names[1],
+        4 => names[2],
+        _ => names[3],
+    }
+}
+
+fn asm_operand(arg: &AsmArg, modifier: Option<char>) -> String {
+    let size = match modifier {
+        Some('b') => 1,
+        Some('w') => 2,
+        Some('k') => 4,
+        Some('q') => 8,
+        _ => arg.size,
+    };
+    let rn = match arg.reg {
+        Some(ref r) => r.borrow().rn as usize,
+        None => 0,
+    };
+
+    match arg.kind {
+        AsmKind::IMM => arg.imm.to_string(),
+        AsmKind::MEM => format!("[{}]", regs[rn]),
+        AsmKind::REG => reg(rn, size).to_string(),
+        AsmKind::FIXED(c) => fixed_reg(c, size).to_string(),
+    }
+}
+
+fn emit_asm(ir: &IR) {
+    for arg in ir.asm_args.iter() {
+        if let AsmKind::FIXED(c) = arg.kind {
+            if arg.is_input {
+                let r = arg.reg.clone().unwrap();
+                emit!(
+                    "mov",
+                    reg_op(fixed_reg(c, 8)),
+                    reg_op(regs[r.borrow().rn as usize])
+                );
+            }
+        }
+    }
+
+    let mut buf = String::new();
+    for piece in ir.asm.iter() {
+        match *piece {
+            AsmPiece::TEXT(ref s) => buf.push_str(s),
+            AsmPiece::OPERAND(i, modifier) => buf.push_str(&asm_operand(&ir.asm_args[i], modifier)),
+            AsmPiece::UNIQUE => buf.push_str(&ir.label.to_string()),
+        }
+    }
+    push_insn(Insn::Raw(buf));
+
+    for arg in ir.asm_args.iter() {
+        if let AsmKind::FIXED(c) = arg.kind {
+            if arg.is_output {
+                let r = arg.reg.clone().unwrap();
+                emit!(
+                    "mov",
+                    reg_op(regs[r.borrow().rn as usize]),
+                    reg_op(fixed_reg(c, 8))
+                );
+            }
+        }
+    }
+}
+
+// Layout of the stack frame of a function
+struct Frame {
+    // Size of local variables
+    size: i32,
+    realign: bool,
+    has_alloca: bool,
+    // Local variables are addressed relative to RSP instead of RBP.
+    omit_fp: bool,
+    // Callee-saved registers used by the function
+    saved: Vec<&'static str>,
+}
+
+impl Frame {
+    // Returns the address of a local variable.
+    fn addr(&self, var: &Rc<RefCell<Var>>) -> Operand {
+        let offset = var.borrow().offset;
+        if self.omit_fp {
+            return mem_op(&format!("rsp+{}", roundup(self.size, 16) + offset));
+        }
+        return mem_op(&format!("rbp{}", offset));
+    }
+
+    // Returns the size of the area below the saved registers, which
+    // makes RSP 16-byte aligned.
+    fn rsp_size(&self) -> i32 {
+        let pad = if self.saved.len() % 2 == 0 { 8 } else { 0 };
+        return roundup(self.size, 16) + pad;
+    }
+}
+
+// Moves arguments to the argument registers. An argument may be in an
+// argument register itself, so a register is not overwritten until the
+// moves reading it are done. A cycle of moves is broken with rax.
+fn emit_args(ir: &IR) {
+    let mut moves = Vec::new();
+    for i in 0..ir.nargs {
+        let src = regs[ir.args[i].borrow().rn as usize];
+        if src != argregs[i] {
+            moves.push((argregs[i], src));
+        }
+    }
+
+    while !moves.is_empty() {
+        let ready = (0..moves.len()).find(|&i| moves.iter().all(|&(_, src)| src != moves[i].0));
+        match ready {
+            Some(i) => {
+                let (dst, src) = moves.remove(i);
+                emit!("mov", reg_op(dst), reg_op(src));
+            }
+            None => {
+                let src = moves[0].1;
+                emit!("mov", reg_op("rax"), reg_op(src));
+                for m in moves.iter_mut() {
+                    if m.1 == src {
+                        m.1 = "rax";
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Restores callee-saved registers and the caller's frame.
+fn emit_epilogue(frame: &Frame) {
+    if frame.omit_fp {
+        if frame.rsp_size() > 0 {
+            emit!("add", reg_op("rsp"), imm_op(frame.rsp_size() as i64));
+        }
+        for r in frame.saved.iter().rev() {
+            emit!("pop", reg_op(r));
+        }
+        return;
+    }
+
+    // Discard the dynamic part of the stack frame and the padding
+    // below the saved registers.
+    let n = frame.saved.len() as i32;
+    if frame.has_alloca {
+        emit!(
+            "lea",
+            reg_op("rsp"),
+            mem_op(&format!("rbp-{}", roundup(frame.size, 16) + n * 8))
+        );
+    } else if n % 2 == 1 {
+        emit!("add", reg_op("rsp"), imm_op(8));
+    }
+    for r in frame.saved.iter().rev() {
+        emit!("pop", reg_op(r));
+    }
+    if frame.realign {
+        emit!("mov", reg_op("rsp"), mem_op("rbp-8"));
+    } else {
+        emit!("mov", reg_op("rsp"), reg_op("rbp"));
+    }
+    emit!("pop", reg_op("rbp"));
+}
+
+fn emit_ir(ir: &IR, ret: &String, frame: &Frame) {
     let r0 = match ir.clone().r0 {
         Some(r) => r.borrow().rn,
         None => 0,
@@ -139,40 +510,76 @@ fn emit_ir(ir: &IR, ret: &String) {
 
     match ir.op {
         IRType::IMM => {
-            emit!("mov {}, {}", regs[r0 as usize], ir.imm);
+            // `mov` takes a sign-extended 32-bit immediate.
+            if ir.imm == ir.imm as i32 as i64 {
+                emit!("mov", reg_op(regs[r0 as usize]), imm_op(ir.imm));
+            } else {
+                emit!("movabs", reg_op(regs[r0 as usize]), imm_op(ir.imm));
+            }
         }
         IRType::BPREL => {
-            let var = ir.var.clone().unwrap();
-            let offset = var.borrow().offset;
-            emit!("lea {}, [rbp{}]", regs[r0 as usize], offset);
+            let addr = frame.addr(ir.var.as_ref().unwrap());
+            emit!("lea", reg_op(regs[r0 as usize]), addr);
         }
         IRType::MOV => {
-            emit!("mov {}, {}", regs[r0 as usize], regs[r2 as usize]);
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op(regs[r2 as usize]));
+        }
+        IRType::ARG => {
+            emit!(
+                "mov",
+                reg_op(regs[r0 as usize]),
+                reg_op(argregs[ir.imm as usize])
+            );
         }
         IRType::RETURN => {
-            emit!("mov rax, {}", regs[r2 as usize]);
-            emit!("jmp {}", ret);
+            emit!("mov", reg_op("rax"), reg_op(regs[r2 as usize]));
+            emit!("jmp", sym_op(ret));
         }
         IRType::CALL => {
-            for i in 0..ir.nargs {
-                emit!(
-                    "mov {}, {}",
-                    argregs[i],
-                    regs[ir.args[i].borrow().rn as usize]
-                );
-            }
-
-            emit!("push r10");
-            emit!("push r11");
-            emit!("mov rax, 0");
-            emit!("call {}", ir.name);
-            emit!("pop r11");
-            emit!("pop r10");
-
-            emit!("mov {}, rax", regs[r0 as usize]);
+            // Registers live across the call are callee-saved ones.
+            emit_args(ir);
+            emit!("mov", reg_op("rax"), imm_op(0));
+            emit!("call", sym_op(&ir.name));
+
+            // The upper bits of a narrow return value are undefined.
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rax"));
+            emit_extend(r0 as usize, ir.size, ir.is_signed);
+        }
+        IRType::TAIL_CALL => {
+            // The callee returns to our caller.
+            emit_args(ir);
+            emit_epilogue(frame);
+            emit!("mov", reg_op("rax"), imm_op(0));
+            emit!("jmp", sym_op(&ir.name));
+        }
+        IRType::ASM => {
+            emit_asm(ir);
+        }
+        IRType::ALLOCA => {
+            // Keep RSP 16-byte aligned for function calls.
+            emit!("mov", reg_op("rax"), reg_op(regs[r2 as usize]));
+            emit!("add", reg_op("rax"), imm_op(15));
+            emit!("and", reg_op("rax"), imm_op(-16));
+            emit!("sub", reg_op("rsp"), reg_op("rax"));
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rsp"));
+        }
+        IRType::STACK_SAVE => {
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rsp"));
+        }
+        IRType::STACK_RESTORE => {
+            emit!("mov", reg_op("rsp"), reg_op(regs[r2 as usize]));
         }
         IRType::LABEL_ADDR => {
-            emit!("lea {}, {}", regs[r0 as usize], ir.name);
+            emit!("lea", reg_op(regs[r0 as usize]), sym_op(&ir.name));
+        }
+        IRType::TLS_ADDR => {
+            // Local-exec TLS model: the thread pointer is at fs:0.
+            emit!("mov", reg_op(regs[r0 as usize]), sym_op("fs:0"));
+            emit!(
+                "lea",
+                reg_op(regs[r0 as usize]),
+                mem_op(&format!("{0}+{1}@tpoff", regs[r0 as usize], ir.name))
+            );
         }
         IRType::EQ => {
             emit_cmp("sete", ir);
@@ -186,132 +593,269 @@ fn emit_ir(ir: &IR, ret: &String) {
         IRType::LE => {
             emit_cmp("setle", ir);
         }
+        IRType::ULT => {
+            emit_cmp("setb", ir);
+        }
+        IRType::ULE => {
+            emit_cmp("setbe", ir);
+        }
         IRType::AND => {
-            emit!("and {}, {}", regs[r0 as usize], regs[r2 as usize]);
+            emit!("and", reg_op(regs[r0 as usize]), rhs(ir));
         }
         IRType::OR => {
-            emit!("or {}, {}", regs[r0 as usize], regs[r2 as usize]);
+            emit!("or", reg_op(regs[r0 as usize]), rhs(ir));
         }
         IRType::XOR => {
-            emit!("xor {}, {}", regs[r0 as usize], regs[r2 as usize]);
-        }
-        IRType::SHL => {
-            emit!("mov cl, {}", regs8[r2 as usize]);
-            emit!("shl {}, cl", regs[r0 as usize]);
-        }
-        IRType::SHR => {
-            emit!("mov cl, {}", regs8[r2 as usize]);
-            emit!("shr {}, cl", regs[r0 as usize]);
+            emit!("xor", reg_op(regs[r0 as usize]), rhs(ir));
         }
+        IRType::SHL => emit_shift("shl", ir),
+        IRType::SHR => emit_shift("shr", ir),
+        IRType::SAR => emit_shift("sar", ir),
         IRType::JMP => {
             let bb1 = ir.bb1.clone().unwrap();
-            if ir.bbarg.is_some() {
-                let param = bb1.borrow().param.clone().unwrap();
-                let bbarg = ir.bbarg.clone().unwrap();
-                emit!(
-                    "mov {}, {}",
-                    regs[param.borrow().rn as usize],
-                    regs[bbarg.borrow().rn as usize]
-                );
-            }
-            emit!("jmp .L{}", bb1.borrow().label);
+            emit!("jmp", sym_op(&format!(".L{}", bb1.borrow().label)));
         }
         IRType::BR => {
             let bb1 = ir.bb1.clone().unwrap();
             let bb2 = ir.bb2.clone().unwrap();
-            emit!("cmp {}, 0", regs[r2 as usize]);
-            emit!("jne .L{}", bb1.borrow().label);
-            emit!("jmp .L{}", bb2.borrow().label);
-        }
-        IRType::LOAD => {
-            emit!("mov {}, [{}]", reg(r0 as usize, ir.size), regs[r2 as usize]);
-            if ir.size == 1 {
-                emit!("movzb {}, {}", regs[r0 as usize], regs8[r0 as usize]);
+            emit!("cmp", reg_op(regs[r2 as usize]), imm_op(0));
+            emit!("jne", sym_op(&format!(".L{}", bb1.borrow().label)));
+            emit!("jmp", sym_op(&format!(".L{}", bb2.borrow().label)));
+        }
+        // A naturally aligned load or store is atomic on x86-64. A
+        // store uses xchg, which is a full barrier as well.
+        IRType::ATOMIC_STORE => {
+            emit!("mov", reg_op("rax"), reg_op(regs[r2 as usize]));
+            emit!("xchg", mem_op(regs[r1 as usize]), reg_op(rax(ir.size)));
+        }
+        IRType::ATOMIC_FETCH_ADD => {
+            emit!("mov", reg_op("rax"), reg_op(regs[r2 as usize]));
+            emit!("lock xadd", mem_op(regs[r0 as usize]), reg_op(rax(ir.size)));
+            emit_rax_result(r0 as usize, ir.size, ir.is_signed);
+        }
+        IRType::ATOMIC_CAS => {
+            let addr = ir.args[0].borrow().rn as usize;
+            emit!("mov", reg_op("rax"), reg_op(regs[r0 as usize]));
+            emit!(
+                "lock cmpxchg",
+                mem_op(regs[addr]),
+                reg_op(reg(r2 as usize, ir.size))
+            );
+            emit_rax_result(r0 as usize, ir.size, ir.is_signed);
+        }
+        IRType::FENCE => {
+            emit!("mfence");
+        }
+        IRType::TRAP => {
+            emit!("ud2");
+        }
+        IRType::UNREACHABLE => {}
+        IRType::CLZ => emit_clz(r0 as usize, r2 as usize, ir.size),
+        IRType::CTZ => emit_ctz(r0 as usize, r2 as usize, ir.size),
+        IRType::POPCOUNT => emit_popcount(r0 as usize, r2 as usize, ir.size),
+        IRType::LOAD | IRType::ATOMIC_LOAD => {
+            emit!(
+                "mov",
+                reg_op(reg(r0 as usize, ir.size)),
+                mem_op(regs[r2 as usize])
+            );
+            if ir.size < 4 || ir.is_signed {
+                emit_extend(r0 as usize, ir.size, ir.is_signed);
             }
         }
         IRType::LOAD_SPILL => {
-            let var = ir.var.clone().unwrap();
-            let offset = var.borrow().offset;
-            emit!("mov {}, [rbp{}]", regs[r0 as usize], offset);
+            let addr = frame.addr(ir.var.as_ref().unwrap());
+            emit!("mov", reg_op(regs[r0 as usize]), addr);
         }
         IRType::STORE => {
-            emit!("mov [{}], {}", regs[r1 as usize], reg(r2 as usize, ir.size));
+            emit!(
+                "mov",
+                mem_op(regs[r1 as usize]),
+                reg_op(reg(r2 as usize, ir.size))
+            );
         }
         IRType::STORE_ARG => {
-            let var = ir.var.clone().unwrap();
-            let offset = var.borrow().offset;
-            emit!("mov [rbp{}], {}", offset, argreg(ir.imm as usize, ir.size));
+            let addr = frame.addr(ir.var.as_ref().unwrap());
+            emit!("mov", addr, reg_op(argreg(ir.imm as usize, ir.size)));
         }
         IRType::STORE_SPILL => {
-            let var = ir.var.clone().unwrap();
-            let offset = var.borrow().offset;
-            emit!("mov [rbp{}], {}", offset, regs[r1 as usize]);
+            let addr = frame.addr(ir.var.as_ref().unwrap());
+            emit!("mov", addr, reg_op(regs[r1 as usize]));
         }
         IRType::ADD => {
-            emit!("add {}, {}", regs[r0 as usize], regs[r2 as usize]);
+            emit!("add", reg_op(regs[r0 as usize]), rhs(ir));
         }
         IRType::SUB => {
-            emit!("sub {}, {}", regs[r0 as usize], regs[r2 as usize]);
-        }
-        IRType::MUL => loop {
-            emit!("mov rax, {}", regs[r2 as usize]);
-            emit!("imul {}", regs[r0 as usize]);
-            emit!("mov {}, rax", regs[r0 as usize]);
-            break;
-        },
+            emit!("sub", reg_op(regs[r0 as usize]), rhs(ir));
+        }
+        IRType::MUL => {
+            if ir.r2.is_some() {
+                emit!("mov", reg_op("rax"), reg_op(regs[r2 as usize]));
+                emit!("imul", reg_op(regs[r0 as usize]));
+                emit!("mov", reg_op(regs[r0 as usize]), reg_op("rax"));
+            } else if ir.imm == 3 || ir.imm == 5 || ir.imm == 9 {
+                emit!(
+                    "lea",
+                    reg_op(regs[r0 as usize]),
+                    mem_op(&format!("{0}+{0}*{1}", regs[r0 as usize], ir.imm - 1))
+                );
+            } else {
+                emit!(
+                    "imul",
+                    reg_op(regs[r0 as usize]),
+                    reg_op(regs[r0 as usize]),
+                    imm_op(ir.imm)
+                );
+            }
+        }
+        IRType::MULH => {
+            emit!("mov", reg_op("rax"), reg_op(regs[r2 as usize]));
+            emit!("imul", reg_op(regs[r0 as usize]));
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rdx"));
+        }
         IRType::DIV => {
-            emit!("mov rax, {}", regs[r0 as usize]);
+            emit!("mov", reg_op("rax"), reg_op(regs[r0 as usize]));
             emit!("cqo");
-            emit!("idiv {}", regs[r2 as usize]);
-            emit!("mov {}, rax", regs[r0 as usize]);
+            emit!("idiv", reg_op(regs[r2 as usize]));
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rax"));
         }
         IRType::MOD => {
-            emit!("mov rax, {}", regs[r0 as usize]);
+            emit!("mov", reg_op("rax"), reg_op(regs[r0 as usize]));
             emit!("cqo");
-            emit!("idiv {}", regs[r2 as usize]);
-            emit!("mov {}, rdx", regs[r0 as usize]);
+            emit!("idiv", reg_op(regs[r2 as usize]));
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rdx"));
+        }
+        IRType::UDIV => {
+            emit!("mov", reg_op("rax"), reg_op(regs[r0 as usize]));
+            emit!("xor", reg_op("edx"), reg_op("edx"));
+            emit!("div", reg_op(regs[r2 as usize]));
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rax"));
         }
-        IRType::NOP => {}
+        IRType::UMOD => {
+            emit!("mov", reg_op("rax"), reg_op(regs[r0 as usize]));
+            emit!("xor", reg_op("edx"), reg_op("edx"));
+            emit!("div", reg_op(regs[r2 as usize]));
+            emit!("mov", reg_op(regs[r0 as usize]), reg_op("rdx"));
+        }
+        IRType::NOP | IRType::SCOPE_END => {}
     }
 }
 
-fn emit_code(fun: &Function) {
-    // Assign an offset from RBP to each local variable.
-    let mut off = 0;
-    for v in fun.lvars.clone().iter_mut() {
-        off += v.borrow().ty.size;
-        off = roundup(off, v.borrow().ty.align);
-        v.borrow_mut().offset = -off;
+fn has_alloca(fun: &Function) -> bool {
+    for bb in fun.bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            match ir.borrow().op {
+                IRType::ALLOCA | IRType::STACK_RESTORE => return true,
+                _ => (),
+            }
+        }
+    }
+    return false;
+}
+
+// Returns the callee-saved registers which are assigned to some
+// registers or clobbered by inline assembly in a function.
+fn used_callee_saved(fun: &Function) -> Vec<&'static str> {
+    let mut used = 0u32;
+    for bb in fun.bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            for r in ir.r0.iter().chain(ir.outs.iter()).chain(uses(&ir).iter()) {
+                if r.borrow().rn >= 0 {
+                    used |= 1 << r.borrow().rn;
+                }
+            }
+            for name in ir.clobbers.iter() {
+                if let Some(i) = reg_index(name) {
+                    used |= 1 << i;
+                }
+            }
+        }
     }
 
+    return callee_saved
+        .iter()
+        .cloned()
+        .filter(|r| used & (1 << reg_index(r).unwrap()) != 0)
+        .collect();
+}
+
+fn emit_code(fun: &Function) {
+    // Variables aligned by _Alignas to more than 16 bytes need
+    // a realigned frame. The original RSP is saved at [rbp-8].
+    let max_align = frame_align(fun);
+    let realign = max_align > 16;
+    let has_alloca = has_alloca(fun);
+
     // Emit assembly
     let ret = format!(".Lend{}", bump_nlabel());
 
-    p!(".text");
-    p!(".global {}", fun.name);
+    match fun.section {
+        Some(ref name) => {
+            p!(".section {},\"ax\",@progbits", name);
+        }
+        None => {
+            p!(".text");
+        }
+    }
+    if fun.is_weak {
+        p!(".weak {}", fun.name);
+    } else {
+        p!(".global {}", fun.name);
+    }
     p!("{}:", fun.name);
-    emit!("push rbp");
-    emit!("mov rbp, rsp");
-    emit!("sub rsp, {}", roundup(off, 16));
-    emit!("push r12");
-    emit!("push r13");
-    emit!("push r14");
-    emit!("push r15");
+
+    // RSP doesn't move in the function body unless the frame is
+    // realigned or has a variable size, so locals can be addressed
+    // relative to it.
+    let frame = Frame {
+        size: fun.stacksize,
+        realign: realign,
+        has_alloca: has_alloca,
+        omit_fp: options().omit_frame_pointer && !realign && !has_alloca,
+        saved: used_callee_saved(fun),
+    };
+
+    if frame.omit_fp {
+        for r in frame.saved.iter() {
+            emit!("push", reg_op(r));
+        }
+        if frame.rsp_size() > 0 {
+            emit!("sub", reg_op("rsp"), imm_op(frame.rsp_size() as i64));
+        }
+    } else {
+        emit!("push", reg_op("rbp"));
+        if realign {
+            emit!("mov", reg_op("rax"), reg_op("rsp"));
+            emit!("and", reg_op("rsp"), imm_op(-max_align as i64));
+            emit!("mov", reg_op("rbp"), reg_op("rsp"));
+            emit!("sub", reg_op("rsp"), imm_op(roundup(frame.size, 16) as i64));
+            emit!("mov", mem_op("rbp-8"), reg_op("rax"));
+        } else {
+            emit!("mov", reg_op("rbp"), reg_op("rsp"));
+            if frame.size > 0 {
+                emit!("sub", reg_op("rsp"), imm_op(roundup(frame.size, 16) as i64));
+            }
+        }
+        // Save callee-saved registers. rsp is kept 16-byte aligned.
+        for r in frame.saved.iter() {
+            emit!("push", reg_op(r));
+        }
+        if frame.saved.len() % 2 == 1 {
+            emit!("sub", reg_op("rsp"), imm_op(8));
+        }
+    }
 
     for bb in fun.bbs.iter() {
-        p!(".L{}:", bb.borrow().label);
+        push_insn(Insn::Label(format!(".L{}", bb.borrow().label)));
         for ir in bb.borrow().ir.iter() {
-            emit_ir(&*ir.borrow(), &ret);
+            emit_ir(&*ir.borrow(), &ret, &frame);
         }
     }
-    p!("{}:", ret);
-    emit!("pop r15");
-    emit!("pop r14");
-    emit!("pop r13");
-    emit!("pop r12");
-    emit!("mov rsp, rbp");
-    emit!("pop rbp");
+    push_insn(Insn::Label(ret));
+    emit_epilogue(&frame);
     emit!("ret");
+    flush_code();
 }
 
 fn backslash_escape(s: &String) -> String {
@@ -334,20 +878,66 @@ fn backslash_escape(s: &String) -> String {
     return buf;
 }
 
+fn emit_section(var: &Var) {
+    if let Some(ref name) = var.section {
+        let flags = if var.is_thread_local { "awT" } else { "aw" };
+        p!(".section {},\"{}\",@progbits", name, flags);
+        return;
+    }
+
+    if var.data.is_some() {
+        if var.is_thread_local {
+            p!(".section .tdata,\"awT\",@progbits");
+        } else {
+            p!(".data");
+        }
+        return;
+    }
+
+    if var.is_thread_local {
+        p!(".section .tbss,\"awT\",@nobits");
+    } else {
+        p!(".bss");
+    }
+}
+
+// With -fcommon, tentative definitions are emitted as common symbols
+// so that the linker merges them across translation units.
+fn is_common(var: &Var) -> bool {
+    return options().common
+        && var.data.is_none()
+        && var.section.is_none()
+        && !var.is_weak
+        && !var.is_thread_local;
+}
+
 fn emit_data(var: &Var) {
+    let align = var_align(var);
+
+    if is_common(var) {
+        p!(".comm {},{},{}", var.name, var.ty.size, align);
+        return;
+    }
+
+    emit_section(var);
+    if align > 1 {
+        p!(".align {}", align);
+    }
+    if var.is_weak {
+        p!(".weak {}", var.name);
+    } else if !var.name.starts_with(".L") {
+        p!(".global {}", var.name);
+    }
+    p!("{}:", var.name);
+
     if var.data.is_some() {
-        p!(".data");
-        p!("{}:", var.name);
-        emit!(
-            ".ascii \"{}\"",
+        p!(
+            "\t.ascii \"{}\"",
             backslash_escape(&var.data.clone().unwrap())
         );
         return;
     }
-
-    p!(".bss");
-    p!("{}:", var.name);
-    emit!(".zero {}", var.ty.size);
+    p!("\t.zero {}", var.ty.size);
 }
 
 pub fn gen_x86(prog: &mut Program) -> String {
@@ -357,6 +947,11 @@ pub fn gen_x86(prog: &mut Program) -> String {
         emit_data(&v.borrow());
     }
 
+    for s in prog.asm.iter() {
+        p!(".text");
+        p!("{}", s);
+    }
+
     for f in prog.funcs.iter() {
         emit_code(&*f.borrow());
     }
@@ -0,0 +1,244 @@
+// Global value numbering
+//
+// This pass removes computations whose results are already available in
+// a register. The dominator tree is walked from the entry BB, and each
+// pure instruction is looked up in a table keyed by its operation and
+// operands. If an equivalent instruction dominates it, its result is
+// reused. Since the IR is in SSA form, a register holds the same value
+// wherever it is visible, so the table only needs to be scoped by the
+// dominator tree. Registers defined by IMM are compared by their values,
+// so that e.g. `x+1` in two places is found to be the same.
+//
+// LOADs depend on memory as well. A STORE kills the available LOADs
+// which may read the same memory according to alias.rs, and a CALL,
+// inline assembly or an atomic operation kills all of them except LOADs
+// from stack variables whose addresses never escape. Available LOADs are
+// carried only into BBs with a single predecessor, so that no path
+// through a store is missed.
+
+use crate::alias::*;
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Operand {
+    None,
+    Reg(i32),
+    Const(i64),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    op: IRType,
+    r1: Operand,
+    r2: Operand,
+    imm: i64,
+    label: i32,
+    size: i32,
+    name: String,
+    var: usize,
+}
+
+#[derive(Clone)]
+struct Load {
+    addr: Operand,
+    size: i32,
+    is_signed: bool,
+    base: Base,
+    r0: Rc<RefCell<Reg>>,
+}
+
+struct Gvn {
+    bbs: Vec<Rc<RefCell<BB>>>,
+    ndefs: HashMap<i32, usize>,
+    consts: HashMap<i32, i64>,
+    mem: MemInfo,
+
+    exprs: HashMap<Key, Rc<RefCell<Reg>>>,
+    subst: HashMap<i32, Rc<RefCell<Reg>>>,
+}
+
+pub fn is_pure(op: &IRType) -> bool {
+    match op {
+        IRType::BPREL
+        | IRType::LABEL_ADDR
+        | IRType::TLS_ADDR
+        | IRType::CLZ
+        | IRType::CTZ
+        | IRType::POPCOUNT
+        | IRType::EQ
+        | IRType::NE
+        | IRType::LE
+        | IRType::LT
+        | IRType::ULE
+        | IRType::ULT
+        | IRType::AND
+        | IRType::OR
+        | IRType::XOR
+        | IRType::SHL
+        | IRType::SHR
+        | IRType::SAR
+        | IRType::MOD
+        | IRType::UMOD
+        | IRType::ADD
+        | IRType::SUB
+        | IRType::MUL
+        | IRType::MULH
+        | IRType::DIV
+        | IRType::UDIV => true,
+        _ => false,
+    }
+}
+
+fn is_commutative(op: &IRType) -> bool {
+    match op {
+        IRType::ADD
+        | IRType::MUL
+        | IRType::AND
+        | IRType::OR
+        | IRType::XOR
+        | IRType::EQ
+        | IRType::NE => true,
+        _ => false,
+    }
+}
+
+impl Gvn {
+    fn operand(&self, r: &Option<Rc<RefCell<Reg>>>) -> Option<Operand> {
+        let vn = match r {
+            Some(r) => r.borrow().vn,
+            None => return Some(Operand::None),
+        };
+        if self.ndefs.get(&vn) != Some(&1) {
+            return None;
+        }
+        if let Some(c) = self.consts.get(&vn) {
+            return Some(Operand::Const(*c));
+        }
+        return Some(Operand::Reg(vn));
+    }
+
+    fn key(&self, ir: &IR) -> Option<Key> {
+        let mut r1 = self.operand(&ir.r1)?;
+        let mut r2 = self.operand(&ir.r2)?;
+        if is_commutative(&ir.op) && r1 > r2 {
+            std::mem::swap(&mut r1, &mut r2);
+        }
+        return Some(Key {
+            op: ir.op.clone(),
+            r1: r1,
+            r2: r2,
+            imm: ir.imm,
+            label: ir.label,
+            size: ir.size,
+            name: ir.name.clone(),
+            var: var_key(&ir.var),
+        });
+    }
+
+    fn visit(&mut self, b: usize, mut loads: Vec<Load>, dom: &DomTree) {
+        let bb = self.bbs[b].clone();
+        if bb.borrow().pred.len() != 1 {
+            loads = Vec::new();
+        }
+        let mut added = Vec::new();
+        let mut v = Vec::new();
+
+        let irs = bb.borrow().ir.clone();
+        for ir in irs.into_iter() {
+            {
+                let subst = &self.subst;
+                map_uses(&mut ir.borrow_mut(), |r| match subst.get(&r.borrow().vn) {
+                    Some(r2) => r2.clone(),
+                    None => r.clone(),
+                });
+            }
+
+            let op = ir.borrow().op.clone();
+            let r0 = ir.borrow().r0.clone();
+            let single_def = match r0.as_ref() {
+                Some(r0) => self.ndefs.get(&r0.borrow().vn) == Some(&1),
+                None => false,
+            };
+
+            if single_def && is_pure(&op) {
+                if let Some(key) = self.key(&ir.borrow()) {
+                    let r0 = r0.unwrap();
+                    if let Some(r) = self.exprs.get(&key) {
+                        self.subst.insert(r0.borrow().vn, r.clone());
+                        continue;
+                    }
+                    self.exprs.insert(key.clone(), r0);
+                    added.push(key);
+                }
+            } else if single_def && op == IRType::LOAD && !ir.borrow().is_volatile {
+                if let Some(addr) = self.operand(&ir.borrow().r2) {
+                    let size = ir.borrow().size;
+                    let is_signed = ir.borrow().is_signed;
+                    let r0 = r0.unwrap();
+                    if let Some(l) = loads
+                        .iter()
+                        .find(|l| l.addr == addr && l.size == size && l.is_signed == is_signed)
+                    {
+                        self.subst.insert(r0.borrow().vn, l.r0.clone());
+                        continue;
+                    }
+                    loads.push(Load {
+                        addr: addr,
+                        size: size,
+                        is_signed: is_signed,
+                        base: self.mem.base(&ir.borrow().r2),
+                        r0: r0,
+                    });
+                }
+            } else if let Some(base) = self.mem.store_base(&ir.borrow()) {
+                loads.retain(|l| !self.mem.may_alias(&l.base, &base));
+            } else if clobbers_memory(&op) {
+                loads.retain(|l| self.mem.is_local(&l.base));
+            }
+            v.push(ir);
+        }
+        bb.borrow_mut().ir = v;
+
+        for &c in dom.children[b].iter() {
+            self.visit(c, loads.clone(), dom);
+        }
+
+        for key in added.iter() {
+            self.exprs.remove(key);
+        }
+    }
+}
+
+pub fn gvn(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+    let bbs = fun.borrow().bbs.clone();
+    let dom = dominators(fun);
+
+    let ndefs = count_defs(&bbs);
+    let mut consts = HashMap::new();
+    for bb in bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            if ir.op == IRType::IMM {
+                consts.insert(ir.r0.as_ref().unwrap().borrow().vn, ir.imm);
+            }
+        }
+    }
+
+    let mem = mem_info(&bbs, &ndefs);
+
+    let mut s = Gvn {
+        bbs: bbs,
+        ndefs: ndefs,
+        consts: consts,
+        mem: mem,
+        exprs: HashMap::new(),
+        subst: HashMap::new(),
+    };
+    s.visit(0, Vec::new(), &dom);
+}
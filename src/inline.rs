@@ -0,0 +1,272 @@
+// Function inlining
+//
+// A call to a function defined in the same file is replaced with a copy
+// of the callee's body. The BB containing the call is split after it, and
+// the copy is placed in between: the BBs of the callee are cloned with
+// fresh labels, registers and local variables, a STORE_ARG of the callee
+// becomes a store of the corresponding argument, and a RETURN becomes a
+// jump to the rest of the caller, passing the return value as a block
+// parameter.
+//
+// Whether a call is inlined depends on the size of the callee. A function
+// marked `inline` is given a larger budget unless optimizing for size,
+// `always_inline` is inlined regardless of its size and `noinline` never
+// is. Weak functions may be replaced at link time, so they are not
+// inlined either. Neither are functions calling themselves or using
+// alloca, whose stack would grow with every iteration if inlined in a
+// loop.
+//
+// Inlining runs before the other optimizations, so the copied code is
+// optimized together with the caller. Code copied into a function is not
+// scanned for calls again, so mutually recursive functions are expanded
+// only once.
+
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use crate::util::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Maximum number of instructions of a function to be inlined
+const INLINE_LIMIT: usize = 24;
+const INLINE_KEYWORD_LIMIT: usize = 96;
+// With -Os, about the size of a call
+const INLINE_SIZE_LIMIT: usize = 8;
+
+fn size(fun: &Function) -> usize {
+    return fun.bbs.iter().map(|bb| bb.borrow().ir.len()).sum();
+}
+
+fn nparams(fun: &Function) -> usize {
+    let mut n = 0;
+    for bb in fun.bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            if ir.borrow().op == IRType::STORE_ARG {
+                n += 1;
+            }
+        }
+    }
+    return n;
+}
+
+fn uses_alloca(fun: &Function) -> bool {
+    for bb in fun.bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            match ir.borrow().op {
+                IRType::ALLOCA | IRType::STACK_SAVE | IRType::STACK_RESTORE => return true,
+                _ => (),
+            }
+        }
+    }
+    return false;
+}
+
+fn should_inline(caller: &Function, callee: &Function, ir: &IR) -> bool {
+    if callee.name == caller.name || callee.is_weak || callee.is_noinline {
+        return false;
+    }
+    if ir.nargs != nparams(callee) || uses_alloca(callee) {
+        return false;
+    }
+    if callee.is_always_inline {
+        return true;
+    }
+    let limit = if options().opt_level == OptLevel::Os {
+        INLINE_SIZE_LIMIT
+    } else if callee.is_inline {
+        INLINE_KEYWORD_LIMIT
+    } else {
+        INLINE_LIMIT
+    };
+    return size(callee) <= limit;
+}
+
+struct Inliner {
+    regs: HashMap<i32, Rc<RefCell<Reg>>>,
+    vars: Vec<(Rc<RefCell<Var>>, Rc<RefCell<Var>>)>,
+    bbs: HashMap<usize, Rc<RefCell<BB>>>,
+}
+
+impl Inliner {
+    fn reg(&mut self, r: &Rc<RefCell<Reg>>) -> Rc<RefCell<Reg>> {
+        let vn = r.borrow().vn;
+        return self.regs.entry(vn).or_insert_with(new_reg).clone();
+    }
+
+    fn var(&mut self, var: &Rc<RefCell<Var>>) -> Rc<RefCell<Var>> {
+        if let Some((_, v)) = self.vars.iter().find(|(v, _)| Rc::ptr_eq(v, var)) {
+            return v.clone();
+        }
+        let mut v = var.borrow().clone();
+        v.offset = 0;
+        let v = Rc::new(RefCell::new(v));
+        self.vars.push((var.clone(), v.clone()));
+        return v;
+    }
+
+    fn bb(&self, bb: &Option<Rc<RefCell<BB>>>) -> Option<Rc<RefCell<BB>>> {
+        return bb.as_ref().map(|bb| self.bbs[&bb.borrow().label].clone());
+    }
+
+    fn clone_ir(
+        &mut self,
+        ir: &IR,
+        args: &Vec<Rc<RefCell<Reg>>>,
+        tail: &Rc<RefCell<BB>>,
+    ) -> Vec<Rc<RefCell<IR>>> {
+        // Parameters are initialized with the arguments.
+        if ir.op == IRType::STORE_ARG {
+            let var = self.var(ir.var.as_ref().unwrap());
+            let addr = new_reg();
+
+            let mut bprel = alloc_ir();
+            bprel.op = IRType::BPREL;
+            bprel.r0 = Some(addr.clone());
+            bprel.var = Some(var);
+
+            let mut store = alloc_ir();
+            store.op = IRType::STORE;
+            store.r1 = Some(addr);
+            store.r2 = Some(args[ir.imm as usize].clone());
+            store.size = ir.size;
+            return vec![Rc::new(RefCell::new(bprel)), Rc::new(RefCell::new(store))];
+        }
+
+        let mut ir2 = ir.clone();
+        if ir.op == IRType::RETURN {
+            ir2.op = IRType::JMP;
+            ir2.r2 = None;
+            ir2.bb1 = Some(tail.clone());
+            ir2.bbargs = vec![self.reg(ir.r2.as_ref().unwrap())];
+            return vec![Rc::new(RefCell::new(ir2))];
+        }
+
+        ir2.r0 = ir.r0.as_ref().map(|r| self.reg(r));
+        map_uses(&mut ir2, |r| self.reg(r));
+        for arg in ir2.asm_args.iter_mut() {
+            if arg.is_output && arg.kind != AsmKind::MEM {
+                arg.reg = arg.reg.as_ref().map(|r| self.reg(r));
+            }
+        }
+        ir2.outs = ir.outs.iter().map(|r| self.reg(r)).collect();
+        ir2.var = ir.var.as_ref().map(|v| self.var(v));
+        ir2.bb1 = self.bb(&ir.bb1);
+        ir2.bb2 = self.bb(&ir.bb2);
+
+        // "%=" in an asm template must be unique to each copy.
+        if ir.op == IRType::ASM {
+            ir2.label = bump_nlabel() as i32;
+        }
+        return vec![Rc::new(RefCell::new(ir2))];
+    }
+}
+
+// Returns copies of the BBs of `callee` called with `args`, which return
+// to `tail`. The first one is the entry.
+fn clone_body(
+    caller: &Rc<RefCell<Function>>,
+    callee: &Function,
+    args: &Vec<Rc<RefCell<Reg>>>,
+    tail: &Rc<RefCell<BB>>,
+) -> Vec<Rc<RefCell<BB>>> {
+    let mut s = Inliner {
+        regs: HashMap::new(),
+        vars: Vec::new(),
+        bbs: HashMap::new(),
+    };
+
+    let mut v = Vec::new();
+    for bb in callee.bbs.iter() {
+        let mut bb2 = alloc_bb();
+        bb2.label = bump_nlabel();
+        let bb2 = Rc::new(RefCell::new(bb2));
+        s.bbs.insert(bb.borrow().label, bb2.clone());
+        v.push(bb2);
+    }
+
+    for (bb, bb2) in callee.bbs.iter().zip(v.iter()) {
+        let bb = bb.borrow();
+        let params = bb.params.iter().map(|r| s.reg(r)).collect();
+        let mut irs = Vec::new();
+        for ir in bb.ir.iter() {
+            irs.append(&mut s.clone_ir(&ir.borrow(), args, tail));
+        }
+        bb2.borrow_mut().params = params;
+        bb2.borrow_mut().ir = irs;
+    }
+
+    for (_, var) in s.vars.into_iter() {
+        caller.borrow_mut().lvars.push(var);
+    }
+    return v;
+}
+
+fn inline_calls(fun: &Rc<RefCell<Function>>, funcs: &HashMap<String, Rc<RefCell<Function>>>) {
+    let bbs = fun.borrow().bbs.clone();
+    let mut v = Vec::new();
+
+    for bb in bbs.into_iter() {
+        let mut cur = bb;
+        loop {
+            let found = cur.borrow().ir.iter().enumerate().find_map(|(i, ir)| {
+                let ir = ir.borrow();
+                if ir.op != IRType::CALL {
+                    return None;
+                }
+                let callee = funcs.get(&ir.name)?;
+                if !should_inline(&fun.borrow(), &callee.borrow(), &ir) {
+                    return None;
+                }
+                return Some((i, callee.clone()));
+            });
+
+            let (i, callee) = match found {
+                Some(x) => x,
+                None => {
+                    v.push(cur);
+                    break;
+                }
+            };
+
+            // Split the BB after the call. The rest of it receives the
+            // return value.
+            let call = cur.borrow().ir[i].clone();
+            let mut tail = alloc_bb();
+            tail.label = bump_nlabel();
+            tail.params = vec![call.borrow().r0.clone().unwrap()];
+            tail.ir = cur.borrow_mut().ir.split_off(i + 1);
+            let tail = Rc::new(RefCell::new(tail));
+
+            let body = clone_body(fun, &callee.borrow(), &call.borrow().args, &tail);
+
+            let mut jmp = alloc_ir();
+            jmp.op = IRType::JMP;
+            jmp.bb1 = Some(body[0].clone());
+            let mut b = cur.borrow_mut();
+            b.ir.pop();
+            b.ir.push(Rc::new(RefCell::new(jmp)));
+            drop(b);
+
+            v.push(cur);
+            v.extend(body.into_iter());
+            cur = tail;
+        }
+    }
+
+    fun.borrow_mut().bbs = v;
+    build_cfg(fun);
+}
+
+pub fn inline_functions(prog: &mut Program) {
+    let mut funcs = HashMap::new();
+    for fun in prog.funcs.iter() {
+        build_cfg(fun);
+        funcs.insert(fun.borrow().name.clone(), fun.clone());
+    }
+
+    for fun in prog.funcs.iter() {
+        inline_calls(fun, &funcs);
+    }
+}
@@ -0,0 +1,88 @@
+// Machine instructions
+//
+// gen_x86 builds the instructions of a function as `Insn`s with typed
+// operands, so that the code can be rewritten by the peephole optimizer
+// before it is printed out in Intel syntax.
+//
+// Inline assembly and directives are kept as text. Nothing is known
+// about them, so the optimizer treats them conservatively.
+
+#![allow(non_upper_case_globals)]
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Reg(String),
+    Imm(i64),
+    // A memory operand without the brackets, e.g. "rbp-8"
+    Mem(String),
+    // Labels, symbols and everything else
+    Sym(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Insn {
+    Label(String),
+    // A mnemonic, which may have a prefix such as "lock", and operands
+    Op(String, Vec<Operand>),
+    Raw(String),
+}
+
+// General purpose registers by size (64, 32, 16 and 8 bits)
+pub const gprs: [[&'static str; 4]; 16] = [
+    ["rax", "eax", "ax", "al"],
+    ["rcx", "ecx", "cx", "cl"],
+    ["rdx", "edx", "dx", "dl"],
+    ["rbx", "ebx", "bx", "bl"],
+    ["rsp", "esp", "sp", "spl"],
+    ["rbp", "ebp", "bp", "bpl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+    ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"],
+    ["r10", "r10d", "r10w", "r10b"],
+    ["r11", "r11d", "r11w", "r11b"],
+    ["r12", "r12d", "r12w", "r12b"],
+    ["r13", "r13d", "r13w", "r13b"],
+    ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+
+// Returns the number of a register and the size of the name as an
+// index to `gprs`.
+pub fn gpr(name: &str) -> Option<(usize, usize)> {
+    for (i, names) in gprs.iter().enumerate() {
+        if let Some(size) = names.iter().position(|&n| n == name) {
+            return Some((i, size));
+        }
+    }
+    return None;
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(s) | Operand::Sym(s) => write!(f, "{}", s),
+            Operand::Imm(n) => write!(f, "{}", n),
+            Operand::Mem(s) => write!(f, "[{}]", s),
+        }
+    }
+}
+
+impl fmt::Display for Insn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Insn::Label(name) => write!(f, "{}:", name),
+            Insn::Raw(s) => write!(f, "\t{}", s),
+            Insn::Op(op, args) => {
+                write!(f, "\t{}", op)?;
+                for (i, arg) in args.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}", sep, arg)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
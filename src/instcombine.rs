@@ -0,0 +1,530 @@
+// Instruction combining
+//
+// This pass rewrites instructions with constant operands to cheaper
+// equivalents:
+//
+//  x + 0, x - 0, x * 1, x / 1, ...    x
+//  x * 0, x & 0                       0
+//  x * 2^k                            x << k
+//  x / 2^k                            shifts rounding toward zero
+//  x / c                              multiplication by a magic number
+//  x % c                              x - x / c * c
+//  extension of an extended value     the value
+//
+// Division by a constant follows Hacker's Delight, chapter 10: `x / c`
+// is the high 64 bits of `x * m`, shifted right, for some `m` which
+// depends only on `c`, plus one if `x` is negative.
+//
+// A value loaded or returned by a call is already extended from its
+// size (see extend_ops), so extending it again from the same or a wider
+// width, by `(x << n) >> n` or `x & (2^w - 1)`, is a no-op.
+//
+// A constant operand is moved to the right of a commutative operation,
+// and becomes an immediate if it fits in 32 bits; such an instruction
+// has no `r2` and its right operand is in `imm`. Comparisons are
+// canonicalized as well: `x <= c` becomes `x < c+1`, a comparison
+// compared with zero becomes the comparison or its inverse, and a
+// branch on `x != 0` branches on `x` instead.
+
+use crate::alias::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+struct Combiner {
+    ndefs: HashMap<i32, usize>,
+    consts: HashMap<i32, i64>,
+    defs: HashMap<i32, Rc<RefCell<IR>>>,
+    subst: HashMap<i32, Rc<RefCell<Reg>>>,
+}
+
+fn is_commutative(op: &IRType) -> bool {
+    match op {
+        IRType::ADD
+        | IRType::MUL
+        | IRType::AND
+        | IRType::OR
+        | IRType::XOR
+        | IRType::EQ
+        | IRType::NE => true,
+        _ => false,
+    }
+}
+
+// Operations which can take an immediate as the right operand
+fn takes_imm(op: &IRType) -> bool {
+    match op {
+        IRType::ADD
+        | IRType::SUB
+        | IRType::MUL
+        | IRType::AND
+        | IRType::OR
+        | IRType::XOR
+        | IRType::SHL
+        | IRType::SHR
+        | IRType::SAR
+        | IRType::EQ
+        | IRType::NE
+        | IRType::LT
+        | IRType::LE
+        | IRType::ULT
+        | IRType::ULE => true,
+        _ => false,
+    }
+}
+
+fn is_comparison(op: &IRType) -> bool {
+    match op {
+        IRType::EQ | IRType::NE | IRType::LT | IRType::LE | IRType::ULT | IRType::ULE => true,
+        _ => false,
+    }
+}
+
+fn fits_imm(c: i64) -> bool {
+    return c == c as i32 as i64;
+}
+
+fn log2(c: i64) -> Option<i64> {
+    if c > 0 && c & (c - 1) == 0 {
+        return Some(c.trailing_zeros() as i64);
+    }
+    return None;
+}
+
+// Returns a magic number and a shift amount for signed division by
+// `d`, which must be greater than 1 and not a power of two.
+fn magic(d: i64) -> (i64, i64) {
+    let two63: u64 = 1 << 63;
+    let ad = d as u64;
+    let anc = two63 - 1 - two63 % ad;
+    let mut p = 63;
+    let mut q1 = two63 / anc;
+    let mut r1 = two63 - q1 * anc;
+    let mut q2 = two63 / ad;
+    let mut r2 = two63 - q2 * ad;
+
+    loop {
+        p += 1;
+        q1 = q1.wrapping_mul(2);
+        r1 = r1.wrapping_mul(2);
+        if r1 >= anc {
+            q1 = q1.wrapping_add(1);
+            r1 = r1.wrapping_sub(anc);
+        }
+        q2 = q2.wrapping_mul(2);
+        r2 = r2.wrapping_mul(2);
+        if r2 >= ad {
+            q2 = q2.wrapping_add(1);
+            r2 = r2.wrapping_sub(ad);
+        }
+        let delta = ad - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
+        }
+    }
+    return (q2.wrapping_add(1) as i64, p - 64);
+}
+
+// Appends `r0 = r1 op imm` or `r0 = r1 op r2` to `v` and returns r0.
+fn binop(
+    v: &mut Vec<Rc<RefCell<IR>>>,
+    op: IRType,
+    r1: &Rc<RefCell<Reg>>,
+    r2: Option<&Rc<RefCell<Reg>>>,
+    imm: i64,
+) -> Rc<RefCell<Reg>> {
+    let mut ir = alloc_ir();
+    ir.op = op;
+    ir.r0 = Some(new_reg());
+    ir.r1 = Some(r1.clone());
+    ir.r2 = r2.cloned();
+    ir.imm = imm;
+    let r0 = ir.r0.clone().unwrap();
+    v.push(Rc::new(RefCell::new(ir)));
+    return r0;
+}
+
+// Emits `x / d` for d > 1 to `v`.
+fn div(v: &mut Vec<Rc<RefCell<IR>>>, x: &Rc<RefCell<Reg>>, d: i64) -> Rc<RefCell<Reg>> {
+    // Add 2^k-1 to a negative dividend, so that the shift rounds
+    // toward zero.
+    if let Some(k) = log2(d) {
+        let sign = binop(v, IRType::SAR, x, None, 63);
+        let bias = binop(v, IRType::SHR, &sign, None, 64 - k);
+        let t = binop(v, IRType::ADD, x, Some(&bias), 0);
+        return binop(v, IRType::SAR, &t, None, k);
+    }
+
+    let (m, s) = magic(d);
+    let mut ir = alloc_ir();
+    ir.op = IRType::IMM;
+    ir.r0 = Some(new_reg());
+    ir.imm = m;
+    let m_reg = ir.r0.clone().unwrap();
+    v.push(Rc::new(RefCell::new(ir)));
+
+    let mut q = binop(v, IRType::MULH, x, Some(&m_reg), 0);
+    if m < 0 {
+        q = binop(v, IRType::ADD, &q, Some(x), 0);
+    }
+    if s > 0 {
+        q = binop(v, IRType::SAR, &q, None, s);
+    }
+    let sign = binop(v, IRType::SHR, x, None, 63);
+    return binop(v, IRType::ADD, &q, Some(&sign), 0);
+}
+
+impl Combiner {
+    fn is_single_def(&self, r: &Rc<RefCell<Reg>>) -> bool {
+        return self.ndefs.get(&r.borrow().vn) == Some(&1);
+    }
+
+    fn konst(&self, r: &Option<Rc<RefCell<Reg>>>) -> Option<i64> {
+        let r = r.as_ref()?;
+        if !self.is_single_def(r) {
+            return None;
+        }
+        return self.consts.get(&r.borrow().vn).cloned();
+    }
+
+    // Returns the right operand if it is a constant.
+    fn rhs(&self, ir: &IR) -> Option<i64> {
+        match ir.r2 {
+            Some(_) => self.konst(&ir.r2),
+            None => Some(ir.imm),
+        }
+    }
+
+    // Returns the comparison defining a register.
+    fn comparison(&self, r: &Rc<RefCell<Reg>>) -> Option<Rc<RefCell<IR>>> {
+        if !self.is_single_def(r) {
+            return None;
+        }
+        let ir = self.defs.get(&r.borrow().vn)?;
+        if !is_comparison(&ir.borrow().op) {
+            return None;
+        }
+        return Some(ir.clone());
+    }
+
+    // Returns the width in bits from which a register is known to be
+    // extended, and whether it is sign-extended.
+    fn extended(&self, r: &Rc<RefCell<Reg>>) -> Option<(i64, bool)> {
+        if !self.is_single_def(r) {
+            return None;
+        }
+        let ir = self.defs.get(&r.borrow().vn)?;
+        let ir = ir.borrow();
+        match ir.op {
+            IRType::LOAD | IRType::CALL if ir.size > 0 && ir.size < 8 => {
+                return Some((ir.size as i64 * 8, ir.is_signed));
+            }
+            _ => return None,
+        }
+    }
+
+    // Returns `y` if `x` is `y << n` and `y` is already extended from
+    // 64 - n bits, so that `x >> n` (a SAR if `signed`, a SHR otherwise)
+    // is `y` again. The SHL is then dead.
+    fn redundant_extend(
+        &self,
+        x: &Rc<RefCell<Reg>>,
+        n: i64,
+        signed: bool,
+    ) -> Option<Rc<RefCell<Reg>>> {
+        if !self.is_single_def(x) {
+            return None;
+        }
+        let shl = self.defs.get(&x.borrow().vn)?.borrow();
+        if shl.op != IRType::SHL || self.rhs(&shl) != Some(n) || n <= 0 || n >= 64 {
+            return None;
+        }
+        let y = shl.r1.clone()?;
+        let (bits, is_signed) = self.extended(&y)?;
+        let width = 64 - n;
+        let ok = if signed {
+            bits < width || (bits == width && is_signed)
+        } else {
+            bits <= width && !is_signed
+        };
+        if !ok {
+            return None;
+        }
+        return Some(y);
+    }
+
+    fn replace(&mut self, ir: &IR, r: &Rc<RefCell<Reg>>) {
+        let r0 = ir.r0.as_ref().unwrap().borrow().vn;
+        self.subst.insert(r0, r.clone());
+    }
+
+    // Rewrites `x == 0` to the inverse of `x` if `x` is a comparison.
+    fn invert(&self, ir: &mut IR, cmp: &IR) -> bool {
+        let op = match cmp.op {
+            IRType::EQ => IRType::NE,
+            IRType::NE => IRType::EQ,
+            IRType::LT => IRType::LE,
+            IRType::LE => IRType::LT,
+            IRType::ULT => IRType::ULE,
+            IRType::ULE => IRType::ULT,
+            _ => return false,
+        };
+
+        if cmp.op == IRType::EQ || cmp.op == IRType::NE {
+            ir.op = op;
+            ir.r1 = cmp.r1.clone();
+            ir.r2 = cmp.r2.clone();
+            ir.imm = cmp.imm;
+            return true;
+        }
+
+        // !(a < b) is b <= a.
+        if cmp.r2.is_none() {
+            return false;
+        }
+        ir.op = op;
+        ir.r1 = cmp.r2.clone();
+        ir.r2 = cmp.r1.clone();
+        return true;
+    }
+
+    // Combines `ir`, emitting new instructions to `v`. Returns false if
+    // the instruction is removed.
+    fn combine(&mut self, ir: &Rc<RefCell<IR>>, v: &mut Vec<Rc<RefCell<IR>>>) -> bool {
+        let mut ir = ir.borrow_mut();
+        if ir.op == IRType::BR {
+            self.combine_br(&mut ir);
+            return true;
+        }
+
+        match ir.r0.as_ref() {
+            Some(r0) if self.is_single_def(r0) => (),
+            _ => return true,
+        }
+        let x = match ir.r1.clone() {
+            Some(r1) => r1,
+            None => return true,
+        };
+
+        if is_commutative(&ir.op) && self.konst(&ir.r1).is_some() && self.konst(&ir.r2).is_none() {
+            if let Some(r2) = ir.r2.clone() {
+                ir.r1 = Some(r2);
+                ir.r2 = Some(x);
+            }
+        }
+        return self.combine_rhs(&mut ir, v);
+    }
+
+    fn combine_rhs(&mut self, ir: &mut IR, v: &mut Vec<Rc<RefCell<IR>>>) -> bool {
+        let c = match self.rhs(ir) {
+            Some(c) => c,
+            None => return true,
+        };
+        let x = ir.r1.clone().unwrap();
+        if !self.is_single_def(&x) {
+            return true;
+        }
+
+        let identity = match ir.op {
+            IRType::ADD
+            | IRType::SUB
+            | IRType::OR
+            | IRType::XOR
+            | IRType::SHL
+            | IRType::SHR
+            | IRType::SAR => c == 0,
+            IRType::MUL | IRType::DIV | IRType::UDIV => c == 1,
+            IRType::AND => {
+                c == -1
+                    || match (log2(c.wrapping_add(1)), self.extended(&x)) {
+                        (Some(width), Some((bits, false))) => bits <= width,
+                        _ => false,
+                    }
+            }
+            IRType::NE => c == 0 && self.comparison(&x).is_some(),
+            _ => false,
+        };
+        if identity {
+            self.replace(ir, &x);
+            return false;
+        }
+
+        if ir.op == IRType::SAR || ir.op == IRType::SHR {
+            if let Some(y) = self.redundant_extend(&x, c, ir.op == IRType::SAR) {
+                self.replace(ir, &y);
+                return false;
+            }
+        }
+
+        match ir.op {
+            IRType::MUL | IRType::AND if c == 0 => {
+                ir.op = IRType::IMM;
+                ir.r1 = None;
+                ir.r2 = None;
+                ir.imm = 0;
+                return true;
+            }
+            IRType::MUL => {
+                if let Some(k) = log2(c) {
+                    ir.op = IRType::SHL;
+                    ir.r2 = None;
+                    ir.imm = k;
+                    return true;
+                }
+            }
+            IRType::SUB if c != i64::MIN && fits_imm(-c) => {
+                ir.op = IRType::ADD;
+                ir.r2 = None;
+                ir.imm = -c;
+                return true;
+            }
+            IRType::DIV if c > 1 => {
+                let q = div(v, &x, c);
+                self.replace(ir, &q);
+                return false;
+            }
+            IRType::MOD if c > 1 && fits_imm(c) => {
+                let q = div(v, &x, c);
+                let t = match log2(c) {
+                    Some(k) => binop(v, IRType::SHL, &q, None, k),
+                    None => binop(v, IRType::MUL, &q, None, c),
+                };
+                ir.op = IRType::SUB;
+                ir.r2 = Some(t);
+                return true;
+            }
+            IRType::UDIV => {
+                if let Some(k) = log2(c) {
+                    ir.op = IRType::SHR;
+                    ir.r2 = None;
+                    ir.imm = k;
+                    return true;
+                }
+            }
+            IRType::UMOD if log2(c).is_some() && fits_imm(c - 1) => {
+                ir.op = IRType::AND;
+                ir.r2 = None;
+                ir.imm = c - 1;
+                return true;
+            }
+            IRType::LE if c != i64::MAX && fits_imm(c + 1) => {
+                ir.op = IRType::LT;
+                ir.r2 = None;
+                ir.imm = c + 1;
+                return true;
+            }
+            IRType::ULE if c != -1 && fits_imm(c + 1) => {
+                ir.op = IRType::ULT;
+                ir.r2 = None;
+                ir.imm = c + 1;
+                return true;
+            }
+            IRType::EQ if c == 0 => {
+                if let Some(cmp) = self.comparison(&x) {
+                    if self.invert(ir, &cmp.borrow()) {
+                        return true;
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        if ir.r2.is_some() && takes_imm(&ir.op) && fits_imm(c) {
+            ir.r2 = None;
+            ir.imm = match ir.op {
+                IRType::SHL | IRType::SHR | IRType::SAR => c & 63,
+                _ => c,
+            };
+        }
+        return true;
+    }
+
+    // Rewrites `BR (x != 0)` to `BR x`, and `BR (x == 0)` to `BR x`
+    // with the targets swapped.
+    fn combine_br(&self, ir: &mut IR) {
+        loop {
+            let r = ir.r2.clone().unwrap();
+            if !self.is_single_def(&r) {
+                return;
+            }
+            let cond = match self.defs.get(&r.borrow().vn) {
+                Some(cond) => cond.clone(),
+                None => return,
+            };
+            let cond = cond.borrow();
+            if (cond.op != IRType::EQ && cond.op != IRType::NE) || self.rhs(&cond) != Some(0) {
+                return;
+            }
+
+            ir.r2 = cond.r1.clone();
+            if cond.op == IRType::EQ {
+                std::mem::swap(&mut ir.bb1, &mut ir.bb2);
+            }
+        }
+    }
+}
+
+fn subst(map: &HashMap<i32, Rc<RefCell<Reg>>>, r: &Rc<RefCell<Reg>>) -> Rc<RefCell<Reg>> {
+    let mut r = r.clone();
+    loop {
+        let vn = r.borrow().vn;
+        match map.get(&vn) {
+            Some(next) => r = next.clone(),
+            None => return r,
+        }
+    }
+}
+
+pub fn instcombine(fun: &Rc<RefCell<Function>>) {
+    let bbs = fun.borrow().bbs.clone();
+    let mut s = Combiner {
+        ndefs: count_defs(&bbs),
+        consts: HashMap::new(),
+        defs: HashMap::new(),
+        subst: HashMap::new(),
+    };
+
+    for bb in bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            if let Some(r0) = ir.borrow().r0.as_ref() {
+                s.defs.insert(r0.borrow().vn, ir.clone());
+            }
+            if ir.borrow().op == IRType::IMM {
+                let vn = ir.borrow().r0.as_ref().unwrap().borrow().vn;
+                s.consts.insert(vn, ir.borrow().imm);
+            }
+        }
+    }
+
+    for bb in bbs.iter() {
+        let irs = bb.borrow().ir.clone();
+        let mut v = Vec::new();
+        for ir in irs.into_iter() {
+            {
+                let map = &s.subst;
+                map_uses(&mut ir.borrow_mut(), |r| subst(map, r));
+            }
+            let mut new = Vec::new();
+            let keep = s.combine(&ir, &mut new);
+            for ir2 in new.iter() {
+                let r0 = ir2.borrow().r0.as_ref().unwrap().borrow().vn;
+                s.ndefs.insert(r0, 1);
+                s.defs.insert(r0, ir2.clone());
+            }
+            v.append(&mut new);
+            if keep {
+                v.push(ir);
+            }
+        }
+        bb.borrow_mut().ir = v;
+    }
+
+    // Uses in BBs visited before the definitions
+    for bb in bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            map_uses(&mut ir.borrow_mut(), |r| subst(&s.subst, r));
+        }
+    }
+}
@@ -5,7 +5,7 @@ use crate::parse::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-fn regno(r: Option<Rc<RefCell<Reg>>>) -> i32 {
+pub fn regno(r: Option<Rc<RefCell<Reg>>>) -> i32 {
     if r.is_none() {
         return 0;
     }
@@ -17,9 +17,52 @@ fn regno(r: Option<Rc<RefCell<Reg>>>) -> i32 {
     return rr.borrow().vn;
 }
 
+// Returns the right operand of a binary operation, which is an
+// immediate if there is no r2.
+fn rhs(ir: &IR) -> String {
+    match ir.r2 {
+        Some(ref r) => format!("r{}", regno(Some(r.clone()))),
+        None => ir.imm.to_string(),
+    }
+}
+
+fn regs(v: &Vec<Rc<RefCell<Reg>>>) -> String {
+    let v: Vec<String> = v
+        .iter()
+        .map(|r| format!("r{}", regno(Some(r.clone()))))
+        .collect();
+    return v.join(", ");
+}
+
 fn tostr_call(ir: &IR) -> String {
     let mut s = String::new();
-    s.push_str(&format!("r{} = {}(", regno(ir.r0.clone()), ir.name));
+    if ir.op == IRType::TAIL_CALL {
+        s.push_str(&format!("TAIL_CALL {}(", ir.name));
+    } else {
+        s.push_str(&format!("r{} = {}(", regno(ir.r0.clone()), ir.name));
+    }
+    for i in 0..ir.nargs {
+        if i != 0 {
+            s.push_str(", ");
+        }
+        s.push_str(&format!("r{}", regno(Some(ir.args[i].clone()))));
+    }
+    s.push_str(")");
+    return s;
+}
+
+fn tostr_asm(ir: &IR) -> String {
+    let mut s = String::new();
+    for (i, r) in ir.outs.iter().enumerate() {
+        if i != 0 {
+            s.push_str(", ");
+        }
+        s.push_str(&format!("r{}", regno(Some(r.clone()))));
+    }
+    if !ir.outs.is_empty() {
+        s.push_str(" = ");
+    }
+    s.push_str("ASM(");
     for i in 0..ir.nargs {
         if i != 0 {
             s.push_str(", ");
@@ -36,35 +79,64 @@ pub fn tostr(ir: &IR) -> String {
     let r2 = regno(ir.r2.clone());
 
     match ir.op {
-        IRType::ADD => format!("r{} = r{} + r{}", r0, r1, r2),
-        IRType::CALL => tostr_call(ir),
-        IRType::DIV => format!("r{} = r{} / r{}", r0, r1, r2),
+        IRType::ADD => format!("r{} = r{} + {}", r0, r1, rhs(ir)),
+        IRType::ARG => format!("r{} = ARG {}", r0, ir.imm),
+        IRType::ALLOCA => format!("r{} = ALLOCA r{}", r0, r2),
+        IRType::ASM => tostr_asm(ir),
+        IRType::ATOMIC_LOAD => format!("ATOMIC_LOAD{} r{}, r{}", ir.size, r0, r2),
+        IRType::ATOMIC_STORE => format!("ATOMIC_STORE{} r{}, r{}", ir.size, r1, r2),
+        IRType::ATOMIC_FETCH_ADD => format!("r{} = FETCH_ADD{} r{}, r{}", r0, ir.size, r1, r2),
+        IRType::ATOMIC_CAS => format!(
+            "r{} = CAS{} r{}, r{}, r{}",
+            r0,
+            ir.size,
+            regno(Some(ir.args[0].clone())),
+            r1,
+            r2
+        ),
+        IRType::FENCE => "FENCE".to_string(),
+        IRType::TRAP => "TRAP".to_string(),
+        IRType::UNREACHABLE => "UNREACHABLE".to_string(),
+        IRType::CLZ => format!("r{} = CLZ{} r{}", r0, ir.size, r2),
+        IRType::CTZ => format!("r{} = CTZ{} r{}", r0, ir.size, r2),
+        IRType::POPCOUNT => format!("r{} = POPCOUNT{} r{}", r0, ir.size, r2),
+        IRType::CALL | IRType::TAIL_CALL => tostr_call(ir),
+        IRType::DIV => format!("r{} = r{} / {}", r0, r1, rhs(ir)),
+        IRType::UDIV => format!("r{} = r{} /u {}", r0, r1, rhs(ir)),
         IRType::IMM => format!("r{} = {}", r0, ir.imm),
         IRType::JMP => {
             let bb1 = ir.bb1.clone().unwrap();
-            let bbarg = ir.bbarg.clone();
-            if bbarg.is_some() {
-                return format!("JMP .L{} (r{})", bb1.borrow().label, regno(bbarg));
+            if !ir.bbargs.is_empty() {
+                return format!("JMP .L{} ({})", bb1.borrow().label, regs(&ir.bbargs));
             }
             return format!("JMP .L{}", bb1.borrow().label);
         }
         IRType::LABEL_ADDR => format!("r{} = .L{}", r0, ir.label),
-        IRType::EQ => format!("r{} = r{} == r{}", r0, r1, r2),
-        IRType::NE => format!("r{} = r{} != r{}", r0, r1, r2),
-        IRType::LE => format!("r{} = r{} <= r{}", r0, r1, r2),
-        IRType::LT => format!("r{} = r{} < r{}", r0, r1, r2),
-        IRType::AND => format!("r{} = r{} & r{}", r0, r1, r2),
-        IRType::OR => format!("r{} = r{} | r{}", r0, r1, r2),
-        IRType::XOR => format!("r{} = r{} ^ r{}", r0, r1, r2),
-        IRType::SHL => format!("r{} = r{} << r{}", r0, r1, r2),
-        IRType::SHR => format!("r{} = r{} >> r{}", r0, r1, r2),
+        IRType::TLS_ADDR => format!("r{} = {}@tpoff", r0, ir.name),
+        IRType::EQ => format!("r{} = r{} == {}", r0, r1, rhs(ir)),
+        IRType::NE => format!("r{} = r{} != {}", r0, r1, rhs(ir)),
+        IRType::LE => format!("r{} = r{} <= {}", r0, r1, rhs(ir)),
+        IRType::LT => format!("r{} = r{} < {}", r0, r1, rhs(ir)),
+        IRType::ULE => format!("r{} = r{} <=u {}", r0, r1, rhs(ir)),
+        IRType::ULT => format!("r{} = r{} <u {}", r0, r1, rhs(ir)),
+        IRType::AND => format!("r{} = r{} & {}", r0, r1, rhs(ir)),
+        IRType::OR => format!("r{} = r{} | {}", r0, r1, rhs(ir)),
+        IRType::XOR => format!("r{} = r{} ^ {}", r0, r1, rhs(ir)),
+        IRType::SHL => format!("r{} = r{} << {}", r0, r1, rhs(ir)),
+        IRType::SHR => format!("r{} = r{} >> {}", r0, r1, rhs(ir)),
+        IRType::SAR => format!("r{} = r{} >>> {}", r0, r1, rhs(ir)),
         IRType::LOAD => format!("LOAD{} r{}, r{}", ir.size, r0, r2),
         IRType::LOAD_SPILL => format!("LOAD_SPILL r{}, {}", r0, ir.imm),
-        IRType::MOD => format!("r{} = r{} % r{}", r0, r1, r2),
+        IRType::MOD => format!("r{} = r{} % {}", r0, r1, rhs(ir)),
+        IRType::UMOD => format!("r{} = r{} %u {}", r0, r1, rhs(ir)),
         IRType::MOV => format!("r{} = r{}", r0, r2),
-        IRType::MUL => format!("r{} = r{} * r{}", r0, r1, r2),
+        IRType::MUL => format!("r{} = r{} * {}", r0, r1, rhs(ir)),
+        IRType::MULH => format!("r{} = MULH r{}, {}", r0, r1, rhs(ir)),
         IRType::NOP => "NOP".to_string(),
         IRType::RETURN => format!("RET r{}", r2),
+        IRType::STACK_RESTORE => format!("STACK_RESTORE r{}", r2),
+        IRType::STACK_SAVE => format!("r{} = STACK_SAVE", r0),
+        IRType::SCOPE_END => format!("SCOPE_END {}", ir.var.as_ref().unwrap().borrow().name),
         IRType::STORE => format!("STORE{} r{}, r{}", ir.size, r1, r2),
         IRType::STORE_ARG => {
             let var = ir.var.clone().unwrap();
@@ -73,7 +145,7 @@ pub fn tostr(ir: &IR) -> String {
             format!("STORE_ARG{} {} {} ({})", ir.size, ir.imm, name, offset)
         }
         IRType::STORE_SPILL => format!("STORE_SPILL r{}, {}", r1, ir.imm),
-        IRType::SUB => format!("r{} = r{} - r{}", r0, r1, r2),
+        IRType::SUB => format!("r{} = r{} - {}", r0, r1, rhs(ir)),
         IRType::BPREL => {
             let var = ir.var.clone().unwrap();
             let name = var.borrow().name.clone();
@@ -123,9 +195,9 @@ fn print_regs(name: &str, v: Vec<Rc<RefCell<Reg>>>) {
 
 fn print_bb(bb: Rc<RefCell<BB>>) {
     let label = bb.borrow().label.clone();
-    let param = bb.borrow().param.clone();
-    if param.is_some() {
-        eprint!(".L{}({})", label, regno(param));
+    let params = bb.borrow().params.clone();
+    if !params.is_empty() {
+        eprint!(".L{}({})", label, regs(&params));
     } else {
         eprint!(".L{}", label);
     }
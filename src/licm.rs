@@ -0,0 +1,185 @@
+// Loop-invariant code motion
+//
+// An instruction in a loop is invariant if it computes the same value in
+// every iteration, i.e. it is pure and its operands are defined outside
+// the loop or by other invariant instructions. Invariant instructions
+// are moved to the preheader of the loop, so they are executed only once.
+//
+// Since the moved instructions are executed even if the loop body is
+// not, only instructions that never fault are moved: DIV and MOD are
+// left in place, and a LOAD is moved only if it reads a stack variable
+// which is not written in the loop.
+//
+// The address of an escaped variable which goes out of scope in the
+// loop is not moved either. Its stack slot would then be live throughout
+// the loop instead of up to the end of its scope.
+//
+// IMMs are cheap, so they are not worth a register across the loop by
+// themselves. Instead, an IMM used by a moved instruction is copied to
+// the preheader.
+//
+// Loops are processed from inner to outer, so an instruction can move
+// out of a loop nest one level at a time.
+
+use crate::alias::*;
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::gvn::*;
+use crate::loops::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+fn can_hoist(op: &IRType) -> bool {
+    match op {
+        IRType::DIV | IRType::MOD | IRType::UDIV | IRType::UMOD => false,
+        _ => is_pure(op),
+    }
+}
+
+// Returns true if the loop may write memory read through the address.
+fn writes_memory(bbs: &Vec<Rc<RefCell<BB>>>, l: &Loop, mem: &MemInfo, base: &Base) -> bool {
+    for &b in l.blocks.iter() {
+        for ir in bbs[b].borrow().ir.iter() {
+            let ir = ir.borrow();
+            if let Some(b2) = mem.store_base(&ir) {
+                if mem.may_alias(base, &b2) {
+                    return true;
+                }
+            } else if clobbers_memory(&ir.op) && !mem.is_local(base) {
+                return true;
+            }
+        }
+    }
+    return false;
+}
+
+fn hoist(
+    bbs: &Vec<Rc<RefCell<BB>>>,
+    l: &Loop,
+    mem: &MemInfo,
+    ndefs: &mut HashMap<i32, usize>,
+    pre: &Rc<RefCell<BB>>,
+) {
+    // Registers defined in the loop, except constants
+    let mut inside = HashSet::new();
+    let mut consts = HashMap::new();
+    // Escaped variables going out of scope in the loop
+    let mut scoped = HashSet::new();
+    for &b in l.blocks.iter() {
+        let bb = bbs[b].borrow();
+        for r in bb.params.iter() {
+            inside.insert(r.borrow().vn);
+        }
+        for ir in bb.ir.iter() {
+            let ir = ir.borrow();
+            if ir.op == IRType::SCOPE_END && mem.escaped.contains(&var_key(&ir.var)) {
+                scoped.insert(var_key(&ir.var));
+            }
+            for r in ir.r0.iter().chain(ir.outs.iter()) {
+                let vn = r.borrow().vn;
+                if ir.op == IRType::IMM && ndefs.get(&vn) == Some(&1) {
+                    consts.insert(vn, ir.imm);
+                } else {
+                    inside.insert(vn);
+                }
+            }
+        }
+    }
+
+    let mut hoisted = Vec::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in l.blocks.iter() {
+            let irs = bbs[b].borrow().ir.clone();
+            let mut v = Vec::new();
+
+            for ir in irs.into_iter() {
+                let invariant = {
+                    let ir = ir.borrow();
+                    let single_def = match ir.r0.as_ref() {
+                        Some(r0) => ndefs.get(&r0.borrow().vn) == Some(&1),
+                        None => false,
+                    };
+                    let is_load = ir.op == IRType::LOAD && !ir.is_volatile && {
+                        let base = mem.base(&ir.r2);
+                        match base {
+                            Base::Stack(_) => !writes_memory(bbs, l, mem, &base),
+                            _ => false,
+                        }
+                    };
+                    let is_scoped = ir.op == IRType::BPREL && scoped.contains(&var_key(&ir.var));
+                    single_def
+                        && !is_scoped
+                        && (can_hoist(&ir.op) || is_load)
+                        && uses(&ir).iter().all(|r| !inside.contains(&r.borrow().vn))
+                };
+
+                if invariant {
+                    let mut copies = Vec::new();
+                    map_uses(&mut ir.borrow_mut(), |r| {
+                        let imm = match consts.get(&r.borrow().vn) {
+                            Some(imm) => *imm,
+                            None => return r.clone(),
+                        };
+                        let mut copy = alloc_ir();
+                        copy.op = IRType::IMM;
+                        copy.r0 = Some(new_reg());
+                        copy.imm = imm;
+                        let r0 = copy.r0.clone().unwrap();
+                        copies.push(Rc::new(RefCell::new(copy)));
+                        return r0;
+                    });
+                    for copy in copies.into_iter() {
+                        let vn = copy.borrow().r0.as_ref().unwrap().borrow().vn;
+                        ndefs.insert(vn, 1);
+                        hoisted.push(copy);
+                    }
+
+                    inside.remove(&ir.borrow().r0.as_ref().unwrap().borrow().vn);
+                    hoisted.push(ir);
+                    changed = true;
+                } else {
+                    v.push(ir);
+                }
+            }
+            bbs[b].borrow_mut().ir = v;
+        }
+    }
+
+    if hoisted.is_empty() {
+        return;
+    }
+
+    let jmp = pre.borrow_mut().ir.pop().unwrap();
+    pre.borrow_mut().ir.append(&mut hoisted);
+    pre.borrow_mut().ir.push(jmp);
+}
+
+pub fn licm(fun: &Rc<RefCell<Function>>) {
+    insert_preheaders(fun);
+    let info = find_loops(fun);
+    if info.loops.is_empty() {
+        return;
+    }
+
+    let bbs = fun.borrow().bbs.clone();
+    let mut ndefs = count_defs(&bbs);
+    let mem = mem_info(&bbs, &ndefs);
+
+    for l in info.loops.iter() {
+        let header = bbs[l.header].clone();
+        let pre = header
+            .borrow()
+            .pred
+            .iter()
+            .find(|p| !l.blocks.iter().any(|&b| Rc::ptr_eq(&bbs[b], p)))
+            .cloned();
+        if let Some(pre) = pre {
+            hoist(&bbs, l, &mem, &mut ndefs, &pre);
+        }
+    }
+    build_cfg(fun);
+}
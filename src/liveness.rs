@@ -1,39 +1,13 @@
+use crate::dom::*;
 use crate::gen_ir::*;
 use crate::parse::*;
 use crate::util::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-fn add_edges(bb: Rc<RefCell<BB>>) {
-    if bb.borrow().succ.len() > 0 {
-        return;
-    }
-    assert!(bb.borrow().ir.len() > 0);
-
-    let ir_length = bb.borrow().ir.len();
-    let ir = bb.borrow().ir[ir_length - 1].clone();
-
-    let bb1 = ir.borrow().bb1.clone();
-    if bb1.is_some() {
-        let tmp = bb1.clone().unwrap();
-        bb.borrow_mut().succ.push(tmp.clone());
-        tmp.borrow_mut().pred.push(bb.clone());
-        add_edges(tmp);
-    }
-
-    let bb2 = ir.borrow().bb2.clone();
-    if bb2.is_some() {
-        let tmp = bb2.clone().unwrap();
-        bb.borrow_mut().succ.push(tmp.clone());
-        tmp.borrow_mut().pred.push(bb.clone());
-        add_edges(tmp);
-    }
-}
-
 fn set_def_regs(bb: &Rc<RefCell<BB>>) {
-    let param = bb.borrow().param.clone();
-    if param.is_some() {
-        vec_union(bb.borrow().def_regs.clone(), &param.clone().unwrap());
+    for param in bb.borrow().params.iter() {
+        vec_union(bb.borrow().def_regs.clone(), param);
     }
 
     let irs = bb.borrow().ir.clone();
@@ -42,6 +16,9 @@ fn set_def_regs(bb: &Rc<RefCell<BB>>) {
         if r0.is_some() {
             vec_union(bb.borrow().def_regs.clone(), &r0.clone().unwrap());
         }
+        for r in ir.borrow().outs.iter() {
+            vec_union(bb.borrow().def_regs.clone(), r);
+        }
     }
 }
 
@@ -53,10 +30,14 @@ fn propagate(bb: &Rc<RefCell<BB>>, r: Option<Rc<RefCell<Reg>>>) {
     if def_regs.borrow().contains(&r.clone().unwrap()) {
         return;
     }
+    add_in_reg(bb, r.unwrap());
+}
 
-    if !vec_union(bb.borrow().in_regs.clone(), &r.clone().unwrap()) {
+fn add_in_reg(bb: &Rc<RefCell<BB>>, r: Rc<RefCell<Reg>>) {
+    if !vec_union(bb.borrow().in_regs.clone(), &r) {
         return;
     }
+    let r = Some(r);
 
     let preds = bb.borrow().pred.clone();
     for pred in preds.iter() {
@@ -66,48 +47,61 @@ fn propagate(bb: &Rc<RefCell<BB>>, r: Option<Rc<RefCell<Reg>>>) {
     }
 }
 
-fn visit(bb: &Rc<RefCell<BB>>, ir: &Rc<RefCell<IR>>) {
-    propagate(bb, ir.borrow().r1.clone());
-    propagate(bb, ir.borrow().r2.clone());
-    propagate(bb, ir.borrow().bbarg.clone());
-
-    let op = ir.borrow().op.clone();
-    if op == IRType::CALL {
-        let nargs = ir.borrow().nargs;
-        for i in 0..nargs {
-            propagate(bb, Some(ir.borrow().args[i].clone()));
+// A register read before it is assigned in a BB is live into the BB.
+fn visit(bb: &Rc<RefCell<BB>>, ir: &Rc<RefCell<IR>>, defined: &mut Vec<i32>) {
+    let ir = ir.borrow();
+    for r in uses(&ir) {
+        if !defined.contains(&r.borrow().vn) {
+            add_in_reg(bb, r);
         }
     }
+    for r in ir.r0.iter().chain(ir.outs.iter()) {
+        defined.push(r.borrow().vn);
+    }
 }
 
-pub fn liveness(prog: &mut Program) {
-    for fun in prog.funcs.iter() {
-        let bbs = fun.borrow().bbs.clone();
-        add_edges(bbs[0].clone());
+pub fn liveness_fn(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+    let bbs = fun.borrow().bbs.clone();
 
-        for bb in bbs.iter() {
-            set_def_regs(bb);
+    for bb in bbs.iter() {
+        let mut bb = bb.borrow_mut();
+        bb.def_regs = Rc::new(RefCell::new(Vec::new()));
+        bb.in_regs = Rc::new(RefCell::new(Vec::new()));
+        bb.out_regs = Rc::new(RefCell::new(Vec::new()));
+    }
 
-            let irs = bb.borrow().ir.clone();
-            for ir in irs.iter() {
-                visit(bb, ir);
-            }
-        }
+    for bb in bbs.iter() {
+        set_def_regs(bb);
+    }
 
-        // Incoming registers of the entry BB correspond to
-        // uninitialized variables in a program.
-        // Add dummy definitions to make later analysis easy.
-        let ent = bbs[0].clone();
-        let in_regs = ent.borrow().in_regs.clone();
-        for r in in_regs.borrow().iter() {
-            let mut ir = alloc_ir();
-            ir.op = IRType::MOV;
-            ir.r0 = Some(r.clone());
-            ir.imm = 0;
-            ent.borrow_mut().ir.push(Rc::new(RefCell::new(ir)));
-            let def_regs = ent.borrow_mut().def_regs.clone();
-            def_regs.borrow_mut().push(r.clone());
+    for bb in bbs.iter() {
+        let irs = bb.borrow().ir.clone();
+        let mut defined = bb.borrow().params.iter().map(|r| r.borrow().vn).collect();
+        for ir in irs.iter() {
+            visit(bb, ir, &mut defined);
         }
-        ent.borrow_mut().in_regs = Rc::new(RefCell::new(Vec::new()));
+    }
+
+    // Incoming registers of the entry BB correspond to
+    // uninitialized variables in a program.
+    // Add dummy definitions to make later analysis easy.
+    let ent = bbs[0].clone();
+    let in_regs = ent.borrow().in_regs.clone();
+    for r in in_regs.borrow().iter() {
+        let mut ir = alloc_ir();
+        ir.op = IRType::MOV;
+        ir.r0 = Some(r.clone());
+        ir.imm = 0;
+        ent.borrow_mut().ir.insert(0, Rc::new(RefCell::new(ir)));
+        let def_regs = ent.borrow_mut().def_regs.clone();
+        def_regs.borrow_mut().push(r.clone());
+    }
+    ent.borrow_mut().in_regs = Rc::new(RefCell::new(Vec::new()));
+}
+
+pub fn liveness(prog: &mut Program) {
+    for fun in prog.funcs.iter() {
+        liveness_fn(fun);
     }
 }
@@ -0,0 +1,171 @@
+// Loop analysis
+//
+// A back edge is an edge whose target dominates its source. The target
+// is the header of a natural loop, which consists of the header and the
+// BBs that can reach the source of the edge without going through the
+// header. Back edges to the same header form a single loop.
+//
+// Two natural loops are either disjoint or nested, so they form a tree.
+// The loop depth of a BB is the number of loops containing it.
+//
+// A preheader is a BB which is the only predecessor of a loop header
+// from outside the loop. It is where code hoisted out of the loop goes.
+
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use crate::util::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub struct Loop {
+    pub header: usize,
+    pub blocks: Vec<usize>,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+}
+
+pub struct LoopInfo {
+    // Loops ordered from inner to outer
+    pub loops: Vec<Loop>,
+    // Loop depth of each BB
+    pub depth: Vec<usize>,
+}
+
+// Finds natural loops. `build_cfg` must be called beforehand.
+pub fn find_loops(fun: &Rc<RefCell<Function>>) -> LoopInfo {
+    let bbs = fun.borrow().bbs.clone();
+    let index = bb_indices(&bbs);
+    let dom = dominators(fun);
+
+    let mut loops: Vec<Loop> = Vec::new();
+    for (h, bb) in bbs.iter().enumerate() {
+        let mut blocks = vec![h];
+        let mut work = Vec::new();
+        let mut is_header = false;
+
+        for p in bb.borrow().pred.iter() {
+            let p = index[&p.borrow().label];
+            if !dominates(&dom, h, p) {
+                continue;
+            }
+            is_header = true;
+            if !blocks.contains(&p) {
+                blocks.push(p);
+                work.push(p);
+            }
+        }
+        if !is_header {
+            continue;
+        }
+
+        while let Some(b) = work.pop() {
+            for p in bbs[b].borrow().pred.iter() {
+                let p = index[&p.borrow().label];
+                if !blocks.contains(&p) {
+                    blocks.push(p);
+                    work.push(p);
+                }
+            }
+        }
+
+        loops.push(Loop {
+            header: h,
+            blocks: blocks,
+            parent: None,
+            children: Vec::new(),
+        });
+    }
+
+    // An inner loop is smaller than the loops containing it.
+    loops.sort_by_key(|l| l.blocks.len());
+
+    for i in 0..loops.len() {
+        let header = loops[i].header;
+        let parent = (i + 1..loops.len()).find(|&j| loops[j].blocks.contains(&header));
+        loops[i].parent = parent;
+        if let Some(j) = parent {
+            loops[j].children.push(i);
+        }
+    }
+
+    let mut depth = vec![0; bbs.len()];
+    for l in loops.iter() {
+        for &b in l.blocks.iter() {
+            depth[b] += 1;
+        }
+    }
+
+    return LoopInfo {
+        loops: loops,
+        depth: depth,
+    };
+}
+
+// Inserts a preheader before each loop header that has no unique
+// predecessor outside the loop ending with a jump to it. The preheader
+// takes over the block parameters of the header for the values coming
+// from outside the loop.
+pub fn insert_preheaders(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+    let info = find_loops(fun);
+    let bbs = fun.borrow().bbs.clone();
+    let mut preheaders = Vec::new();
+
+    for l in info.loops.iter() {
+        let header = bbs[l.header].clone();
+        let outside: Vec<Rc<RefCell<BB>>> = header
+            .borrow()
+            .pred
+            .iter()
+            .filter(|p| !l.blocks.iter().any(|&b| Rc::ptr_eq(&bbs[b], p)))
+            .cloned()
+            .collect();
+
+        if outside.is_empty() || (outside.len() == 1 && outside[0].borrow().succ.len() == 1) {
+            continue;
+        }
+
+        let mut pre = alloc_bb();
+        pre.label = bump_nlabel();
+        let mut jmp = alloc_ir();
+        jmp.op = IRType::JMP;
+        jmp.bb1 = Some(header.clone());
+        for _ in header.borrow().params.iter() {
+            let r = new_reg();
+            pre.params.push(r.clone());
+            jmp.bbargs.push(r);
+        }
+        pre.ir.push(Rc::new(RefCell::new(jmp)));
+        let pre = Rc::new(RefCell::new(pre));
+
+        for p in outside.iter() {
+            let ir = p.borrow().ir.last().unwrap().clone();
+            let mut ir = ir.borrow_mut();
+            if ir.bb1.as_ref().map_or(false, |t| Rc::ptr_eq(t, &header)) {
+                ir.bb1 = Some(pre.clone());
+            }
+            if ir.bb2.as_ref().map_or(false, |t| Rc::ptr_eq(t, &header)) {
+                ir.bb2 = Some(pre.clone());
+            }
+        }
+        preheaders.push((l.header, pre));
+    }
+
+    if preheaders.is_empty() {
+        return;
+    }
+
+    // Place each preheader right before its header.
+    let mut v = Vec::new();
+    for (i, bb) in bbs.iter().enumerate() {
+        for (h, pre) in preheaders.iter() {
+            if *h == i {
+                v.push(pre.clone());
+            }
+        }
+        v.push(bb.clone());
+    }
+    fun.borrow_mut().bbs = v;
+    build_cfg(fun);
+}
@@ -3,25 +3,41 @@
 mod util;
 #[macro_use]
 mod token;
+mod alias;
+mod coloring;
+mod dce;
+mod dom;
 mod gen_ir;
 mod gen_x86;
+mod gvn;
+mod inline;
+mod insn;
+mod instcombine;
 mod irdump;
+mod licm;
 mod liveness;
-mod opt;
+mod loops;
+mod mem2reg;
 mod parse;
+mod passes;
+mod peephole;
 mod preprocess;
 mod regalloc;
+mod sccp;
 mod sema;
+mod slots;
+mod ssa;
+mod tailcall;
+mod verify;
 
 use crate::gen_ir::*;
 use crate::gen_x86::*;
 use crate::irdump::*;
-use crate::liveness::*;
-use crate::opt::*;
 use crate::parse::*;
-use crate::regalloc::*;
+use crate::passes::*;
 use crate::sema::*;
 use crate::token::*;
+use crate::util::*;
 use std::cell::RefCell;
 use std::env;
 use std::rc::Rc;
@@ -76,10 +92,16 @@ fn print_node(n: Rc<RefCell<Node>>, offset: usize) {
     for i in node.args.iter() {
         print_node(i.clone(), offset + 2);
     }
+
+    for (_, e) in node.assocs.iter() {
+        print_node(e.clone(), offset + 2);
+    }
 }
 
 fn usage() {
-    println!("Usage: r9ir [-test] [-dump-ir1] [-dump-ir2] <file>");
+    println!(
+        "Usage: r9ir [-test] [-dump-ir1] [-dump-ir2] [-Wunused] [-fcommon] [-mlzcnt] [-mbmi] [-mpopcnt] [-fregalloc=graph|linear] [-fomit-frame-pointer] [-O0|-O1|-O2|-Os] [-fno-<pass>] [-dump-before=<pass>] [-dump-after=<pass>] [-verify-each] <file>"
+    );
 }
 
 fn main() {
@@ -89,30 +111,59 @@ fn main() {
         return;
     }
 
-    let path: String;
+    let mut path: Option<String> = None;
     let mut dump_node = false;
     let mut dump_ir1 = false;
     let mut dump_ir2 = false;
-
-    if argv.len() == 3 && argv[1] == "-dump-node" {
-        dump_node = true;
-        path = argv[2].clone();
-    } else if argv.len() == 3 && argv[1] == "-dump-ir1" {
-        dump_ir1 = true;
-        path = argv[2].clone();
-    } else if argv.len() == 3 && argv[1] == "-dump-ir2" {
-        dump_ir2 = true;
-        path = argv[2].clone();
-    } else {
-        if argv.len() != 2 {
-            usage();
-            return;
+    let mut opts = default_options();
+
+    for arg in argv[1..].iter() {
+        match arg.as_str() {
+            "-dump-node" => dump_node = true,
+            "-dump-ir1" => dump_ir1 = true,
+            "-dump-ir2" => dump_ir2 = true,
+            "-Wunused" => opts.warn_unused = true,
+            "-fcommon" => opts.common = true,
+            "-fno-common" => opts.common = false,
+            "-mlzcnt" => opts.lzcnt = true,
+            "-mbmi" => opts.bmi = true,
+            "-mpopcnt" => opts.popcnt = true,
+            "-fregalloc=linear" => opts.regalloc = RegAlloc::Linear,
+            "-fregalloc=graph" => opts.regalloc = RegAlloc::Graph,
+            "-fomit-frame-pointer" => opts.omit_frame_pointer = true,
+            "-fno-omit-frame-pointer" => opts.omit_frame_pointer = false,
+            "-O0" => opts.opt_level = OptLevel::O0,
+            "-O" | "-O1" => opts.opt_level = OptLevel::O1,
+            "-O2" => opts.opt_level = OptLevel::O2,
+            "-Os" => opts.opt_level = OptLevel::Os,
+            "-verify-each" => opts.verify_each = true,
+            s if s.starts_with("-fno-") && is_optional_pass(&s[5..]) => {
+                opts.disabled_passes.push(s[5..].to_string());
+            }
+            s if s.starts_with("-dump-before=") && is_ir_pass(&s[13..]) => {
+                opts.dump_before.push(s[13..].to_string());
+            }
+            s if s.starts_with("-dump-after=") && is_ir_pass(&s[12..]) => {
+                opts.dump_after.push(s[12..].to_string());
+            }
+            _ => {
+                if path.is_some() || (arg.starts_with('-') && arg != "-") {
+                    usage();
+                    return;
+                }
+                path = Some(arg.clone());
+            }
         }
-        path = argv[1].clone();
     }
+    if path.is_none() {
+        usage();
+        return;
+    }
+    let path = path.unwrap();
+    set_options(opts);
 
     // Token -> Node -> IR -> asm
-    // token -> parse -> sema -> gen_ir(irdump) -> regalloc -> gen_x86
+    // token -> parse -> sema -> gen_ir(irdump) -> passes -> gen_x86
 
     // Tokenize and parse.
     let tokens = tokenize(path, true);
@@ -134,9 +185,7 @@ fn main() {
         return;
     }
 
-    optimize(prog);
-    liveness(prog);
-    alloc_regs(prog);
+    run_passes(prog);
 
     if dump_ir2 {
         dump_ir(prog.funcs.clone());
@@ -0,0 +1,150 @@
+// Promotion of local variables to registers. In this pass, we promote
+// all non-address-taken, non-volatile scalar variables to register
+// values. As a result, we may have more register values than the number
+// of the physical registers, but that's fine. Regalloc will spill them
+// out to memory.
+
+// Rewrite
+//
+//  BPREL r1, <offset>
+//  STORE r1, r2
+//  LOAD  r3, r1
+//
+// to
+//
+//  NOP
+//  (uses of r3 are replaced with r2)
+//
+// The BPRELs are marked here, and ssa.rs renames the loads and stores,
+// adding block parameters where values of a variable meet.
+//
+// A register is 64 bits wide, while a variable may be narrower, so a
+// value stored to a variable is truncated and extended to 64 bits
+// here, by the same rule as loads from memory (see extend_ops).
+//
+// A parameter is passed in a register. If it is promoted, its STORE_ARG
+// is replaced with an ARG, which reads the register, and a store of the
+// value, so the argument never goes through memory.
+
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn is_promotable(var: &Var) -> bool {
+    if var.address_taken || var.ty.is_volatile || var.ty.is_atomic || var.ty.vla_len.is_some() {
+        return false;
+    }
+    match var.ty.ty {
+        CType::BOOL | CType::CHAR | CType::SHORT | CType::INT | CType::LONG | CType::PTR => true,
+        _ => false,
+    }
+}
+
+fn promoted_var(r: &Option<Rc<RefCell<Reg>>>) -> Option<Rc<RefCell<Var>>> {
+    match r {
+        Some(r) => r.borrow().promoted.clone(),
+        None => None,
+    }
+}
+
+// Appends `r0 = r1 op imm` to `v` and returns r0.
+fn emit_imm(
+    v: &mut Vec<Rc<RefCell<IR>>>,
+    op: IRType,
+    r1: Rc<RefCell<Reg>>,
+    imm: i64,
+) -> Rc<RefCell<Reg>> {
+    let mut ir = alloc_ir();
+    ir.op = op;
+    ir.r0 = Some(new_reg());
+    ir.r1 = Some(r1);
+    ir.imm = imm;
+    let r0 = ir.r0.clone().unwrap();
+    v.push(Rc::new(RefCell::new(ir)));
+    return r0;
+}
+
+// Emits instructions to `v` to convert a value to the type of a
+// variable, and returns the converted value.
+fn extend(v: &mut Vec<Rc<RefCell<IR>>>, r: Rc<RefCell<Reg>>, ty: &Type) -> Rc<RefCell<Reg>> {
+    let mut r = r;
+    for (op, n) in extend_ops(ty) {
+        r = emit_imm(v, op, r, n);
+    }
+    return r;
+}
+
+// Replaces `STORE_ARG var, i` with `ARG r, i` and a store of r.
+fn promote_param(ir: &IR, var: &Rc<RefCell<Var>>, v: &mut Vec<Rc<RefCell<IR>>>) {
+    let addr = new_reg();
+    addr.borrow_mut().promoted = Some(var.clone());
+    let mut nop = alloc_ir();
+    nop.op = IRType::NOP;
+    nop.r0 = Some(addr.clone());
+    v.push(Rc::new(RefCell::new(nop)));
+
+    let mut arg = alloc_ir();
+    arg.op = IRType::ARG;
+    arg.r0 = Some(new_reg());
+    arg.imm = ir.imm;
+    let r = arg.r0.clone().unwrap();
+    v.push(Rc::new(RefCell::new(arg)));
+
+    let mut store = alloc_ir();
+    store.op = IRType::STORE;
+    store.r1 = Some(addr);
+    store.r2 = Some(r);
+    store.size = ir.size;
+    v.push(Rc::new(RefCell::new(store)));
+}
+
+pub fn mem2reg(fun: &Rc<RefCell<Function>>) {
+    let bbs = fun.borrow().bbs.clone();
+
+    // Mark the addresses of promoted variables.
+    for bb in bbs.iter() {
+        let irs = bb.borrow().ir.clone();
+        let mut v = Vec::new();
+        for ir in irs.into_iter() {
+            let var = ir.borrow().var.clone();
+            let promote = match var {
+                Some(ref var) => is_promotable(&var.borrow()),
+                None => false,
+            };
+            let op = ir.borrow().op.clone();
+
+            if promote && op == IRType::BPREL {
+                ir.borrow_mut().op = IRType::NOP;
+                let r0 = ir.borrow().r0.clone().unwrap();
+                r0.borrow_mut().promoted = var;
+            } else if promote && op == IRType::STORE_ARG {
+                promote_param(&ir.borrow(), var.as_ref().unwrap(), &mut v);
+                continue;
+            } else if promote && op == IRType::SCOPE_END {
+                continue;
+            }
+            v.push(ir);
+        }
+        bb.borrow_mut().ir = v;
+    }
+
+    // Truncate and extend stored values.
+    for bb in bbs.iter() {
+        let irs = bb.borrow().ir.clone();
+        let mut v = Vec::new();
+        for ir in irs.into_iter() {
+            let var = match ir.borrow().op {
+                IRType::STORE => promoted_var(&ir.borrow().r1),
+                _ => None,
+            };
+            if let Some(var) = var {
+                let r2 = ir.borrow().r2.clone().unwrap();
+                let r = extend(&mut v, r2, &var.borrow().ty);
+                ir.borrow_mut().r2 = Some(r);
+            }
+            v.push(ir);
+        }
+        bb.borrow_mut().ir = v;
+    }
+}
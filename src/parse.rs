@@ -24,7 +24,8 @@ use crate::util::*;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::fmt;
+use std::rc::{Rc, Weak};
 
 thread_local! {
     static PROGRAM: RefCell<Program> = RefCell::new(new_program());
@@ -34,6 +35,17 @@ thread_local! {
     static BREAKS: RefCell<Vec<Rc<RefCell<Node>>>> = RefCell::new(Vec::new());
     static CONTINUES: RefCell<Vec<Rc<RefCell<Node>>>> = RefCell::new(Vec::new());
     static SWITCHES: RefCell<Vec<Rc<RefCell<Node>>>> = RefCell::new(Vec::new());
+
+    // Whether each enclosing block allocates a variable-length array,
+    // and whether it calls alloca
+    static VLA_SCOPES: RefCell<Vec<(bool, bool)>> = RefCell::new(Vec::new());
+
+    // Local variables declared in each enclosing block
+    static SCOPE_VARS: RefCell<Vec<Vec<Rc<RefCell<Var>>>>> = RefCell::new(Vec::new());
+
+    // Statements computing sizes of variable-length arrays read by
+    // the current declaration
+    static VLA_STMTS: RefCell<Vec<Rc<RefCell<Node>>>> = RefCell::new(Vec::new());
 }
 
 fn init_lvars() {
@@ -147,6 +159,70 @@ fn switches_last() -> Rc<RefCell<Node>> {
     })
 }
 
+fn vla_scopes_push() {
+    VLA_SCOPES.with(|p| {
+        p.borrow_mut().push((false, false));
+    })
+}
+
+// Returns true if the block has to restore the stack pointer at exit.
+fn vla_scopes_pop() -> bool {
+    VLA_SCOPES.with(|p| {
+        let (has_vla, has_alloca) = p.borrow_mut().pop().unwrap();
+        return has_vla && !has_alloca;
+    })
+}
+
+fn vla_scopes_set() {
+    VLA_SCOPES.with(|p| {
+        if let Some(last) = p.borrow_mut().last_mut() {
+            last.0 = true;
+        }
+    })
+}
+
+// Memory allocated by alloca lives until the function returns, so
+// none of the enclosing blocks may release it.
+fn vla_scopes_set_alloca() {
+    VLA_SCOPES.with(|p| {
+        for scope in p.borrow_mut().iter_mut() {
+            scope.1 = true;
+        }
+    })
+}
+
+fn scope_vars_push() {
+    SCOPE_VARS.with(|p| {
+        p.borrow_mut().push(Vec::new());
+    })
+}
+
+fn scope_vars_pop() -> Vec<Rc<RefCell<Var>>> {
+    SCOPE_VARS.with(|p| {
+        return p.borrow_mut().pop().unwrap();
+    })
+}
+
+fn scope_vars_add(var: Rc<RefCell<Var>>) {
+    SCOPE_VARS.with(|p| {
+        if let Some(last) = p.borrow_mut().last_mut() {
+            last.push(var);
+        }
+    })
+}
+
+fn vla_stmts_push(node: Rc<RefCell<Node>>) {
+    VLA_STMTS.with(|p| {
+        p.borrow_mut().push(node);
+    })
+}
+
+fn take_vla_stmts() -> Vec<Rc<RefCell<Node>>> {
+    VLA_STMTS.with(|p| {
+        return p.borrow_mut().drain(..).collect();
+    })
+}
+
 fn prog_gvars_push(var: Rc<RefCell<Var>>) {
     PROGRAM.with(|p| {
         p.borrow_mut().gvars.push(var);
@@ -159,6 +235,12 @@ fn prog_funcs_push(func: Rc<RefCell<Function>>) {
     })
 }
 
+fn prog_asm_push(s: String) {
+    PROGRAM.with(|p| {
+        p.borrow_mut().asm.push(s);
+    })
+}
+
 fn pos() -> usize {
     POS.with(|pos| *pos.borrow())
 }
@@ -185,18 +267,33 @@ pub struct Function {
     pub node: Rc<RefCell<Node>>,
     pub lvars: Vec<Rc<RefCell<Var>>>,
     pub bbs: Vec<Rc<RefCell<BB>>>,
+
+    // Size of the area for local variables, which is computed by
+    // slots.rs
+    pub stacksize: i32,
+
+    // GNU attributes
+    pub section: Option<String>,
+    pub is_weak: bool,
+    pub is_inline: bool,
+    pub is_always_inline: bool,
+    pub is_noinline: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct Program {
     pub gvars: Vec<Rc<RefCell<Var>>>,
     pub funcs: Vec<Rc<RefCell<Function>>>,
+
+    // File-scope asm statements
+    pub asm: Vec<String>,
 }
 
 pub fn new_program() -> Program {
     Program {
         gvars: Vec::new(),
         funcs: Vec::new(),
+        asm: Vec::new(),
     }
 }
 
@@ -204,7 +301,7 @@ pub fn new_program() -> Program {
 struct Env {
     vars: HashMap<String, Rc<RefCell<Var>>>,
     typedefs: HashMap<String, Type>,
-    tags: HashMap<String, Type>,
+    tags: HashMap<String, Rc<RefCell<Type>>>,
     prev: Option<Box<Env>>,
 }
 
@@ -229,7 +326,7 @@ impl Env {
         }
     }
 
-    fn find_tag(&self, name: &String) -> Option<Type> {
+    fn find_tag(&self, name: &String) -> Option<Rc<RefCell<Type>>> {
         match self.tags.get(name) {
             None => match self.prev {
                 None => None,
@@ -261,7 +358,7 @@ fn env_typedefs_put(key: String, val: Type) {
     })
 }
 
-fn env_tags_put(key: String, val: Type) {
+fn env_tags_put(key: String, val: Rc<RefCell<Type>>) {
     ENV.with(|env| {
         env.borrow_mut().tags.insert(key, val);
     })
@@ -298,35 +395,50 @@ pub enum NodeType {
     COMMA,  // ,
     NUM,    // Number literal
     //STRUCT,    // Struct
-    DECL,      // declaration
-    VARDEF,    // Variable definition
-    VARREF,    // Variable reference
-    CAST,      // Cast
-    IF,        // "if"
-    FOR,       // "for"
-    DO_WHILE,  // do ... while
-    SWITCH,    // switch
-    CASE,      // case
-    BREAK,     // break
-    CONTINUE,  // continue
-    ADDR,      // address-of operator ("&")
-    DEREF,     // pointer dereference ("*")
-    DOT,       // Struct member access
-    EQ,        // ==
-    NE,        // !=
-    LE,        // <=
-    LOGAND,    // &&
-    LOGOR,     // ||
-    SHL,       // <<
-    SHR,       // >>
-    MOD,       // %
-    RETURN,    // "return"
-    CALL,      // Function call
-    FUNC,      // Function definition
-    COMP_STMT, // Compound statement
-    EXPR_STMT, // Expression statement
-    STMT_EXPR, // Statement expression (GNU extn.)
-    NULL,      // Null statement
+    DECL,             // declaration
+    VARDEF,           // Variable definition
+    VARREF,           // Variable reference
+    CAST,             // Cast
+    IF,               // "if"
+    FOR,              // "for"
+    DO_WHILE,         // do ... while
+    SWITCH,           // switch
+    CASE,             // case
+    BREAK,            // break
+    CONTINUE,         // continue
+    ADDR,             // address-of operator ("&")
+    DEREF,            // pointer dereference ("*")
+    DOT,              // Struct member access
+    EQ,               // ==
+    NE,               // !=
+    LE,               // <=
+    LOGAND,           // &&
+    LOGOR,            // ||
+    SHL,              // <<
+    SHR,              // >>
+    MOD,              // %
+    RETURN,           // "return"
+    CALL,             // Function call
+    ALLOCA,           // __builtin_alloca
+    EXPECT,           // __builtin_expect
+    UNREACHABLE,      // __builtin_unreachable
+    TRAP,             // __builtin_trap
+    CLZ,              // __builtin_clz
+    CTZ,              // __builtin_ctz
+    POPCOUNT,         // __builtin_popcount
+    ATOMIC_LOAD,      // __atomic_load_n
+    ATOMIC_STORE,     // __atomic_store_n
+    ATOMIC_FETCH_ADD, // __atomic_fetch_add
+    ATOMIC_CAS,       // __atomic_compare_exchange_n
+    FENCE,            // __sync_synchronize
+    VLA_ALLOC,        // Allocation of a variable-length array
+    ASM,              // asm statement
+    GENERIC,          // _Generic selection
+    FUNC,             // Function definition
+    COMP_STMT,        // Compound statement
+    EXPR_STMT,        // Expression statement
+    STMT_EXPR,        // Statement expression (GNU extn.)
+    NULL,             // Null statement
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -334,19 +446,32 @@ pub enum CType {
     VOID,
     BOOL,
     CHAR,
+    SHORT,
     INT,
+    LONG,
     PTR,
     ARY,
     STRUCT,
     FUNC,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone)]
 pub struct Type {
     pub ty: CType,
     pub size: i32,  // sizeof
     pub align: i32, // alignof
 
+    // Integer
+    pub is_unsigned: bool,
+
+    // _Atomic-qualified. Loads and stores of an atomic object are
+    // never promoted to registers.
+    pub is_atomic: bool,
+
+    // volatile-qualified. Every access to a volatile object is done
+    // in memory and is kept by the optimizer.
+    pub is_volatile: bool,
+
     // Pointer
     pub ptr_to: Option<Rc<RefCell<Type>>>,
 
@@ -354,25 +479,74 @@ pub struct Type {
     pub ary_of: Option<Box<Type>>,
     pub len: i32,
 
-    // Struct
+    // Struct. `def` is the type object shared by all references to
+    // the same struct, which is completed in place when the body is
+    // read. `members` is None while the struct is incomplete. The
+    // object refers to itself by `def`, so the reference is weak; the
+    // tag table and pointers to the struct keep it alive.
     pub members: Option<BTreeMap<String, Rc<RefCell<Type>>>>,
     pub offset: i32,
+    pub def: Option<Weak<RefCell<Type>>>,
 
     // Function
     pub returning: Option<Box<Type>>,
+    pub is_noreturn: bool,
+
+    // Variable-length array. Its length is evaluated at runtime and
+    // the size in bytes is kept in a hidden local variable. An object
+    // of the type holds a pointer to storage allocated on the stack.
+    pub vla_len: Option<Rc<RefCell<Node>>>,
+    pub vla_size: Option<Rc<RefCell<Var>>>,
+}
+
+// Struct types are equal only if they share the definition. This also
+// keeps comparison and printing of self-referential structs finite.
+impl PartialEq for Type {
+    fn eq(&self, other: &Type) -> bool {
+        if self.ty == CType::STRUCT && other.ty == CType::STRUCT {
+            let x = self.def.as_ref().unwrap();
+            let y = other.def.as_ref().unwrap();
+            return Weak::ptr_eq(x, y) && self.offset == other.offset;
+        }
+        return self.ty == other.ty
+            && self.size == other.size
+            && self.align == other.align
+            && self.is_unsigned == other.is_unsigned
+            && self.is_atomic == other.is_atomic
+            && self.is_volatile == other.is_volatile
+            && self.ptr_to == other.ptr_to
+            && self.ary_of == other.ary_of
+            && self.len == other.len
+            && self.offset == other.offset
+            && self.returning == other.returning
+            && self.is_noreturn == other.is_noreturn
+            && self.vla_len == other.vla_len
+            && self.vla_size == other.vla_size;
+    }
+}
+
+impl fmt::Debug for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ty {
+            CType::PTR => write!(f, "{:?}*", self.ptr_to.as_ref().unwrap().borrow()),
+            CType::ARY => write!(f, "{:?}[{}]", self.ary_of.as_ref().unwrap(), self.len),
+            CType::FUNC => write!(f, "{:?}()", self.returning.as_ref().unwrap()),
+            _ => write!(f, "{:?}", self.ty),
+        }
+    }
 }
 
 impl Type {
-    fn fix_struct_offsets(&mut self) {
+    fn fix_struct_offsets(&mut self, is_packed: bool) {
         let mut off = 0;
         match self.members {
             Some(ref ms) => {
                 for t2 in ms.values() {
-                    off = roundup(off, t2.borrow().align);
+                    let align = if is_packed { 1 } else { t2.borrow().align };
+                    off = roundup(off, align);
                     t2.borrow_mut().offset = off;
                     off += t2.borrow().size;
 
-                    let align = t2.borrow().align;
                     if self.align < align {
                         self.align = align;
                     }
@@ -389,12 +563,19 @@ pub fn alloc_type() -> Type {
         ty: CType::INT,
         size: 0,
         align: 0,
+        is_unsigned: false,
+        is_atomic: false,
+        is_volatile: false,
         ptr_to: None,
         ary_of: None,
         len: 0,
         members: None,
         offset: 0,
+        def: None,
         returning: None,
+        is_noreturn: false,
+        vla_len: None,
+        vla_size: None,
     }
 }
 
@@ -411,10 +592,64 @@ pub struct Var {
     // Global variables are compiled to labels with optional
     // initialized data.
     pub data: Option<String>,
+    pub is_thread_local: bool,
+
+    // True if the variable has only been declared `extern` so far.
+    // Such variables are defined in other translation units.
+    pub is_extern: bool,
+
+    // Alignment given by _Alignas. Zero means the natural
+    // alignment of the type.
+    pub align: i32,
+
+    // GNU attributes
+    pub section: Option<String>,
+    pub is_weak: bool,
+    pub is_unused: bool,
+
+    // For -Wunused
+    pub is_used: bool,
+
+    // For error reporting
+    pub token: Option<Box<Token>>,
 
     // For optimizatin passes.
     pub address_taken: bool,
-    pub promoted: Option<Rc<RefCell<Reg>>>,
+}
+
+// Declaration specifiers which are not part of a type, such as
+// `_Noreturn` or `_Alignas(16)`, and GNU attributes.
+#[derive(Clone, Debug, PartialEq)]
+struct VarAttr {
+    align: i32,
+    is_noreturn: bool,
+    is_thread_local: bool,
+    is_packed: bool,
+    is_weak: bool,
+    is_unused: bool,
+    is_inline: bool,
+    is_always_inline: bool,
+    is_noinline: bool,
+    is_atomic: bool,
+    is_volatile: bool,
+    section: Option<String>,
+}
+
+fn alloc_var_attr() -> VarAttr {
+    VarAttr {
+        align: 0,
+        is_noreturn: false,
+        is_thread_local: false,
+        is_packed: false,
+        is_weak: false,
+        is_unused: false,
+        is_inline: false,
+        is_always_inline: false,
+        is_noinline: false,
+        is_atomic: false,
+        is_volatile: false,
+        section: None,
+    }
 }
 
 pub fn alloc_var() -> Var {
@@ -426,9 +661,20 @@ pub fn alloc_var() -> Var {
 
         name: String::new(),
         data: None,
+        is_thread_local: false,
+        is_extern: false,
+
+        align: 0,
+
+        section: None,
+        is_weak: false,
+        is_unused: false,
+
+        is_used: false,
+
+        token: None,
 
         address_taken: false,
-        promoted: None,
     }
 }
 
@@ -439,7 +685,7 @@ pub struct Node {
     pub ty: Rc<RefCell<Type>>,           // C type
     pub lhs: Option<Rc<RefCell<Node>>>,  // left-hand side
     pub rhs: Option<Rc<RefCell<Node>>>,  // right-hand side
-    pub val: i32,                        // Nubmer literal
+    pub val: i64,                        // Nubmer literal
     pub expr: Option<Rc<RefCell<Node>>>, // "return" or expression stmt
     pub stmts: Vec<Rc<RefCell<Node>>>,   // Compound statemtn
 
@@ -470,16 +716,71 @@ pub struct Node {
     pub break_: Rc<RefCell<BB>>,
     pub continue_: Rc<RefCell<BB>>,
 
+    // A compound statement which allocates variable-length arrays
+    // restores the stack pointer at exit, unless alloca is called in
+    // it. So do break and continue jumping out of it; `vla_depth` is
+    // the nesting level of such blocks at a loop or switch.
+    pub has_vla: bool,
+    pub vla_depth: usize,
+
+    // Compound statement. The variables declared in it, whose
+    // lifetimes end when the block is left.
+    pub scope_vars: Vec<Rc<RefCell<Var>>>,
+
     // Function definition
     pub params: Vec<Rc<RefCell<Var>>>,
 
     // Function call
     pub args: Vec<Rc<RefCell<Node>>>,
 
+    // _Generic associations. `None` is the default association.
+    pub assocs: Vec<(Option<Type>, Rc<RefCell<Node>>)>,
+
+    // asm statement
+    pub asm: Option<Box<Asm>>,
+
     // For error reporting
     pub token: Option<Box<Token>>,
 }
 
+// How an asm operand is passed to the assembly code,
+// decided by its constraint.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AsmKind {
+    REG,         // "r": any general-purpose register
+    FIXED(char), // "a", "c", "d", "S" or "D": the specific register
+    MEM,         // "m": memory
+    IMM,         // "i": immediate
+}
+
+// [name] "constraint" (expr)
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmOperand {
+    pub name: String,
+    pub constraint: String,
+    pub kind: AsmKind,
+    pub is_output: bool,
+    pub is_inout: bool,        // "+"
+    pub is_earlyclobber: bool, // "&"
+    pub expr: Rc<RefCell<Node>>,
+    pub val: i64, // AsmKind::IMM
+}
+
+// An asm template is split into literal text and substitutions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AsmPiece {
+    TEXT(String),
+    OPERAND(usize, Option<char>), // %0, %k0, %[name], ...
+    UNIQUE,                       // %=
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Asm {
+    pub template: Vec<AsmPiece>,
+    pub operands: Vec<AsmOperand>, // outputs followed by inputs
+    pub clobbers: Vec<String>,
+}
+
 // default node
 pub fn alloc_node() -> Node {
     Node {
@@ -509,9 +810,18 @@ pub fn alloc_node() -> Node {
 
         target: None,
 
+        has_vla: false,
+        vla_depth: 0,
+
+        scope_vars: Vec::new(),
+
         params: Vec::new(),
         args: Vec::new(),
 
+        assocs: Vec::new(),
+
+        asm: None,
+
         token: None,
     }
 }
@@ -530,12 +840,19 @@ fn find_typedef(name: &String) -> Option<Type> {
     ENV.with(|env| return env.borrow().find_typedef(name))
 }
 
-fn find_tag(name: &String) -> Option<Type> {
+fn find_tag(name: &String) -> Option<Rc<RefCell<Type>>> {
     ENV.with(|env| {
         return env.borrow().find_tag(name);
     })
 }
 
+// Looks up a tag in the innermost scope only.
+fn find_tag_in_scope(name: &String) -> Option<Rc<RefCell<Type>>> {
+    ENV.with(|env| {
+        return env.borrow().tags.get(name).cloned();
+    })
+}
+
 fn add_lvar(ty: Type, name: String) -> Rc<RefCell<Var>> {
     let mut var = alloc_var();
     var.ty = ty;
@@ -544,6 +861,7 @@ fn add_lvar(ty: Type, name: String) -> Rc<RefCell<Var>> {
     let v = Rc::new(RefCell::new(var));
     env_vars_put(name, v.clone());
     lvars_push(v.clone());
+    scope_vars_add(v.clone());
     return v;
 }
 
@@ -552,6 +870,7 @@ fn add_gvar(ty: Type, name: String, data: Option<String>, is_extern: bool) -> Rc
     var.ty = ty;
     var.name = name.clone();
     var.data = data;
+    var.is_extern = is_extern;
     let v = Rc::new(RefCell::new(var));
     env_vars_put(name, v.clone());
     if !is_extern {
@@ -589,14 +908,195 @@ fn is_typename(tokens: &Vec<Token>) -> bool {
         return find_typedef(&t.name).is_some();
     }
     return t.ty == TokenType::INT
+        || t.ty == TokenType::LONG
         || t.ty == TokenType::CHAR
         || t.ty == TokenType::VOID
         || t.ty == TokenType::STRUCT
         || t.ty == TokenType::TYPEOF
-        || t.ty == TokenType::BOOL;
+        || t.ty == TokenType::BOOL
+        || t.ty == TokenType::ALIGNAS
+        || t.ty == TokenType::ATOMIC
+        || t.ty == TokenType::NORETURN
+        || t.ty == TokenType::THREAD_LOCAL
+        || t.ty == TokenType::INLINE
+        || t.ty == TokenType::VOLATILE
+        || t.ty == TokenType::ATTRIBUTE;
+}
+
+fn set_align(t: &Token, attr: &mut VarAttr, align: i32) {
+    if align <= 0 || align & (align - 1) != 0 {
+        bad_token(t, format!("invalid alignment: {}", align));
+    }
+    if attr.align < align {
+        attr.align = align;
+    }
+}
+
+// Skips a parenthesized attribute argument list.
+fn skip_attribute_args(tokens: &Vec<Token>) {
+    if !consume(TokenType::BRA, tokens) {
+        return;
+    }
+    let mut level = 1;
+    while level > 0 {
+        let t = &tokens[bump_pos()];
+        match t.ty {
+            TokenType::BRA => level += 1,
+            TokenType::KET => level -= 1,
+            TokenType::EOF => bad_token(t, "unclosed attribute".to_string()),
+            _ => {}
+        }
+    }
+}
+
+fn attribute(tokens: &Vec<Token>, attr: &mut VarAttr) {
+    let t = &tokens[bump_pos()];
+    if t.ty != TokenType::IDENT {
+        bad_token(t, "attribute name expected".to_string());
+    }
+
+    // `__packed__` is the same as `packed`.
+    let mut name = t.name.as_str();
+    if name.len() > 4 && name.starts_with("__") && name.ends_with("__") {
+        name = &name[2..name.len() - 2];
+    }
+
+    match name {
+        "packed" => attr.is_packed = true,
+        "aligned" => {
+            // `aligned` without an argument means the largest
+            // alignment on the target.
+            if consume(TokenType::BRA, tokens) {
+                let align = const_expr(tokens);
+                expect(TokenType::KET, tokens);
+                set_align(t, attr, align as i32);
+            } else {
+                set_align(t, attr, 16);
+            }
+        }
+        "section" => {
+            expect(TokenType::BRA, tokens);
+            let s = &tokens[bump_pos()];
+            if s.ty != TokenType::STR {
+                bad_token(s, "section name expected".to_string());
+            }
+            expect(TokenType::KET, tokens);
+            attr.section = Some(s.string());
+        }
+        "weak" => attr.is_weak = true,
+        "noreturn" => attr.is_noreturn = true,
+        "unused" => attr.is_unused = true,
+        "always_inline" => attr.is_always_inline = true,
+        "noinline" => attr.is_noinline = true,
+        _ => {
+            warn_token!(t, format!("unknown attribute '{}' ignored", t.name));
+            skip_attribute_args(tokens);
+        }
+    }
+}
+
+// __attribute__ (( attribute, ... ))
+fn attribute_list(tokens: &Vec<Token>, attr: &mut VarAttr) {
+    while consume(TokenType::ATTRIBUTE, tokens) {
+        expect(TokenType::BRA, tokens);
+        expect(TokenType::BRA, tokens);
+        loop {
+            if consume(TokenType::KET, tokens) {
+                break;
+            }
+            if consume(TokenType::COMMA, tokens) {
+                continue;
+            }
+            attribute(tokens, attr);
+        }
+        expect(TokenType::KET, tokens);
+    }
+}
+
+fn var_attr(tokens: &Vec<Token>, attr: &mut VarAttr) {
+    loop {
+        let t = &tokens[pos()];
+        if consume(TokenType::NORETURN, tokens) {
+            attr.is_noreturn = true;
+            continue;
+        }
+
+        if consume(TokenType::THREAD_LOCAL, tokens) {
+            attr.is_thread_local = true;
+            continue;
+        }
+
+        if consume(TokenType::INLINE, tokens) {
+            attr.is_inline = true;
+            continue;
+        }
+
+        if consume(TokenType::VOLATILE, tokens) {
+            attr.is_volatile = true;
+            continue;
+        }
+
+        // `_Atomic` followed by "(" is a type specifier.
+        if t.ty == TokenType::ATOMIC && tokens[pos() + 1].ty != TokenType::BRA {
+            bump_pos();
+            attr.is_atomic = true;
+            continue;
+        }
+
+        if consume(TokenType::ALIGNAS, tokens) {
+            expect(TokenType::BRA, tokens);
+            let align = if is_typename(tokens) {
+                type_name(tokens).align
+            } else {
+                const_expr(tokens) as i32
+            };
+            expect(TokenType::KET, tokens);
+            set_align(t, attr, align);
+            continue;
+        }
+
+        if t.ty == TokenType::ATTRIBUTE {
+            attribute_list(tokens, attr);
+            continue;
+        }
+        return;
+    }
+}
+
+fn decl_specifiers(tokens: &Vec<Token>, attr: &mut VarAttr) -> Type {
+    var_attr(tokens, attr);
+    let t = &tokens[pos()];
+    let ty = type_specifier(tokens);
+    var_attr(tokens, attr);
+    let mut ty = ty;
+    ty.is_volatile = attr.is_volatile;
+    if attr.is_atomic {
+        return atomic_of(t, ty);
+    }
+    return ty;
+}
+
+// Reads `*` and the qualifiers following it, as in `int *volatile p`.
+fn pointer(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>) -> Type {
+    let mut ty = ptr_to(ty);
+    while consume(TokenType::VOLATILE, tokens) {
+        ty.is_volatile = true;
+    }
+    return ty;
+}
+
+// Only scalars which fit in a register can be atomic.
+fn atomic_of(t: &Token, ty: Type) -> Type {
+    match ty.ty {
+        CType::BOOL | CType::CHAR | CType::SHORT | CType::INT | CType::LONG | CType::PTR => {}
+        _ => bad_token(t, format!("unsupported atomic type: {:?}", ty)),
+    }
+    let mut ty = ty;
+    ty.is_atomic = true;
+    return ty;
 }
 
-fn decl_specifiers(tokens: &Vec<Token>) -> Type {
+fn type_specifier(tokens: &Vec<Token>) -> Type {
     let t = &tokens[bump_pos()];
     match t.ty {
         TokenType::IDENT => {
@@ -604,7 +1104,15 @@ fn decl_specifiers(tokens: &Vec<Token>) -> Type {
             if ty.is_none() {
                 dump_pos();
             }
-            return ty.unwrap();
+
+            // The struct may have been completed after the typedef.
+            let ty = ty.unwrap();
+            if is_incomplete_struct(&ty) {
+                if let Some(def) = ty.def.as_ref().unwrap().upgrade() {
+                    return def.borrow().clone();
+                }
+            }
+            return ty;
         }
         TokenType::VOID => {
             return void_ty();
@@ -618,6 +1126,18 @@ fn decl_specifiers(tokens: &Vec<Token>) -> Type {
         TokenType::INT => {
             return int_ty();
         }
+        TokenType::LONG => {
+            // `long long` is the same as `long`.
+            consume(TokenType::LONG, tokens);
+            consume(TokenType::INT, tokens);
+            return long_ty();
+        }
+        TokenType::ATOMIC => {
+            expect(TokenType::BRA, tokens);
+            let ty = type_name(tokens);
+            expect(TokenType::KET, tokens);
+            return atomic_of(t, ty);
+        }
         TokenType::TYPEOF => {
             expect(TokenType::BRA, tokens);
             let node = expr(tokens);
@@ -625,44 +1145,76 @@ fn decl_specifiers(tokens: &Vec<Token>) -> Type {
             return get_type(node);
         }
         TokenType::STRUCT => {
+            let mut attr = alloc_var_attr();
+            attribute_list(tokens, &mut attr);
+
             let t = &tokens[pos()];
-            let mut ty: Option<Type> = None;
+            let mut def: Option<Rc<RefCell<Type>>> = None;
             let mut tag: Option<String> = None;
 
             if t.ty == TokenType::IDENT {
                 bump_pos();
                 tag = Some(t.name.clone());
-                ty = find_tag(&t.name);
+
+                // A struct body or `struct tag;` declares a new type
+                // unless the tag is already declared in the same scope.
+                let next = tokens[pos()].ty.clone();
+                def = if next == TokenType::C_BRA || next == TokenType::SEMI_COLON {
+                    find_tag_in_scope(&t.name)
+                } else {
+                    find_tag(&t.name)
+                };
             }
 
-            if ty.is_none() {
-                let mut ty_tmp = alloc_type();
-                ty_tmp.ty = CType::STRUCT;
-                ty = Some(ty_tmp);
+            if def.is_none() {
+                let ty_tmp = Rc::new(RefCell::new(alloc_type()));
+                ty_tmp.borrow_mut().ty = CType::STRUCT;
+                ty_tmp.borrow_mut().def = Some(Rc::downgrade(&ty_tmp));
+                if let Some(ref name) = tag {
+                    env_tags_put(name.clone(), ty_tmp.clone());
+                }
+                def = Some(ty_tmp);
             }
+            let def = def.unwrap();
 
             if consume(TokenType::C_BRA, tokens) {
-                let mut ty_tmp = ty.clone().unwrap();
+                if def.borrow().members.is_some() {
+                    bad_token(
+                        t,
+                        format!("redefinition of 'struct {}'", tag.clone().unwrap()),
+                    );
+                }
+
                 let mut members = BTreeMap::new();
                 while !consume(TokenType::C_KET, tokens) {
                     let node = declaration_type(tokens);
+                    if node.ty.borrow().vla_len.is_some() {
+                        bad_token(
+                            &node.token.unwrap(),
+                            "variable length array in struct".to_string(),
+                        );
+                    }
                     members.insert(node.name, node.ty);
                 }
+                attribute_list(tokens, &mut attr);
+
+                // Complete the shared type so that pointers to it
+                // declared earlier see the members.
+                let mut ty_tmp = def.borrow_mut();
                 ty_tmp.members = Some(members);
-                ty_tmp.fix_struct_offsets();
-                ty = Some(ty_tmp);
+                ty_tmp.fix_struct_offsets(attr.is_packed);
+                if ty_tmp.align < attr.align {
+                    ty_tmp.align = attr.align;
+                    ty_tmp.size = roundup(ty_tmp.size, ty_tmp.align);
+                }
             }
 
-            if tag.is_none() && ty.clone().unwrap().members.is_none() {
+            if tag.is_none() && def.borrow().members.is_none() {
                 bad_token(t, "bad struct definition".to_string());
             }
 
-            if tag.is_some() {
-                let ty_tmp = ty.clone().unwrap();
-                env_tags_put(tag.unwrap(), ty_tmp.clone());
-            }
-
-            return ty.unwrap();
+            let ty = def.borrow().clone();
+            return ty;
         }
         _ => {
             bad_token(&t, "typename expected".to_string());
@@ -707,7 +1259,7 @@ fn new_deref(t: Option<Box<Token>>, var: Rc<RefCell<Var>>) -> Rc<RefCell<Node>>
     return new_expr(NodeType::DEREF, t.clone(), new_varref(t, var));
 }
 
-pub fn new_int_node(val: i32, t: Option<Box<Token>>) -> Rc<RefCell<Node>> {
+pub fn new_int_node(val: i64, t: Option<Box<Token>>) -> Rc<RefCell<Node>> {
     let mut node = new_node(NodeType::NUM, t);
     node.ty = Rc::new(RefCell::new(int_ty()));
     node.val = val;
@@ -723,7 +1275,15 @@ fn ident(tokens: &Vec<Token>) -> String {
 }
 
 fn string_literal(t: &Token) -> Rc<RefCell<Node>> {
-    let ty = ary_of(char_ty(), t.str_cnt.len() as i32);
+    // char16_t and char32_t are unsigned, and wchar_t is int.
+    let mut elem = match t.enc {
+        Encoding::CHAR | Encoding::UTF8 => char_ty(),
+        Encoding::UTF16 => short_ty(),
+        Encoding::UTF32 | Encoding::WCHAR => int_ty(),
+    };
+    elem.is_unsigned = t.enc == Encoding::UTF16 || t.enc == Encoding::UTF32;
+    let len = t.str_cnt.chars().count() / unit_size(t.enc);
+    let ty = ary_of(elem, len as i32);
     let name = format!(".L.str{}", bump_nlabel()).to_string();
 
     let mut node = new_node(NodeType::VARREF, Some(Box::new(t.clone())));
@@ -739,6 +1299,7 @@ fn local_variable(t: &Token) -> Rc<RefCell<Node>> {
     }
     let mut node = new_node(NodeType::VARREF, Some(Box::new(t.clone())));
     let v = var.clone().unwrap();
+    v.borrow_mut().is_used = true;
     node.ty = Rc::new(RefCell::new(v.borrow().ty.clone()));
     node.name = t.name.clone();
     node.var = Some(var.unwrap());
@@ -756,6 +1317,7 @@ fn function_call(t: &Token, tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
         let v = var.clone().unwrap();
         if v.borrow().ty.ty == CType::FUNC {
             node.ty = Rc::new(RefCell::new(v.borrow().ty.clone()));
+            node.var = Some(v.clone());
 
             should_init = false;
         }
@@ -774,6 +1336,129 @@ fn function_call(t: &Token, tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     return Rc::new(RefCell::new(node));
 }
 
+// Builtin functions are compiled to their own node types instead of
+// calls. Builtins which take a type operand, or which only inspect
+// their operand, are folded to constants by the parser.
+#[derive(Clone, Debug, PartialEq)]
+enum Builtin {
+    Node(NodeType, usize), // Node type and the number of arguments
+    Offsetof,
+    TypesCompatibleP,
+    ConstantP,
+}
+
+// Memory order arguments of atomic builtins are evaluated but
+// ignored; every atomic operation is sequentially consistent.
+fn builtin(name: &str) -> Option<Builtin> {
+    let b = match name {
+        "__builtin_alloca" => Builtin::Node(NodeType::ALLOCA, 1),
+        "__builtin_expect" => Builtin::Node(NodeType::EXPECT, 2),
+        "__builtin_unreachable" => Builtin::Node(NodeType::UNREACHABLE, 0),
+        "__builtin_trap" => Builtin::Node(NodeType::TRAP, 0),
+        "__builtin_clz" | "__builtin_clzl" | "__builtin_clzll" => Builtin::Node(NodeType::CLZ, 1),
+        "__builtin_ctz" | "__builtin_ctzl" | "__builtin_ctzll" => Builtin::Node(NodeType::CTZ, 1),
+        "__builtin_popcount" | "__builtin_popcountl" | "__builtin_popcountll" => {
+            Builtin::Node(NodeType::POPCOUNT, 1)
+        }
+        "__builtin_offsetof" => Builtin::Offsetof,
+        "__builtin_types_compatible_p" => Builtin::TypesCompatibleP,
+        "__builtin_constant_p" => Builtin::ConstantP,
+        "__atomic_load_n" => Builtin::Node(NodeType::ATOMIC_LOAD, 2),
+        "__atomic_store_n" => Builtin::Node(NodeType::ATOMIC_STORE, 3),
+        "__atomic_fetch_add" => Builtin::Node(NodeType::ATOMIC_FETCH_ADD, 3),
+        "__atomic_compare_exchange_n" => Builtin::Node(NodeType::ATOMIC_CAS, 6),
+        "__sync_synchronize" => Builtin::Node(NodeType::FENCE, 0),
+        _ => return None,
+    };
+    return Some(b);
+}
+
+fn builtin_call(t: &Token, b: Builtin, tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
+    let tok = Some(Box::new(t.clone()));
+    match b {
+        Builtin::Node(op, nargs) => {
+            if op == NodeType::ALLOCA {
+                vla_scopes_set_alloca();
+            }
+            let mut node = new_node(op, tok);
+            node.name = t.name.clone();
+            while !consume(TokenType::KET, tokens) {
+                if node.args.len() > 0 {
+                    expect(TokenType::COMMA, tokens);
+                }
+                node.args.push(assign(tokens));
+            }
+            if node.args.len() != nargs {
+                bad_token(t, format!("wrong number of arguments to '{}'", t.name));
+            }
+            return Rc::new(RefCell::new(node));
+        }
+        Builtin::Offsetof => {
+            let ty = type_name(tokens);
+            expect(TokenType::COMMA, tokens);
+            let offset = offsetof(ty, tokens);
+            expect(TokenType::KET, tokens);
+            let node = new_int_node(offset as i64, tok);
+            let mut ty = long_ty();
+            ty.is_unsigned = true;
+            node.borrow_mut().ty = Rc::new(RefCell::new(ty));
+            return node;
+        }
+        Builtin::TypesCompatibleP => {
+            let x = type_name(tokens);
+            expect(TokenType::COMMA, tokens);
+            let y = type_name(tokens);
+            expect(TokenType::KET, tokens);
+            let val = same_type(Rc::new(RefCell::new(x)), Rc::new(RefCell::new(y)));
+            return new_int_node(val as i64, tok);
+        }
+        Builtin::ConstantP => {
+            // The operand is never evaluated.
+            let node = assign(tokens);
+            expect(TokenType::KET, tokens);
+            return new_int_node(eval(&node).is_some() as i64, tok);
+        }
+    }
+}
+
+// Computes the offset of a member designator such as `a.b[2].c`.
+fn offsetof(ty: Type, tokens: &Vec<Token>) -> i32 {
+    let mut ty = ty;
+    let mut offset = 0;
+    loop {
+        let t = &tokens[bump_pos()];
+        if t.ty != TokenType::IDENT {
+            bad_token(t, "member name expected".to_string());
+        }
+        if ty.ty != CType::STRUCT || ty.members.is_none() {
+            bad_token(
+                t,
+                format!("offsetof of incomplete or non-struct type: {:?}", ty),
+            );
+        }
+        let member = ty.members.as_ref().unwrap().get(&t.name).cloned();
+        if member.is_none() {
+            bad_token(t, format!("member missing: {}", t.name));
+        }
+        ty = member.unwrap().borrow().clone();
+        offset += ty.offset;
+
+        while consume(TokenType::S_BRA, tokens) {
+            if ty.ty != CType::ARY {
+                bad_token(t, "subscripted value is not an array".to_string());
+            }
+            let idx = const_expr(tokens);
+            expect(TokenType::S_KET, tokens);
+            ty = *ty.ary_of.unwrap();
+            offset += ty.size * idx as i32;
+        }
+
+        if !consume(TokenType::DOT, tokens) {
+            return offset;
+        }
+    }
+}
+
 fn stmt_expr(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     let t = &tokens[pos()];
     let mut v = Vec::new();
@@ -802,6 +1487,32 @@ fn stmt_expr(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     return Rc::new(RefCell::new(node));
 }
 
+// _Generic ( assignment-expression , generic-assoc-list )
+//
+// The association is chosen by sema once the type of the
+// controlling expression is known.
+fn generic_selection(t: &Token, tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
+    let mut node = new_node(NodeType::GENERIC, Some(Box::new(t.clone())));
+    expect(TokenType::BRA, tokens);
+    node.expr = Some(assign(tokens));
+
+    while consume(TokenType::COMMA, tokens) {
+        let t = &tokens[pos()];
+        let ty = if consume(TokenType::DEFAULT, tokens) {
+            if node.assocs.iter().any(|a| a.0.is_none()) {
+                bad_token(t, "duplicate default generic association".to_string());
+            }
+            None
+        } else {
+            Some(type_name(tokens))
+        };
+        expect(TokenType::COLON, tokens);
+        node.assocs.push((ty, assign(tokens)));
+    }
+    expect(TokenType::KET, tokens);
+    return Rc::new(RefCell::new(node));
+}
+
 fn primary(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     let t = &tokens[bump_pos()];
 
@@ -815,14 +1526,27 @@ fn primary(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     }
 
     if t.ty == TokenType::NUM {
-        return new_int_node(t.val, Some(Box::new(t.clone())));
+        let node = new_int_node(t.val, Some(Box::new(t.clone())));
+        let mut ty = if t.is_long { long_ty() } else { int_ty() };
+        ty.is_unsigned = t.is_unsigned;
+        node.borrow_mut().ty = Rc::new(RefCell::new(ty));
+        return node;
     }
 
     if t.ty == TokenType::STR {
         return string_literal(t);
     }
 
+    if t.ty == TokenType::GENERIC {
+        return generic_selection(t, tokens);
+    }
+
     if t.ty == TokenType::IDENT {
+        if let Some(b) = builtin(&t.name) {
+            if consume(TokenType::BRA, tokens) {
+                return builtin_call(t, b, tokens);
+            }
+        }
         if consume(TokenType::BRA, tokens) {
             return function_call(t, tokens);
         }
@@ -852,28 +1576,171 @@ fn new_stmt_expr(t: Option<Box<Token>>, es: Vec<Rc<RefCell<Node>>>) -> Rc<RefCel
     return Rc::new(RefCell::new(node));
 }
 
-// `x++` where x is of type T is compiled as
-// `({ T *y = &x; T z = *y; *y = *y + 1; *z; })`.
-fn new_post_inc(t: Option<Box<Token>>, e: Rc<RefCell<Node>>, imm: i32) -> Rc<RefCell<Node>> {
+// `x op= y` where x is an atomic object of type T is compiled to a
+// compare-and-swap loop
+//
+//   ({ T *z = &x; T old = *z; T new;
+//      do { new = old op y; }
+//      while (!__atomic_compare_exchange_n(z, &old, new, 0, 5, 5));
+//      new; })
+//
+// which evaluates to `old` instead for postfix operators. An operand
+// with side effects is evaluated only once before the loop.
+fn new_atomic_op(
+    op: NodeType,
+    t: Option<Box<Token>>,
+    lhs: Rc<RefCell<Node>>,
+    rhs: Rc<RefCell<Node>>,
+    is_post: bool,
+) -> Rc<RefCell<Node>> {
     let mut v = Vec::new();
 
-    let var1 = add_lvar(ptr_to(e.borrow().ty.clone()), "tmp".to_string());
-    let e_ty = e.borrow().ty.clone();
-    let var2 = add_lvar(e_ty.borrow().clone(), "tmp".to_string());
+    let mut ty = lhs.borrow().ty.borrow().clone();
+    ty.is_atomic = false;
+    let z = add_lvar(ptr_to(lhs.borrow().ty.clone()), "tmp".to_string());
+    let old = add_lvar(ty.clone(), "tmp".to_string());
+    let new = add_lvar(ty.clone(), "tmp".to_string());
 
     v.push(new_binop(
         NodeType::EQL,
         t.clone(),
-        new_varref(t.clone(), var1.clone()),
-        new_expr(NodeType::ADDR, t.clone(), e.clone()),
+        new_varref(t.clone(), z.clone()),
+        new_expr(NodeType::ADDR, t.clone(), lhs),
     ));
+
+    let mut rhs = rhs;
+    let rhs_op = rhs.borrow().op.clone();
+    if rhs_op != NodeType::NUM && rhs_op != NodeType::VARREF {
+        let tmp_ty = if ty.ty == CType::PTR { long_ty() } else { ty };
+        let tmp = add_lvar(tmp_ty, "tmp".to_string());
+        v.push(new_binop(
+            NodeType::EQL,
+            t.clone(),
+            new_varref(t.clone(), tmp.clone()),
+            rhs,
+        ));
+        rhs = new_varref(t.clone(), tmp);
+    }
+
     v.push(new_binop(
         NodeType::EQL,
         t.clone(),
-        new_varref(t.clone(), var2.clone()),
-        new_deref(t.clone(), var1.clone()),
+        new_varref(t.clone(), old.clone()),
+        new_deref(t.clone(), z.clone()),
     ));
-    v.push(new_binop(
+
+    let mut cas = new_node(NodeType::ATOMIC_CAS, t.clone());
+    cas.name = "__atomic_compare_exchange_n".to_string();
+    cas.args = vec![
+        new_varref(t.clone(), z),
+        new_expr(
+            NodeType::ADDR,
+            t.clone(),
+            new_varref(t.clone(), old.clone()),
+        ),
+        new_varref(t.clone(), new.clone()),
+        new_int_node(0, t.clone()),
+        new_int_node(5, t.clone()),
+        new_int_node(5, t.clone()),
+    ];
+
+    let mut node = new_node(NodeType::DO_WHILE, t.clone());
+    node.body = Some(new_expr(
+        NodeType::EXPR_STMT,
+        t.clone(),
+        new_binop(
+            NodeType::EQL,
+            t.clone(),
+            new_varref(t.clone(), new.clone()),
+            new_binop(op, t.clone(), new_varref(t.clone(), old.clone()), rhs),
+        ),
+    ));
+    node.cond = Some(new_expr(
+        NodeType::EXCLAM,
+        t.clone(),
+        Rc::new(RefCell::new(cas)),
+    ));
+
+    let mut stmt_expr = new_node(NodeType::STMT_EXPR, t.clone());
+    for e in v.iter() {
+        stmt_expr
+            .stmts
+            .push(new_expr(NodeType::EXPR_STMT, t.clone(), e.clone()));
+    }
+    stmt_expr.stmts.push(Rc::new(RefCell::new(node)));
+
+    let result = if is_post { old } else { new };
+    stmt_expr.expr = Some(new_varref(t, result));
+    return Rc::new(RefCell::new(stmt_expr));
+}
+
+// Returns the variable if a given node refers to one by name. Reading
+// and writing it again has no side effects, so it can be done without
+// taking its address, which would keep it from being promoted.
+fn named_var(node: &Rc<RefCell<Node>>) -> Option<Rc<RefCell<Var>>> {
+    if node.borrow().op == NodeType::VARREF {
+        return node.borrow().var.clone();
+    }
+    return None;
+}
+
+// `x++` where x is of type T is compiled as
+// `({ T *y = &x; T z = *y; *y = *y + 1; *z; })`, or as
+// `({ T z = x; x = x + 1; z; })` if x is a variable.
+fn new_post_inc(t: Option<Box<Token>>, e: Rc<RefCell<Node>>, imm: i32) -> Rc<RefCell<Node>> {
+    if e.borrow().ty.borrow().is_atomic {
+        return new_atomic_op(
+            NodeType::ADD,
+            t.clone(),
+            e,
+            new_int_node(imm as i64, t),
+            true,
+        );
+    }
+
+    let mut v = Vec::new();
+
+    if let Some(var) = named_var(&e) {
+        let e_ty = e.borrow().ty.clone();
+        let old = add_lvar(e_ty.borrow().clone(), "tmp".to_string());
+        v.push(new_binop(
+            NodeType::EQL,
+            t.clone(),
+            new_varref(t.clone(), old.clone()),
+            e,
+        ));
+        v.push(new_binop(
+            NodeType::EQL,
+            t.clone(),
+            new_varref(t.clone(), var.clone()),
+            new_binop(
+                NodeType::ADD,
+                t.clone(),
+                new_varref(t.clone(), var),
+                new_int_node(imm as i64, t.clone()),
+            ),
+        ));
+        v.push(new_varref(t.clone(), old));
+        return new_stmt_expr(t, v);
+    }
+
+    let var1 = add_lvar(ptr_to(e.borrow().ty.clone()), "tmp".to_string());
+    let e_ty = e.borrow().ty.clone();
+    let var2 = add_lvar(e_ty.borrow().clone(), "tmp".to_string());
+
+    v.push(new_binop(
+        NodeType::EQL,
+        t.clone(),
+        new_varref(t.clone(), var1.clone()),
+        new_expr(NodeType::ADDR, t.clone(), e.clone()),
+    ));
+    v.push(new_binop(
+        NodeType::EQL,
+        t.clone(),
+        new_varref(t.clone(), var2.clone()),
+        new_deref(t.clone(), var1.clone()),
+    ));
+    v.push(new_binop(
         NodeType::EQL,
         t.clone(),
         new_deref(t.clone(), var1.clone()),
@@ -881,7 +1748,7 @@ fn new_post_inc(t: Option<Box<Token>>, e: Rc<RefCell<Node>>, imm: i32) -> Rc<Ref
             NodeType::ADD,
             t.clone(),
             new_deref(t.clone(), var1),
-            new_int_node(imm, t.clone()),
+            new_int_node(imm as i64, t.clone()),
         ),
     ));
     v.push(new_varref(t.clone(), var2));
@@ -962,10 +1829,24 @@ fn unary(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
         return new_expr(NodeType::NOT, Some(Box::new(t.clone())), unary(tokens));
     }
     if consume(TokenType::SIZEOF, tokens) {
-        return new_int_node(get_type(unary(tokens)).size, Some(Box::new(t.clone())));
+        let ty = get_type(unary(tokens));
+        if is_incomplete_struct(&ty) {
+            bad_token(
+                t,
+                "invalid application of 'sizeof' to incomplete type".to_string(),
+            );
+        }
+        return type_size_node(&ty, Some(Box::new(t.clone())));
     }
     if consume(TokenType::ALIGNOF, tokens) {
-        return new_int_node(get_type(unary(tokens)).align, Some(Box::new(t.clone())));
+        let ty = get_type(unary(tokens));
+        if is_incomplete_struct(&ty) {
+            bad_token(
+                t,
+                "invalid application of '_Alignof' to incomplete type".to_string(),
+            );
+        }
+        return new_int_node(ty.align as i64, Some(Box::new(t.clone())));
     }
 
     if consume(TokenType::INC, tokens) {
@@ -1153,19 +2034,29 @@ fn conditional(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     node.cond = Some(cond);
     node.then = Some(expr(tokens));
     expect(TokenType::COLON, tokens);
-    node.els = Some(expr(tokens));
+    node.els = Some(conditional(tokens));
     return Rc::new(RefCell::new(node));
 }
 
 // `x op= y` where x is of type T is compiled as
-// `({T *z = &x; *z = *z op y; })`.
+// `({T *z = &x; *z = *z op y; })`, or as `x = x op y` if x is a
+// variable.
 fn new_assign_eq(
     op: NodeType,
     lhs: Rc<RefCell<Node>>,
     rhs: Rc<RefCell<Node>>,
 ) -> Rc<RefCell<Node>> {
-    let mut v = Vec::new();
     let t = lhs.borrow().token.clone();
+    if lhs.borrow().ty.borrow().is_atomic {
+        return new_atomic_op(op, t, lhs, rhs, false);
+    }
+
+    if let Some(var) = named_var(&lhs) {
+        let val = new_binop(op, t.clone(), new_varref(t.clone(), var), rhs);
+        return new_binop(NodeType::EQL, t, lhs, val);
+    }
+
+    let mut v = Vec::new();
 
     // T *z = &x;
     let var = add_lvar(ptr_to(lhs.borrow().ty.clone()), "tmp".to_string());
@@ -1236,13 +2127,410 @@ fn expr(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     );
 }
 
-fn const_expr(tokens: &Vec<Token>) -> i32 {
+// Evaluates a given node as a constant expression. Returns None
+// if the node is not a constant.
+fn eval(node: &Rc<RefCell<Node>>) -> Option<i64> {
+    return eval_typed(node).map(|(val, _)| val);
+}
+
+// Truncates a value to the size of a given integer type and extends
+// it back to 64 bits.
+fn truncate(val: i64, ty: &Type) -> i64 {
+    if ty.size >= 8 {
+        return val;
+    }
+    let bits = 64 - ty.size * 8;
+    if ty.is_unsigned {
+        return ((val << bits) as u64 >> bits) as i64;
+    }
+    return (val << bits) >> bits;
+}
+
+// Evaluates a constant expression in its type. Operands undergo the
+// usual arithmetic conversions, and the result of each operation is
+// truncated to its type, as they would be at runtime.
+fn eval_typed(node: &Rc<RefCell<Node>>) -> Option<(i64, Type)> {
+    let n = node.borrow();
+    let op = n.op.clone();
+    match op {
+        NodeType::NUM => {
+            let ty = promote(&n.ty);
+            return Some((truncate(n.val, &ty), ty));
+        }
+        NodeType::EXCLAM => {
+            let (x, _) = eval_typed(n.expr.as_ref().unwrap())?;
+            return Some(((x == 0) as i64, int_ty()));
+        }
+        NodeType::NOT => {
+            let (x, ty) = eval_typed(n.expr.as_ref().unwrap())?;
+            return Some((truncate(!x, &ty), ty));
+        }
+        NodeType::QUEST => {
+            let (cond, _) = eval_typed(n.cond.as_ref().unwrap())?;
+            let then = eval_typed(n.then.as_ref().unwrap());
+            let els = eval_typed(n.els.as_ref().unwrap());
+            let (val, ty) = if cond != 0 {
+                then.clone()?
+            } else {
+                els.clone()?
+            };
+            return match (then, els) {
+                (Some((_, t)), Some((_, e))) => {
+                    let ty = common_ty(t, e);
+                    Some((truncate(val, &ty), ty))
+                }
+                _ => Some((val, ty)),
+            };
+        }
+        NodeType::LOGAND => {
+            if eval_typed(n.lhs.as_ref().unwrap())?.0 == 0 {
+                return Some((0, int_ty()));
+            }
+            let (x, _) = eval_typed(n.rhs.as_ref().unwrap())?;
+            return Some(((x != 0) as i64, int_ty()));
+        }
+        NodeType::LOGOR => {
+            if eval_typed(n.lhs.as_ref().unwrap())?.0 != 0 {
+                return Some((1, int_ty()));
+            }
+            let (x, _) = eval_typed(n.rhs.as_ref().unwrap())?;
+            return Some(((x != 0) as i64, int_ty()));
+        }
+        NodeType::COMMA => {
+            eval_typed(n.lhs.as_ref().unwrap())?;
+            return eval_typed(n.rhs.as_ref().unwrap());
+        }
+        _ => {}
+    }
+
+    if n.lhs.is_none() || n.rhs.is_none() {
+        return None;
+    }
+    let (lhs, lty) = eval_typed(n.lhs.as_ref().unwrap())?;
+    let (rhs, rty) = eval_typed(n.rhs.as_ref().unwrap())?;
+
+    // The result of a shift has the type of the left operand.
+    match op {
+        NodeType::SHL => return Some((truncate(lhs.wrapping_shl(rhs as u32), &lty), lty)),
+        NodeType::SHR if lty.is_unsigned => {
+            return Some(((lhs as u64).wrapping_shr(rhs as u32) as i64, lty));
+        }
+        NodeType::SHR => return Some((lhs.wrapping_shr(rhs as u32), lty)),
+        _ => {}
+    }
+
+    let ty = common_ty(lty, rty);
+    let lhs = truncate(lhs, &ty);
+    let rhs = truncate(rhs, &ty);
+    let (l, r) = (lhs as u64, rhs as u64);
+    let val = match op {
+        NodeType::ADD => lhs.wrapping_add(rhs),
+        NodeType::SUB => lhs.wrapping_sub(rhs),
+        NodeType::MUL => lhs.wrapping_mul(rhs),
+        NodeType::DIV if ty.is_unsigned => l.checked_div(r)? as i64,
+        NodeType::DIV => lhs.checked_div(rhs)?,
+        NodeType::MOD if ty.is_unsigned => l.checked_rem(r)? as i64,
+        NodeType::MOD => lhs.checked_rem(rhs)?,
+        NodeType::AND => lhs & rhs,
+        NodeType::OR => lhs | rhs,
+        NodeType::XOR => lhs ^ rhs,
+        NodeType::EQ => return Some(((lhs == rhs) as i64, int_ty())),
+        NodeType::NE => return Some(((lhs != rhs) as i64, int_ty())),
+        NodeType::LT if ty.is_unsigned => return Some(((l < r) as i64, int_ty())),
+        NodeType::LT => return Some(((lhs < rhs) as i64, int_ty())),
+        NodeType::LE if ty.is_unsigned => return Some(((l <= r) as i64, int_ty())),
+        NodeType::LE => return Some(((lhs <= rhs) as i64, int_ty())),
+        _ => return None,
+    };
+    return Some((truncate(val, &ty), ty));
+}
+
+fn const_expr(tokens: &Vec<Token>) -> i64 {
+    let t = &tokens[pos()];
+    let node = conditional(tokens);
+    match eval(&node) {
+        Some(val) => val,
+        None => {
+            bad_token(t, "constant expression expected".to_string());
+            panic!();
+        }
+    }
+}
+
+// _Static_assert ( constant-expression , string-literal ) ;
+fn static_assert(tokens: &Vec<Token>) {
+    expect(TokenType::BRA, tokens);
+    let t = &tokens[pos()];
+    let val = const_expr(tokens);
+    expect(TokenType::COMMA, tokens);
+    let msg = &tokens[bump_pos()];
+    if msg.ty != TokenType::STR {
+        bad_token(msg, "string literal expected".to_string());
+    }
+    expect(TokenType::KET, tokens);
+    expect(TokenType::SEMI_COLON, tokens);
+
+    if val == 0 {
+        let s = msg.string();
+        bad_token(t, format!("static assertion failed: {}", s));
+    }
+}
+
+fn string(tokens: &Vec<Token>) -> (Token, String) {
+    let t = &tokens[bump_pos()];
+    if t.ty != TokenType::STR {
+        bad_token(t, "string literal expected".to_string());
+    }
+    return (t.clone(), t.string());
+}
+
+fn is_register_name(name: &str) -> bool {
+    let name = name.trim_start_matches('%');
+    for r in ["ax", "bx", "cx", "dx", "si", "di", "bp", "sp"].iter() {
+        if name == *r || name == format!("e{}", r) || name == format!("r{}", r) {
+            return true;
+        }
+    }
+    for r in ["al", "bl", "cl", "dl", "sil", "dil", "bpl", "spl"].iter() {
+        if name == *r {
+            return true;
+        }
+    }
+    for i in 8..16 {
+        for suffix in ["", "d", "w", "b"].iter() {
+            if name == format!("r{}{}", i, suffix) {
+                return true;
+            }
+        }
+    }
+    return false;
+}
+
+// [ "[" ident "]" ] string-literal "(" expr ")"
+fn asm_operand(tokens: &Vec<Token>, is_output: bool) -> AsmOperand {
+    let mut name = String::new();
+    if consume(TokenType::S_BRA, tokens) {
+        name = ident(tokens);
+        expect(TokenType::S_KET, tokens);
+    }
+
+    let (t, constraint) = string(tokens);
+    expect(TokenType::BRA, tokens);
+    let e = expr(tokens);
+    expect(TokenType::KET, tokens);
+
+    let mut op = AsmOperand {
+        name: name,
+        constraint: constraint.clone(),
+        kind: AsmKind::REG,
+        is_output: is_output,
+        is_inout: false,
+        is_earlyclobber: false,
+        expr: e,
+        val: 0,
+    };
+
+    let mut letters = String::new();
+    let mut has_eq = false;
+    for c in constraint.chars() {
+        match c {
+            '=' => has_eq = true,
+            '+' => op.is_inout = true,
+            '&' => op.is_earlyclobber = true,
+            _ => letters.push(c),
+        }
+    }
+
+    if is_output && !has_eq && !op.is_inout {
+        bad_token(&t, "output operand constraint lacks '='".to_string());
+    }
+    if !is_output && (has_eq || op.is_inout || op.is_earlyclobber) {
+        bad_token(
+            &t,
+            "input operand constraint contains '=', '+' or '&'".to_string(),
+        );
+    }
+
+    // Prefer an immediate, then a register, then memory.
+    let val = eval(&op.expr);
+    let fixed = letters.chars().find(|c| "acdSD".contains(*c));
+    if !is_output && val.is_some() && letters.contains(|c| "ing".contains(c)) {
+        op.kind = AsmKind::IMM;
+        op.val = val.unwrap();
+    } else if letters.contains(|c| "rqg".contains(c)) {
+        op.kind = AsmKind::REG;
+    } else if fixed.is_some() {
+        op.kind = AsmKind::FIXED(fixed.unwrap());
+    } else if letters.contains('m') {
+        op.kind = AsmKind::MEM;
+    } else if letters.contains(|c| "in".contains(c)) {
+        bad_token(
+            &t,
+            "impossible constraint in asm: not a constant".to_string(),
+        );
+    } else {
+        bad_token(
+            &t,
+            format!("unsupported constraint '{}' in asm", constraint),
+        );
+    }
+    return op;
+}
+
+fn asm_operands(tokens: &Vec<Token>, asm: &mut Asm, is_output: bool) {
     let t = &tokens[pos()];
-    let node = expr(tokens);
-    if node.borrow().op != NodeType::NUM {
-        bad_token(t, "constant expression expected".to_string());
+    if t.ty == TokenType::COLON || t.ty == TokenType::KET {
+        return;
+    }
+    asm.operands.push(asm_operand(tokens, is_output));
+    while consume(TokenType::COMMA, tokens) {
+        asm.operands.push(asm_operand(tokens, is_output));
+    }
+}
+
+fn asm_clobbers(tokens: &Vec<Token>, asm: &mut Asm) {
+    if tokens[pos()].ty == TokenType::KET {
+        return;
+    }
+    loop {
+        let (t, name) = string(tokens);
+        if name != "memory" && name != "cc" && !is_register_name(&name) {
+            bad_token(&t, format!("unknown register name '{}' in asm", name));
+        }
+        asm.clobbers.push(name);
+        if !consume(TokenType::COMMA, tokens) {
+            return;
+        }
+    }
+}
+
+// Splits an extended asm template into text and operand references.
+fn asm_template(t: &Token, s: &String, asm: &Asm) -> Vec<AsmPiece> {
+    let mut v = Vec::new();
+    let mut text = String::new();
+    let chars: Vec<char> = s.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] != '%' {
+            text.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        i += 1;
+        if i < chars.len() && chars[i] == '%' {
+            text.push('%');
+            i += 1;
+            continue;
+        }
+
+        if !text.is_empty() {
+            v.push(AsmPiece::TEXT(text));
+            text = String::new();
+        }
+
+        if i < chars.len() && chars[i] == '=' {
+            v.push(AsmPiece::UNIQUE);
+            i += 1;
+            continue;
+        }
+
+        // Size modifier: %b0 (8 bits), %w0 (16), %k0 (32) or %q0 (64).
+        // %c0 prints a constant as is.
+        let mut modifier = None;
+        if i + 1 < chars.len()
+            && "bwkqc".contains(chars[i])
+            && (chars[i + 1].is_ascii_digit() || chars[i + 1] == '[')
+        {
+            modifier = Some(chars[i]);
+            i += 1;
+        }
+
+        let idx;
+        if i < chars.len() && chars[i].is_ascii_digit() {
+            let mut n = 0;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                n = n * 10 + chars[i].to_digit(10).unwrap() as usize;
+                i += 1;
+            }
+            idx = n;
+        } else if i < chars.len() && chars[i] == '[' {
+            let mut name = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != ']' {
+                name.push(chars[i]);
+                i += 1;
+            }
+            i += 1;
+            match asm.operands.iter().position(|op| op.name == name) {
+                Some(n) => idx = n,
+                None => {
+                    bad_token(t, format!("undefined named operand '{}' in asm", name));
+                    panic!();
+                }
+            }
+        } else {
+            bad_token(t, "invalid '%' in asm template".to_string());
+            panic!();
+        }
+
+        if idx >= asm.operands.len() {
+            bad_token(t, "operand number out of range in asm".to_string());
+        }
+        v.push(AsmPiece::OPERAND(idx, modifier));
+    }
+
+    if !text.is_empty() {
+        v.push(AsmPiece::TEXT(text));
+    }
+    return v;
+}
+
+// asm-statement:
+//   "asm" qualifier* "(" string-literal
+//         [ ":" outputs [ ":" inputs [ ":" clobbers ] ] ] ")"
+//
+// An asm without operands is a basic asm. Its template is emitted
+// as is, without '%' substitution.
+fn asm_stmt(tokens: &Vec<Token>) -> Asm {
+    loop {
+        if consume(TokenType::VOLATILE, tokens) || consume(TokenType::INLINE, tokens) {
+            continue;
+        }
+        let t = &tokens[pos()];
+        if t.ty == TokenType::IDENT && t.name == "goto" {
+            bad_token(t, "asm goto is not supported".to_string());
+        }
+        break;
     }
-    return node.borrow().val;
+
+    expect(TokenType::BRA, tokens);
+    let (t, template) = string(tokens);
+
+    let mut asm = Asm {
+        template: Vec::new(),
+        operands: Vec::new(),
+        clobbers: Vec::new(),
+    };
+
+    if !consume(TokenType::COLON, tokens) {
+        expect(TokenType::KET, tokens);
+        asm.template.push(AsmPiece::TEXT(template));
+        return asm;
+    }
+
+    asm_operands(tokens, &mut asm, true);
+    if consume(TokenType::COLON, tokens) {
+        asm_operands(tokens, &mut asm, false);
+        if consume(TokenType::COLON, tokens) {
+            asm_clobbers(tokens, &mut asm);
+        }
+    }
+    expect(TokenType::KET, tokens);
+
+    asm.template = asm_template(&t, &template, &asm);
+    return asm;
 }
 
 fn read_array<'a>(ty: &'a mut Type, tokens: &Vec<Token>) -> &'a mut Type {
@@ -1250,20 +2538,65 @@ fn read_array<'a>(ty: &'a mut Type, tokens: &Vec<Token>) -> &'a mut Type {
 
     while consume(TokenType::S_BRA, tokens) {
         if consume(TokenType::S_KET, tokens) {
-            v.push(-1);
+            v.push((-1, None));
             continue;
         }
 
-        v.push(const_expr(tokens));
+        // A length which is not a constant makes a variable-length array.
+        let len = conditional(tokens);
+        match eval(&len) {
+            Some(val) => v.push((val, None)),
+            None => v.push((-1, Some(len))),
+        }
         expect(TokenType::S_KET, tokens);
     }
-    for len in v.iter().rev() {
-        *ty = ary_of(ty.clone(), *len);
+
+    // An array of variable-length arrays is variable-length as well.
+    // Sizes are computed where the declaration is, in the order from
+    // the innermost array.
+    for (len, e) in v.into_iter().rev() {
+        let is_vla = e.is_some() || ty.vla_len.is_some();
+        let base = ty.clone();
+        *ty = ary_of(ty.clone(), len as i32);
+        if !is_vla {
+            continue;
+        }
+
+        let len = e.unwrap_or_else(|| new_int_node(len, None));
+        let var = add_lvar(int_ty(), ".vla_size".to_string());
+        ty.size = 8;
+        ty.vla_len = Some(len.clone());
+        ty.vla_size = Some(var.clone());
+
+        let t = len.borrow().token.clone();
+        let size = new_binop(
+            NodeType::MUL,
+            t.clone(),
+            len,
+            type_size_node(&base, t.clone()),
+        );
+        let expr = new_binop(NodeType::EQL, t.clone(), new_varref(t.clone(), var), size);
+        vla_stmts_push(new_expr(NodeType::EXPR_STMT, t, expr));
     }
     return ty;
 }
 
-fn direct_decl(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>) -> Node {
+// Returns a node for the size of a given type, which is evaluated
+// at runtime for a variable-length array.
+pub fn type_size_node(ty: &Type, t: Option<Box<Token>>) -> Rc<RefCell<Node>> {
+    match ty.vla_size {
+        Some(ref var) => new_varref(t, var.clone()),
+        None => new_int_node(ty.size as i64, t),
+    }
+}
+
+fn new_comp_stmt(t: Option<Box<Token>>, stmts: Vec<Rc<RefCell<Node>>>) -> Rc<RefCell<Node>> {
+    let mut node = new_node(NodeType::COMP_STMT, t);
+    node.stmts = stmts;
+    return Rc::new(RefCell::new(node));
+}
+
+fn direct_decl(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>, attr: &mut VarAttr) -> Node {
     let t = &tokens[pos()];
     let mut node = alloc_node();
     let node_ty = Rc::new(RefCell::new(alloc_type()));
@@ -1274,7 +2607,7 @@ fn direct_decl(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>) -> Node {
         node.ty = node_ty;
         node.name = ident(tokens);
     } else if consume(TokenType::BRA, tokens) {
-        node = declarator(node_ty, tokens);
+        node = declarator(node_ty, tokens, attr);
         expect(TokenType::KET, tokens);
     } else {
         bad_token(t, "bad direct-declarator".to_string());
@@ -1282,6 +2615,7 @@ fn direct_decl(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>) -> Node {
 
     // Read the second half of type name (e.g. `[3][5]`)
     *placeholder.borrow_mut() = read_array(&mut *ty.borrow_mut(), tokens).clone();
+    attribute_list(tokens, attr);
 
     // Read an initializer.
     if consume(TokenType::EQL, tokens) {
@@ -1290,29 +2624,84 @@ fn direct_decl(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>) -> Node {
     return node;
 }
 
-fn declarator(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>) -> Node {
+fn declarator(ty: Rc<RefCell<Type>>, tokens: &Vec<Token>, attr: &mut VarAttr) -> Node {
     let mut t = ty;
     while consume(TokenType::MUL, tokens) {
-        t = Rc::new(RefCell::new(ptr_to(t)));
+        t = Rc::new(RefCell::new(pointer(t, tokens)));
     }
-    return direct_decl(t, tokens);
+    return direct_decl(t, tokens, attr);
+}
+
+// Reads a type name without a declarator name, as in `int *[3]`.
+fn type_name(tokens: &Vec<Token>) -> Type {
+    let mut ty = decl_specifiers(tokens, &mut alloc_var_attr());
+    while consume(TokenType::MUL, tokens) {
+        ty = pointer(Rc::new(RefCell::new(ty)), tokens);
+    }
+    read_array(&mut ty, tokens);
+    return ty;
 }
 
 fn declaration_type(tokens: &Vec<Token>) -> Node {
-    let ty = decl_specifiers(tokens);
-    let node = declarator(Rc::new(RefCell::new(ty)), tokens);
+    let mut attr = alloc_var_attr();
+    let ty = decl_specifiers(tokens, &mut attr);
+    let node = declarator(Rc::new(RefCell::new(ty)), tokens, &mut attr);
     expect(TokenType::SEMI_COLON, tokens);
+    if attr.is_packed {
+        node.ty.borrow_mut().align = 1;
+    }
+    if node.ty.borrow().align < attr.align {
+        node.ty.borrow_mut().align = attr.align;
+    }
     return node;
 }
 
 fn declaration(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
-    let ty = decl_specifiers(tokens);
-    let mut node = declarator(Rc::new(RefCell::new(ty)), tokens);
+    let t = &tokens[pos()];
+    let mut attr = alloc_var_attr();
+    let ty = decl_specifiers(tokens, &mut attr);
+    if attr.is_thread_local {
+        bad_token(
+            t,
+            "_Thread_local at block scope is not supported".to_string(),
+        );
+    }
+    if consume(TokenType::SEMI_COLON, tokens) {
+        return null_stmt();
+    }
+    let mut node = declarator(Rc::new(RefCell::new(ty)), tokens, &mut attr);
     expect(TokenType::SEMI_COLON, tokens);
+    if is_incomplete_struct(&node.ty.borrow()) {
+        bad_token(t, format!("variable '{}' has incomplete type", node.name));
+    }
     let var = add_lvar(node.ty.borrow().clone(), node.name);
+    var.borrow_mut().align = attr.align;
+    var.borrow_mut().is_unused = attr.is_unused;
+    var.borrow_mut().token = node.token.clone();
+
+    // Variable-length arrays are allocated when the declaration
+    // is reached.
+    let mut stmts = take_vla_stmts();
+    let ty = var.borrow().ty.clone();
+    if ty.vla_len.is_some() {
+        if node.init.is_some() {
+            bad_token(
+                t,
+                "variable-sized object may not be initialized".to_string(),
+            );
+        }
+        let mut alloc = new_node(NodeType::VLA_ALLOC, node.token.clone());
+        alloc.var = Some(var.clone());
+        alloc.expr = Some(type_size_node(&ty, node.token.clone()));
+        stmts.push(Rc::new(RefCell::new(alloc)));
+        vla_scopes_set();
+    }
 
     if node.init.is_none() {
-        return null_stmt();
+        if stmts.is_empty() {
+            return null_stmt();
+        }
+        return new_comp_stmt(node.token, stmts);
     }
 
     // Convert `T var = init` to `T var; var = init`.
@@ -1322,18 +2711,27 @@ fn declaration(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     node.init = None;
 
     let expr = new_binop(NodeType::EQL, t.clone(), lhs, rhs);
-    return new_expr(NodeType::EXPR_STMT, t, expr);
+    let init = new_expr(NodeType::EXPR_STMT, t.clone(), expr);
+    if stmts.is_empty() {
+        return init;
+    }
+    stmts.push(init);
+    return new_comp_stmt(t, stmts);
 }
 
 fn param_declaration(tokens: &Vec<Token>) -> Rc<RefCell<Var>> {
-    let mut ty = decl_specifiers(tokens);
-    let node = declarator(Rc::new(RefCell::new(ty)), tokens);
+    let mut attr = alloc_var_attr();
+    let mut ty = decl_specifiers(tokens, &mut attr);
+    let node = declarator(Rc::new(RefCell::new(ty)), tokens, &mut attr);
     ty = node.ty.borrow().clone();
     if ty.ty == CType::ARY {
         let ary_of = ty.clone().ary_of;
         ty = ptr_to(Rc::new(RefCell::new(*ary_of.clone().unwrap())));
     }
-    return add_lvar(ty, node.name);
+    let var = add_lvar(ty, node.name);
+    var.borrow_mut().is_unused = attr.is_unused;
+    var.borrow_mut().token = node.token.clone();
+    return var;
 }
 
 fn expr_stmt(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
@@ -1351,7 +2749,12 @@ pub fn stmt(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
             let node = declaration_type(tokens);
             assert!(node.name.len() > 0);
             env_typedefs_put(node.name, node.ty.borrow().clone());
-            return null_stmt();
+
+            let stmts = take_vla_stmts();
+            if stmts.is_empty() {
+                return null_stmt();
+            }
+            return new_comp_stmt(node.token, stmts);
         }
         TokenType::IF => {
             let mut node = new_node(NodeType::IF, Some(Box::new(t.clone())));
@@ -1493,6 +2896,16 @@ pub fn stmt(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
         TokenType::C_BRA => {
             return compound_stmt(tokens);
         }
+        TokenType::STATIC_ASSERT => {
+            static_assert(tokens);
+            return null_stmt();
+        }
+        TokenType::ASM => {
+            let mut node = new_node(NodeType::ASM, Some(Box::new(t.clone())));
+            node.asm = Some(Box::new(asm_stmt(tokens)));
+            expect(TokenType::SEMI_COLON, tokens);
+            return Rc::new(RefCell::new(node));
+        }
         TokenType::SEMI_COLON => {
             return null_stmt();
         }
@@ -1511,23 +2924,55 @@ pub fn compound_stmt(tokens: &Vec<Token>) -> Rc<RefCell<Node>> {
     let mut node = new_node(NodeType::COMP_STMT, Some(Box::new(t.clone())));
 
     env_push();
+    vla_scopes_push();
+    scope_vars_push();
     while !consume(TokenType::C_KET, tokens) {
         node.stmts.push(stmt(tokens));
     }
+    node.scope_vars = scope_vars_pop();
+    node.has_vla = vla_scopes_pop();
     env_pop();
 
     return Rc::new(RefCell::new(node));
 }
 
 fn toplevel(tokens: &Vec<Token>) {
+    if consume(TokenType::STATIC_ASSERT, tokens) {
+        static_assert(tokens);
+        return;
+    }
+
+    let t = &tokens[pos()];
+    if consume(TokenType::ASM, tokens) {
+        let asm = asm_stmt(tokens);
+        expect(TokenType::SEMI_COLON, tokens);
+        if !asm.operands.is_empty() || !asm.clobbers.is_empty() {
+            bad_token(
+                t,
+                "extended asm is not allowed outside a function".to_string(),
+            );
+        }
+        for piece in asm.template.iter() {
+            if let AsmPiece::TEXT(ref s) = *piece {
+                prog_asm_push(s.clone());
+            }
+        }
+        return;
+    }
+
     let is_typedef = consume(TokenType::TYPEDEF, tokens);
     let is_extern = consume(TokenType::EXTERN, tokens);
 
-    let mut ty = decl_specifiers(tokens);
+    let mut attr = alloc_var_attr();
+    let mut ty = decl_specifiers(tokens, &mut attr);
+    if consume(TokenType::SEMI_COLON, tokens) {
+        return;
+    }
     while consume(TokenType::MUL, tokens) {
         ty = ptr_to(Rc::new(RefCell::new(ty)));
     }
 
+    let name_t = &tokens[pos()];
     let name = ident(tokens);
 
     // Function
@@ -1542,6 +2987,20 @@ fn toplevel(tokens: &Vec<Token>) {
             }
             params.push(param_declaration(tokens));
         }
+        let vla_stmts = take_vla_stmts();
+        attribute_list(tokens, &mut attr);
+
+        // Attributes given to earlier declarations are inherited.
+        if let Some(prev) = find_var(&name) {
+            let prev = prev.borrow();
+            if prev.ty.ty == CType::FUNC {
+                attr.is_noreturn |= prev.ty.is_noreturn;
+                attr.is_weak |= prev.is_weak;
+                if attr.section.is_none() {
+                    attr.section = prev.section.clone();
+                }
+            }
+        }
 
         let t = &tokens[pos()];
         let node = Rc::new(RefCell::new(new_node(
@@ -1559,10 +3018,14 @@ fn toplevel(tokens: &Vec<Token>) {
         let mut node_ty = alloc_type();
         node_ty.ty = CType::FUNC;
         node_ty.returning = Some(Box::new(ty));
+        node_ty.is_noreturn = attr.is_noreturn;
         node.borrow_mut().ty = Rc::new(RefCell::new(node_ty));
 
         let ty = node.borrow().ty.clone();
-        add_lvar(ty.borrow().clone(), name.clone());
+        let var = add_lvar(ty.borrow().clone(), name.clone());
+        var.borrow_mut().section = attr.section.clone();
+        var.borrow_mut().is_weak = attr.is_weak;
+        var.borrow_mut().is_used = true;
 
         if consume(TokenType::SEMI_COLON, tokens) {
             return;
@@ -1574,28 +3037,190 @@ fn toplevel(tokens: &Vec<Token>) {
         if is_typedef {
             bad_token(t, format!("typedef has function definition"));
         }
-        node.borrow_mut().body = Some(compound_stmt(tokens));
+        let body = compound_stmt(tokens);
+
+        // Sizes of variable-length array parameters are computed
+        // on function entry.
+        let mut stmts = vla_stmts;
+        stmts.append(&mut body.borrow_mut().stmts);
+        body.borrow_mut().stmts = stmts;
+        node.borrow_mut().body = Some(body);
+
+        if options().warn_unused {
+            warn_unused_vars();
+        }
 
         prog_funcs_push(Rc::new(RefCell::new(Function {
             name: name,
             node: node,
             lvars: lvars(),
             bbs: Vec::new(),
+            stacksize: 0,
+            section: attr.section,
+            is_weak: attr.is_weak,
+            is_inline: attr.is_inline,
+            is_always_inline: attr.is_always_inline,
+            is_noinline: attr.is_noinline,
         })));
         return;
     }
 
     let mut ty_tmp = ty.clone();
     let ty = read_array(&mut ty_tmp, tokens);
+    attribute_list(tokens, &mut attr);
+
+    let mut data = None;
+    if consume(TokenType::EQL, tokens) {
+        let t = &tokens[pos()];
+        if is_typedef {
+            bad_token(t, format!("typedef '{}' is initialized", name));
+        }
+        data = Some(global_data(t, ty, assign(tokens)));
+    }
     expect(TokenType::SEMI_COLON, tokens);
 
     if is_typedef {
-        env_typedefs_put(name, ty.clone());
+        let mut ty = ty.clone();
+        if ty.align < attr.align {
+            ty.align = attr.align;
+        }
+        env_typedefs_put(name, ty);
         return;
     }
 
+    if ty.vla_len.is_some() {
+        bad_token(
+            t,
+            "variable length array declared at file scope".to_string(),
+        );
+    }
+    take_vla_stmts();
+
     // Global variable
-    add_gvar(ty.clone(), name, None, is_extern);
+    let is_extern = is_extern && data.is_none();
+    if !is_extern && is_incomplete_struct(ty) {
+        bad_token(name_t, format!("variable '{}' has incomplete type", name));
+    }
+    if let Some(prev) = find_var(&name) {
+        redeclare_gvar(name_t, &prev, ty, data, is_extern, &attr);
+        return;
+    }
+
+    let var = add_gvar(ty.clone(), name, data, is_extern);
+    var.borrow_mut().is_thread_local = attr.is_thread_local;
+    var.borrow_mut().align = attr.align;
+    var.borrow_mut().section = attr.section;
+    var.borrow_mut().is_weak = attr.is_weak;
+    var.borrow_mut().is_unused = attr.is_unused;
+}
+
+// Returns the initial contents of a global variable of a scalar type.
+// Integers are stored in little endian.
+fn global_data(t: &Token, ty: &Type, init: Rc<RefCell<Node>>) -> String {
+    let val = match eval(&init) {
+        Some(val) => val,
+        None => {
+            bad_token(t, "initializer element is not constant".to_string());
+            panic!();
+        }
+    };
+    let val = match ty.ty {
+        CType::BOOL => (val != 0) as i64,
+        CType::CHAR | CType::SHORT | CType::INT | CType::LONG | CType::PTR => val,
+        _ => {
+            bad_token(t, "unsupported initializer".to_string());
+            panic!();
+        }
+    };
+
+    let mut data = String::new();
+    for i in 0..ty.size {
+        data.push(char::from((val >> (i * 8)) as u8));
+    }
+    return data;
+}
+
+fn is_compatible_redecl(x: &Type, y: &Type) -> bool {
+    // An array of unknown size is completed by a later declaration.
+    if x.ty == CType::ARY && y.ty == CType::ARY && (x.len < 0 || y.len < 0) {
+        let xbase = x.ary_of.clone().unwrap();
+        let ybase = y.ary_of.clone().unwrap();
+        return same_type(Rc::new(RefCell::new(*xbase)), Rc::new(RefCell::new(*ybase)));
+    }
+    return same_type(
+        Rc::new(RefCell::new(x.clone())),
+        Rc::new(RefCell::new(y.clone())),
+    );
+}
+
+// C11 6.9.2: a file-scope declaration without an initializer is a
+// tentative definition. All declarations of a global variable refer to
+// the same object, and at most one of them may have an initializer.
+// A variable which has no initializer at the end of the translation
+// unit is emitted as a common symbol or zero-initialized.
+fn redeclare_gvar(
+    t: &Token,
+    var: &Rc<RefCell<Var>>,
+    ty: &Type,
+    data: Option<String>,
+    is_extern: bool,
+    attr: &VarAttr,
+) {
+    let mut v = var.borrow_mut();
+    if v.is_local || v.ty.ty == CType::FUNC {
+        bad_token(
+            t,
+            format!("'{}' redeclared as different kind of symbol", v.name),
+        );
+    }
+    if !is_compatible_redecl(&v.ty, ty) {
+        bad_token(t, format!("conflicting types for '{}'", v.name));
+    }
+    if v.is_thread_local != attr.is_thread_local {
+        bad_token(
+            t,
+            format!("conflicting thread-local storage for '{}'", v.name),
+        );
+    }
+    if v.ty.ty == CType::ARY && v.ty.len < 0 {
+        v.ty = ty.clone();
+    }
+
+    if data.is_some() {
+        if v.data.is_some() {
+            bad_token(t, format!("redefinition of '{}'", v.name));
+        }
+        v.data = data;
+    }
+
+    if v.align < attr.align {
+        v.align = attr.align;
+    }
+    if attr.section.is_some() {
+        v.section = attr.section.clone();
+    }
+    v.is_weak |= attr.is_weak;
+    v.is_unused |= attr.is_unused;
+
+    if v.is_extern && !is_extern {
+        v.is_extern = false;
+        prog_gvars_push(var.clone());
+    }
+}
+
+// Warns about local variables which are never referenced,
+// unless they are marked `__attribute__((unused))`.
+fn warn_unused_vars() {
+    for var in lvars().iter() {
+        let v = var.borrow();
+        if v.is_used || v.is_unused || v.token.is_none() {
+            continue;
+        }
+        warn_token!(
+            v.token.clone().unwrap(),
+            format!("unused variable '{}'", v.name)
+        );
+    }
 }
 
 fn is_eof(tokens: &Vec<Token>) -> bool {
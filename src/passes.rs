@@ -0,0 +1,239 @@
+// Pass manager
+//
+// The IR goes through the passes below in order. Calls are inlined
+// first, so that the inlined code is optimized along with the caller.
+// Then local variables are promoted to registers in SSA form, which the
+// other optimizations work on. The rest of the passes lower the IR for
+// gen_x86 and always run.
+//
+// Which optimizations run depends on the optimization level. -O1 runs
+// the ones within a function except for LICM and GVN, and -O2, the
+// default, runs all of them. -Os is -O2 with a smaller inlining budget.
+// -fno-<pass> turns off an optimization on top of that.
+//
+// The IR can be dumped to stderr before or after a pass with
+// -dump-before=<pass> and -dump-after=<pass>, and checked after each
+// pass by verify.rs, so that a miscompilation can be narrowed down to
+// the pass causing it. The verifier always runs in debug builds, and
+// -verify-each turns it on in release builds.
+
+#![allow(non_upper_case_globals)]
+
+use crate::dce::*;
+use crate::dom::*;
+use crate::gvn::*;
+use crate::inline::*;
+use crate::instcombine::*;
+use crate::irdump::*;
+use crate::licm::*;
+use crate::liveness::*;
+use crate::mem2reg::*;
+use crate::parse::*;
+use crate::regalloc::*;
+use crate::sccp::*;
+use crate::slots::*;
+use crate::ssa::*;
+use crate::tailcall::*;
+use crate::util::*;
+use crate::verify::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// The lowest optimization level at which a pass runs
+#[derive(Clone, Copy, PartialEq)]
+enum Level {
+    Always,
+    O1,
+    O2,
+}
+
+enum Run {
+    Program(fn(&mut Program)),
+    Function(fn(&Rc<RefCell<Function>>)),
+    // Runs on the instructions of each function in gen_x86
+    Machine,
+}
+
+struct Pass {
+    name: &'static str,
+    level: Level,
+    run: Run,
+}
+
+// gen_ir leaves code after jumps and unreachable BBs, which building the
+// CFG removes.
+fn cfg(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+}
+
+// Promoted variables are not valid until renamed by to_ssa.
+fn promote(fun: &Rc<RefCell<Function>>) {
+    mem2reg(fun);
+    to_ssa(fun);
+}
+
+const passes: [Pass; 16] = [
+    Pass {
+        name: "cfg",
+        level: Level::Always,
+        run: Run::Function(cfg),
+    },
+    Pass {
+        name: "inline",
+        level: Level::O2,
+        run: Run::Program(inline_functions),
+    },
+    Pass {
+        name: "mem2reg",
+        level: Level::O1,
+        run: Run::Function(promote),
+    },
+    Pass {
+        name: "sccp",
+        level: Level::O1,
+        run: Run::Function(sccp),
+    },
+    Pass {
+        name: "instcombine",
+        level: Level::O1,
+        run: Run::Function(instcombine),
+    },
+    Pass {
+        name: "licm",
+        level: Level::O2,
+        run: Run::Function(licm),
+    },
+    Pass {
+        name: "gvn",
+        level: Level::O2,
+        run: Run::Function(gvn),
+    },
+    Pass {
+        name: "dce",
+        level: Level::O1,
+        run: Run::Function(dce),
+    },
+    Pass {
+        name: "simplify-cfg",
+        level: Level::O1,
+        run: Run::Function(simplify_cfg),
+    },
+    Pass {
+        name: "tail-calls",
+        level: Level::O1,
+        run: Run::Function(tail_calls),
+    },
+    // Stack slots are marked in SSA form.
+    Pass {
+        name: "mark-slots",
+        level: Level::Always,
+        run: Run::Program(mark_slots),
+    },
+    Pass {
+        name: "out-of-ssa",
+        level: Level::Always,
+        run: Run::Program(out_of_ssa),
+    },
+    Pass {
+        name: "liveness",
+        level: Level::Always,
+        run: Run::Program(liveness),
+    },
+    Pass {
+        name: "regalloc",
+        level: Level::Always,
+        run: Run::Program(alloc_regs),
+    },
+    Pass {
+        name: "stack-slots",
+        level: Level::Always,
+        run: Run::Program(alloc_slots),
+    },
+    Pass {
+        name: "peephole",
+        level: Level::O1,
+        run: Run::Machine,
+    },
+];
+
+fn find_pass(name: &str) -> Option<&'static Pass> {
+    return passes.iter().find(|p| p.name == name);
+}
+
+// Returns true if a pass can be turned off with -fno-<name>.
+pub fn is_optional_pass(name: &str) -> bool {
+    match find_pass(name) {
+        Some(p) => p.level != Level::Always,
+        None => false,
+    }
+}
+
+// Returns true if the IR can be dumped around a pass.
+pub fn is_ir_pass(name: &str) -> bool {
+    match find_pass(name) {
+        Some(p) => match p.run {
+            Run::Machine => false,
+            _ => true,
+        },
+        None => false,
+    }
+}
+
+fn enabled(pass: &Pass, opts: &Options) -> bool {
+    if opts.disabled_passes.iter().any(|name| name == pass.name) {
+        return false;
+    }
+    match pass.level {
+        Level::Always => true,
+        Level::O1 => opts.opt_level != OptLevel::O0,
+        Level::O2 => opts.opt_level == OptLevel::O2 || opts.opt_level == OptLevel::Os,
+    }
+}
+
+pub fn is_enabled(name: &str) -> bool {
+    return enabled(find_pass(name).unwrap(), &options());
+}
+
+fn dump(prog: &Program, when: &str, pass: &str) {
+    eprintln!("*** IR dump {} {} ***", when, pass);
+    dump_ir(prog.funcs.clone());
+}
+
+pub fn run_passes(prog: &mut Program) {
+    let opts = options();
+    let verify_each = opts.verify_each || cfg!(debug_assertions);
+    let mut form = Form::SSA;
+
+    for pass in passes.iter() {
+        if !enabled(pass, &opts) {
+            continue;
+        }
+
+        let name = pass.name.to_string();
+        if opts.dump_before.contains(&name) {
+            dump(prog, "before", pass.name);
+        }
+
+        match pass.run {
+            Run::Program(f) => f(prog),
+            Run::Function(f) => {
+                for fun in prog.funcs.iter() {
+                    f(fun);
+                }
+            }
+            Run::Machine => continue,
+        }
+
+        match pass.name {
+            "out-of-ssa" => form = Form::Lowered,
+            "regalloc" => form = Form::Allocated,
+            _ => (),
+        }
+        if verify_each {
+            verify(prog, pass.name, form);
+        }
+        if opts.dump_after.contains(&name) {
+            dump(prog, "after", pass.name);
+        }
+    }
+}
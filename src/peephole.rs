@@ -0,0 +1,401 @@
+// Peephole optimization
+//
+// gen_x86 emits a fixed sequence of instructions for each IR, which
+// leaves redundant code at the boundaries between them. This pass
+// rewrites the instructions of a function until none of these patterns
+// is left:
+//
+//  setCC r8; movzb r, r8; cmp r, 0; jne L  ->  setCC r8; movzb r, r8; jCC L
+//  jCC L1; jmp L2; L1:                     ->  jNCC L2; L1:
+//  jmp L; L:                               ->  L:
+//  mov r, r                                ->  (removed)
+//  mov a, b; mov b, a                      ->  mov a, b
+//  mov rax, a; imul b; mov b, rax          ->  imul b, a
+//
+// setCC and movzb leave the flags as they are, so the jump can test the
+// flags of the comparison directly. The value computed by them is often
+// not needed anymore after that. Such an instruction writing a dead
+// register is removed if it has no other effects. Registers live at
+// each instruction are computed by a backward dataflow analysis over
+// the instructions, in which anything unknown, e.g. inline assembly,
+// reads all registers.
+
+use crate::insn::*;
+use std::collections::HashMap;
+
+const ALL: u32 = 0xffff;
+
+fn bit(name: &str) -> u32 {
+    match gpr(name) {
+        Some((i, _)) => 1 << i,
+        None => 0,
+    }
+}
+
+fn bits(names: &[&str]) -> u32 {
+    return names.iter().map(|name| bit(name)).fold(0, |a, b| a | b);
+}
+
+fn arg_regs() -> u32 {
+    return bits(&["rdi", "rsi", "rdx", "rcx", "r8", "r9"]);
+}
+
+// Registers a function has to preserve for its caller
+fn callee_saved() -> u32 {
+    return bits(&["rbx", "rsp", "rbp", "r12", "r13", "r14", "r15"]);
+}
+
+// Registers used in a memory operand or text
+fn regs_in(s: &str) -> u32 {
+    return s
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .map(bit)
+        .fold(0, |a, b| a | b);
+}
+
+fn uses_of(arg: &Operand) -> u32 {
+    match arg {
+        Operand::Reg(name) => bit(name),
+        Operand::Mem(s) => regs_in(s),
+        _ => 0,
+    }
+}
+
+// Returns the mnemonic and the operands of an instruction.
+fn parts(insn: &Insn) -> Option<(&str, &Vec<Operand>)> {
+    match insn {
+        Insn::Op(op, args) => Some((op.as_str(), args)),
+        _ => None,
+    }
+}
+
+fn is_jcc(op: &str) -> bool {
+    return op.starts_with('j') && op != "jmp";
+}
+
+// Instructions whose first operand is only written
+fn is_write_only(op: &str, args: &Vec<Operand>) -> bool {
+    match op {
+        "mov" | "movzb" | "movzx" | "movsx" | "movsxd" | "movabs" | "lea" | "lzcnt" | "tzcnt"
+        | "popcnt" | "pop" => true,
+        "imul" => args.len() == 3,
+        _ => op.starts_with("set"),
+    }
+}
+
+// Instructions which have no effect other than writing the first
+// operand. A load is not, since it may be volatile.
+fn is_pure(op: &str, args: &Vec<Operand>) -> bool {
+    match op {
+        "mov" | "movzb" | "movzx" | "movsx" | "movsxd" | "movabs" => {
+            args.iter().all(|a| !is_mem(a))
+        }
+        "lea" => true,
+        _ => op.starts_with("set"),
+    }
+}
+
+fn is_mem(arg: &Operand) -> bool {
+    match arg {
+        Operand::Mem(_) => true,
+        _ => false,
+    }
+}
+
+// Returns the registers read and the registers overwritten as a whole
+// by an instruction.
+fn effects(insn: &Insn) -> (u32, u32) {
+    let (op, args) = match insn {
+        Insn::Label(_) => return (0, 0),
+        Insn::Raw(_) => return (ALL, 0),
+        Insn::Op(op, args) => (op.as_str(), args),
+    };
+
+    let mut uses = 0;
+    let mut defs = 0;
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 || !is_write_only(op, args) {
+            uses |= uses_of(arg);
+            continue;
+        }
+        // A write to an 8- or 16-bit register keeps the other bits.
+        match arg {
+            Operand::Reg(name) if gpr(name).unwrap().1 <= 1 => defs |= bit(name),
+            _ => uses |= uses_of(arg),
+        }
+    }
+
+    let rax = bit("rax");
+    let rdx = bit("rdx");
+    match op {
+        "imul" | "mul" | "idiv" | "div" if args.len() == 1 => {
+            uses |= rax | rdx;
+            defs |= rax | rdx;
+        }
+        "add" | "sub" | "and" | "or" | "xor" | "shl" | "shr" | "sar" | "cmp" | "test" => (),
+        "imul" | "bsr" | "bsf" | "xchg" | "lock xadd" | "push" | "mfence" | "nop" | "ud2" => (),
+        "cqo" => {
+            uses |= rax;
+            defs |= rdx;
+        }
+        "lock cmpxchg" => uses |= rax,
+        "call" => {
+            uses |= arg_regs() | rax;
+            defs |= !callee_saved() & ALL;
+        }
+        "ret" => uses |= rax | callee_saved(),
+        "jmp" => match args[0] {
+            Operand::Sym(ref l) if l.starts_with(".L") => (),
+            // A tail call
+            Operand::Sym(_) => uses |= arg_regs() | rax | callee_saved(),
+            _ => uses = ALL,
+        },
+        _ if is_jcc(op) || is_write_only(op, args) => (),
+        _ => uses = ALL,
+    }
+    return (uses, defs & !uses);
+}
+
+// Returns the label an instruction may jump to and whether it may fall
+// through to the next instruction.
+fn successors(insn: &Insn) -> (Option<&str>, bool) {
+    match parts(insn) {
+        Some(("ret", _)) | Some(("ud2", _)) => (None, false),
+        Some(("jmp", args)) => match args[0] {
+            Operand::Sym(ref l) if l.starts_with(".L") => (Some(l.as_str()), false),
+            _ => (None, false),
+        },
+        Some((op, args)) if is_jcc(op) => match args[0] {
+            Operand::Sym(ref l) => (Some(l.as_str()), true),
+            _ => (None, true),
+        },
+        _ => (None, true),
+    }
+}
+
+// Returns the registers live after each instruction.
+fn live_out(code: &Vec<Insn>) -> Vec<u32> {
+    let mut labels = HashMap::new();
+    for (i, insn) in code.iter().enumerate() {
+        if let Insn::Label(name) = insn {
+            labels.insert(name.as_str(), i);
+        }
+    }
+
+    let n = code.len();
+    let mut live_in = vec![0; n];
+    let mut out = vec![0; n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..n).rev() {
+            let (target, falls) = successors(&code[i]);
+            let mut live = 0;
+            if falls && i + 1 < n {
+                live |= live_in[i + 1];
+            }
+            if let Some(l) = target {
+                live |= match labels.get(l) {
+                    Some(&j) => live_in[j],
+                    None => ALL,
+                };
+            }
+            out[i] = live;
+
+            let (uses, defs) = effects(&code[i]);
+            let live = uses | (live & !defs);
+            if live != live_in[i] {
+                live_in[i] = live;
+                changed = true;
+            }
+        }
+    }
+    return out;
+}
+
+// Returns the number of a register if the operand is one.
+fn reg(arg: &Operand) -> Option<usize> {
+    match arg {
+        Operand::Reg(name) => Some(gpr(name).unwrap().0),
+        _ => None,
+    }
+}
+
+fn reg64(arg: &Operand) -> Option<usize> {
+    match arg {
+        Operand::Reg(name) if gpr(name).unwrap().1 == 0 => reg(arg),
+        _ => None,
+    }
+}
+
+fn negate(cc: &str) -> Option<&'static str> {
+    let pairs = [
+        ("e", "ne"),
+        ("l", "ge"),
+        ("le", "g"),
+        ("b", "ae"),
+        ("be", "a"),
+        ("s", "ns"),
+    ];
+    for &(a, b) in pairs.iter() {
+        if cc == a {
+            return Some(b);
+        }
+        if cc == b {
+            return Some(a);
+        }
+    }
+    return None;
+}
+
+// Returns the condition and the target of the jump if `w` begins with
+// "setCC r8; movzb r, r8; cmp r, 0; jne L".
+fn fused_branch(w: &[Insn]) -> Option<(String, Operand)> {
+    let (set, a) = parts(&w[0])?;
+    let (_, b) = parts(&w[1]).filter(|&(op, _)| op == "movzb")?;
+    let (_, c) = parts(&w[2]).filter(|&(op, _)| op == "cmp")?;
+    let (_, d) = parts(&w[3]).filter(|&(op, _)| op == "jne")?;
+    if !set.starts_with("set") || c[1] != Operand::Imm(0) {
+        return None;
+    }
+
+    let r = reg64(&b[0])?;
+    if reg(&a[0])? != r || reg(&b[1])? != r || reg64(&c[0])? != r {
+        return None;
+    }
+    return Some((format!("j{}", &set[3..]), d[0].clone()));
+}
+
+fn fuse_branches(code: &mut Vec<Insn>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 3 < code.len() {
+        if let Some((jcc, target)) = fused_branch(&code[i..i + 4]) {
+            code.splice(i + 2..i + 4, vec![Insn::Op(jcc, vec![target])]);
+            changed = true;
+        }
+        i += 1;
+    }
+    return changed;
+}
+
+// Returns true if the labels following the i-th instruction include the
+// target of a jump.
+fn falls_to(code: &Vec<Insn>, i: usize, target: &Operand) -> bool {
+    for insn in code[i + 1..].iter() {
+        match (insn, target) {
+            (Insn::Label(l), Operand::Sym(t)) if l == t => return true,
+            (Insn::Label(_), _) => (),
+            _ => return false,
+        }
+    }
+    return false;
+}
+
+fn remove_jumps(code: &mut Vec<Insn>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        let jump = match parts(&code[i]) {
+            Some((op, args)) if op == "jmp" || is_jcc(op) => Some(args[0].clone()),
+            _ => None,
+        };
+        if let Some(target) = jump {
+            if falls_to(code, i, &target) {
+                code.remove(i);
+                changed = true;
+                continue;
+            }
+        }
+
+        // Swap the targets of a conditional jump over a jump.
+        if i + 1 < code.len() {
+            if let (Some((jcc, a)), Some(("jmp", b))) = (parts(&code[i]), parts(&code[i + 1])) {
+                let inverse = if is_jcc(jcc) { negate(&jcc[1..]) } else { None };
+                if let Some(cc) = inverse.filter(|_| falls_to(code, i + 1, &a[0])) {
+                    code[i] = Insn::Op(format!("j{}", cc), b.clone());
+                    code.remove(i + 1);
+                    changed = true;
+                }
+            }
+        }
+        i += 1;
+    }
+    return changed;
+}
+
+// Returns `imul b, a` if `w` is "mov rax, a; imul b; mov b, rax".
+fn two_operand_imul(w: &[Insn]) -> Option<Insn> {
+    let (_, a) = parts(&w[0]).filter(|&(op, _)| op == "mov")?;
+    let (_, b) = parts(&w[1]).filter(|&(op, _)| op == "imul")?;
+    let (_, c) = parts(&w[2]).filter(|&(op, _)| op == "mov")?;
+    if a[0] != Operand::Reg("rax".to_string()) || c[1] != a[0] || b.len() != 1 || c[0] != b[0] {
+        return None;
+    }
+    reg64(&b[0])?;
+    return Some(Insn::Op(
+        "imul".to_string(),
+        vec![b[0].clone(), a[1].clone()],
+    ));
+}
+
+fn remove_moves(code: &mut Vec<Insn>) -> bool {
+    let out = live_out(code);
+    let rax_rdx = bit("rax") | bit("rdx");
+    let sp_bp = bit("rsp") | bit("rbp");
+
+    let mut v: Vec<Insn> = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        if i + 2 < code.len() && out[i + 2] & rax_rdx == 0 {
+            if let Some(insn) = two_operand_imul(&code[i..i + 3]) {
+                v.push(insn);
+                i += 3;
+                continue;
+            }
+        }
+
+        let insn = &code[i];
+        i += 1;
+        let (op, args) = match parts(insn) {
+            Some(x) => x,
+            None => {
+                v.push(insn.clone());
+                continue;
+            }
+        };
+
+        if op == "mov" && reg64(&args[0]).is_some() && args[0] == args[1] {
+            continue;
+        }
+        // The second of two moves swapping registers changes nothing.
+        let is_mov = op == "mov" && reg64(&args[0]).is_some() && reg64(&args[1]).is_some();
+        if let Some(("mov", prev)) = v.last().and_then(parts).filter(|_| is_mov) {
+            if prev[0] == args[1] && prev[1] == args[0] {
+                continue;
+            }
+        }
+        if is_pure(op, args) {
+            let r = uses_of(&args[0]);
+            if reg(&args[0]).is_some() && r & sp_bp == 0 && r & out[i - 1] == 0 {
+                continue;
+            }
+        }
+        v.push(insn.clone());
+    }
+
+    let changed = v.len() != code.len();
+    *code = v;
+    return changed;
+}
+
+pub fn peephole(code: &mut Vec<Insn>) {
+    loop {
+        let mut changed = fuse_branches(code);
+        changed |= remove_jumps(code);
+        changed |= remove_moves(code);
+        if !changed {
+            break;
+        }
+    }
+}
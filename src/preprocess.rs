@@ -173,7 +173,7 @@ fn read_until_eol() -> Vec<Token> {
 fn new_int(tmpl: &Token, val: i32) -> Token {
     let mut t = tmpl.clone();
     t.ty = TokenType::NUM;
-    t.val = val;
+    t.val = val as i64;
     return t;
 }
 
@@ -188,7 +188,7 @@ fn new_string(tmpl: &Token, str_cnt: String, len: usize) -> Token {
 fn new_param(tmpl: &Token, val: i32) -> Token {
     let mut t = tmpl.clone();
     t.ty = TokenType::PARAM;
-    t.val = val;
+    t.val = val as i64;
     return t;
 }
 
@@ -385,7 +385,7 @@ fn define() {
 
 fn include() {
     let t = get(TokenType::STR, "string expected".to_string());
-    let path = t.str_cnt;
+    let path = t.string();
     get(TokenType::NEW_LINE, "newline expected".to_string());
     return append(&mut tokenize(path, false));
 }
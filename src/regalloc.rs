@@ -12,13 +12,24 @@
 // correct. As a heuristic, we spill a register whose last use is
 // furthest.
 //
-// We then insert load and store instructions for spilled registesr.
-// The last register (num_regs-1'th register) is reserved for that
-// purpose.
+// We then replace each access to a spilled register with a temporary
+// register, which is loaded from or stored to the stack right before
+// or after the access, and run the allocation again. The temporaries
+// are short-lived, so the allocation eventually succeeds.
+//
+// Registers clobbered by inline assembly are not assigned to values
+// that are live across it. Likewise, values live across a function call
+// are given callee-saved registers, so that nothing needs to be saved
+// around the call, and argument registers are not assigned to values
+// defined before all the parameters are read from them.
+//
+// With -fregalloc=graph, coloring.rs is used instead of the linear scan.
 
 #![allow(non_upper_case_globals)]
 
+use crate::coloring::*;
 use crate::gen_ir::*;
+use crate::liveness::*;
 use crate::parse::*;
 use crate::util::*;
 use crate::*;
@@ -63,15 +74,98 @@ fn set_last_use(r: Option<Rc<RefCell<Reg>>>, ic: i32) {
     }
 }
 
-fn collect_regs(fun: &Rc<RefCell<Function>>) -> Vec<Rc<RefCell<Reg>>> {
+// Returns the set of physical registers clobbered by an ASM, including
+// the ones its operands are moved to or from.
+pub fn clobber_mask(ir: &IR) -> u32 {
+    let mut mask = 0;
+    for name in ir.clobbers.iter() {
+        if let Some(i) = reg_index(name) {
+            mask |= 1 << i;
+        }
+    }
+    for arg in ir.asm_args.iter() {
+        if let AsmKind::FIXED(c) = arg.kind {
+            if let Some(i) = reg_index(fixed_reg(c, 8)) {
+                mask |= 1 << i;
+            }
+        }
+    }
+    return mask;
+}
+
+// Parameters are read from the argument registers at the beginning of
+// the entry BB. Returns the index of the last IR reading one, and the
+// set of the registers read.
+pub fn param_regs(fun: &Rc<RefCell<Function>>) -> Option<(usize, u32)> {
+    let ent = fun.borrow().bbs[0].clone();
+    let mut ret = None;
+    let mut mask = 0;
+    for (i, ir) in ent.borrow().ir.iter().enumerate() {
+        let ir = ir.borrow();
+        if ir.op == IRType::ARG || ir.op == IRType::STORE_ARG {
+            mask |= argreg_mask(ir.imm as usize);
+            ret = Some((i, mask));
+        }
+    }
+    return ret;
+}
+
+// A register defined at or before `to` and used at or after `from`
+// can't be assigned to the registers in `mask`.
+pub struct Clobber {
+    from: i32,
+    to: i32,
+    mask: u32,
+}
+
+fn reset_reg(r: &Rc<RefCell<Reg>>) {
+    let mut r = r.borrow_mut();
+    r.def = -1;
+    r.last_use = -1;
+    r.rn = -1;
+}
+
+// Clears the result of the previous allocation.
+pub fn reset_regs(fun: &Rc<RefCell<Function>>) {
+    for bb in fun.borrow().bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            for r in ir.r0.iter().chain(ir.outs.iter()) {
+                reset_reg(r);
+            }
+            for r in uses(&ir) {
+                reset_reg(&r);
+            }
+        }
+    }
+}
+
+// Collects registers in order of definition. Registers clobbered by
+// inline assembly and function calls are pushed to `clobbers` with
+// their positions.
+fn collect_regs(fun: &Rc<RefCell<Function>>, clobbers: &mut Vec<Clobber>) -> Vec<Rc<RefCell<Reg>>> {
     let mut v = Vec::new();
     let mut ic = 1; // instruction counter
 
+    // The entry BB comes first, so IR i in it is at i + 1.
+    if let Some((i, mask)) = param_regs(fun) {
+        clobbers.push(Clobber {
+            from: 0,
+            to: i as i32 + 1,
+            mask: mask,
+        });
+    }
+
     for bb in fun.borrow().bbs.iter() {
-        if bb.borrow().param.is_some() {
-            let param = bb.borrow().param.clone().unwrap();
-            param.borrow_mut().def = ic;
-            v.push(param);
+        // A BB may be placed before the definition of a register that is
+        // live into it, e.g. the increment of a loop. The live range
+        // starts there then.
+        let in_regs = bb.borrow().in_regs.clone();
+        for r in in_regs.borrow().iter() {
+            if r.borrow().def == -1 {
+                r.borrow_mut().def = ic;
+                v.push(r.clone());
+            }
         }
 
         for ir in bb.borrow().ir.iter() {
@@ -85,15 +179,17 @@ fn collect_regs(fun: &Rc<RefCell<Function>>) -> Vec<Rc<RefCell<Reg>>> {
                 }
             }
 
+            // A promoted variable may be assigned again after its last
+            // read. The register must stay reserved until then.
+            set_last_use(r0, ic);
+
             let r1 = ir.borrow().r1.clone();
             set_last_use(r1, ic);
             let r2 = ir.borrow().r2.clone();
             set_last_use(r2, ic);
-            let bbarg = ir.borrow().bbarg.clone();
-            set_last_use(bbarg, ic);
 
             let op = ir.borrow().op.clone();
-            if op == IRType::CALL {
+            if op == IRType::CALL || op == IRType::TAIL_CALL || op == IRType::ATOMIC_CAS {
                 let nargs = ir.borrow().nargs;
                 for i in 0..nargs {
                     let arg = ir.borrow().args[i].clone();
@@ -101,6 +197,38 @@ fn collect_regs(fun: &Rc<RefCell<Function>>) -> Vec<Rc<RefCell<Reg>>> {
                 }
             }
 
+            if op == IRType::ASM {
+                // An early-clobber output is written before inputs are
+                // consumed, so inputs must stay alive past the asm.
+                let ir = ir.borrow();
+                let early = ir.asm_args.iter().any(|a| a.is_earlyclobber);
+                for arg in ir.args.iter() {
+                    set_last_use(Some(arg.clone()), if early { ic + 1 } else { ic });
+                }
+                for r in ir.outs.iter() {
+                    if r.borrow().def == -1 {
+                        r.borrow_mut().def = ic;
+                        v.push(r.clone());
+                    }
+                    set_last_use(Some(r.clone()), ic);
+                }
+                clobbers.push(Clobber {
+                    from: ic,
+                    to: ic,
+                    mask: clobber_mask(&ir),
+                });
+            }
+
+            // Arguments and the result of a call may be in caller-saved
+            // registers.
+            if op == IRType::CALL {
+                clobbers.push(Clobber {
+                    from: ic + 1,
+                    to: ic - 1,
+                    mask: caller_saved_mask(),
+                });
+            }
+
             ic += 1;
         }
 
@@ -113,25 +241,64 @@ fn collect_regs(fun: &Rc<RefCell<Function>>) -> Vec<Rc<RefCell<Reg>>> {
     return v;
 }
 
-fn choose_to_spill(used: &Vec<Option<Rc<RefCell<Reg>>>>) -> usize {
-    let mut k = 0;
-    for i in 1..num_regs() {
-        let uk = used[k].clone().unwrap();
-        let ui = used[i].clone().unwrap();
-        if uk.borrow().last_use < ui.borrow().last_use {
-            k = i;
+// Returns the index of a register to be spilled among `used` and `r`
+// (`num_regs()` for `r` itself). As a heuristic, we spill a register
+// whose last use is furthest. Temporaries for spill code are spilled
+// only if there is no other choice, as spilling them again would not
+// make progress.
+fn choose_to_spill(used: &Vec<Option<Rc<RefCell<Reg>>>>, r: &Rc<RefCell<Reg>>, mask: u32) -> usize {
+    let mut cands: Vec<(usize, Rc<RefCell<Reg>>)> = Vec::new();
+    for i in 0..num_regs() {
+        if mask & (1 << i) == 0 {
+            cands.push((i, used[i].clone().unwrap()));
         }
     }
+    cands.push((num_regs(), r.clone()));
+
+    let is_temp = |r: &Rc<RefCell<Reg>>| r.borrow().var.is_some();
+    let mut k = None;
+    for (i, u) in cands.iter() {
+        let better = match k {
+            None => true,
+            Some((_, ref uk)) => {
+                if is_temp(uk) != is_temp(u) {
+                    is_temp(uk)
+                } else {
+                    uk.borrow().last_use < u.borrow().last_use
+                }
+            }
+        };
+        if better {
+            k = Some((*i, u.clone()));
+        }
+    }
+
+    let (k, uk) = k.unwrap();
+    if is_temp(&uk) {
+        panic!("too many registers are in use");
+    }
     return k;
 }
 
-// Allocate registers.
-fn scan(regs: &Vec<Rc<RefCell<Reg>>>) {
+// Allocate registers. Returns false if some registers are spilled.
+fn scan(regs: &Vec<Rc<RefCell<Reg>>>, clobbers: &Vec<Clobber>) -> bool {
     let mut used: Vec<Option<Rc<RefCell<Reg>>>> = vec![None; num_regs()];
+    let mut ok = true;
 
     for r in regs.iter() {
+        // Registers clobbered while `r` is alive
+        let mut mask = 0;
+        for c in clobbers.iter() {
+            if r.borrow().def <= c.to && c.from <= r.borrow().last_use {
+                mask |= c.mask;
+            }
+        }
+
         let mut found = false;
         for i in 0..num_regs() {
+            if mask & (1 << i) != 0 {
+                continue;
+            }
             if used[i].is_some() {
                 let u = used[i].clone().unwrap();
                 if r.borrow().def < u.borrow().last_use {
@@ -149,98 +316,140 @@ fn scan(regs: &Vec<Rc<RefCell<Reg>>>) {
             continue;
         }
 
-        used[num_regs() - 1] = Some(r.clone());
-        let k = choose_to_spill(&used);
+        ok = false;
+        let k = choose_to_spill(&used, r, mask);
+        if k == num_regs() {
+            r.borrow_mut().spill = true;
+            continue;
+        }
 
-        r.borrow_mut().rn = k as i32;
         let uk = used[k].clone().unwrap();
-        uk.borrow_mut().rn = (num_regs() - 1) as i32;
         uk.borrow_mut().spill = true;
+        r.borrow_mut().rn = k as i32;
         used[k] = Some(r.clone());
     }
+    return ok;
 }
 
-fn spill_store(v: &mut Vec<Rc<RefCell<IR>>>, ir: &Rc<RefCell<IR>>) {
-    let r = ir.borrow().r0.clone();
-    if r.is_none() {
-        return;
-    }
-    let rr = r.clone().unwrap();
-    if !rr.borrow().spill {
-        return;
+fn new_spill(op: IRType, r: &Rc<RefCell<Reg>>) -> Rc<RefCell<IR>> {
+    let mut ir = alloc_ir();
+    ir.op = op;
+    if ir.op == IRType::LOAD_SPILL {
+        ir.r0 = Some(r.clone());
+    } else {
+        ir.r1 = Some(r.clone());
     }
-
-    let mut ir2 = alloc_ir();
-    ir2.op = IRType::STORE_SPILL;
-    ir2.r1 = r.clone();
-    ir2.var = rr.borrow().var.clone();
-    v.push(Rc::new(RefCell::new(ir2)));
-}
-
-fn spill_load(v: &mut Vec<Rc<RefCell<IR>>>, _ir: &Rc<RefCell<IR>>, r: Option<Rc<RefCell<Reg>>>) {
-    if r.is_none() {
-        return;
-    }
-    let rr = r.clone().unwrap();
-    if !rr.borrow().spill {
-        return;
-    }
-
-    let mut ir2 = alloc_ir();
-    ir2.op = IRType::LOAD_SPILL;
-    ir2.r0 = r.clone();
-    ir2.var = rr.borrow().var.clone();
-    v.push(Rc::new(RefCell::new(ir2)));
+    ir.var = r.borrow().var.clone();
+    return Rc::new(RefCell::new(ir));
 }
 
+// Replaces each spilled register in an IR with a new temporary,
+// which is loaded from the stack before the IR if the IR reads it, and
+// stored back after the IR if the IR writes it.
 fn emit_spill_code(bb: &Rc<RefCell<BB>>) {
     let mut v: Vec<Rc<RefCell<IR>>> = Vec::new();
 
     let irs = bb.borrow().ir.clone();
     for ir in irs.iter() {
-        let r1 = ir.borrow().r1.clone();
-        spill_load(&mut v, ir, r1);
-        let r2 = ir.borrow().r2.clone();
-        spill_load(&mut v, ir, r2);
-        let bbarg = ir.borrow().bbarg.clone();
-        spill_load(&mut v, ir, bbarg);
-        v.push(ir.clone());
-        spill_store(&mut v, ir);
-    }
+        let mut ir = ir.borrow().clone();
 
+        let mut defs = Vec::new();
+        for r in ir.r0.iter().chain(ir.outs.iter()) {
+            defs.push(r.borrow().vn);
+        }
+        let reads = uses(&ir);
+
+        let mut temps: Vec<(i32, Rc<RefCell<Reg>>)> = Vec::new();
+        let mut stores = Vec::new();
+        {
+            let mut temp = |r: &Rc<RefCell<Reg>>| {
+                if !r.borrow().spill {
+                    return r.clone();
+                }
+                let vn = r.borrow().vn;
+                if let Some((_, t)) = temps.iter().find(|(n, _)| *n == vn) {
+                    return t.clone();
+                }
+
+                let t = new_reg();
+                t.borrow_mut().var = r.borrow().var.clone();
+                if reads.iter().any(|r| r.borrow().vn == vn) {
+                    v.push(new_spill(IRType::LOAD_SPILL, &t));
+                }
+                if defs.contains(&vn) {
+                    stores.push(new_spill(IRType::STORE_SPILL, &t));
+                }
+                temps.push((vn, t.clone()));
+                return t;
+            };
+
+            map_uses(&mut ir, &mut temp);
+            ir.r0 = ir.r0.as_ref().map(|r| temp(r));
+            for r in ir.outs.iter_mut() {
+                *r = temp(r);
+            }
+            for arg in ir.asm_args.iter_mut() {
+                if arg.is_output {
+                    arg.reg = arg.reg.as_ref().map(|r| temp(r));
+                }
+            }
+        }
+
+        v.push(Rc::new(RefCell::new(ir)));
+        v.append(&mut stores);
+    }
     bb.borrow_mut().ir = v;
 }
 
-pub fn alloc_regs(prog: &mut Program) {
-    for fun in prog.funcs.iter() {
-        // Convert SSA to x86-ish two-address form.
-        for bb in fun.borrow().bbs.iter() {
-            three_to_two(bb.clone());
+// Reserves a stack area for each spilled register, and converts
+// accesses to them to loads and stores of temporaries.
+pub fn spill_regs(fun: &Rc<RefCell<Function>>, regs: &Vec<Rc<RefCell<Reg>>>) {
+    for r in regs.iter() {
+        if !r.borrow().spill {
+            continue;
         }
 
-        // Allocate registers and decide which registers to spill.
-        let regs = collect_regs(fun);
-        scan(&regs);
+        let mut var = alloc_var();
+        var.ty = ptr_to(Rc::new(RefCell::new(int_ty())));
+        var.is_local = true;
+        var.name = "spill".to_string();
 
-        // Reserve a stack area for spilled registers.
-        for r in regs.iter() {
-            if !r.borrow().spill {
-                continue;
-            }
+        let v = Rc::new(RefCell::new(var));
+        r.borrow_mut().var = Some(v.clone());
+        fun.borrow_mut().lvars.push(v);
+    }
 
-            let mut var = alloc_var();
-            var.ty = ptr_to(Rc::new(RefCell::new(int_ty())));
-            var.is_local = true;
-            var.name = "spill".to_string();
+    for bb in fun.borrow().bbs.iter() {
+        emit_spill_code(bb);
+    }
+    liveness_fn(fun);
+}
 
-            let v = Rc::new(RefCell::new(var));
-            r.borrow_mut().var = Some(v.clone());
-            fun.borrow_mut().lvars.push(v);
+fn linear_scan(fun: &Rc<RefCell<Function>>) {
+    loop {
+        // Allocate registers and decide which registers to spill.
+        reset_regs(fun);
+        let mut clobbers = Vec::new();
+        let regs = collect_regs(fun, &mut clobbers);
+        if scan(&regs, &clobbers) {
+            return;
         }
 
-        // Convert accesses to spilled registers to loads and stores
+        // Allocate registers again with the spilled ones replaced.
+        spill_regs(fun, &regs);
+    }
+}
+
+pub fn alloc_regs(prog: &mut Program) {
+    for fun in prog.funcs.iter() {
+        // Convert SSA to x86-ish two-address form.
         for bb in fun.borrow().bbs.iter() {
-            emit_spill_code(bb);
+            three_to_two(bb.clone());
+        }
+
+        match options().regalloc {
+            RegAlloc::Linear => linear_scan(fun),
+            RegAlloc::Graph => color_regs(fun),
         }
     }
 }
@@ -0,0 +1,351 @@
+// Sparse conditional constant propagation
+//
+// This pass finds registers holding constants by the algorithm of
+// Wegman and Zadeck. Each register has a value in the lattice
+//
+//   Top (not known yet) > Const(n) > Bottom (not a constant)
+//
+// and only BBs reachable through jumps whose conditions may hold are
+// evaluated. As a result, a constant flowing around a loop, or a value
+// guarded by a constant condition such as `if (DEBUG)`, is found.
+//
+// Instructions computing constants are then rewritten to IMMs, and
+// conditional branches on constants are rewritten to JMPs. BBs that are
+// never executed become unreachable and are removed.
+//
+// The pass works on SSA form. A register assigned more than once, such as
+// an output of inline assembly, is never a constant.
+
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Top,
+    Const(i64),
+    Bottom,
+}
+
+fn meet(a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Top, x) | (x, Value::Top) => x,
+        (Value::Const(x), Value::Const(y)) if x == y => a,
+        _ => Value::Bottom,
+    }
+}
+
+// Computes an operation as the code generator does, i.e. on 64-bit
+// registers. Returns None if the result is not defined.
+fn fold(op: &IRType, x: i64, y: i64) -> Option<i64> {
+    let v = match op {
+        IRType::ADD => x.wrapping_add(y),
+        IRType::SUB => x.wrapping_sub(y),
+        IRType::MUL => x.wrapping_mul(y),
+        IRType::DIV => x.checked_div(y)?,
+        IRType::MOD => x.checked_rem(y)?,
+        IRType::UDIV => (x as u64).checked_div(y as u64)? as i64,
+        IRType::UMOD => (x as u64).checked_rem(y as u64)? as i64,
+        IRType::AND => x & y,
+        IRType::OR => x | y,
+        IRType::XOR => x ^ y,
+        IRType::SHL => x.wrapping_shl((y & 63) as u32),
+        IRType::SHR => ((x as u64) >> (y & 63)) as i64,
+        IRType::SAR => x >> (y & 63),
+        IRType::MULH => ((x as i128 * y as i128) >> 64) as i64,
+        IRType::EQ => (x == y) as i64,
+        IRType::NE => (x != y) as i64,
+        IRType::LT => (x < y) as i64,
+        IRType::LE => (x <= y) as i64,
+        IRType::ULT => ((x as u64) < (y as u64)) as i64,
+        IRType::ULE => ((x as u64) <= (y as u64)) as i64,
+        _ => return None,
+    };
+    return Some(v);
+}
+
+fn is_foldable(op: &IRType) -> bool {
+    match op {
+        IRType::IMM | IRType::MOV => true,
+        _ => fold(op, 0, 1).is_some(),
+    }
+}
+
+struct Sccp {
+    bbs: Vec<Rc<RefCell<BB>>>,
+    index: HashMap<usize, usize>,
+    preds: Vec<Vec<usize>>,
+
+    values: HashMap<i32, Value>,
+    ndefs: HashMap<i32, usize>,
+    users: HashMap<i32, Vec<usize>>,
+
+    executable: Vec<bool>,
+    edges: HashSet<(usize, usize)>,
+    work: Vec<usize>,
+}
+
+impl Sccp {
+    fn value(&self, r: &Option<Rc<RefCell<Reg>>>) -> Value {
+        let vn = match r {
+            Some(r) => r.borrow().vn,
+            None => return Value::Bottom,
+        };
+        if self.ndefs.get(&vn) != Some(&1) {
+            return Value::Bottom;
+        }
+        return *self.values.get(&vn).unwrap_or(&Value::Top);
+    }
+
+    fn set(&mut self, r: &Rc<RefCell<Reg>>, v: Value) {
+        let vn = r.borrow().vn;
+        let old = self.value(&Some(r.clone()));
+        let new = meet(old, v);
+        if new == old {
+            return;
+        }
+
+        self.values.insert(vn, new);
+        if let Some(users) = self.users.get(&vn) {
+            for &b in users.iter() {
+                if self.executable[b] {
+                    self.work.push(b);
+                }
+            }
+        }
+    }
+
+    fn add_edge(&mut self, from: usize, to: &Rc<RefCell<BB>>) {
+        let to = self.index[&to.borrow().label];
+        if self.edges.insert((from, to)) {
+            self.executable[to] = true;
+            self.work.push(to);
+        }
+    }
+
+    fn eval(&self, ir: &IR) -> Value {
+        match ir.op {
+            IRType::IMM => return Value::Const(ir.imm),
+            IRType::MOV => return self.value(&ir.r2),
+            _ => {}
+        }
+        if !is_foldable(&ir.op) {
+            return Value::Bottom;
+        }
+
+        // A binary operation without r2 takes an immediate.
+        let rhs = match ir.r2 {
+            Some(_) => self.value(&ir.r2),
+            None => Value::Const(ir.imm),
+        };
+        match (self.value(&ir.r1), rhs) {
+            (Value::Const(x), Value::Const(y)) => match fold(&ir.op, x, y) {
+                Some(v) => Value::Const(v),
+                None => Value::Bottom,
+            },
+            (Value::Bottom, _) | (_, Value::Bottom) => Value::Bottom,
+            _ => Value::Top,
+        }
+    }
+
+    fn visit(&mut self, b: usize) {
+        let bb = self.bbs[b].clone();
+
+        // A block parameter has the values passed by executable jumps.
+        let params = bb.borrow().params.clone();
+        for (i, param) in params.iter().enumerate() {
+            let mut v = Value::Top;
+            for &p in self.preds[b].iter() {
+                if !self.edges.contains(&(p, b)) {
+                    continue;
+                }
+                let jmp = self.bbs[p].borrow().ir.last().unwrap().clone();
+                let arg = jmp.borrow().bbargs[i].clone();
+                v = meet(v, self.value(&Some(arg)));
+            }
+            self.set(param, v);
+        }
+
+        let irs = bb.borrow().ir.clone();
+        for ir in irs.iter() {
+            let ir = ir.borrow();
+            if let Some(r0) = ir.r0.as_ref() {
+                let v = self.eval(&ir);
+                self.set(r0, v);
+            }
+            for r in ir.outs.iter() {
+                self.set(r, Value::Bottom);
+            }
+
+            match ir.op {
+                IRType::JMP => {
+                    let t = ir.bb1.clone().unwrap();
+                    self.add_edge(b, &t);
+                }
+                IRType::BR => {
+                    let then = ir.bb1.clone().unwrap();
+                    let els = ir.bb2.clone().unwrap();
+                    match self.value(&ir.r2) {
+                        Value::Top => {}
+                        Value::Const(0) => self.add_edge(b, &els),
+                        Value::Const(_) => self.add_edge(b, &then),
+                        Value::Bottom => {
+                            self.add_edge(b, &then);
+                            self.add_edge(b, &els);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn new_sccp(fun: &Rc<RefCell<Function>>) -> Sccp {
+    let bbs = fun.borrow().bbs.clone();
+    let index = bb_indices(&bbs);
+    let mut preds = vec![Vec::new(); bbs.len()];
+    let mut ndefs = HashMap::new();
+    let mut users: HashMap<i32, Vec<usize>> = HashMap::new();
+
+    for (b, bb) in bbs.iter().enumerate() {
+        for p in bb.borrow().pred.iter() {
+            preds[b].push(index[&p.borrow().label]);
+        }
+        for r in bb.borrow().params.iter() {
+            *ndefs.entry(r.borrow().vn).or_insert(0) += 1;
+        }
+
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            for r in ir.r0.iter().chain(ir.outs.iter()) {
+                *ndefs.entry(r.borrow().vn).or_insert(0) += 1;
+            }
+            for r in uses(&ir) {
+                users.entry(r.borrow().vn).or_insert(Vec::new()).push(b);
+            }
+            // Values passed to block parameters are used by the target.
+            if let Some(t) = ir.bb1.as_ref() {
+                for r in ir.bbargs.iter() {
+                    let t = index[&t.borrow().label];
+                    users.entry(r.borrow().vn).or_insert(Vec::new()).push(t);
+                }
+            }
+        }
+    }
+
+    let mut executable = vec![false; bbs.len()];
+    executable[0] = true;
+
+    return Sccp {
+        bbs: bbs,
+        index: index,
+        preds: preds,
+        values: HashMap::new(),
+        ndefs: ndefs,
+        users: users,
+        executable: executable,
+        edges: HashSet::new(),
+        work: vec![0],
+    };
+}
+
+fn new_imm(r0: Rc<RefCell<Reg>>, imm: i64) -> Rc<RefCell<IR>> {
+    let mut ir = alloc_ir();
+    ir.op = IRType::IMM;
+    ir.r0 = Some(r0);
+    ir.imm = imm;
+    return Rc::new(RefCell::new(ir));
+}
+
+// Replaces constant block parameters with IMMs.
+fn rewrite_params(s: &Sccp, b: usize) {
+    let bb = s.bbs[b].clone();
+    let params = bb.borrow().params.clone();
+    let mut keep = Vec::new();
+    let mut imms = Vec::new();
+
+    for param in params.iter() {
+        match s.value(&Some(param.clone())) {
+            Value::Const(c) => {
+                imms.push(new_imm(param.clone(), c));
+                keep.push(false);
+            }
+            _ => keep.push(true),
+        }
+    }
+    if imms.is_empty() {
+        return;
+    }
+
+    for &p in s.preds[b].iter() {
+        let jmp = s.bbs[p].borrow().ir.last().unwrap().clone();
+        let args = jmp.borrow().bbargs.clone();
+        jmp.borrow_mut().bbargs = args
+            .into_iter()
+            .zip(keep.iter())
+            .filter(|(_, k)| **k)
+            .map(|(r, _)| r)
+            .collect();
+    }
+
+    let mut bb = bb.borrow_mut();
+    bb.params = params
+        .into_iter()
+        .zip(keep.iter())
+        .filter(|(_, k)| **k)
+        .map(|(r, _)| r)
+        .collect();
+    imms.append(&mut bb.ir);
+    bb.ir = imms;
+}
+
+pub fn sccp(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+    let mut s = new_sccp(fun);
+
+    while let Some(b) = s.work.pop() {
+        s.visit(b);
+    }
+
+    for b in 0..s.bbs.len() {
+        if !s.executable[b] {
+            continue;
+        }
+        rewrite_params(&s, b);
+
+        for ir in s.bbs[b].borrow().ir.iter() {
+            let mut ir = ir.borrow_mut();
+
+            if ir.op == IRType::BR {
+                // If the condition is still unknown, no value can reach
+                // here, so either target will do.
+                let t = match s.value(&ir.r2) {
+                    Value::Bottom => continue,
+                    Value::Const(0) | Value::Top => ir.bb2.clone(),
+                    Value::Const(_) => ir.bb1.clone(),
+                };
+                ir.op = IRType::JMP;
+                ir.bb1 = t;
+                ir.bb2 = None;
+                ir.r2 = None;
+                continue;
+            }
+
+            if ir.op == IRType::IMM || !is_foldable(&ir.op) {
+                continue;
+            }
+            if let Value::Const(c) = s.value(&ir.r0) {
+                ir.op = IRType::IMM;
+                ir.imm = c;
+                ir.r1 = None;
+                ir.r2 = None;
+            }
+        }
+    }
+
+    build_cfg(fun);
+}
@@ -16,13 +16,36 @@
 //   for integer and becomes ptr+8 for pointer.
 //
 // - Reject bad assignments, such as `1=2+3`.
+//
+// - Resolve _Generic selections to the chosen association.
 
+use crate::gen_ir::is_signed;
 use crate::parse::*;
 use crate::token::*;
 use crate::util::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+thread_local! {
+    // The type of the function being analyzed.
+    static FN_TY: RefCell<Option<Type>> = RefCell::new(None);
+}
+
+fn fn_is_noreturn() -> bool {
+    FN_TY.with(|f| match *f.borrow() {
+        Some(ref ty) => ty.is_noreturn,
+        None => false,
+    })
+}
+
+// Returns the return type of the function being analyzed.
+fn fn_returning() -> Option<Type> {
+    FN_TY.with(|f| match *f.borrow() {
+        Some(ref ty) => ty.returning.clone().map(|ty| *ty),
+        None => None,
+    })
+}
+
 fn maybe_decay(tmp: Rc<RefCell<Node>>, decay: bool) -> Rc<RefCell<Node>> {
     let tmp_ty = tmp.borrow().ty.clone();
     if !decay || tmp_ty.borrow().ty != CType::ARY {
@@ -49,6 +72,12 @@ macro_rules! bad_node {
     };
 }
 
+macro_rules! warn_node {
+    ($node:expr, $msg:expr) => {
+        warn_token!(&*$node.borrow().token.clone().unwrap(), $msg.to_string());
+    };
+}
+
 fn check_lval(node: Rc<RefCell<Node>>) {
     let op = node.borrow().op.clone();
     match op {
@@ -64,7 +93,7 @@ fn scale_ptr(op: NodeType, base: Rc<RefCell<Node>>, ty: Type) -> Rc<RefCell<Node
     node.op = op;
     node.lhs = Some(base.clone());
     let ptr = ty.ptr_to.unwrap();
-    node.rhs = Some(new_int_node(ptr.borrow().size, base.borrow().token.clone()));
+    node.rhs = Some(type_size_node(&ptr.borrow(), base.borrow().token.clone()));
     node.token = base.borrow().token.clone();
     return Rc::new(RefCell::new(node));
 }
@@ -81,11 +110,127 @@ fn cast(base: Rc<RefCell<Node>>, ty: Type) -> Rc<RefCell<Node>> {
 fn check_int(node: Rc<RefCell<Node>>) {
     let node_ty = node.borrow().ty.clone();
     let ty = node_ty.borrow();
-    if ty.ty != CType::INT && ty.ty != CType::CHAR && ty.ty != CType::BOOL {
+    if ty.ty != CType::INT
+        && ty.ty != CType::SHORT
+        && ty.ty != CType::LONG
+        && ty.ty != CType::CHAR
+        && ty.ty != CType::BOOL
+    {
         bad_node!(node, "not an integer");
     }
 }
 
+fn is_integer(ty: &Type) -> bool {
+    match ty.ty {
+        CType::BOOL | CType::CHAR | CType::SHORT | CType::INT | CType::LONG => true,
+        _ => false,
+    }
+}
+
+// Returns whether a value of an integer type has to be converted to
+// be a value of another integer type. A value which the type can
+// represent is already extended to a register as it would be.
+fn needs_conversion(from: &Type, to: &Type) -> bool {
+    if to.ty == CType::BOOL {
+        return from.ty != CType::BOOL;
+    }
+    if to.size == 8 || (from.size == to.size && is_signed(from) == is_signed(to)) {
+        return false;
+    }
+    return from.size >= to.size || (is_signed(from) && !is_signed(to));
+}
+
+// Converts an integer expression to an integer type.
+fn convert(node: Rc<RefCell<Node>>, ty: &Type) -> Rc<RefCell<Node>> {
+    if needs_conversion(&node.borrow().ty.borrow(), ty) {
+        return cast(node, ty.clone());
+    }
+    return node;
+}
+
+// Converts both operands of a binary operator to the type of the
+// usual arithmetic conversions, which is returned. Both operands have
+// the type afterwards, so that gen_ir can tell a comparison of
+// unsigned values by the type of either operand.
+fn convert_operands(node: &Rc<RefCell<Node>>) -> Type {
+    let lhs = node.borrow().lhs.clone().unwrap();
+    let rhs = node.borrow().rhs.clone().unwrap();
+    let ty = arith_ty(&lhs, &rhs);
+    node.borrow_mut().lhs = Some(convert_operand(lhs, &ty));
+    node.borrow_mut().rhs = Some(convert_operand(rhs, &ty));
+    return ty;
+}
+
+fn convert_operand(node: Rc<RefCell<Node>>, ty: &Type) -> Rc<RefCell<Node>> {
+    let from = promote(&node.borrow().ty);
+    if from.size != ty.size || from.is_unsigned != ty.is_unsigned {
+        return cast(node, ty.clone());
+    }
+    return node;
+}
+
+// The result of an unsigned int operation is computed in 64 bits, so
+// it is wrapped around to 32 bits.
+fn wrap(node: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
+    let ty = node.borrow().ty.borrow().clone();
+    if ty.ty == CType::INT && ty.is_unsigned {
+        return cast(node, ty);
+    }
+    return node;
+}
+
+// Integer promotion. Types narrower than int are promoted to int.
+pub fn promote(ty: &Rc<RefCell<Type>>) -> Type {
+    let ty = ty.borrow();
+    if ty.ty == CType::LONG || (ty.ty == CType::INT && ty.is_unsigned) {
+        let mut ty = ty.clone();
+        ty.is_atomic = false;
+        ty.is_volatile = false;
+        return ty;
+    }
+    return int_ty();
+}
+
+// Usual arithmetic conversions. The result has the type of the wider
+// operand, and is unsigned if an operand of that width is unsigned.
+fn arith_ty(lhs: &Rc<RefCell<Node>>, rhs: &Rc<RefCell<Node>>) -> Type {
+    let l = promote(&lhs.borrow().ty);
+    let r = promote(&rhs.borrow().ty);
+    return common_ty(l, r);
+}
+
+// The common type of two promoted operands.
+pub fn common_ty(l: Type, r: Type) -> Type {
+    if l.size != r.size {
+        return if l.size > r.size { l } else { r };
+    }
+    return if l.is_unsigned { l } else { r };
+}
+
+// Returns the type pointed to by the i-th argument of an atomic
+// builtin, which must be an integer or a pointer.
+fn atomic_operand(node: &Rc<RefCell<Node>>, i: usize) -> Type {
+    let arg = node.borrow().args[i].clone();
+    let arg_ty = arg.borrow().ty.clone();
+    if arg_ty.borrow().ty == CType::PTR {
+        let mut ty = arg_ty.borrow().ptr_to.clone().unwrap().borrow().clone();
+        match ty.ty {
+            CType::BOOL | CType::CHAR | CType::SHORT | CType::INT | CType::LONG | CType::PTR => {
+                ty.is_atomic = false;
+                return ty;
+            }
+            _ => {}
+        }
+    }
+    bad_node!(
+        arg,
+        format!(
+            "argument to '{}' must be a pointer to an integer or pointer",
+            node.borrow().name
+        )
+    );
+}
+
 fn walk(node: Rc<RefCell<Node>>, prog: &mut Program) -> Rc<RefCell<Node>> {
     return do_walk(node, true, prog);
 }
@@ -185,7 +330,9 @@ fn do_walk(node: Rc<RefCell<Node>>, decay: bool, prog: &mut Program) -> Rc<RefCe
                         Some(scale_ptr(NodeType::MUL, rhs, lhs_ty.borrow().clone()));
                     node.borrow_mut().ty = lhs.borrow().ty.clone();
                 } else {
-                    node.borrow_mut().ty = Rc::new(RefCell::new(int_ty()));
+                    let ty = convert_operands(&node);
+                    node.borrow_mut().ty = Rc::new(RefCell::new(ty));
+                    return wrap(node);
                 }
             }
 
@@ -210,8 +357,10 @@ fn do_walk(node: Rc<RefCell<Node>>, decay: bool, prog: &mut Program) -> Rc<RefCe
                 }
                 ret = scale_ptr(NodeType::DIV, node.clone(), lty.borrow().clone());
                 ret.borrow_mut().ty = lty.clone();
-            } else {
-                ret.borrow_mut().ty = Rc::new(RefCell::new(int_ty()));
+            } else if rty.borrow().ty != CType::PTR {
+                let ty = convert_operands(&ret);
+                ret.borrow_mut().ty = Rc::new(RefCell::new(ty));
+                return wrap(ret);
             }
 
             let node_lhs = ret.borrow().lhs.clone().unwrap();
@@ -232,6 +381,13 @@ fn do_walk(node: Rc<RefCell<Node>>, decay: bool, prog: &mut Program) -> Rc<RefCe
             if lty.borrow().ty == CType::BOOL {
                 let rhs = node.borrow().rhs.clone();
                 node.borrow_mut().rhs = Some(cast(rhs.unwrap(), bool_ty()));
+            } else if is_integer(&lty.borrow()) {
+                // Convert the value, which is the value of the
+                // assignment as well, to the type of the variable.
+                let rhs = node.borrow().rhs.clone().unwrap();
+                if is_integer(&rhs.borrow().ty.borrow()) {
+                    node.borrow_mut().rhs = Some(convert(rhs, &lty.borrow()));
+                }
             }
             node.borrow_mut().ty = lhs.borrow().ty.clone();
             return node;
@@ -269,7 +425,16 @@ fn do_walk(node: Rc<RefCell<Node>>, decay: bool, prog: &mut Program) -> Rc<RefCe
             node.borrow_mut().then = Some(walk(then.unwrap(), prog));
             node.borrow_mut().els = Some(walk(els.unwrap(), prog));
 
+            // Integer operands undergo the usual arithmetic conversions.
             let then = node.borrow().then.clone().unwrap();
+            let els = node.borrow().els.clone().unwrap();
+            if is_integer(&then.borrow().ty.borrow()) && is_integer(&els.borrow().ty.borrow()) {
+                let ty = arith_ty(&then, &els);
+                node.borrow_mut().then = Some(convert_operand(then, &ty));
+                node.borrow_mut().els = Some(convert_operand(els, &ty));
+                node.borrow_mut().ty = Rc::new(RefCell::new(ty));
+                return node;
+            }
             node.borrow_mut().ty = then.borrow().ty.clone();
             return node;
         }
@@ -296,12 +461,25 @@ fn do_walk(node: Rc<RefCell<Node>>, decay: bool, prog: &mut Program) -> Rc<RefCe
             check_int(node.borrow().lhs.clone().unwrap());
             check_int(node.borrow().rhs.clone().unwrap());
 
-            let lhs = node.borrow().lhs.clone();
-            if lhs.is_some() {
-                let l = lhs.unwrap();
-                node.borrow_mut().ty = l.borrow().ty.clone();
+            let lhs = node.borrow().lhs.clone().unwrap();
+            let ty = match op {
+                NodeType::MUL
+                | NodeType::DIV
+                | NodeType::MOD
+                | NodeType::OR
+                | NodeType::XOR
+                | NodeType::AND => convert_operands(&node),
+                NodeType::SHL | NodeType::SHR => promote(&lhs.borrow().ty),
+                NodeType::LT | NodeType::LE | NodeType::EQ | NodeType::NE => {
+                    convert_operands(&node);
+                    int_ty()
+                }
+                _ => int_ty(),
+            };
+            node.borrow_mut().ty = Rc::new(RefCell::new(ty));
+            if op == NodeType::MUL || op == NodeType::SHL {
+                return wrap(node);
             }
-            node.borrow_mut().ty = Rc::new(RefCell::new(int_ty()));
             return node;
         }
         NodeType::COMMA => {
@@ -361,8 +539,19 @@ fn do_walk(node: Rc<RefCell<Node>>, decay: bool, prog: &mut Program) -> Rc<RefCe
             return maybe_decay(node, decay);
         }
         NodeType::RETURN | NodeType::EXPR_STMT => {
-            let expr = node.borrow().expr.clone();
-            node.borrow_mut().expr = Some(walk(expr.unwrap(), prog));
+            if op == NodeType::RETURN && fn_is_noreturn() {
+                warn_node!(
+                    node,
+                    "function declared '_Noreturn' has a 'return' statement"
+                );
+            }
+            let expr = walk(node.borrow().expr.clone().unwrap(), prog);
+            node.borrow_mut().expr = Some(expr.clone());
+            if op == NodeType::RETURN && is_integer(&expr.borrow().ty.borrow()) {
+                if let Some(ty) = fn_returning().filter(is_integer) {
+                    node.borrow_mut().expr = Some(convert(expr, &ty));
+                }
+            }
             return node;
         }
         NodeType::CALL => {
@@ -375,6 +564,145 @@ fn do_walk(node: Rc<RefCell<Node>>, decay: bool, prog: &mut Program) -> Rc<RefCe
             node.borrow_mut().ty = Rc::new(RefCell::new(*ty));
             return node;
         }
+        NodeType::GENERIC => {
+            let expr = node.borrow().expr.clone();
+            let ctrl = walk(expr.unwrap(), prog);
+            let ty = ctrl.borrow().ty.clone();
+
+            let mut selected = None;
+            let mut default = None;
+            for (assoc_ty, e) in node.borrow().assocs.iter() {
+                match assoc_ty {
+                    Some(t) => {
+                        if selected.is_none()
+                            && same_type(ty.clone(), Rc::new(RefCell::new(t.clone())))
+                        {
+                            selected = Some(e.clone());
+                        }
+                    }
+                    None => {
+                        default = Some(e.clone());
+                    }
+                }
+            }
+
+            if selected.is_none() {
+                selected = default;
+            }
+            if selected.is_none() {
+                bad_node!(node, "no matching association in _Generic");
+            }
+            return do_walk(selected.unwrap(), decay, prog);
+        }
+        NodeType::ALLOCA => {
+            let arg = walk(node.borrow().args[0].clone(), prog);
+            check_int(arg.clone());
+            node.borrow_mut().args[0] = arg;
+            node.borrow_mut().ty = Rc::new(RefCell::new(ptr_to(Rc::new(RefCell::new(void_ty())))));
+            return node;
+        }
+        NodeType::EXPECT => {
+            let args = node.borrow().args.clone();
+            for i in 0..args.len() {
+                let arg = walk(args[i].clone(), prog);
+                check_int(arg.clone());
+                node.borrow_mut().args[i] = arg;
+            }
+            node.borrow_mut().ty = Rc::new(RefCell::new(long_ty()));
+            return node;
+        }
+        NodeType::UNREACHABLE | NodeType::TRAP => {
+            node.borrow_mut().ty = Rc::new(RefCell::new(void_ty()));
+            return node;
+        }
+        NodeType::CLZ | NodeType::CTZ | NodeType::POPCOUNT => {
+            // The operand is unsigned int, or unsigned long for the
+            // "l" and "ll" variants.
+            let arg = walk(node.borrow().args[0].clone(), prog);
+            check_int(arg.clone());
+            let mut ty = if node.borrow().name.ends_with('l') {
+                long_ty()
+            } else {
+                int_ty()
+            };
+            ty.is_unsigned = true;
+            node.borrow_mut().args[0] = cast(arg, ty);
+            node.borrow_mut().ty = Rc::new(RefCell::new(int_ty()));
+            return node;
+        }
+        NodeType::ATOMIC_LOAD
+        | NodeType::ATOMIC_STORE
+        | NodeType::ATOMIC_FETCH_ADD
+        | NodeType::ATOMIC_CAS
+        | NodeType::FENCE => {
+            let args = node.borrow().args.clone();
+            for i in 0..args.len() {
+                node.borrow_mut().args[i] = walk(args[i].clone(), prog);
+            }
+
+            let ty = match op {
+                NodeType::ATOMIC_LOAD => atomic_operand(&node, 0),
+                NodeType::ATOMIC_FETCH_ADD => {
+                    let ty = atomic_operand(&node, 0);
+                    if ty.ty == CType::PTR {
+                        bad_node!(node, format!("'{}' on a pointer", node.borrow().name));
+                    }
+                    ty
+                }
+                NodeType::ATOMIC_CAS => {
+                    let ty = atomic_operand(&node, 0);
+                    if atomic_operand(&node, 1).size != ty.size {
+                        bad_node!(
+                            node,
+                            format!("incompatible arguments to '{}'", node.borrow().name)
+                        );
+                    }
+                    bool_ty()
+                }
+                NodeType::ATOMIC_STORE => {
+                    atomic_operand(&node, 0);
+                    void_ty()
+                }
+                _ => void_ty(),
+            };
+            node.borrow_mut().ty = Rc::new(RefCell::new(ty));
+            return node;
+        }
+        NodeType::VLA_ALLOC => {
+            let expr = node.borrow().expr.clone();
+            node.borrow_mut().expr = Some(walk(expr.unwrap(), prog));
+            return node;
+        }
+        NodeType::ASM => {
+            let mut asm = node.borrow().asm.clone().unwrap();
+            for op in asm.operands.iter_mut() {
+                if op.kind == AsmKind::IMM {
+                    continue;
+                }
+
+                op.expr = walk(op.expr.clone(), prog);
+                if op.is_output || op.kind == AsmKind::MEM {
+                    check_lval(op.expr.clone());
+                }
+
+                let expr = op.expr.clone();
+                if op.kind == AsmKind::MEM {
+                    if expr.borrow().op == NodeType::VARREF {
+                        let var = expr.borrow().var.clone().unwrap();
+                        var.borrow_mut().address_taken = true;
+                    }
+                    continue;
+                }
+
+                let ty = expr.borrow().ty.clone();
+                let size = ty.borrow().size;
+                if ty.borrow().ty == CType::STRUCT || (size != 1 && size != 4 && size != 8) {
+                    bad_node!(expr, "invalid operand type for a register constraint");
+                }
+            }
+            node.borrow_mut().asm = Some(asm);
+            return node;
+        }
         NodeType::COMP_STMT => {
             let stmts = node.borrow().stmts.clone();
             for i in 0..stmts.len() {
@@ -416,6 +744,8 @@ pub fn sema(prog: &mut Program) {
         }
 
         assert!(node.borrow().op == NodeType::FUNC);
+        let ty = node.borrow().ty.borrow().clone();
+        FN_TY.with(|f| *f.borrow_mut() = Some(ty));
 
         let body = node.borrow_mut().body.clone().unwrap();
         node.borrow_mut().body = Some(walk(body, prog));
@@ -0,0 +1,324 @@
+// Stack slot allocation
+//
+// Each local variable and spilled register needs a slot in the stack
+// frame, but two of them can share a slot if their values are never
+// needed at the same time, e.g. locals in disjoint blocks.
+//
+// The lifetime of a slot is approximated by the accesses to it. The
+// slot is live at a point if the point is reachable from an access and
+// some access is reachable from the point. Whatever is stored there is
+// not read outside of that.
+//
+// Accesses to a local variable are found while the IR is still in SSA
+// form, since addresses of the variable may be copied to other
+// registers. An IR computing or using an address derived from the
+// variable is marked with it in `mark_slots`. If the address escapes,
+// the variable may be accessed anywhere after its address is computed
+// until it goes out of scope at a SCOPE_END, or throughout the function
+// if it has none. Accesses to a spill slot are LOAD_SPILLs and
+// STORE_SPILLs.
+//
+// Slots are then assigned offsets from the top of the frame one by one
+// in order of decreasing alignment to minimize padding. Each slot is
+// put at the lowest offset not overlapping with the slots interfering
+// with it.
+
+use crate::alias::*;
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use crate::util::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub fn var_align(var: &Var) -> i32 {
+    // A variable-length array is a pointer to its storage.
+    if var.ty.vla_len.is_some() {
+        return var.align.max(8);
+    }
+    if var.align > var.ty.align {
+        return var.align;
+    }
+    // A function declared in a function body has no storage.
+    return var.ty.align.max(1);
+}
+
+// Returns the alignment of the stack frame of a function. Variables
+// aligned to more than 16 bytes need a realigned frame.
+pub fn frame_align(fun: &Function) -> i32 {
+    let mut align = 16;
+    for v in fun.lvars.iter() {
+        align = align.max(var_align(&v.borrow()));
+    }
+    return align;
+}
+
+fn mark_fn(fun: &Rc<RefCell<Function>>) {
+    let bbs = fun.borrow().bbs.clone();
+    let ndefs = count_defs(&bbs);
+    let mem = mem_info(&bbs, &ndefs);
+
+    let mut vars = HashMap::new();
+    for v in fun.borrow().lvars.iter() {
+        vars.insert(var_key(&Some(v.clone())), v.clone());
+    }
+
+    let local = |base: Base| match base {
+        Base::Stack(v) if !mem.escaped.contains(&v) => vars.get(&v).cloned(),
+        _ => None,
+    };
+
+    for bb in bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            let mut ir = ir.borrow_mut();
+            let mut slot = match ir.op {
+                IRType::BPREL | IRType::STORE_ARG => local(Base::Stack(var_key(&ir.var))),
+                _ => None,
+            };
+            for r in ir.r0.iter().cloned().chain(uses(&ir).into_iter()) {
+                if slot.is_none() {
+                    slot = local(mem.base(&Some(r)));
+                }
+            }
+            ir.slot = slot;
+        }
+    }
+}
+
+// Marks IRs with the local variables they access.
+pub fn mark_slots(prog: &mut Program) {
+    for fun in prog.funcs.iter() {
+        mark_fn(fun);
+    }
+}
+
+struct Slot {
+    var: Rc<RefCell<Var>>,
+    size: i32,
+    align: i32,
+    // The ranges of live IRs as (BB, first, last) in order of BBs, or
+    // None if the slot is live throughout the function.
+    live: Option<Vec<(usize, usize, usize)>>,
+}
+
+fn interferes(a: &Slot, b: &Slot) -> bool {
+    let (x, y) = match (&a.live, &b.live) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return true,
+    };
+
+    let (mut i, mut j) = (0, 0);
+    while i < x.len() && j < y.len() {
+        let (b1, lo1, hi1) = x[i];
+        let (b2, lo2, hi2) = y[j];
+        if b1 == b2 && lo1 <= hi2 && lo2 <= hi1 {
+            return true;
+        }
+        if b1 <= b2 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    return false;
+}
+
+// Returns the BBs reachable from `from` by following at least one edge,
+// forward or backward.
+fn reachable(
+    bbs: &Vec<Rc<RefCell<BB>>>,
+    index: &HashMap<usize, usize>,
+    from: &Vec<usize>,
+    forward: bool,
+) -> Vec<bool> {
+    let mut seen = vec![false; bbs.len()];
+    let mut work = from.clone();
+    while let Some(b) = work.pop() {
+        let bb = bbs[b].borrow();
+        let next = if forward { &bb.succ } else { &bb.pred };
+        for n in next.iter() {
+            let n = index[&n.borrow().label];
+            if !seen[n] {
+                seen[n] = true;
+                work.push(n);
+            }
+        }
+    }
+    return seen;
+}
+
+// Computes the live ranges of a slot accessed at `uses`.
+fn live_ranges(
+    bbs: &Vec<Rc<RefCell<BB>>>,
+    index: &HashMap<usize, usize>,
+    uses: &Vec<(usize, usize)>,
+) -> Vec<(usize, usize, usize)> {
+    let blocks = uses.iter().map(|&(b, _)| b).collect();
+    let after = reachable(bbs, index, &blocks, true);
+    let before = reachable(bbs, index, &blocks, false);
+
+    let mut v = Vec::new();
+    for (b, bb) in bbs.iter().enumerate() {
+        let first = uses.iter().filter(|u| u.0 == b).map(|u| u.1).min();
+        let last = uses.iter().filter(|u| u.0 == b).map(|u| u.1).max();
+        let lo = if after[b] { Some(0) } else { first };
+        let hi = if before[b] {
+            Some(bb.borrow().ir.len())
+        } else {
+            last
+        };
+        if let (Some(lo), Some(hi)) = (lo, hi) {
+            if lo <= hi {
+                v.push((b, lo, hi));
+            }
+        }
+    }
+    return v;
+}
+
+// Computes the live ranges of an escaped variable whose address is
+// computed at `uses` and which goes out of scope at `ends`. It is live
+// at a point reachable from a use without passing an end.
+fn escaped_ranges(
+    bbs: &Vec<Rc<RefCell<BB>>>,
+    index: &HashMap<usize, usize>,
+    uses: &Vec<(usize, usize)>,
+    ends: &Vec<(usize, usize)>,
+) -> Vec<(usize, usize, usize)> {
+    let mut lo: Vec<Option<usize>> = vec![None; bbs.len()];
+    let mut hi = vec![0; bbs.len()];
+    let mut entered = vec![false; bbs.len()];
+    let mut work = uses.clone();
+    while let Some((b, i)) = work.pop() {
+        let end = ends
+            .iter()
+            .filter(|e| e.0 == b && e.1 >= i)
+            .map(|e| e.1)
+            .min();
+        lo[b] = Some(lo[b].map_or(i, |lo| lo.min(i)));
+        hi[b] = hi[b].max(end.unwrap_or(bbs[b].borrow().ir.len()));
+        if end.is_some() {
+            continue;
+        }
+        for s in bbs[b].borrow().succ.iter() {
+            let s = index[&s.borrow().label];
+            if !entered[s] {
+                entered[s] = true;
+                work.push((s, 0));
+            }
+        }
+    }
+
+    let mut v = Vec::new();
+    for b in 0..bbs.len() {
+        if let Some(lo) = lo[b] {
+            v.push((b, lo, hi[b]));
+        }
+    }
+    return v;
+}
+
+fn alloc_fn(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+    let bbs = fun.borrow().bbs.clone();
+    let index = bb_indices(&bbs);
+
+    // Collect slots in use and their accesses.
+    let mut slots: Vec<Slot> = Vec::new();
+    let mut uses: Vec<Vec<(usize, usize)>> = Vec::new();
+    let mut slot_index = HashMap::new();
+    let mut ends: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for (b, bb) in bbs.iter().enumerate() {
+        for (i, ir) in bb.borrow().ir.iter().enumerate() {
+            let ir = ir.borrow();
+            if ir.op == IRType::SCOPE_END {
+                ends.entry(var_key(&ir.var))
+                    .or_insert(Vec::new())
+                    .push((b, i));
+                continue;
+            }
+            let (var, whole) = match ir.op {
+                IRType::BPREL | IRType::STORE_ARG if ir.slot.is_none() => (ir.var.clone(), true),
+                IRType::LOAD_SPILL | IRType::STORE_SPILL => (ir.var.clone(), false),
+                _ => (ir.slot.clone(), false),
+            };
+            let var = match var {
+                Some(var) => var,
+                None => continue,
+            };
+
+            let key = var_key(&Some(var.clone()));
+            let n = match slot_index.get(&key) {
+                Some(&n) => n,
+                None => {
+                    slot_index.insert(key, slots.len());
+                    slots.push(Slot {
+                        var: var.clone(),
+                        size: var.borrow().ty.size.max(1),
+                        align: var_align(&var.borrow()),
+                        live: Some(Vec::new()),
+                    });
+                    uses.push(Vec::new());
+                    slots.len() - 1
+                }
+            };
+            if whole {
+                slots[n].live = None;
+            }
+            uses[n].push((b, i));
+        }
+    }
+
+    for (s, u) in slots.iter_mut().zip(uses.iter()) {
+        let key = var_key(&Some(s.var.clone()));
+        if s.live.is_some() {
+            s.live = Some(live_ranges(&bbs, &index, u));
+        } else if let Some(e) = ends.get(&key) {
+            s.live = Some(escaped_ranges(&bbs, &index, u, e));
+        }
+    }
+
+    // The original RSP is saved at the top of a realigned frame.
+    let top = if frame_align(&fun.borrow()) > 16 {
+        8
+    } else {
+        0
+    };
+    let mut size = top;
+    let mut placed: Vec<(usize, i32)> = Vec::new();
+
+    let mut order: Vec<usize> = (0..slots.len()).collect();
+    order.sort_by_key(|&i| (-slots[i].align, -slots[i].size));
+
+    // A slot at offset `off` occupies [rbp-off, rbp-off+size).
+    for &i in order.iter() {
+        let s = &slots[i];
+        let mut off = roundup(top + s.size, s.align);
+
+        // Find the lowest offset not overlapping with interfering slots.
+        loop {
+            let conflict = placed.iter().find(|&&(j, o)| {
+                let t = &slots[j];
+                o - t.size < off && off - s.size < o && interferes(s, t)
+            });
+            match conflict {
+                Some(&(_, o)) => off = roundup(o + s.size, s.align),
+                None => break,
+            }
+        }
+
+        s.var.borrow_mut().offset = -off;
+        size = size.max(off);
+        placed.push((i, off));
+    }
+    fun.borrow_mut().stacksize = size;
+}
+
+// Assigns an offset from the top of the stack frame to each local
+// variable and spill slot.
+pub fn alloc_slots(prog: &mut Program) {
+    for fun in prog.funcs.iter() {
+        alloc_fn(fun);
+    }
+}
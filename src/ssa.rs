@@ -0,0 +1,374 @@
+// SSA construction and destruction
+//
+// mem2reg.rs marks variables that can live in registers. This pass rewrites
+// their loads and stores to SSA form, in which every register is
+// assigned exactly once. Where values of a variable from different paths
+// meet, a block parameter is added to the BB, and each jump to it passes
+// the current value of the variable in `bbargs`. Block parameters are
+// placed on the iterated dominance frontiers of the BBs storing to the
+// variable (Cytron et al.), and only for variables that are read before
+// written in some BB (semi-pruned SSA).
+//
+// The code generator does not know block parameters, so `out_of_ssa`
+// lowers them to copies at the end of each predecessor before register
+// allocation. The copies on a jump happen in parallel, e.g. `a, b = b, a`,
+// so they are sequentialized with a temporary register if needed. A copy
+// must not be executed on a path that does not go to the BB, so edges
+// from a conditional branch to a BB with parameters are split first.
+
+use crate::dce::*;
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use crate::util::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+type Params = HashMap<usize, Vec<(usize, Rc<RefCell<Reg>>)>>;
+
+struct Renamer {
+    vars: usize,
+    params: Params,
+    stacks: Vec<Vec<Rc<RefCell<Reg>>>>,
+    subst: HashMap<i32, Rc<RefCell<Reg>>>,
+    asm_outs: HashSet<i32>,
+}
+
+fn promoted_var(r: &Option<Rc<RefCell<Reg>>>) -> Option<Rc<RefCell<Var>>> {
+    match r {
+        Some(r) => r.borrow().promoted.clone(),
+        None => None,
+    }
+}
+
+fn var_key(var: &Rc<RefCell<Var>>) -> usize {
+    return Rc::as_ptr(var) as usize;
+}
+
+fn new_mov(dst: Rc<RefCell<Reg>>, src: Rc<RefCell<Reg>>) -> Rc<RefCell<IR>> {
+    let mut ir = alloc_ir();
+    ir.op = IRType::MOV;
+    ir.r0 = Some(dst);
+    ir.r2 = Some(src);
+    return Rc::new(RefCell::new(ir));
+}
+
+// Splits edges from conditional branches to BBs with parameters.
+fn split_edges(fun: &Rc<RefCell<Function>>, params: &Params) {
+    let bbs = fun.borrow().bbs.clone();
+    let mut v = Vec::new();
+
+    for bb in bbs.iter() {
+        v.push(bb.clone());
+
+        let ir = bb.borrow().ir.last().unwrap().clone();
+        if ir.borrow().op != IRType::BR {
+            continue;
+        }
+
+        let mut targets = [ir.borrow().bb1.clone(), ir.borrow().bb2.clone()];
+        for t in targets.iter_mut() {
+            let label = t.as_ref().unwrap().borrow().label;
+            if !params.contains_key(&label) {
+                continue;
+            }
+
+            let mut jmp = alloc_ir();
+            jmp.op = IRType::JMP;
+            jmp.bb1 = t.clone();
+
+            let mut split = alloc_bb();
+            split.label = bump_nlabel();
+            split.ir.push(Rc::new(RefCell::new(jmp)));
+            let split = Rc::new(RefCell::new(split));
+            v.push(split.clone());
+            *t = Some(split);
+        }
+
+        let [bb1, bb2] = targets;
+        ir.borrow_mut().bb1 = bb1;
+        ir.borrow_mut().bb2 = bb2;
+    }
+
+    fun.borrow_mut().bbs = v;
+    build_cfg(fun);
+}
+
+impl Renamer {
+    // Returns the current value of a variable. A read of an
+    // uninitialized variable can be any value, so a zero is emitted to
+    // `out` in that case.
+    fn current(&self, v: usize, out: &mut Vec<Rc<RefCell<IR>>>) -> Rc<RefCell<Reg>> {
+        if let Some(r) = self.stacks[v].last() {
+            return r.clone();
+        }
+
+        let r = new_reg();
+        let mut ir = alloc_ir();
+        ir.op = IRType::IMM;
+        ir.r0 = Some(r.clone());
+        out.push(Rc::new(RefCell::new(ir)));
+        return r;
+    }
+
+    fn rename(
+        &mut self,
+        b: usize,
+        bbs: &Vec<Rc<RefCell<BB>>>,
+        dom: &DomTree,
+        keys: &HashMap<usize, usize>,
+    ) {
+        let bb = bbs[b].clone();
+        let label = bb.borrow().label;
+        let mut pushed = vec![0; self.vars];
+
+        if let Some(params) = self.params.get(&label) {
+            for (v, r) in params.iter() {
+                self.stacks[*v].push(r.clone());
+                pushed[*v] += 1;
+            }
+        }
+
+        let mut v = Vec::new();
+        let irs = bb.borrow().ir.clone();
+        for ir in irs.iter() {
+            {
+                let subst = &self.subst;
+                map_uses(&mut ir.borrow_mut(), |r| match subst.get(&r.borrow().vn) {
+                    Some(r2) => r2.clone(),
+                    None => r.clone(),
+                });
+            }
+
+            let op = ir.borrow().op.clone();
+            match op {
+                IRType::NOP => {
+                    if promoted_var(&ir.borrow().r0).is_some() {
+                        continue;
+                    }
+                }
+                IRType::LOAD => {
+                    if let Some(var) = promoted_var(&ir.borrow().r2) {
+                        let cur = self.current(keys[&var_key(&var)], &mut v);
+                        let r0 = ir.borrow().r0.clone().unwrap();
+                        let vn = r0.borrow().vn;
+                        if self.asm_outs.contains(&vn) {
+                            // The register is written again by inline
+                            // assembly, so it cannot be an alias.
+                            v.push(new_mov(r0, cur));
+                        } else {
+                            self.subst.insert(vn, cur);
+                        }
+                        continue;
+                    }
+                }
+                IRType::STORE => {
+                    if let Some(var) = promoted_var(&ir.borrow().r1) {
+                        let i = keys[&var_key(&var)];
+                        let r2 = ir.borrow().r2.clone().unwrap();
+                        self.stacks[i].push(r2);
+                        pushed[i] += 1;
+                        continue;
+                    }
+                }
+                IRType::JMP => {
+                    let t = ir.borrow().bb1.clone().unwrap();
+                    let label = t.borrow().label;
+                    let vars: Vec<usize> = match self.params.get(&label) {
+                        Some(params) => params.iter().map(|(v, _)| *v).collect(),
+                        None => Vec::new(),
+                    };
+                    for i in vars {
+                        let r = self.current(i, &mut v);
+                        ir.borrow_mut().bbargs.push(r);
+                    }
+                }
+                _ => {}
+            }
+            v.push(ir.clone());
+        }
+        bb.borrow_mut().ir = v;
+
+        for &c in dom.children[b].iter() {
+            self.rename(c, bbs, dom, keys);
+        }
+
+        for i in 0..self.vars {
+            for _ in 0..pushed[i] {
+                self.stacks[i].pop();
+            }
+        }
+    }
+}
+
+pub fn to_ssa(fun: &Rc<RefCell<Function>>) {
+    build_cfg(fun);
+    let bbs = fun.borrow().bbs.clone();
+    let dom = dominators(fun);
+
+    // Find promoted variables, BBs storing to them, and whether they are
+    // live across BBs.
+    let mut vars: Vec<Rc<RefCell<Var>>> = Vec::new();
+    let mut keys = HashMap::new();
+    let mut defs: Vec<Vec<usize>> = Vec::new();
+    let mut globals = HashSet::new();
+    let mut asm_outs = HashSet::new();
+
+    for (b, bb) in bbs.iter().enumerate() {
+        let mut killed = HashSet::new();
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            for r in ir.outs.iter() {
+                asm_outs.insert(r.borrow().vn);
+            }
+
+            let (var, is_store) = match ir.op {
+                IRType::LOAD => (promoted_var(&ir.r2), false),
+                IRType::STORE => (promoted_var(&ir.r1), true),
+                _ => (None, false),
+            };
+            if var.is_none() {
+                continue;
+            }
+            let var = var.unwrap();
+
+            let key = var_key(&var);
+            if !keys.contains_key(&key) {
+                keys.insert(key, vars.len());
+                vars.push(var.clone());
+                defs.push(Vec::new());
+            }
+            let i = keys[&key];
+
+            if is_store {
+                if !defs[i].contains(&b) {
+                    defs[i].push(b);
+                }
+                killed.insert(i);
+            } else if !killed.contains(&i) {
+                globals.insert(i);
+            }
+        }
+    }
+
+    if vars.is_empty() {
+        return;
+    }
+
+    // Place block parameters.
+    let mut params: Params = HashMap::new();
+    for i in 0..vars.len() {
+        if !globals.contains(&i) {
+            continue;
+        }
+
+        let mut has_param = HashSet::new();
+        let mut work = defs[i].clone();
+        while let Some(b) = work.pop() {
+            for &d in dom.frontier[b].iter() {
+                if !has_param.insert(d) {
+                    continue;
+                }
+                let label = bbs[d].borrow().label;
+                params
+                    .entry(label)
+                    .or_insert(Vec::new())
+                    .push((i, new_reg()));
+                if !defs[i].contains(&d) {
+                    work.push(d);
+                }
+            }
+        }
+    }
+
+    split_edges(fun, &params);
+    let bbs = fun.borrow().bbs.clone();
+    let dom = dominators(fun);
+
+    let mut renamer = Renamer {
+        vars: vars.len(),
+        params: params,
+        stacks: vec![Vec::new(); vars.len()],
+        subst: HashMap::new(),
+        asm_outs: asm_outs,
+    };
+    renamer.rename(0, &bbs, &dom, &keys);
+
+    for bb in bbs.iter() {
+        let label = bb.borrow().label;
+        if let Some(params) = renamer.params.get(&label) {
+            for (_, r) in params.iter() {
+                bb.borrow_mut().params.push(r.clone());
+            }
+        }
+    }
+}
+
+// Emits parallel copies `dst <- src` as a sequence of MOVs.
+fn sequentialize(mut copies: Vec<(Rc<RefCell<Reg>>, Rc<RefCell<Reg>>)>) -> Vec<Rc<RefCell<IR>>> {
+    let mut v = Vec::new();
+    copies.retain(|(dst, src)| dst.borrow().vn != src.borrow().vn);
+
+    while !copies.is_empty() {
+        // A copy is ready if no other pending copy reads its destination.
+        let ready = (0..copies.len()).find(|&i| {
+            let dst = &copies[i].0;
+            return !copies
+                .iter()
+                .any(|(_, src)| src.borrow().vn == dst.borrow().vn);
+        });
+
+        match ready {
+            Some(i) => {
+                let (dst, src) = copies.remove(i);
+                v.push(new_mov(dst, src));
+            }
+            None => {
+                // All destinations are in cycles. Save one of them to
+                // a temporary to break its cycle.
+                let dst = copies[0].0.clone();
+                let tmp = new_reg();
+                v.push(new_mov(tmp.clone(), dst.clone()));
+                for (_, src) in copies.iter_mut() {
+                    if src.borrow().vn == dst.borrow().vn {
+                        *src = tmp.clone();
+                    }
+                }
+            }
+        }
+    }
+    return v;
+}
+
+pub fn out_of_ssa(prog: &mut Program) {
+    for fun in prog.funcs.iter() {
+        build_cfg(fun);
+        let bbs = fun.borrow().bbs.clone();
+
+        for bb in bbs.iter() {
+            let params = bb.borrow().params.clone();
+            if params.is_empty() {
+                continue;
+            }
+
+            let preds = bb.borrow().pred.clone();
+            for pred in preds.iter() {
+                let jmp = pred.borrow_mut().ir.pop().unwrap();
+                assert!(jmp.borrow().op == IRType::JMP);
+
+                let args = jmp.borrow().bbargs.clone();
+                assert!(args.len() == params.len());
+                let copies = params.iter().cloned().zip(args.into_iter()).collect();
+
+                pred.borrow_mut().ir.append(&mut sequentialize(copies));
+                jmp.borrow_mut().bbargs = Vec::new();
+                pred.borrow_mut().ir.push(jmp);
+            }
+            bb.borrow_mut().params = Vec::new();
+        }
+
+        // Split edges that received no copies are empty now.
+        simplify_cfg(fun);
+    }
+}
@@ -0,0 +1,115 @@
+// Tail call optimization
+//
+// A call whose result is returned immediately is in tail position:
+//
+//  r1 = CALL f(r2, r3)
+//  RET r1
+//
+// A jump to a BB returning the value counts as well, since that is what
+// `return` in a branch of an inlined function becomes.
+//
+// Nothing in the caller is needed after such a call, so the caller can
+// tear down its stack frame first and jump to the callee, which returns
+// directly to the caller's caller. This pass rewrites the pair to
+//
+//  TAIL_CALL f(r2, r3)
+//
+// so that recursion in tail position runs in constant stack space.
+//
+// The callee must not see the caller's frame, so no tail calls are made
+// from a function whose stack variables escape or which uses alloca.
+// All arguments have to be passed in registers as well.
+
+use crate::alias::*;
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Number of argument registers
+const MAX_ARGS: usize = 6;
+
+// Returns true if a callee may access the stack frame of the function.
+fn frame_escapes(bbs: &Vec<Rc<RefCell<BB>>>) -> bool {
+    let ndefs = count_defs(bbs);
+    let mem = mem_info(bbs, &ndefs);
+    if !mem.escaped.is_empty() {
+        return true;
+    }
+
+    for bb in bbs.iter() {
+        for ir in bb.borrow().ir.iter() {
+            let ir = ir.borrow();
+            match ir.op {
+                IRType::ALLOCA | IRType::STACK_SAVE => return true,
+                IRType::ASM => {
+                    for arg in ir.asm_args.iter() {
+                        if let Base::Stack(_) = mem.base(&arg.reg) {
+                            return true;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    return false;
+}
+
+// Returns true if the IR returns the value of `r`, either directly or
+// by jumping to a BB which does nothing but return it.
+fn returns(ir: &IR, r: i32) -> bool {
+    if ir.op == IRType::RETURN {
+        return ir.r2.as_ref().unwrap().borrow().vn == r;
+    }
+    if ir.op != IRType::JMP {
+        return false;
+    }
+
+    let bb = ir.bb1.as_ref().unwrap().borrow();
+    if bb.ir.len() != 1 || bb.ir[0].borrow().op != IRType::RETURN {
+        return false;
+    }
+    let ret = bb.ir[0].borrow().r2.as_ref().unwrap().borrow().vn;
+    if ret == r {
+        return true;
+    }
+    return bb
+        .params
+        .iter()
+        .zip(ir.bbargs.iter())
+        .any(|(p, arg)| p.borrow().vn == ret && arg.borrow().vn == r);
+}
+
+fn is_tail_call(call: &IR, next: &IR) -> bool {
+    if call.op != IRType::CALL || call.nargs > MAX_ARGS {
+        return false;
+    }
+    return returns(next, call.r0.as_ref().unwrap().borrow().vn);
+}
+
+pub fn tail_calls(fun: &Rc<RefCell<Function>>) {
+    let bbs = fun.borrow().bbs.clone();
+    if frame_escapes(&bbs) {
+        return;
+    }
+
+    for bb in bbs.iter() {
+        let n = bb.borrow().ir.len();
+        if n < 2 {
+            continue;
+        }
+        let call = bb.borrow().ir[n - 2].clone();
+        let next = bb.borrow().ir[n - 1].clone();
+        if !is_tail_call(&call.borrow(), &next.borrow()) {
+            continue;
+        }
+
+        bb.borrow_mut().ir.pop();
+        let mut call = call.borrow_mut();
+        call.op = IRType::TAIL_CALL;
+        call.r0 = None;
+    }
+    build_cfg(fun);
+}
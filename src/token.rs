@@ -215,89 +215,114 @@ struct Symbol {
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TokenType {
-    ADD,        // +
-    SUB,        // -
-    MUL,        // *
-    DIV,        // /
-    EQL,        // =
-    LT,         // <
-    GT,         // >
-    OR,         // |
-    HAT,        // ^
-    TILDA,      // ~
-    BRA,        // (
-    KET,        // )
-    C_BRA,      // {
-    C_KET,      // }
-    S_BRA,      // [
-    S_KET,      // ]
-    AMP,        // &
-    MOD,        // %
-    EXCLAM,     // !
-    QUEST,      // ?
-    DOT,        // .
-    COMMA,      // ,
-    COLON,      // :
-    SEMI_COLON, // ;
-    SHARP,      // #
-    NEW_LINE,   // \n
-    NUM,        // Number literal
-    STR,        // String literal
-    IDENT,      // Identifier
-    ARROW,      // "->"
-    EXTERN,     // "extern"
-    TYPEDEF,    // "typedef"
-    INT,        // "int"
-    CHAR,       // "char"
-    VOID,       // "void"
-    STRUCT,     // "struct"
-    BOOL,       // "_Bool"
-    IF,         // "if"
-    ELSE,       // "else"
-    FOR,        // "for"
-    DO,         // "do"
-    WHILE,      // "while"
-    SWITCH,     // "switch"
-    CASE,       // "case"
-    BREAK,      // "break"
-    CONTINUE,   // "continue"
-    EQ,         // ==
-    NE,         // !=
-    LE,         // <=
-    GE,         // >=
-    LOGOR,      // ||
-    LOGAND,     // &&
-    SHL,        // <<
-    SHR,        // >>
-    INC,        // ++
-    DEC,        // --
-    MUL_EQ,     // *=
-    DIV_EQ,     // /=
-    MOD_EQ,     // %=
-    ADD_EQ,     // +=
-    SUB_EQ,     // -=
-    SHL_EQ,     // <<=
-    SHR_EQ,     // >>=
-    AND_EQ,     // &=
-    XOR_EQ,     // ^=
-    OR_EQ,      // |=
-    RETURN,     // "return"
-    SIZEOF,     // "sizeof"
-    ALIGNOF,    // "_Alignof"
-    TYPEOF,     // "typeof"
-    PARAM,      // Function-like macro parameter
-    EOF,        // End marker
+    ADD,           // +
+    SUB,           // -
+    MUL,           // *
+    DIV,           // /
+    EQL,           // =
+    LT,            // <
+    GT,            // >
+    OR,            // |
+    HAT,           // ^
+    TILDA,         // ~
+    BRA,           // (
+    KET,           // )
+    C_BRA,         // {
+    C_KET,         // }
+    S_BRA,         // [
+    S_KET,         // ]
+    AMP,           // &
+    MOD,           // %
+    EXCLAM,        // !
+    QUEST,         // ?
+    DOT,           // .
+    COMMA,         // ,
+    COLON,         // :
+    SEMI_COLON,    // ;
+    SHARP,         // #
+    NEW_LINE,      // \n
+    NUM,           // Number literal
+    STR,           // String literal
+    IDENT,         // Identifier
+    ARROW,         // "->"
+    EXTERN,        // "extern"
+    TYPEDEF,       // "typedef"
+    INT,           // "int"
+    LONG,          // "long"
+    CHAR,          // "char"
+    VOID,          // "void"
+    STRUCT,        // "struct"
+    BOOL,          // "_Bool"
+    IF,            // "if"
+    ELSE,          // "else"
+    FOR,           // "for"
+    DO,            // "do"
+    WHILE,         // "while"
+    SWITCH,        // "switch"
+    CASE,          // "case"
+    DEFAULT,       // "default"
+    BREAK,         // "break"
+    CONTINUE,      // "continue"
+    EQ,            // ==
+    NE,            // !=
+    LE,            // <=
+    GE,            // >=
+    LOGOR,         // ||
+    LOGAND,        // &&
+    SHL,           // <<
+    SHR,           // >>
+    INC,           // ++
+    DEC,           // --
+    MUL_EQ,        // *=
+    DIV_EQ,        // /=
+    MOD_EQ,        // %=
+    ADD_EQ,        // +=
+    SUB_EQ,        // -=
+    SHL_EQ,        // <<=
+    SHR_EQ,        // >>=
+    AND_EQ,        // &=
+    XOR_EQ,        // ^=
+    OR_EQ,         // |=
+    RETURN,        // "return"
+    SIZEOF,        // "sizeof"
+    ALIGNOF,       // "_Alignof"
+    ALIGNAS,       // "_Alignas"
+    ATOMIC,        // "_Atomic"
+    NORETURN,      // "_Noreturn"
+    INLINE,        // "inline"
+    GENERIC,       // "_Generic"
+    STATIC_ASSERT, // "_Static_assert"
+    THREAD_LOCAL,  // "_Thread_local"
+    TYPEOF,        // "typeof"
+    ATTRIBUTE,     // "__attribute__"
+    ASM,           // "asm"
+    VOLATILE,      // "volatile"
+    PARAM,         // Function-like macro parameter
+    EOF,           // End marker
+}
+
+// Encoding prefix of a character or string literal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    CHAR,  // No prefix
+    UTF8,  // u8
+    UTF16, // u
+    UTF32, // U
+    WCHAR, // L
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub ty: TokenType, // Token type
-    pub val: i32,      // Nuber literal
-    pub name: String,  // Identifier
+    pub val: i64,      // Nuber literal
+    pub is_long: bool,
+    pub is_unsigned: bool,
+    pub name: String, // Identifier
 
     // String literal
     pub str_cnt: String,
     pub len: usize,
+    pub enc: Encoding,
 
     // For preprocessor
     pub stringize: bool,
@@ -310,9 +335,50 @@ pub struct Token {
 }
 
 impl Token {
+    // Concatenating an unprefixed literal with a prefixed one yields
+    // the prefixed encoding.
     fn append(&mut self, t: &Token) {
-        self.str_cnt.pop(); // pop '\0'
-        self.str_cnt.push_str(&t.str_cnt);
+        let enc = if self.enc == Encoding::CHAR {
+            t.enc
+        } else {
+            self.enc
+        };
+        if t.enc != Encoding::CHAR && t.enc != enc {
+            bad_token(
+                t,
+                "unsupported non-standard concatenation of string literals".to_string(),
+            );
+        }
+
+        let mut units = self.units_in(enc);
+        units.append(&mut t.units_in(enc));
+        self.str_cnt = encode_units(&units, enc);
+        self.enc = enc;
+    }
+
+    // Returns code units of a string literal converted to a given
+    // encoding.
+    fn units_in(&self, enc: Encoding) -> Vec<u32> {
+        let units = decode_units(&self.str_cnt, self.enc);
+        if unit_size(self.enc) == unit_size(enc) {
+            return units;
+        }
+
+        let bytes: Vec<u8> = units.iter().map(|u| *u as u8).collect();
+        let mut v = Vec::new();
+        for c in String::from_utf8_lossy(&bytes).chars() {
+            push_code_point(&mut v, u32::from(c), enc);
+        }
+        return v;
+    }
+
+    // Returns the contents of a narrow string literal.
+    pub fn string(&self) -> String {
+        let bytes: Vec<u8> = decode_units(&self.str_cnt, Encoding::CHAR)
+            .iter()
+            .map(|u| *u as u8)
+            .collect();
+        return String::from_utf8_lossy(&bytes).to_string();
     }
 }
 
@@ -320,10 +386,13 @@ pub fn new_token(ty: TokenType, start: usize) -> Token {
     Token {
         ty: ty,
         val: 0,
+        is_long: false,
+        is_unsigned: false,
         name: String::new(),
 
         str_cnt: String::new(),
         len: 0,
+        enc: Encoding::CHAR,
 
         stringize: false,
 
@@ -387,72 +456,70 @@ fn new_env(prev: Option<Env>, path: String, buf: String) -> Env {
 
 // Returns true if s1 starts with s2
 fn startswith(s1: &String, pos: usize, s2: &str) -> bool {
-    let len = s2.len();
-    if s1.len() < pos + len {
-        false;
-    }
-    return &s1[pos..pos + len] == s2;
+    return s1.as_bytes()[pos..].starts_with(s2.as_bytes());
 }
 
 // Error reporting
 
 // Finds a line pointed by a given pointer from the input file
-// to print it out.
-pub fn print_line(start: &String, path: &String, pos: usize) {
-    let mut line = 0;
-    let mut col = 0;
-
-    let mut target = String::new();
-    let mut idx = 0;
-    let input = start;
-    let bytes = input.as_bytes();
-    while idx < bytes.len() {
-        let p = char::from(bytes[idx]);
-        if p == '\n' && idx != pos {
-            target = String::new();
-            line += 1;
-            col = 0;
-            idx += 1;
-            continue;
-        }
-
-        if idx != pos {
-            target.push(p);
-            col += 1;
-            idx += 1;
-            continue;
-        }
+// to print it out. `severity` is "error" or "warning".
+pub fn print_line(severity: &str, start: &String, path: &String, pos: usize) {
+    let input = &start[..];
+
+    // `pos` is a byte offset. Columns are counted in characters so
+    // that they are right for non-ASCII source.
+    let begin = match input[..pos].rfind('\n') {
+        Some(i) => i + 1,
+        None => 0,
+    };
+    let end = match input[pos..].find('\n') {
+        Some(i) => pos + i,
+        None => input.len(),
+    };
+    let line = input[..pos].matches('\n').count();
+    let col = input[begin..pos].chars().count();
 
-        eprintln!("error at {}:{}:{}", path, line + 1, col + 1);
-        eprintln!();
+    eprintln!("{} at {}:{}:{}", severity, path, line + 1, col + 1);
+    eprintln!();
 
-        // Print out the line containing the error location.
-        while char::from(bytes[idx]) != '\n' {
-            target.push(char::from(bytes[idx]));
-            idx += 1;
-        }
-        eprintln!("{}", target);
+    // Print out the line containing the error location.
+    eprintln!("{}", &input[begin..end]);
 
-        // Show tabs for tabs and spaces for other characters
-        // so that the column matches
-        for i in 0..col {
-            if char::from(bytes[i]) == '\t' {
-                eprint!("\t");
-            } else {
-                eprint!(" ");
-            }
+    // Show tabs for tabs and spaces for other characters
+    // so that the column matches
+    for c in input[begin..pos].chars() {
+        if c == '\t' {
+            eprint!("\t");
+        } else {
+            eprint!("{}", " ".repeat(char_width(c)));
         }
-        eprintln!("^");
-        eprintln!();
-        return;
     }
+    eprintln!("^");
+    eprintln!();
+}
+
+// Returns the number of terminal columns a character occupies. East
+// Asian wide and fullwidth characters take two.
+fn char_width(c: char) -> usize {
+    let c = u32::from(c);
+    let wide = (0x1100 <= c && c <= 0x115f)
+        || (0x2e80 <= c && c <= 0xa4cf && c != 0x303f)
+        || (0xac00 <= c && c <= 0xd7a3)
+        || (0xf900 <= c && c <= 0xfaff)
+        || (0xfe30 <= c && c <= 0xfe4f)
+        || (0xff00 <= c && c <= 0xff60)
+        || (0xffe0 <= c && c <= 0xffe6)
+        || (0x1f300 <= c && c <= 0x1f64f)
+        || (0x1f900 <= c && c <= 0x1f9ff)
+        || (0x20000 <= c && c <= 0x3fffd);
+    return if wide { 2 } else { 1 };
 }
 
 #[macro_export]
 macro_rules! warn_token {
     ($t:expr, $msg:expr) => {
         if $t.start > 0 {
-            print_line(&$t.buf, &$t.path, $t.start);
+            print_line("warning", &$t.buf, &$t.path, $t.start);
         }
         eprintln!("{}", $msg);
     };
@@ -463,12 +530,15 @@ macro_rules! warn_token {
 // If msg is &'static str, format! create a temporary variable
 // and it can't live long enough.
 pub fn bad_token(t: &Token, msg: String) {
-    warn_token!(t, msg);
+    if t.start > 0 {
+        print_line("error", &t.buf, &t.path, t.start);
+    }
+    eprintln!("{}", msg);
     panic!();
 }
 
 pub fn bad_position(idx: usize, msg: String) {
-    print_line(&buf(), &path(), idx);
+    print_line("error", &buf(), &path(), idx);
     panic!(msg);
 }
 
@@ -508,7 +578,9 @@ pub fn stringize(tokens: Vec<Token>) -> String {
             sb.push(' ');
         }
         assert!(t.start != 0 || t.end != 0);
-        sb.push_str(&t.buf[t.start..t.end]);
+        for b in t.buf[t.start..t.end].bytes() {
+            sb.push(char::from(b));
+        }
     }
     sb.push('\0'); // EOS
     return sb;
@@ -529,18 +601,36 @@ fn block_comment(p: &String, idx: usize) -> usize {
 
 fn keyword_map() -> HashMap<String, TokenType> {
     let mut keywords = HashMap::new();
+    keywords.insert("__asm".to_string(), TokenType::ASM);
+    keywords.insert("__asm__".to_string(), TokenType::ASM);
+    keywords.insert("__attribute".to_string(), TokenType::ATTRIBUTE);
+    keywords.insert("__attribute__".to_string(), TokenType::ATTRIBUTE);
+    keywords.insert("__inline".to_string(), TokenType::INLINE);
+    keywords.insert("__inline__".to_string(), TokenType::INLINE);
+    keywords.insert("__volatile".to_string(), TokenType::VOLATILE);
+    keywords.insert("__volatile__".to_string(), TokenType::VOLATILE);
+    keywords.insert("_Alignas".to_string(), TokenType::ALIGNAS);
     keywords.insert("_Alignof".to_string(), TokenType::ALIGNOF);
+    keywords.insert("_Atomic".to_string(), TokenType::ATOMIC);
     keywords.insert("_Bool".to_string(), TokenType::BOOL);
+    keywords.insert("_Generic".to_string(), TokenType::GENERIC);
+    keywords.insert("_Noreturn".to_string(), TokenType::NORETURN);
+    keywords.insert("_Static_assert".to_string(), TokenType::STATIC_ASSERT);
+    keywords.insert("_Thread_local".to_string(), TokenType::THREAD_LOCAL);
+    keywords.insert("asm".to_string(), TokenType::ASM);
     keywords.insert("break".to_string(), TokenType::BREAK);
     keywords.insert("case".to_string(), TokenType::CASE);
     keywords.insert("char".to_string(), TokenType::CHAR);
     keywords.insert("continue".to_string(), TokenType::CONTINUE);
+    keywords.insert("default".to_string(), TokenType::DEFAULT);
     keywords.insert("do".to_string(), TokenType::DO);
     keywords.insert("else".to_string(), TokenType::ELSE);
     keywords.insert("extern".to_string(), TokenType::EXTERN);
     keywords.insert("for".to_string(), TokenType::FOR);
     keywords.insert("if".to_string(), TokenType::IF);
+    keywords.insert("inline".to_string(), TokenType::INLINE);
     keywords.insert("int".to_string(), TokenType::INT);
+    keywords.insert("long".to_string(), TokenType::LONG);
     keywords.insert("return".to_string(), TokenType::RETURN);
     keywords.insert("sizeof".to_string(), TokenType::SIZEOF);
     keywords.insert("struct".to_string(), TokenType::STRUCT);
@@ -548,6 +638,7 @@ fn keyword_map() -> HashMap<String, TokenType> {
     keywords.insert("typedef".to_string(), TokenType::TYPEDEF);
     keywords.insert("typeof".to_string(), TokenType::TYPEOF);
     keywords.insert("void".to_string(), TokenType::VOID);
+    keywords.insert("volatile".to_string(), TokenType::VOLATILE);
     keywords.insert("while".to_string(), TokenType::WHILE);
     return keywords;
 }
@@ -568,109 +659,204 @@ fn isoctal(c: char) -> bool {
     '0' <= c && c <= '7'
 }
 
-fn hex(c: char) -> Option<i32> {
-    match c.to_digit(16) {
-        Some(i) => Some(i as i32),
-        None => None,
+// Reads a universal character name, `\uXXXX` or `\UXXXXXXXX`. `idx`
+// points to the backslash.
+fn ucn(t: &Token, p: &String, idx: usize) -> (u32, usize) {
+    let char_bytes = p.as_bytes();
+    let n = if char_bytes[idx + 1] == b'u' { 4 } else { 8 };
+    let mut c: u32 = 0;
+    for i in 0..n {
+        match char::from(char_bytes[idx + 2 + i]).to_digit(16) {
+            Some(d) => c = c * 16 + d,
+            None => bad_token(t, "incomplete universal character name".to_string()),
+        }
+    }
+
+    // C11 6.4.3p2: a UCN shall not designate a surrogate or a character
+    // in the basic character set.
+    let is_basic = c < 0xa0 && c != 0x24 && c != 0x40 && c != 0x60;
+    if c > 0x10ffff || (0xd800 <= c && c <= 0xdfff) || is_basic {
+        bad_token(
+            t,
+            format!(
+                "{} is not a valid universal character",
+                &p[idx..idx + 2 + n]
+            ),
+        );
     }
+    return (c, n + 2);
 }
 
-// Read a single character in a char or string literal.
-fn c_char(t: &mut Token, p: &String, idx: usize) -> TokenInfo {
+// Read a single character in a char or string literal. Returns the
+// value, whether the value is a code point (as opposed to a code unit
+// given by an octal or hexadecimal escape) and the number of bytes read.
+fn c_char(t: &Token, p: &String, idx: usize) -> (u32, bool, usize) {
     let char_bytes = p.as_bytes();
-    let mut c = char::from(char_bytes[idx]);
-    let mut len = 0;
-
-    // Nonescaped
-    if c != '\\' {
-        t.val = u32::from(c) as i32;
-        return TokenInfo {
-            token: t.clone(),
-            len: len + 1,
-        };
+
+    // Nonescaped. The source is UTF-8, so read a whole code point.
+    if char_bytes[idx] != b'\\' {
+        let c = p[idx..].chars().next().unwrap();
+        return (u32::from(c), true, c.len_utf8());
     }
 
-    len += 1;
-    c = char::from(char_bytes[idx + len]);
+    let c = p[idx + 1..].chars().next().unwrap();
 
     // Simple (e.g. `\n` or `\a`)
-    match escaped(c) {
-        Some(esc) => {
-            c = esc;
-
-            t.val = u32::from(c) as i32;
-            return TokenInfo {
-                token: t.clone(),
-                len: len + 1,
-            };
-        }
-        None => {}
+    if let Some(esc) = escaped(c) {
+        return (u32::from(esc), true, 2);
+    }
+
+    // Universal character name
+    if c == 'u' || c == 'U' {
+        let (c, len) = ucn(t, p, idx);
+        return (c, true, len);
     }
 
     // Hexadecimal
     if c == 'x' {
-        let mut res = 0;
-        len += 1;
-        loop {
-            c = char::from(char_bytes[idx + len]);
-            if let Some(i) = c.to_digit(16) {
-                res = res * 16 + i as i32;
-                len += 1;
-            } else {
-                break;
+        let mut res: u32 = 0;
+        let mut len = 2;
+        while let Some(i) = char::from(char_bytes[idx + len]).to_digit(16) {
+            match res.checked_mul(16) {
+                Some(r) => res = r + i,
+                None => bad_token(t, "hex escape sequence out of range".to_string()),
             }
+            len += 1;
         }
-
-        t.val = res;
-        return TokenInfo {
-            token: t.clone(),
-            len: len,
-        };
+        if len == 2 {
+            bad_token(t, "\\x used with no following hex digits".to_string());
+        }
+        return (res, false, len);
     }
 
     // Octal
     if isoctal(c) {
-        let mut i = c.to_digit(8).unwrap();
-        len += 1;
-        c = char::from(char_bytes[idx + len]);
-        if isoctal(c) {
-            i = i * 8 + c.to_digit(8).unwrap();
+        let mut i = 0;
+        let mut len = 1;
+        while len < 4 && isoctal(char::from(char_bytes[idx + len])) {
+            i = i * 8 + char::from(char_bytes[idx + len]).to_digit(8).unwrap();
             len += 1;
-            c = char::from(char_bytes[idx + len]);
         }
-        if isoctal(c) {
-            i = i * 8 + c.to_digit(8).unwrap();
-            len += 1;
+        return (i, false, len);
+    }
+
+    return (u32::from(c), true, 1 + c.len_utf8());
+}
+
+// Returns the length of an encoding prefix.
+fn prefix_len(enc: Encoding) -> usize {
+    match enc {
+        Encoding::CHAR => 0,
+        Encoding::UTF8 => 2,
+        _ => 1,
+    }
+}
+
+// Returns the size of a code unit in bytes.
+pub fn unit_size(enc: Encoding) -> usize {
+    match enc {
+        Encoding::CHAR | Encoding::UTF8 => 1,
+        Encoding::UTF16 => 2,
+        Encoding::UTF32 | Encoding::WCHAR => 4,
+    }
+}
+
+// Appends a code point as code units of a given encoding.
+fn push_code_point(units: &mut Vec<u32>, c: u32, enc: Encoding) {
+    let c = std::char::from_u32(c).unwrap();
+    match enc {
+        Encoding::CHAR | Encoding::UTF8 => {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                units.push(u32::from(b));
+            }
         }
+        Encoding::UTF16 => {
+            let mut buf = [0; 2];
+            for u in c.encode_utf16(&mut buf).iter() {
+                units.push(u32::from(*u));
+            }
+        }
+        Encoding::UTF32 | Encoding::WCHAR => units.push(u32::from(c)),
+    }
+}
 
-        t.val = i as i32;
-        return TokenInfo {
-            token: t.clone(),
-            len: len,
-        };
+// Appends the result of c_char() to code units of a given encoding.
+fn push_c_char(t: &Token, units: &mut Vec<u32>, c: u32, is_code_point: bool, enc: Encoding) {
+    if is_code_point {
+        push_code_point(units, c, enc);
+        return;
     }
 
-    t.val = u32::from(c) as i32;
-    return TokenInfo {
-        token: t.clone(),
-        len: 2,
-    };
+    let size = unit_size(enc);
+    if size < 4 && c >> (size * 8) != 0 {
+        bad_token(t, "escape sequence out of range".to_string());
+    }
+    units.push(c);
 }
 
-fn char_literal(p: &String, idx: usize) -> TokenInfo {
-    let mut t = new_token(TokenType::NUM, idx);
-    let mut len = 1;
+// String literals are kept in `str_cnt` as a sequence of bytes, one
+// char per byte, including the terminating null code unit. Multibyte
+// code units are stored in little endian.
+fn encode_units(units: &Vec<u32>, enc: Encoding) -> String {
+    let mut s = String::new();
+    for u in units.iter().chain([0].iter()) {
+        for i in 0..unit_size(enc) {
+            s.push(char::from((u >> (i * 8)) as u8));
+        }
+    }
+    return s;
+}
 
-    let mut info = c_char(&mut t, p, idx + len);
-    len += info.len;
+fn decode_units(s: &String, enc: Encoding) -> Vec<u32> {
+    let bytes: Vec<u8> = s.chars().map(|c| u32::from(c) as u8).collect();
+    let mut units = Vec::new();
+    for chunk in bytes.chunks(unit_size(enc)) {
+        let mut u = 0;
+        for i in 0..chunk.len() {
+            u |= u32::from(chunk[i]) << (i * 8);
+        }
+        units.push(u);
+    }
+    units.pop(); // pop '\0'
+    return units;
+}
 
-    if &p[idx + len..idx + len + 1] != "\'" {
-        error_char!(&t);
+fn char_literal(p: &String, idx: usize, enc: Encoding) -> TokenInfo {
+    let mut t = new_token(TokenType::NUM, idx);
+    let mut len = prefix_len(enc) + 1;
+    let char_bytes = p.as_bytes();
+
+    let mut units = Vec::new();
+    while char_bytes[idx + len] != b'\'' {
+        if char_bytes[idx + len] == b'\n' {
+            error_char!(&t);
+        }
+        let (c, is_code_point, n) = c_char(&t, p, idx + len);
+        push_c_char(&t, &mut units, c, is_code_point, enc);
+        len += n;
     }
     len += 1;
-    info.len = len;
-    info.token.end = idx + info.len;
-    return info;
+
+    if units.is_empty() {
+        bad_token(&t, "empty character constant".to_string());
+    }
+
+    if units.len() == 1 {
+        t.val = i64::from(units[0]);
+    } else if enc == Encoding::CHAR {
+        // A multi-character constant such as 'ab' or a non-ASCII
+        // character. The value is implementation-defined; we follow GCC.
+        for u in units.iter() {
+            t.val = (t.val << 8 | i64::from(*u)) as i32 as i64;
+        }
+    } else {
+        bad_token(&t, "character constant too long for its type".to_string());
+    }
+
+    t.enc = enc;
+    t.end = idx + len;
+    return TokenInfo { token: t, len: len };
 }
 
 macro_rules! error_str {
@@ -679,55 +865,80 @@ macro_rules! error_str {
     };
 }
 
-fn string_literal(p: &String, idx: usize) -> TokenInfo {
+fn string_literal(p: &String, idx: usize, enc: Encoding) -> TokenInfo {
     let mut t = new_token(TokenType::STR, idx);
-
-    let mut len = 1;
-    let mut ret = String::new();
-
+    let mut len = prefix_len(enc) + 1;
     let char_bytes = p.as_bytes();
-    while (idx + len) < p.len() && char::from(char_bytes[idx + len]) != '"' {
-        let mut t = new_token(TokenType::NUM, idx);
-        let info = c_char(&mut t, p, idx + len);
-        let c = char::from(info.token.val as u8);
-        ret.push(c);
-        len += info.len;
+
+    let mut units = Vec::new();
+    while (idx + len) < p.len() && char_bytes[idx + len] != b'"' {
+        let (c, is_code_point, n) = c_char(&t, p, idx + len);
+        push_c_char(&t, &mut units, c, is_code_point, enc);
+        len += n;
     }
     if (idx + len) >= p.len() {
         error_str!(&t);
     }
-    //ret.push(&format!("\\{:03o}", u32::from(c)));
-    ret.push(char::from(0));
-    t.str_cnt = ret;
+    len += 1;
+
+    t.enc = enc;
+    t.str_cnt = encode_units(&units, enc);
     t.len = len;
-    t.end = idx + len + 1;
+    t.end = idx + len;
     return TokenInfo { token: t, len: len };
 }
 
+// Returns the encoding of a character or string literal starting at
+// `idx`, or None if `idx` does not start a literal.
+fn literal_prefix(p: &String, idx: usize) -> Option<Encoding> {
+    let prefixes = [
+        ("", Encoding::CHAR),
+        ("u8", Encoding::UTF8),
+        ("u", Encoding::UTF16),
+        ("U", Encoding::UTF32),
+        ("L", Encoding::WCHAR),
+    ];
+    for (s, enc) in prefixes.iter() {
+        if startswith(p, idx, &format!("{}\"", s)) || startswith(p, idx, &format!("{}'", s)) {
+            return Some(*enc);
+        }
+    }
+    return None;
+}
+
+// Returns true if `c` can be a part of an identifier. Non-ASCII
+// letters are allowed as in GCC.
+fn is_ident_char(c: char, first: bool) -> bool {
+    return c == '_' || c.is_alphabetic() || (!first && c.is_alphanumeric());
+}
+
 fn ident(p: &String, idx: usize) -> TokenInfo {
+    let mut t = new_token(TokenType::IDENT, idx);
     let mut ret = idx;
 
     let mut name = String::new();
-    name.push_str(&p[ret..ret + 1]);
-    ret += 1;
     while ret < p.len() {
-        let b = &p[ret..ret + 1].as_bytes();
-        let d = char::from(b[0]);
-        if d.is_alphabetic() || d.is_digit(10) || d == '_' {
-            name.push(d);
-            ret += 1;
-        } else {
+        // Universal character name
+        if startswith(p, ret, "\\u") || startswith(p, ret, "\\U") {
+            let (c, len) = ucn(&t, p, ret);
+            name.push(std::char::from_u32(c).unwrap());
+            ret += len;
+            continue;
+        }
+
+        let c = p[ret..].chars().next().unwrap();
+        if !is_ident_char(c, ret == idx) {
             break;
         }
+        name.push(c);
+        ret += c.len_utf8();
     }
 
-    let ty = match keywords_get(&name) {
+    t.ty = match keywords_get(&name) {
         Some(k) => k,
         None => TokenType::IDENT,
     };
-
-    let mut t = new_token(ty, idx);
-    t.name = name.clone();
+    t.name = name;
     t.end = ret;
 
     return TokenInfo {
@@ -736,51 +947,105 @@ fn ident(p: &String, idx: usize) -> TokenInfo {
     };
 }
 
-fn hexadecimal(p: &String, idx: usize) -> TokenInfo {
-    let mut t = new_token(TokenType::NUM, idx);
-    let mut ret = idx + 2;
-
-    if !char::from((&p[ret..ret + 1].as_bytes())[0]).is_ascii_hexdigit() {
-        bad_token(&t, "bad hexadecimal number".to_string());
+// Reads an integer suffix. Returns (is_unsigned, is_long).
+fn int_suffix(s: &str) -> Option<(bool, bool)> {
+    let mut l = s;
+    let mut is_unsigned = false;
+    if l.starts_with(|c| c == 'u' || c == 'U') {
+        l = &l[1..];
+        is_unsigned = true;
+    } else if l.ends_with(|c| c == 'u' || c == 'U') {
+        l = &l[..l.len() - 1];
+        is_unsigned = true;
     }
 
-    while let Some(i) = hex(char::from((&p[ret..ret + 1].as_bytes())[0])) {
-        t.val = t.val * 16 + i as i32;
-        ret += 1;
+    // `long long` is the same as `long` in this compiler.
+    match l {
+        "" => Some((is_unsigned, false)),
+        "l" | "L" | "ll" | "LL" => Some((is_unsigned, true)),
+        _ => None,
     }
-
-    t.end = ret;
-    return TokenInfo {
-        token: t,
-        len: ret - idx,
-    };
 }
 
-fn octal(p: &String, idx: usize) -> TokenInfo {
+// Reads an integer literal. Its type is the first one of int,
+// unsigned int, long and unsigned long that can represent the value
+// (C11 6.4.4.1p5). Decimal literals without `u` are never unsigned
+// unless they don't fit in long.
+fn number(p: &String, idx: usize) -> TokenInfo {
     let mut t = new_token(TokenType::NUM, idx);
-    let mut ret = idx + 1;
+    let char_bytes = p.as_bytes();
+
+    let (base, mut ret) = if startswith(p, idx, "0x") || startswith(p, idx, "0X") {
+        (16, idx + 2)
+    } else if startswith(p, idx, "0b") || startswith(p, idx, "0B") {
+        (2, idx + 2)
+    } else if char_bytes[idx] == b'0' {
+        (8, idx)
+    } else {
+        (10, idx)
+    };
 
-    while let Some(i) = util::first_char(&p[ret..ret + 1]).to_digit(8) {
-        t.val = t.val * 8 + i as i32;
+    let radix = if base == 16 { 16 } else { 10 };
+    let mut val: u64 = 0;
+    let mut overflow = false;
+    let start = ret;
+    while let Some(d) = char::from(char_bytes[ret]).to_digit(radix) {
+        if d >= base {
+            let kind = if base == 8 { "octal" } else { "binary" };
+            bad_token(&t, format!("invalid digit \"{}\" in {} constant", d, kind));
+        }
+        match val.checked_mul(u64::from(base)) {
+            Some(v) => match v.checked_add(u64::from(d)) {
+                Some(v) => val = v,
+                None => overflow = true,
+            },
+            None => overflow = true,
+        }
         ret += 1;
     }
-    t.end = ret;
+    if ret == start {
+        let kind = if base == 16 { "hexadecimal" } else { "binary" };
+        bad_token(&t, format!("bad {} number", kind));
+    }
 
-    return TokenInfo {
-        token: t,
-        len: ret - idx,
+    let suffix_start = ret;
+    while char_bytes[ret].is_ascii_alphanumeric() || char_bytes[ret] == b'_' {
+        ret += 1;
+    }
+    let suffix = &p[suffix_start..ret];
+    let (is_unsigned, is_long) = match int_suffix(suffix) {
+        Some(x) => x,
+        None => {
+            bad_token(
+                &t,
+                format!("invalid suffix \"{}\" on integer constant", suffix),
+            );
+            panic!();
+        }
     };
-}
 
-fn decimal(p: &String, idx: usize) -> TokenInfo {
-    let mut t = new_token(TokenType::NUM, idx);
-    let mut ret = idx;
+    if overflow {
+        bad_token(&t, "integer constant is too large for its type".to_string());
+    }
 
-    while let Some(i) = util::first_char(&p[ret..ret + 1]).to_digit(10) {
-        t.val = t.val * 10 + i as i32;
-        ret += 1;
+    if !is_long && !is_unsigned && val <= i32::max_value() as u64 {
+        // int
+    } else if !is_long && (is_unsigned || base != 10) && val <= u64::from(u32::max_value()) {
+        t.is_unsigned = true;
+    } else {
+        t.is_long = true;
+        if is_unsigned || (base != 10 && val > i64::max_value() as u64) {
+            t.is_unsigned = true;
+        } else if val > i64::max_value() as u64 {
+            warn_token!(
+                t,
+                "integer constant is so large that it is unsigned".to_string()
+            );
+            t.is_unsigned = true;
+        }
     }
 
+    t.val = val as i64;
     t.end = ret;
     return TokenInfo {
         token: t,
@@ -788,16 +1053,6 @@ fn decimal(p: &String, idx: usize) -> TokenInfo {
     };
 }
 
-fn number(p: &String, idx: usize) -> TokenInfo {
-    if startswith(p, idx, "0x") || startswith(p, idx, "0X") {
-        return hexadecimal(p, idx);
-    }
-    if &p[idx..idx + 1] == "0" {
-        return octal(p, idx);
-    }
-    return decimal(p, idx);
-}
-
 fn scan() {
     init_symbols();
     init_escaped();
@@ -808,7 +1063,7 @@ fn scan() {
     let char_bytes = p.as_bytes();
     let mut idx = 0;
     'outer: while idx < char_bytes.len() {
-        let c: char = char::from(char_bytes[idx]);
+        let c = p[idx..].chars().next().unwrap();
         // New line (preprocessor-only token)
         if c == '\n' {
             let mut t = new_token(TokenType::NEW_LINE, idx);
@@ -820,7 +1075,7 @@ fn scan() {
 
         // Whitespace
         if c.is_whitespace() {
-            idx += 1;
+            idx += c.len_utf8();
             continue;
         }
 
@@ -838,22 +1093,18 @@ fn scan() {
             continue;
         }
 
-        // Character literal
-        if c == '\'' {
-            let info = char_literal(p, idx);
+        // Character or string literal
+        if let Some(enc) = literal_prefix(p, idx) {
+            let info = if char_bytes[idx + prefix_len(enc)] == b'\'' {
+                char_literal(p, idx, enc)
+            } else {
+                string_literal(p, idx, enc)
+            };
             add(info.token);
             idx += info.len;
             continue;
         }
 
-        // String literal
-        if c == '"' {
-            let info = string_literal(p, idx);
-            add(info.token);
-            idx += info.len + 1;
-            continue;
-        }
-
         // Multi-letter symbol
         for s in symbols.iter() {
             if !startswith(p, idx, s.name) {
@@ -905,7 +1156,7 @@ fn scan() {
         }
 
         // Keyword or identifier
-        if c.is_alphabetic() || c == '_' {
+        if is_ident_char(c, true) || startswith(p, idx, "\\u") || startswith(p, idx, "\\U") {
             let info = ident(p, idx);
             add(info.token);
             idx += info.len;
@@ -925,17 +1176,7 @@ fn scan() {
 }
 
 fn replace_crlf(p: String) -> String {
-    let mut cnt = String::new();
-    let mut i = 0;
-    while i < p.len() {
-        // TODO fix
-        if i + 1 < p.len() && startswith(&p, i, "\r\n") {
-            i += 1;
-        }
-        cnt.push_str(&p[i..i + 1]);
-        i += 1;
-    }
-    return cnt;
+    return p.replace("\r\n", "\n");
 }
 
 // Concatenates continuation lines. We keep the total number of
@@ -943,25 +1184,23 @@ fn replace_crlf(p: String) -> String {
 fn remove_backslash_newline(p: String) -> String {
     let mut cnt = 0;
     let mut ret = String::new();
-    let mut i = 0;
-    while i < p.len() {
-        if i + 1 < p.len() && startswith(&p, i, "\\\n") {
+    let mut chars = p.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'\n') {
             cnt += 1;
-            i += 2;
+            chars.next();
             continue;
         }
 
-        if &p[i..i + 1] == "\n" {
+        if c == '\n' {
             for _i in 0..cnt + 1 {
                 ret.push('\n');
             }
-            i += 1;
             cnt = 0;
             continue;
         }
 
-        ret.push_str(&p[i..i + 1]);
-        i += 1;
+        ret.push(c);
     }
     return ret;
 }
@@ -2,10 +2,71 @@
 
 use crate::parse::*;
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 
 thread_local! {
     static NLABEL: RefCell<usize> = RefCell::new(1);
+    static OPTIONS: RefCell<Options> = RefCell::new(default_options());
+}
+
+// Register allocation algorithm (-fregalloc=)
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegAlloc {
+    Linear, // linear scan
+    Graph,  // graph coloring
+}
+
+// Optimization level (-O)
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    Os, // -O2 with less inlining
+}
+
+// Command line options which change the behavior of the compiler.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub warn_unused: bool,            // -Wunused
+    pub common: bool,                 // -fcommon
+    pub lzcnt: bool,                  // -mlzcnt
+    pub bmi: bool,                    // -mbmi (tzcnt)
+    pub popcnt: bool,                 // -mpopcnt
+    pub regalloc: RegAlloc,           // -fregalloc=
+    pub omit_frame_pointer: bool,     // -fomit-frame-pointer
+    pub opt_level: OptLevel,          // -O0, -O1, -O2 or -Os
+    pub disabled_passes: Vec<String>, // -fno-<pass>
+    pub dump_before: Vec<String>,     // -dump-before=<pass>
+    pub dump_after: Vec<String>,      // -dump-after=<pass>
+    pub verify_each: bool,            // -verify-each
+}
+
+pub fn default_options() -> Options {
+    Options {
+        warn_unused: false,
+        common: false,
+        lzcnt: false,
+        bmi: false,
+        popcnt: false,
+        regalloc: RegAlloc::Linear,
+        omit_frame_pointer: false,
+        opt_level: OptLevel::O2,
+        disabled_passes: Vec::new(),
+        dump_before: Vec::new(),
+        dump_after: Vec::new(),
+        verify_each: false,
+    }
+}
+
+pub fn options() -> Options {
+    OPTIONS.with(|o| o.borrow().clone())
+}
+
+pub fn set_options(opts: Options) {
+    OPTIONS.with(|o| {
+        *o.borrow_mut() = opts;
+    })
 }
 
 pub fn bump_nlabel() -> usize {
@@ -25,11 +86,8 @@ pub fn vec_union<'a, T: Clone + PartialEq>(v: Rc<RefCell<Vec<T>>>, elem: &T) ->
     return true;
 }
 
-pub fn first_char(s: &str) -> char {
-    if s.len() == 0 {
-        return '\0';
-    }
-    return char::from((&s[0..1].as_bytes())[0]);
+pub fn is_incomplete_struct(ty: &Type) -> bool {
+    return ty.ty == CType::STRUCT && ty.members.is_none();
 }
 
 pub fn roundup(x: i32, align: i32) -> i32 {
@@ -37,6 +95,15 @@ pub fn roundup(x: i32, align: i32) -> i32 {
 }
 
 pub fn ptr_to(base: Rc<RefCell<Type>>) -> Type {
+    // A pointer to an incomplete struct points to the shared
+    // definition so that it sees the members once completed.
+    let def = if is_incomplete_struct(&base.borrow()) {
+        base.borrow().def.as_ref().unwrap().upgrade()
+    } else {
+        None
+    };
+    let base = def.unwrap_or(base);
+
     let mut ty = alloc_type();
     ty.ty = CType::PTR;
     ty.size = 8;
@@ -75,10 +142,18 @@ pub fn char_ty() -> Type {
     return new_ty(CType::CHAR, 1);
 }
 
+pub fn short_ty() -> Type {
+    return new_ty(CType::SHORT, 2);
+}
+
 pub fn int_ty() -> Type {
     return new_ty(CType::INT, 4);
 }
 
+pub fn long_ty() -> Type {
+    return new_ty(CType::LONG, 8);
+}
+
 pub fn func_ty(base: Type) -> Type {
     let mut ty = alloc_type();
     ty.returning = Some(Box::new(base));
@@ -86,7 +161,7 @@ pub fn func_ty(base: Type) -> Type {
 }
 
 pub fn same_type(x: Rc<RefCell<Type>>, y: Rc<RefCell<Type>>) -> bool {
-    if x.borrow().ty != y.borrow().ty {
+    if x.borrow().ty != y.borrow().ty || x.borrow().is_unsigned != y.borrow().is_unsigned {
         return false;
     }
 
@@ -104,7 +179,8 @@ pub fn same_type(x: Rc<RefCell<Type>>, y: Rc<RefCell<Type>>) -> bool {
             xx.size == yy.size
                 && same_type(Rc::new(RefCell::new(*xary)), Rc::new(RefCell::new(*yary)))
         }
-        CType::STRUCT | CType::FUNC => *xx == *yy,
+        CType::STRUCT => Weak::ptr_eq(xx.def.as_ref().unwrap(), yy.def.as_ref().unwrap()),
+        CType::FUNC => *xx == *yy,
         _ => true,
     }
 }
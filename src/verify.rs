@@ -0,0 +1,431 @@
+// IR verifier
+//
+// Checks the invariants of the IR which the passes rely on. It runs
+// after every pass in debug builds, or with -verify-each, so that a
+// broken invariant is reported right after the pass which broke it
+// instead of as wrong code far later. The errors are printed along with
+// the IR of the function.
+//
+// Each BB has to end with a jump or a return, which must not appear
+// anywhere else in it, and jumps must go to BBs of the same function.
+// A BB may end with UNREACHABLE or TRAP as well, where the control
+// never continues. `succ` and `pred` have to match the jumps.
+//
+// In SSA form, each register is defined once, except that an in-out
+// operand of inline assembly is written by the ASM as well, and a jump
+// passes as many values as the parameters of the target BB. A
+// conditional branch can't pass values, so the targets of a BR have no
+// parameters. Out of SSA form, there are no block parameters at all.
+//
+// A register has to be defined on every path to its uses, which is
+// checked by a forward dataflow analysis of registers defined on all
+// paths. Before register allocation, a register defined nowhere is the
+// value of an uninitialized variable, which may be used anywhere.
+// liveness.rs defines such registers at the entry BB.
+//
+// After register allocation, every register has a physical register,
+// by which the definitions and uses are checked from then on, and
+// `r0 = r1 op r2` is in two-address form, i.e. r0 and r1 are the same.
+// They must be different before that.
+//
+// Loads and stores have to be of a size gen_x86 can emit.
+
+use crate::alias::*;
+use crate::dom::*;
+use crate::gen_ir::*;
+use crate::gen_x86::*;
+use crate::irdump::*;
+use crate::parse::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// The form of the IR at a point of the pipeline
+#[derive(Clone, Copy, PartialEq)]
+pub enum Form {
+    SSA,
+    // Out of SSA form
+    Lowered,
+    // Registers are allocated.
+    Allocated,
+}
+
+struct Verifier<'a> {
+    fun: &'a Function,
+    form: Form,
+    errors: Vec<String>,
+}
+
+fn contains(v: &Vec<Rc<RefCell<BB>>>, bb: &Rc<RefCell<BB>>) -> bool {
+    return v.iter().any(|b| Rc::ptr_eq(b, bb));
+}
+
+fn reg_name(r: &Rc<RefCell<Reg>>) -> String {
+    return format!("r{}", regno(Some(r.clone())));
+}
+
+fn defs(ir: &IR) -> Vec<Rc<RefCell<Reg>>> {
+    return ir.r0.iter().chain(ir.outs.iter()).cloned().collect();
+}
+
+// Returns the outputs of an ASM which are inputs as well.
+fn tied_outs(ir: &IR) -> Vec<Rc<RefCell<Reg>>> {
+    let is_arg = |r: &&Rc<RefCell<Reg>>| ir.args.iter().any(|a| a.borrow().vn == r.borrow().vn);
+    return ir.outs.iter().filter(is_arg).cloned().collect();
+}
+
+// Sets of registers are bit sets indexed by `index`.
+fn set_bit(set: &mut Vec<u64>, index: &HashMap<i32, usize>, vn: i32) {
+    if let Some(&n) = index.get(&vn) {
+        set[n / 64] |= 1 << (n % 64);
+    }
+}
+
+fn test_bit(set: &Vec<u64>, index: &HashMap<i32, usize>, vn: i32) -> bool {
+    match index.get(&vn) {
+        Some(&n) => set[n / 64] & (1 << (n % 64)) != 0,
+        None => false,
+    }
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&mut self, bb: &BB, ir: Option<&IR>, msg: String) {
+        match ir {
+            Some(ir) => self
+                .errors
+                .push(format!(".L{}: {}: {}", bb.label, tostr(ir), msg)),
+            None => self.errors.push(format!(".L{}: {}", bb.label, msg)),
+        }
+    }
+
+    fn check_structure(&mut self) {
+        let fun = self.fun;
+        if fun.bbs.is_empty() {
+            self.errors.push("no BBs".to_string());
+        }
+
+        for bb in fun.bbs.iter() {
+            let bb = bb.borrow();
+            let n = bb.ir.len();
+            let ends = |ir: &Rc<RefCell<IR>>| match ir.borrow().op {
+                IRType::UNREACHABLE | IRType::TRAP => true,
+                _ => is_terminator(ir),
+            };
+            if n == 0 || !ends(&bb.ir[n - 1]) {
+                self.error(&bb, None, "no jump or return at the end".to_string());
+            }
+
+            for (i, ir) in bb.ir.iter().enumerate() {
+                if i + 1 < n && is_terminator(ir) {
+                    self.error(&bb, Some(&ir.borrow()), "before the end".to_string());
+                }
+
+                let ir = ir.borrow();
+                let needs = match ir.op {
+                    IRType::JMP => 1,
+                    IRType::BR => 2,
+                    _ => 0,
+                };
+                for (j, t) in [&ir.bb1, &ir.bb2].iter().enumerate() {
+                    match t {
+                        Some(t) if !contains(&fun.bbs, t) => {
+                            let msg = format!(".L{} is not in the function", t.borrow().label);
+                            self.error(&bb, Some(&ir), msg);
+                        }
+                        None if j < needs => {
+                            self.error(&bb, Some(&ir), "no target".to_string());
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_edges(&mut self) {
+        for bb in self.fun.bbs.iter() {
+            let targets = targets(bb);
+            let b = bb.borrow();
+            for t in targets.iter() {
+                if !contains(&b.succ, t) {
+                    let msg = format!(".L{} is not in succ", t.borrow().label);
+                    self.error(&b, None, msg);
+                }
+            }
+            for s in b.succ.iter() {
+                if !contains(&targets, s) {
+                    let msg = format!("succ .L{} is not a jump target", s.borrow().label);
+                    self.error(&b, None, msg);
+                }
+                if !contains(&s.borrow().pred, bb) {
+                    let msg = format!("not in pred of .L{}", s.borrow().label);
+                    self.error(&b, None, msg);
+                }
+            }
+            for p in b.pred.iter() {
+                if !contains(&p.borrow().succ, bb) || !contains(&self.fun.bbs, p) {
+                    let msg = format!("pred .L{} does not jump here", p.borrow().label);
+                    self.error(&b, None, msg);
+                }
+            }
+        }
+    }
+
+    fn check_params(&mut self) {
+        for bb in self.fun.bbs.iter() {
+            let bb = bb.borrow();
+            if self.form != Form::SSA && !bb.params.is_empty() {
+                self.error(&bb, None, "block parameters out of SSA form".to_string());
+            }
+
+            let ir = match bb.ir.last() {
+                Some(ir) => ir.borrow(),
+                None => continue,
+            };
+            if self.form != Form::SSA && !ir.bbargs.is_empty() {
+                self.error(
+                    &bb,
+                    Some(&ir),
+                    "block arguments out of SSA form".to_string(),
+                );
+            }
+
+            if ir.op == IRType::JMP {
+                let n = ir.bb1.as_ref().unwrap().borrow().params.len();
+                if ir.bbargs.len() != n {
+                    let msg = format!("{} arguments for {} parameters", ir.bbargs.len(), n);
+                    self.error(&bb, Some(&ir), msg);
+                }
+            }
+            if ir.op == IRType::BR {
+                for t in ir.bb1.iter().chain(ir.bb2.iter()) {
+                    if !t.borrow().params.is_empty() {
+                        let msg = format!(".L{} has parameters", t.borrow().label);
+                        self.error(&bb, Some(&ir), msg);
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns the registers defined on all paths to each BB, or None if
+    // the BB is not reachable.
+    fn defined_in(&self, index: &HashMap<i32, usize>) -> Vec<Option<Vec<u64>>> {
+        let bbs = &self.fun.bbs;
+        let bb_index = bb_indices(bbs);
+        let mut preds = vec![Vec::new(); bbs.len()];
+        for (i, bb) in bbs.iter().enumerate() {
+            for t in targets(bb) {
+                if let Some(&j) = bb_index.get(&t.borrow().label) {
+                    preds[j].push(i);
+                }
+            }
+        }
+
+        // Registers defined in each BB
+        let words = (index.len() + 63) / 64;
+        let mut gen = vec![vec![0u64; words]; bbs.len()];
+        for (i, bb) in bbs.iter().enumerate() {
+            let bb = bb.borrow();
+            let params = bb.params.iter().cloned();
+            let defs = bb.ir.iter().flat_map(|ir| defs(&ir.borrow()));
+            for r in params.chain(defs) {
+                set_bit(&mut gen[i], index, self.key(&r));
+            }
+        }
+
+        let mut defined_in: Vec<Option<Vec<u64>>> = vec![None; bbs.len()];
+        let mut defined_out: Vec<Option<Vec<u64>>> = vec![None; bbs.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..bbs.len() {
+                let mut set = if i == 0 { Some(vec![0; words]) } else { None };
+                for &p in preds[i].iter() {
+                    set = match (set, &defined_out[p]) {
+                        (None, out) => out.clone(),
+                        (Some(mut s), Some(out)) => {
+                            for (w, o) in s.iter_mut().zip(out.iter()) {
+                                *w &= *o;
+                            }
+                            Some(s)
+                        }
+                        (s, None) => s,
+                    };
+                }
+
+                let mut out = match set {
+                    Some(ref s) => s.clone(),
+                    None => continue,
+                };
+                for (w, g) in out.iter_mut().zip(gen[i].iter()) {
+                    *w |= *g;
+                }
+                if defined_out[i].as_ref() != Some(&out) {
+                    defined_out[i] = Some(out);
+                    changed = true;
+                }
+                defined_in[i] = set;
+            }
+        }
+        return defined_in;
+    }
+
+    // Registers are identified by physical registers after allocation,
+    // which may be shared by several virtual registers.
+    fn key(&self, r: &Rc<RefCell<Reg>>) -> i32 {
+        if self.form == Form::Allocated {
+            return r.borrow().rn;
+        }
+        return r.borrow().vn;
+    }
+
+    fn check_regs(&mut self) {
+        let ndefs: HashMap<i32, usize> = count_defs(&self.fun.bbs);
+        let allocated = self.form == Form::Allocated;
+        let index: HashMap<i32, usize> = if allocated {
+            (0..num_regs()).map(|rn| (rn as i32, rn)).collect()
+        } else {
+            ndefs.keys().enumerate().map(|(n, &vn)| (vn, n)).collect()
+        };
+        let defined_in = self.defined_in(&index);
+
+        if self.form == Form::SSA {
+            // An in-out operand of inline assembly is written again by
+            // the ASM, which is not counted as a definition.
+            let mut tied: HashMap<i32, usize> = HashMap::new();
+            for bb in self.fun.bbs.iter() {
+                for ir in bb.borrow().ir.iter() {
+                    for r in tied_outs(&ir.borrow()) {
+                        *tied.entry(r.borrow().vn).or_insert(0) += 1;
+                    }
+                }
+            }
+            let is_redefined = |vn: i32| ndefs[&vn] - tied.get(&vn).unwrap_or(&0) > 1;
+
+            let mut seen = HashSet::new();
+            for bb in self.fun.bbs.iter() {
+                let bb = bb.borrow();
+                for r in bb.params.iter() {
+                    if is_redefined(r.borrow().vn) && seen.insert(r.borrow().vn) {
+                        let msg = format!("{} is defined more than once", reg_name(r));
+                        self.error(&bb, None, msg);
+                    }
+                }
+                for ir in bb.ir.iter() {
+                    for r in defs(&ir.borrow()) {
+                        if is_redefined(r.borrow().vn) && seen.insert(r.borrow().vn) {
+                            let msg = format!("{} is defined more than once", reg_name(&r));
+                            self.error(&bb, Some(&ir.borrow()), msg);
+                        }
+                    }
+                }
+            }
+        }
+
+        for (i, bb) in self.fun.bbs.iter().enumerate() {
+            let mut defined = match defined_in[i] {
+                Some(ref s) => s.clone(),
+                None => continue,
+            };
+            let bb = bb.borrow();
+            for r in bb.params.iter() {
+                set_bit(&mut defined, &index, self.key(&r));
+            }
+
+            for ir in bb.ir.iter() {
+                let ir = ir.borrow();
+                for r in uses(&ir) {
+                    let key = self.key(&r);
+                    if !test_bit(&defined, &index, key) && (allocated || ndefs.contains_key(&key)) {
+                        let msg = format!("{} is not defined on all paths", reg_name(&r));
+                        self.error(&bb, Some(&ir), msg);
+                    }
+                }
+
+                if let (Some(r0), Some(r1)) = (&ir.r0, &ir.r1) {
+                    let same = self.key(r0) == self.key(r1);
+                    if allocated && !same {
+                        self.error(&bb, Some(&ir), "not in two-address form".to_string());
+                    }
+                    if !allocated && same {
+                        self.error(&bb, Some(&ir), "r0 and r1 are the same".to_string());
+                    }
+                }
+
+                for r in defs(&ir).iter().chain(uses(&ir).iter()) {
+                    let rn = r.borrow().rn;
+                    if allocated && (rn < 0 || rn as usize >= num_regs()) {
+                        let msg = format!("r{} has no physical register", r.borrow().vn);
+                        self.error(&bb, Some(&ir), msg);
+                    }
+                }
+
+                for r in defs(&ir) {
+                    set_bit(&mut defined, &index, self.key(&r));
+                }
+            }
+        }
+    }
+
+    fn check_sizes(&mut self) {
+        for bb in self.fun.bbs.iter() {
+            let bb = bb.borrow();
+            for ir in bb.ir.iter() {
+                let ir = ir.borrow();
+                let sizes: &[i32] = match ir.op {
+                    IRType::LOAD
+                    | IRType::STORE
+                    | IRType::ATOMIC_LOAD
+                    | IRType::ATOMIC_STORE
+                    | IRType::ATOMIC_FETCH_ADD
+                    | IRType::ATOMIC_CAS => &[1, 2, 4, 8],
+                    IRType::STORE_ARG => &[1, 4, 8],
+                    IRType::CLZ | IRType::CTZ | IRType::POPCOUNT => &[4, 8],
+                    _ => continue,
+                };
+                if !sizes.contains(&ir.size) {
+                    self.error(&bb, Some(&ir), format!("invalid size {}", ir.size));
+                }
+            }
+        }
+    }
+}
+
+fn verify_fn(fun: &Function, form: Form) -> Vec<String> {
+    let mut v = Verifier {
+        fun: fun,
+        form: form,
+        errors: Vec::new(),
+    };
+    v.check_structure();
+    if !v.errors.is_empty() {
+        return v.errors;
+    }
+    v.check_edges();
+    v.check_params();
+    v.check_regs();
+    v.check_sizes();
+    return v.errors;
+}
+
+// Checks the IR after `pass` and aborts if it is broken.
+pub fn verify(prog: &Program, pass: &str, form: Form) {
+    let mut ok = true;
+    for fun in prog.funcs.iter() {
+        let errors = verify_fn(&fun.borrow(), form);
+        if errors.is_empty() {
+            continue;
+        }
+
+        eprintln!("invalid IR in {} after {}:", fun.borrow().name, pass);
+        for e in errors.iter() {
+            eprintln!("  {}", e);
+        }
+        dump_ir(vec![fun.clone()]);
+        ok = false;
+    }
+    if !ok {
+        panic!("IR verification failed after {}", pass);
+    }
+}
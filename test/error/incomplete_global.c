@@ -0,0 +1,2 @@
+struct fwd;
+struct fwd x;
@@ -0,0 +1,2 @@
+struct fwd;
+int f() { struct fwd x; return 0; }
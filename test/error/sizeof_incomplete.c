@@ -0,0 +1,2 @@
+struct fwd;
+int f(struct fwd *p) { return sizeof(*p); }
@@ -0,0 +1,2 @@
+// -1 is converted to unsigned int, so the comparison is false.
+_Static_assert(-1 < 0u, "usual arithmetic conversions");
@@ -1,3 +1,27 @@
 // This file is compiled by gcc.
 
 int global_arr[1] = {5};
+
+// Defined in test.c, or merged with its tentative definition as a
+// common symbol when both are built with -fcommon.
+#ifdef COMMON
+int common_var;
+#else
+extern int common_var;
+#endif
+void set_common_var(int x) { common_var = x; }
+
+// Keeps values in callee-saved registers across a call to a function
+// compiled by r9ir.
+int clobber_regs(int x);
+int keep_regs(int x) {
+  register long a asm("rbx") = x;
+  register long b asm("r12") = x + 1;
+  register long c asm("r13") = x + 2;
+  register long d asm("r14") = x + 3;
+  register long e asm("r15") = x + 4;
+  asm volatile("" : "+r"(a), "+r"(b), "+r"(c), "+r"(d), "+r"(e));
+  long y = clobber_regs(x);
+  asm volatile("" : "+r"(a), "+r"(b), "+r"(c), "+r"(d), "+r"(e));
+  return a + b + c + d + e + y;
+}
@@ -0,0 +1,119 @@
+// This file contains functions which the optimizer is expected to
+// transform. The Makefile builds it at -O2 and checks their assembly.
+
+int printf();
+
+// The loop variables are kept in registers.
+int sum_loop(int *a, int n) {
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s += a[i];
+  return s;
+}
+
+__attribute__((noinline)) int fill(int *a, int n) {
+  for (int i = 0; i < n; i++)
+    a[i] = i;
+  return a[n - 1];
+}
+
+// The arrays escape, but they share a slot since their scopes are
+// disjoint.
+int sibling_arrays(int n) {
+  int s = 0;
+  { int a[100]; s += fill(a, n); }
+  { int b[100]; s += fill(b, n); }
+  { int c[100]; s += fill(c, n); }
+  return s;
+}
+
+// Likewise in a loop, whose addresses stay in the loop.
+int loop_arrays(int n) {
+  int s = 0;
+  for (int i = 0; i < n; i++) {
+    { int a[100]; s += fill(a, i + 1); }
+    { int b[100]; s += fill(b, i + 1); }
+  }
+  return s;
+}
+
+// The condition is constant, so the branch and the dead arm fold away.
+int const_branch(int n) {
+  int x = 3;
+  int y;
+  if (x > 2)
+    y = x * 4;
+  else
+    y = n;
+  return y;
+}
+
+// The second load of *p is redundant.
+int twice_load(int *p) {
+  return *p + *p;
+}
+
+// x is not written in the loop, so x[1] is loaded before it.
+int hoisted_load(int n) {
+  int x[2];
+  fill(x, 2);
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s += x[1];
+  return s;
+}
+
+// The multiplication becomes a shift.
+int times8(int x) {
+  return x * 8;
+}
+
+// The loaded values are already extended, so they are not extended
+// again when they are assigned to variables.
+char opt_c = 200;
+
+int loaded_sum(int *p) {
+  int x = *p;
+  char c = opt_c;
+  return x + c;
+}
+
+// The peephole optimizer removes the moves around imul and fuses the
+// comparison with the branch.
+int product(int a, int b) {
+  return a * b;
+}
+
+int smaller(int a, int b) {
+  if (a < b)
+    return a;
+  return b;
+}
+
+int main() {
+  int a[4];
+  for (int i = 0; i < 4; i++)
+    a[i] = i + 1;
+  if (sum_loop(a, 4) != 10)
+    return 1;
+  if (sibling_arrays(100) != 297)
+    return 1;
+  if (loop_arrays(3) != 6)
+    return 1;
+  if (const_branch(5) != 12)
+    return 1;
+  if (twice_load(a) != 2)
+    return 1;
+  if (hoisted_load(3) != 3)
+    return 1;
+  if (times8(-3) != -24)
+    return 1;
+  if (loaded_sum(a) != 201)
+    return 1;
+  if (product(-3, 7) != -21)
+    return 1;
+  if (smaller(5, -2) != -2)
+    return 1;
+  printf("OK\n");
+  return 0;
+}
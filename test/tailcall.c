@@ -0,0 +1,23 @@
+// This file contains tests which recurse too deep to run without tail
+// calls, so it is built at -O1 and above.
+
+int printf();
+
+int even(int n);
+int odd(int n) { if (n == 0) return 0; return even(n - 1); }
+int even(int n) { if (n == 0) return 1; return odd(n - 1); }
+
+int tail_count(int n, int acc) {
+  if (n == 0)
+    return acc;
+  return tail_count(n - 1, acc + 1);
+}
+
+int main() {
+  if (tail_count(10000000, 0) != 10000000)
+    return 1;
+  if (even(10000000) != 1)
+    return 1;
+  printf("OK\n");
+  return 0;
+}
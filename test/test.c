@@ -4,6 +4,8 @@ int printf();
 int fprintf();
 int exit();
 
+#include "include/stddef.h"
+
 #define EXPECT(expected, expr)                                  \
   do {                                                          \
     int e1 = (expected);                                        \
@@ -31,6 +33,210 @@ int var1;
 int var2[5];
 extern int global_arr[1];
 typedef int myint;
+typedef typeof(0u) uint;
+typedef typeof(0ul) ulong;
+
+_Static_assert(sizeof(var1) == 4, "int must be 4 bytes");
+_Alignas(64) char galign1;
+char _Alignas(32) galign2;
+_Thread_local int tls1;
+_Thread_local int tls2[3];
+_Noreturn void noreturn_fn();
+
+int gsec1 __attribute__((section(".data.r9ir"))) __attribute__((aligned(16)));
+int gweak1 __attribute__((weak));
+__attribute__((weak)) int weak_fn() { return 3; }
+int always_inline_fn() __attribute__((always_inline, __unused__)) { return 4; }
+void die() __attribute__((__noreturn__));
+typedef int aligned_int __attribute__((aligned(8)));
+
+int tent1;
+int tent1;
+int tent1 = 5;
+extern int tent2;
+int tent2 = 6;
+extern int tent2;
+int tent3;
+char gch = 'a';
+long glong = 0x100000002;
+int gfold1 = -1 < 0u;
+int gfold2 = (0xffffffffu + 1) == 0;
+int gfold3 = -1u >> 31;
+int gfold4 = -8 / 2u > 0;
+long gfold5 = -1u;
+long gfold6 = 0xffffffffu * 2;
+char gfold7[-1 < 0u ? 1 : 4];
+_Static_assert(-1 > 0u && -1ul > 0, "unsigned constant expression");
+_Bool gbool = 7;
+extern int garr[];
+int garr[3];
+int common_var;
+int common_var;
+void set_common_var();
+int keep_regs();
+
+struct ofs { char a; int b[3]; struct { int x; long y; } l; };
+
+_Atomic int acount;
+_Atomic(long) along;
+
+struct list { int val; struct list *next; };
+struct tree;
+struct forest { int n; struct tree *first; };
+struct tree { int val; struct forest *children; };
+typedef struct handle Handle;
+struct handle { int id; };
+
+int offsetof_l() { return offsetof(struct ofs, l); }
+
+int vla_sum(int n) {
+  int a[n];
+  for (int i = 0; i < n; i++)
+    a[i] = i;
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s = s + a[i];
+  return s;
+}
+
+int vla_param(int n, int m, int a[n][m]) { return a[1][2] + sizeof(a[0]); }
+
+int vla_loop(int n) {
+  int s = 0;
+  for (int i = 0; i < 100000; i++) {
+    char buf[n];
+    buf[n - 1] = 1;
+    s = s + buf[n - 1];
+    if (i % 2)
+      continue;
+    do {
+      char buf2[n];
+      buf2[0] = 0;
+      break;
+    } while (0);
+  }
+  return s;
+}
+
+// Memory from alloca outlives the block of a variable-length array.
+int alloca_in_vla(int n) {
+  int *p;
+  {
+    int a[n];
+    a[0] = 1;
+    p = __builtin_alloca(64);
+    p[0] = 7;
+  }
+  int *q = __builtin_alloca(128);
+  for (int i = 0; i < 32; i++)
+    q[i] = 99;
+  return p[0];
+}
+
+int dead_code(int x) {
+  int y = x * 7;
+  if (0)
+    return y;
+  return x + 1;
+  x = 5;
+  return x;
+}
+
+inline int inline_sum(int n) {
+  int s = 0;
+  for (int i = 1; i <= n; i++)
+    s = s + i;
+  return s;
+}
+
+int noinline_fn(int x) __attribute__((noinline)) { return x * 2; }
+int swap_arg(int *p, int x) { int t = *p; *p = x; return t; }
+int addr_param(int x) { int *p = &x; *p = *p + 1; return x; }
+int even(int n);
+int odd(int n) { if (n == 0) return 0; return even(n - 1); }
+int even(int n) { if (n == 0) return 1; return odd(n - 1); }
+
+int tail_count(int n, int acc) {
+  if (n == 0)
+    return acc;
+  return tail_count(n - 1, acc + 1);
+}
+
+int deref_fn(int *p) __attribute__((noinline)) { return *p; }
+int pass_addr(int n) { int x = n; return deref_fn(&x); }
+
+int is_neg(int x) __attribute__((noinline)) { return x < 0; }
+uint to_unsigned(int x) { return x; }
+long dlong(long x) { return x / 10; }
+int char_arg(char c) __attribute__((noinline)) { return c; }
+int sub4(int a, int b, int c, int d) __attribute__((noinline)) { return a * 1000 + b * 100 + c * 10 + d; }
+int rotate_args(int a, int b, int c, int d) __attribute__((noinline)) {
+  int x = a + 1;
+  int y = b + 1;
+  int z = c + 1;
+  int w = d + 1;
+  return sub4(w, z, y, x);
+}
+
+int clobber_regs(int x) {
+  int a = x + 1;
+  int b = x + 2;
+  int c = x + 3;
+  int d = x + 4;
+  int e = x + 5;
+  int f = x + 6;
+  int g = x + 7;
+  int h = x + 8;
+  for (int i = 0; i < x; i++) {
+    a = a + b;
+    b = b + c;
+    c = c + d;
+    d = d + e;
+    e = e + f;
+    f = f + g;
+    g = g + h;
+    h = h + a;
+  }
+  return a + b + c + d + e + f + g + h;
+}
+
+int ptr_sum(int *p, int n) __attribute__((noinline)) {
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s = s + *p++;
+  return s;
+}
+
+int *ptr_fill(int *p, int n, int v) __attribute__((noinline)) {
+  for (int i = 0; i < n; i++)
+    p[i] = v;
+  return p;
+}
+
+// Escaped arrays in nested and sibling blocks
+int scoped_arrays(int n) {
+  int s = 0;
+  for (int i = 0; i < n; i++) {
+    int a[4];
+    ptr_fill(a, 4, i);
+    if (i == 1)
+      continue;
+    { int b[4]; ptr_fill(b, 4, 10); s = s + ptr_sum(b, 4); }
+    { int c[4]; int *p = ptr_fill(c, 4, 100); if (i == 3) break; s = s + *p; }
+    s = s + ptr_sum(a, 4);
+  }
+  return s;
+}
+
+int cmp_branch(int a, int b) __attribute__((noinline)) {
+  int c = a < b;
+  if (c)
+    return (c + 10) * a;
+  return c - a;
+}
+
+asm(".global asm_fn\nasm_fn:\n\tmov eax, 42\n\tret");
+int asm_fn();
 
 // Single-line comment test
 
@@ -141,6 +347,84 @@ int main() {
   EXPECT(7, ({ int i=0; for (int j=0; j < 10; j++) { if (j<3) continue; i++; } i; }));
 
   EXPECT(45, ({ int i=0; int j=0; while (i<10) { j=j+i; i=i+1; } j;}));
+  EXPECT(21, ({ int a=1; int b=2; int n=3; while (n) { int t=a; a=b; b=t; n=n-1; } a*10+b; }));
+  EXPECT(231, ({ int a=1; int b=2; int c=3; for (int i=0; i<2; i=i+1) { int t=a; a=c; c=b; b=t; } a*100+b*10+c; }));
+  EXPECT(4, ({ int x=0; for (int i=0; i<6; i=i+1) { if (i==2) continue; if (i==5) break; x=x+1; } x; }));
+  EXPECT(12, ({ int x=3; int y; if (x<5) { if (x==3) y=12; else y=7; } else y=9; y; }));
+  EXPECT(6, ({ int k=1; int i=0; while (i<5) { if (k==1) k=1; else k=2; i=i+k; } k+i; }));
+  EXPECT(0, ({ int x=0; if (x) x=1/x; x; }));
+  EXPECT(9, ({ int x=3; int y=x*4-3; if (y==9) x=y; else x=one(); x; }));
+  EXPECT(4, dead_code(3));
+  EXPECT(55, inline_sum(10));
+  EXPECT(12, ({ int s=0; for (int i=0; i<3; i++) s=s+noinline_fn(i+1); s; }));
+  EXPECT(12, ({ int s=0; for (int i=0; i<3; i++) s=s+plus(i, mul(i, 3)); s; }));
+  EXPECT(37, ({ int a=3; int b=swap_arg(&a, 7); b*10+a; }));
+  EXPECT(6, addr_param(5));
+  EXPECT(1, even(10));
+  EXPECT(0, odd(4));
+  EXPECT(10000, tail_count(10000, 0));
+  EXPECT(1, even(10000));
+  EXPECT(5, pass_addr(5));
+  EXPECT(1, is_neg(-5));
+  EXPECT(0, is_neg(5));
+  EXPECT(1, ({ int v[1]; v[0]=-5; is_neg(v[0]); }));
+  EXPECT(-30, ({ int v[1]; v[0]=-100; dlong(v[0]*3); }));
+  EXPECT(44, char_arg(300));
+  EXPECT(10, ({ int a[4]; a[0]=1; a[1]=2; a[2]=3; a[3]=4; ptr_sum(a, 4); }));
+  EXPECT(328, scoped_arrays(5));
+  EXPECT(0, ({ char c=255; c=c+1; c; }));
+  EXPECT(1, ({ long v=-8; int q[2]; q[0]=v; q[0]<0; }));
+  EXPECT(1, ({ long v=-8; int q[2]; q[0]=v; long l=q[0]; l == -8; }));
+  EXPECT(-33, ({ int a[1]; a[0]=-100; a[0]/3; }));
+  EXPECT(-1, ({ int a[1]; a[0]=-100; a[0]%3; }));
+  EXPECT(-3, ({ int a[1]; a[0]=-6; a[0]>>1; }));
+  EXPECT(65535, ({ typeof(u"") s; s[0]=-1; s[0]; }));
+  EXPECT(0, -1 < 0u);
+  EXPECT(1, ({ uint x = -1; -2 < x; }));
+  EXPECT(1, ({ uint x = 3000000000u; x > 2000000000; }));
+  EXPECT(2147483647, ({ uint a[1]; a[0]=0; (a[0]-1)/2; }));
+  EXPECT(1, ({ uint a[1]; a[0]=-1; a[0]%2; }));
+  EXPECT(200, ({ char a[1]; a[0]=200; int x = a[0]; x; }));
+  EXPECT(-1, ({ uint a[1]; a[0]=-1; int x = a[0]; x; }));
+  EXPECT(1, ({ uint a[1]; a[0]=-1; long x = a[0]; x == 4294967295; }));
+  EXPECT(-1, ({ long a[1]; a[0]=-1; int x = a[0]; x; }));
+  EXPECT(65535, ({ typeof(u"") s; s[0]=-1; int x = s[0]; x; }));
+  EXPECT(255, ({ typeof(u"") s; s[0]=-1; char c = s[0]; c; }));
+  EXPECT(1, to_unsigned(-1) > 0);
+  EXPECT(1, ({ ulong x = -1; x > 1; }));
+  EXPECT(1, ({ ulong x = -1; x >> 63; }));
+  EXPECT(1, ({ long v=1; long r = (v ? 0 : 1u) - 3; r == 4294967293; }));
+  EXPECT(1, ({ int c=1; (c ? -1 : 0u) > 0; }));
+  EXPECT(1, ({ int c=0; long r = c ? 1u : -1; r == 4294967295; }));
+  EXPECT(8, ({ int c=1; sizeof(c ? 1 : 2L); }));
+  EXPECT(-1, ({ int c=1; long r = c ? -1 : 2L; r; }));
+  EXPECT(-4, ({ long x = -16; x >> 2; }));
+  EXPECT(1, ({ int i=0x100000001; i; }));
+  EXPECT(5432, rotate_args(1, 2, 3, 4));
+  EXPECT(571, keep_regs(3));
+  EXPECT(30, ({ int s=0; { int a[2]; a[0]=10; a[1]=5; s=s+a[0]+a[1]; } { int b[2]; b[0]=7; b[1]=8; s=s+b[0]+b[1]; } s; }));
+  EXPECT(22, cmp_branch(2, 3));
+  EXPECT(-3, cmp_branch(3, 2));
+  EXPECT(45, ({ int s=0; for (int i=0; i<3; i++) { int a[3]; for (int j=0; j<3; j++) a[j]=i*3+j; { int b[3]; b[0]=a[0]+a[1]+a[2]; s=s+b[0]; } } s+9; }));
+  EXPECT(3, ({ int n=0; int y=n+5; y=one()+two(); n=plus(n, y); n; }));
+  EXPECT(2, ({ int n=0; for (;;) { n=n+1; if (n==2) break; } n; }));
+  EXPECT(12, ({ int a[3]; a[1]=5; int x=a[1]; a[1]=7; x+a[1]; }));
+  EXPECT(10, ({ int a[2]; int *p=a; a[0]=1; int x=a[0]; *p=9; x+a[0]; }));
+  EXPECT(8, ({ struct { int a; int b; } s; s.a=2; s.b=3; s.a*s.b+s.a; }));
+  EXPECT(15, ({ common_var=1; int a=common_var; set_common_var(5); a*10+common_var; }));
+  EXPECT(9, ({ int x=3; int y=x*x; int z=0; if (y>5) z=x*x; z; }));
+  EXPECT(60, ({ int a[3]; a[0]=1; a[1]=2; a[2]=3; int n=2; int s=0; for (int i=0; i<3; i++) for (int j=0; j<5; j++) s=s+a[i]*n; s; }));
+  EXPECT(0, ({ int x=0; int y=0; for (int i=0; i<y; i++) x=x+100/y; x; }));
+  EXPECT(6, ({ int a[2]; a[0]=0; int *p=a; for (int i=0; i<3; i++) { *p=*p+i; a[1]=a[0]; } a[0]+a[1]; }));
+  EXPECT(12, ({ int x=3; int s=0; while (s<10) { int *p=&x; s=s+*p; x=x+0; } s; }));
+  EXPECT(7, ({ long a[1]; a[0]=-7; a[0]/2+10; }));
+  EXPECT(9, ({ long a[1]; a[0]=-7; a[0]%2+10; }));
+  EXPECT(8, ({ long a[1]; a[0]=-7; a[0]/3+10; }));
+  EXPECT(9, ({ long a[1]; a[0]=-7; a[0]%3+10; }));
+  EXPECT(14, ({ long a[1]; a[0]=100; a[0]/7; }));
+  EXPECT(2, ({ long a[1]; a[0]=100; a[0]%7; }));
+  EXPECT(45, ({ long a[1]; a[0]=5; a[0]*9+a[0]*0; }));
+  EXPECT(1, ({ long a[1]; a[0]=5; !(a[0]<3) + (a[0]<=4); }));
 
   EXPECT(6, ({ int x=0; switch(3) { case 2: x=5; break; case 3: x=6; break; case 4: x=7; break; } x; }));
   EXPECT(7, ({ int x=0; switch(3) { case 2: x=5; case 3: x=6; case 4: x=7; } x; }));
@@ -240,6 +524,11 @@ int main() {
   EXPECT(1, ({ int i=5; i&=3; i; }));
   EXPECT(6, ({ int i=5; i^=3; i; }));
   EXPECT(7, ({ int i=5; i|=3; i; }));
+  EXPECT(1, ({ char c=255; c+=2; c; }));
+  EXPECT(255, ({ char c=255; c++; }));
+  EXPECT(0, ({ char c=255; c++; c; }));
+  EXPECT(117, ({ int i=5; int j=i++ + i++; j*10+i; }));
+  EXPECT(9, ({ int i=5; i-=2; i*=i; i; }));
 
   EXPECT(5, ({ int x; typeof(x) y = 5; y; }));
   EXPECT(1, ({ char x; typeof(x) y = 257; y; }));
@@ -258,6 +547,211 @@ int main() {
   EXPECT(-1, ({ _Bool x = 0; ~x; }));
   EXPECT(-2, ({ _Bool x = 1; ~x; }));
 
+  _Static_assert(1 + 2 * 3 == 7 && (4 >> 1) == 2, "constant folding");
+  EXPECT(0, ({ char *p = &galign1; int a = p; a % 64; }));
+  EXPECT(0, ({ char *p = &galign2; int a = p; a % 32; }));
+  EXPECT(0, ({ char x; _Alignas(16) char y; char *p = &y; int a = p; a % 16; }));
+  EXPECT(0, ({ char x; _Alignas(64) char y; char *p = &y; int a = p; a % 64; }));
+  EXPECT(0, ({ char x; _Alignas(int) char y; char *p = &y; int a = p; a % 4; }));
+  EXPECT(32, ({ struct { char a; _Alignas(16) char b; } x; sizeof(x); }));
+  EXPECT(7, ({ tls1 = 7; tls1; }));
+  EXPECT(9, ({ tls2[0] = 4; tls2[2] = 5; tls2[0] + tls2[2]; }));
+  EXPECT(1, _Generic(1, int: 1, char: 2, default: 3));
+  EXPECT(2, ({ char x; _Generic(x, int: 1, char: 2, default: 3); }));
+  EXPECT(3, ({ int *x; _Generic(x, int: 1, char: 2, default: 3); }));
+  EXPECT(4, ({ int *x; _Generic(x, default: 3, int *: 4); }));
+  EXPECT(5, _Generic("abc", char *: 5, int: 6));
+  EXPECT(8, sizeof(_Generic(0, int: var2[0], int *: 0)) * 2);
+
+  EXPECT(5, ({ struct __attribute__((packed)) { char a; int b; } x; sizeof(x); }));
+  EXPECT(5, ({ struct { char a; int b; } __attribute__((__packed__)) x; sizeof(x); }));
+  EXPECT(1, ({ struct { char a; int b; } __attribute__((packed)) x; _Alignof(x); }));
+  EXPECT(7, ({ struct { char a; int b; } __attribute__((packed)) x; x.a = 3; x.b = 4; x.a + x.b; }));
+  EXPECT(8, ({ struct { char a; } __attribute__((aligned(8))) x; sizeof(x); }));
+  EXPECT(8, ({ struct { char a; } __attribute__((aligned(8))) x; _Alignof(x); }));
+  EXPECT(32, ({ struct { char a; int b __attribute__((aligned(16))); } x; sizeof(x); }));
+  EXPECT(0, ({ char x; char y __attribute__((aligned(32))); char *p = &y; int a = p; a % 32; }));
+  EXPECT(0, ({ int *p = &gsec1; int a = p; a % 16; }));
+  EXPECT(5, ({ gsec1 = 5; gsec1; }));
+  EXPECT(6, ({ gweak1 = 6; gweak1; }));
+  EXPECT(3, weak_fn());
+  EXPECT(4, always_inline_fn());
+  EXPECT(8, ({ aligned_int x; _Alignof(x); }));
+  EXPECT(2, ({ int x __attribute__((unused)); 2; }));
+
+  EXPECT(45, vla_sum(10));
+  EXPECT(40, ({ int n = 10; int a[n]; sizeof(a); }));
+  EXPECT(120, ({ int n = 5; int m = 6; int a[n][m]; sizeof(a); }));
+  EXPECT(24, ({ int n = 5; int m = 6; int a[n][m]; sizeof(a[0]); }));
+  EXPECT(60, ({ int n = 5; int a[n][3]; sizeof(a); }));
+  EXPECT(23, ({ int n = 3; int m = 4; int a[n][m]; for (int i = 0; i < n; i++) for (int j = 0; j < m; j++) a[i][j] = i * 10 + j; a[2][3]; }));
+  EXPECT(12, ({ int n = 3; int m = 4; int a[n][m]; int (*p)[m] = a; p[1][2] = 12; a[1][2]; }));
+  EXPECT(19, ({ int x[2][3]; x[1][2] = 7; vla_param(2, 3, x); }));
+  EXPECT(16, ({ int n = 4; typedef int T[n]; n = 5; T a; sizeof(a); }));
+  EXPECT(100000, vla_loop(4096));
+  EXPECT(0, ({ int n = 3; char a[n]; char *p = a; int x = p; x % 16; }));
+  EXPECT(1, ({ int n = 3; char a[n]; a[0] = 1; fprintf(stderr, "%s", ""); a[0]; }));
+  EXPECT(7, ({ int *p = __builtin_alloca(16); p[0] = 3; p[3] = 4; p[0] + p[3]; }));
+  EXPECT(7, alloca_in_vla(4));
+  EXPECT(42, asm_fn());
+  EXPECT(1, ({ asm("nop"); __asm__ volatile ("pause\n\tnop"); 1; }));
+  EXPECT(5, ({ int x; asm("mov %0, 5" : "=r"(x)); x; }));
+  EXPECT(6, ({ int x; int y = 3; asm("mov %0, %1\n\tadd %0, %0" : "=&r"(x) : "r"(y)); x; }));
+  EXPECT(7, ({ int x = 4; asm("add %0, 3" : "+r"(x)); x; }));
+  EXPECT(8, ({ int x; asm("mov %0, %1" : "=r"(x) : "i"(8)); x; }));
+  EXPECT(9, ({ int x = 1; asm("mov dword ptr %0, 9" : "=m"(x)); x; }));
+  EXPECT(10, ({ int x = 10; int y; asm("mov %0, %1" : "=r"(y) : "m"(x)); y; }));
+  EXPECT(11, ({ int x; asm("mov eax, 11" : "=a"(x)); x; }));
+  EXPECT(12, ({ int x; asm("mov %0, %1" : "=r"(x) : "c"(12)); x; }));
+  EXPECT(13, ({ int x; asm("mov %q0, 13" : "=r"(x)); x; }));
+  EXPECT(14, ({ int x; asm("mov %[out], %[in]" : [out] "=r"(x) : [in] "ri"(14)); x; }));
+  EXPECT(15, ({ int x; int y = 5; asm("lea %0, [%1+%1*2]" : "=r"(x) : "r"(y)); x; }));
+  EXPECT(16, ({ int x = 16; asm volatile("jmp .Lasm%=\n.Lasm%=:" ::: "memory", "cc"); x; }));
+  EXPECT(1, ({ int lo; int hi; asm volatile("rdtsc" : "=a"(lo), "=d"(hi)); lo || hi; }));
+  EXPECT(6, ({ int a = 1; int b = 2; int c = 3; asm("mov r10, 0\n\tmov r11, 0\n\tmov r12, 0\n\tmov r13, 0\n\tmov r14, 0" ::: "r10", "r11", "r12", "r13", "r14"); a + b + c; }));
+  EXPECT(17, ({ volatile int x = 17; x; }));
+  EXPECT(18, ({ int volatile x; x = 9; x + x; }));
+  EXPECT(4, ({ volatile int x; sizeof(x); }));
+  EXPECT(10, ({ int x = 5; volatile int *p = &x; *p + *p; }));
+  EXPECT(7, ({ int x = 7; int *volatile p = &x; *p; }));
+  EXPECT(8, ({ int x = 3; volatile int *p = &x; *p = 4; *p + x; }));
+  EXPECT(8, ({ typedef volatile int vint; vint x = 4; x + x; }));
+
+  EXPECT(8, sizeof(L"a"));
+  EXPECT(6, sizeof(u"ab"));
+  EXPECT(16, sizeof(U"abc"));
+  EXPECT(3, sizeof(u8"\u00e9"));
+  EXPECT(4, sizeof("あ"));
+  EXPECT(227, "あ"[0]);
+  EXPECT(12354, L"あ"[0]);
+  EXPECT(12354, u"あい"[0]);
+  EXPECT(12356, U"あい"[1]);
+  EXPECT(12354, L'あ');
+  EXPECT(12354, u'あ');
+  EXPECT(128512, U'\U0001F600');
+  EXPECT(55357, u"\U0001F600"[0]);
+  EXPECT(56832, u"\U0001F600"[1]);
+  EXPECT(0, u"\U0001F600"[2]);
+  EXPECT(195, "\u00e9"[0]);
+  EXPECT(169, "\u00e9"[1]);
+  EXPECT(233, L"\u00e9"[0]);
+  EXPECT(255, L"\xff"[0]);
+  EXPECT(97, u8'a');
+  EXPECT(12, sizeof("a" L"b"));
+  EXPECT(12354, ("x" L"あ")[1]);
+  EXPECT(98, ({ int *p = L"abc"; p[1]; }));
+  EXPECT(5, ({ int 変数 = 5; 変数; }));
+  EXPECT(6, ({ int caf\u00e9 = 6; café; }));
+
+  EXPECT(5, 0b101);
+  EXPECT(10, 0B1010);
+  EXPECT(1, 0x123456789 >> 32);
+  EXPECT(1, 0xFFFFFFFFFFFFFFFF == -1);
+  EXPECT(4, sizeof(2147483647));
+  EXPECT(8, sizeof(2147483648));
+  EXPECT(4, sizeof(0xFFFFFFFF));
+  EXPECT(8, sizeof(0x100000000));
+  EXPECT(4, sizeof(1u));
+  EXPECT(8, sizeof(1L));
+  EXPECT(8, sizeof(1ll));
+  EXPECT(8, sizeof(1uLL));
+  EXPECT(8, sizeof(1LLU));
+  EXPECT(8, sizeof(1 + 1L));
+  EXPECT(8, sizeof(1L << 1));
+  EXPECT(4, sizeof(1 << 1L));
+  EXPECT(0, _Generic(1, int: 0, default: 1));
+  EXPECT(1, _Generic(1u, int: 0, default: 1));
+  EXPECT(1, _Generic(0x80000000, int: 0, default: 1));
+  EXPECT(1, _Generic(2147483648, int: 0, long: 1, default: 2));
+  EXPECT(8, ({ long x; sizeof(x); }));
+  EXPECT(8, ({ long long x; sizeof(x); }));
+  EXPECT(8, ({ long int x; sizeof(x); }));
+  EXPECT(255, ({ ulong x = 0xFFFFFFFF00000000; x >> 56; }));
+  EXPECT(-1, ({ long x = 0xFFFFFFFF00000000; x >> 56; }));
+  EXPECT(1, ({ long x = 0xFFFFFFFF00000000; (x & 0xFFFFFFFF) == 0; }));
+  EXPECT(2, ({ long x = 0x100000001; x + (x >> 32); }));
+
+  EXPECT(3, ({ struct list a; struct list b; a.next = &b; b.val = 3; a.next->val; }));
+  EXPECT(6, ({ struct list a; struct list b; a.next = &b; b.next = &a; a.val = 6; a.next->next->val; }));
+  EXPECT(7, ({ struct tree t; struct forest f; f.first = &t; t.children = &f; f.n = 7; f.first->children->n; }));
+  EXPECT(4, ({ Handle h; h.id = 4; Handle *p = &h; p->id; }));
+  EXPECT(8, ({ struct list { int a; int b; } x; sizeof(x); }));
+  EXPECT(16, ({ struct list x; sizeof(x); }));
+  EXPECT(5, ({ struct fwd; struct fwd *p; struct fwd { int v; } x; p = &x; p->v = 5; x.v; }));
+
+  EXPECT(5, tent1);
+  EXPECT(6, tent2);
+  EXPECT(0, tent3);
+  EXPECT(97, gch);
+  EXPECT(2, glong);
+  EXPECT(1, glong >> 32);
+  EXPECT(0, gfold1);
+  EXPECT(1, gfold2);
+  EXPECT(1, gfold3);
+  EXPECT(1, gfold4);
+  EXPECT(1, gfold5 == 4294967295);
+  EXPECT(1, gfold6 == 4294967294);
+  EXPECT(4, sizeof(gfold7));
+  EXPECT(3, ({ char a[(0u - 1) / 2 > 0 ? 3 : 1]; sizeof(a); }));
+  EXPECT(1, gbool);
+  EXPECT(12, sizeof(garr));
+  EXPECT(9, ({ set_common_var(9); common_var; }));
+
+  EXPECT(3, ({ _Atomic int x = 3; x; }));
+  EXPECT(4, ({ _Atomic int x; sizeof(x); }));
+  EXPECT(8, ({ _Atomic(long) x; sizeof(x); }));
+  EXPECT(5, ({ _Atomic int x = 3; x += 2; x; }));
+  EXPECT(6, ({ _Atomic int x = 3; x *= 2; }));
+  EXPECT(3, ({ _Atomic int x = 3; x++; }));
+  EXPECT(4, ({ _Atomic int x = 3; x++; x; }));
+  EXPECT(2, ({ _Atomic int x = 3; --x; }));
+  EXPECT(0, ({ _Atomic char x = 255; x++; x; }));
+  EXPECT(7, ({ int i = 0; _Atomic int x = 5; x += (i++, 2); x + i - 1; }));
+  EXPECT(8, ({ int a[4]; _Atomic(int *) p = a; p++; p += 2; (p - a) * 2 + 2; }));
+  EXPECT(10, ({ for (int i = 0; i < 10; i++) acount++; acount; }));
+  EXPECT(12, ({ int x = 12; __atomic_load_n(&x, 5); }));
+  EXPECT(7, ({ int x = 0; __atomic_store_n(&x, 7, 5); x; }));
+  EXPECT(2, ({ int x = 2; __atomic_fetch_add(&x, 3, 5); }));
+  EXPECT(5, ({ int x = 2; __atomic_fetch_add(&x, 3, 5); x; }));
+  EXPECT(1, ({ int x = 2; int e = 2; __atomic_compare_exchange_n(&x, &e, 9, 0, 5, 5); }));
+  EXPECT(9, ({ int x = 2; int e = 2; __atomic_compare_exchange_n(&x, &e, 9, 0, 5, 5); x; }));
+  EXPECT(0, ({ int x = 2; int e = 3; __atomic_compare_exchange_n(&x, &e, 9, 0, 5, 5); }));
+  EXPECT(2, ({ int x = 2; int e = 3; __atomic_compare_exchange_n(&x, &e, 9, 0, 5, 5); e; }));
+  EXPECT(4, ({ char x = 4; char e = 4; __atomic_compare_exchange_n(&x, &e, 200, 0, 5, 5); e; }));
+  EXPECT(200, ({ char x = 4; char e = 4; __atomic_compare_exchange_n(&x, &e, 200, 0, 5, 5); x; }));
+  EXPECT(1, ({ along = 0x100000000; __atomic_fetch_add(&along, 1, 5); along >> 32; }));
+  EXPECT(1, ({ __sync_synchronize(); 1; }));
+
+  EXPECT(16, offsetof_l());
+  EXPECT(4, __builtin_offsetof(struct ofs, b));
+  EXPECT(12, __builtin_offsetof(struct ofs, b[2]));
+  EXPECT(24, __builtin_offsetof(struct ofs, l.y));
+  EXPECT(8, sizeof(__builtin_offsetof(struct ofs, a)));
+  EXPECT(3, __builtin_expect(3, 1));
+  EXPECT(5, ({ int x = 0; if (__builtin_expect(x == 0, 0)) x = 5; else x = 6; x; }));
+  EXPECT(6, ({ int x = 1; if (__builtin_expect(x == 0, 0)) { x = 5; } x + 5; }));
+  EXPECT(7, ({ int x = 1; if (__builtin_expect(x, 1)) x = 7; else { for (;;) x++; } x; }));
+  EXPECT(4, ({ int x = 3; if (x == 3) x = 4; else __builtin_unreachable(); x; }));
+  EXPECT(2, ({ int x = 2; if (x == 0) __builtin_trap(); x; }));
+  EXPECT(1, __builtin_constant_p(3 * 4 + 1));
+  EXPECT(0, ({ int x = 1; __builtin_constant_p(x); }));
+  EXPECT(1, ({ int x = 1; __builtin_constant_p(x++); x; }));
+  EXPECT(1, __builtin_types_compatible_p(int, int));
+  EXPECT(0, __builtin_types_compatible_p(int, long));
+  EXPECT(1, __builtin_types_compatible_p(int *, int *));
+  EXPECT(0, __builtin_types_compatible_p(int *, char *));
+  EXPECT(0, __builtin_types_compatible_p(struct list, struct tree));
+  EXPECT(31, __builtin_clz(1));
+  EXPECT(0, __builtin_clz(0x80000000));
+  EXPECT(63, __builtin_clzl(1));
+  EXPECT(27, ({ int x = 16; __builtin_clz(x); }));
+  EXPECT(3, __builtin_ctz(8));
+  EXPECT(32, __builtin_ctzl(0x100000000));
+  EXPECT(8, __builtin_popcount(0xff));
+  EXPECT(32, __builtin_popcount(-1));
+  EXPECT(64, __builtin_popcountl(-1));
+  EXPECT(2, __builtin_popcountll(0x100000001));
+
 	EXPECT(128, ((((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1)))+((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1))))+(((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1)))+((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1)))))+((((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1)))+((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1))))+(((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1)))+((((1+1)+(1+1))+(1+1)+(1+1))+(((1+1)+(1+1))+(1+1)+(1+1))))));
 
   printf("OK\n");
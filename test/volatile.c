@@ -0,0 +1,45 @@
+// This file contains functions whose volatile accesses must survive
+// the optimizer. The Makefile builds it at -O2 and counts the loads
+// in their assembly.
+
+int printf();
+
+int vol_deref(volatile int *p) { return *p + *p; }
+
+int vol_local() {
+  volatile int x = 3;
+  return x + x;
+}
+
+int vol_ptr(int *volatile *pp) { return **pp + **pp; }
+
+int vol_unused(volatile int *p) {
+  *p;
+  return 0;
+}
+
+// The load must stay inside the loop.
+int vol_loop(int n) {
+  volatile int x = 1;
+  int s = 0;
+  for (int i = 0; i < n; i++)
+    s = s + x;
+  return s;
+}
+
+int main() {
+  int x = 5;
+  int *px = &x;
+  if (vol_deref(&x) != 10)
+    return 1;
+  if (vol_local() != 6)
+    return 1;
+  if (vol_ptr(&px) != 10)
+    return 1;
+  if (vol_unused(&x) != 0)
+    return 1;
+  if (vol_loop(3) != 3)
+    return 1;
+  printf("OK\n");
+  return 0;
+}